@@ -1,49 +1,201 @@
-use std::{borrow::Cow, env, error::Error, fmt::Display, fs, path::Path, process, str::FromStr};
+use std::{
+    borrow::Cow,
+    env,
+    error::Error,
+    ffi::OsString,
+    fmt::Display,
+    fs, io, mem,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use pico_args::Arguments;
 
 use crate::{
-    constants, hls::Args as HlsArgs, http::Args as HttpArgs, output::Args as OutputArgs,
+    constants,
+    hls::Args as HlsArgs,
+    http::Args as HttpArgs,
+    output::{Args as OutputArgs, Sink, SummaryFormat},
+    session::Session,
     Args as MainArgs,
 };
 
+mod prefs;
+
+use prefs::Prefs;
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
 pub trait Parse {
     fn parse(&mut self, parser: &mut Parser) -> Result<()>;
 }
 
-pub fn parse() -> Result<(MainArgs, HttpArgs, HlsArgs, OutputArgs)> {
-    let mut parser = Parser::new()?;
+pub fn parse() -> Result<(MainArgs, HttpArgs, Vec<Session>)> {
+    parse_with(Parser::new()?)
+}
 
+fn parse_with(mut parser: Parser) -> Result<(MainArgs, HttpArgs, Vec<Session>)> {
     let mut main = MainArgs::default();
     let mut http = HttpArgs::default();
-    let mut hls = HlsArgs::default();
-    let mut output = OutputArgs::default();
 
     main.parse(&mut parser)?;
     http.parse(&mut parser)?;
-    output.parse(&mut parser)?;
-    hls.parse(&mut parser)?; //must be last because it parses the free args
 
-    if let Some(arg) = parser.finish() {
-        bail!("Unrecognized argument: {arg}");
+    let specs: Vec<String> = parser.parser.values_from_str("--session")?;
+    let sessions = if specs.is_empty() {
+        vec![parser.parse_session(None)?]
+    } else {
+        parser.parse_sessions(&specs)?
+    };
+
+    //logs move to stderr when stdout carries output meant for other programs
+    if main.check {
+        main.stdout.claim("--check")?;
+    }
+    if main.summary == SummaryFormat::Json {
+        main.stdout.claim("--summary=json")?;
+    }
+    for session in &sessions {
+        if session.hls.print_streams() {
+            main.stdout.claim("--print-streams")?;
+        }
+    }
+
+    Ok((main, http, sessions))
+}
+
+//Parses durations like 90, 90s, 90m, 1.5h
+pub fn parse_duration(arg: &str) -> Result<Duration> {
+    let (number, unit) = split_unit(arg);
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 60.0 * 60.0,
+        _ => bail!("Invalid duration unit: {arg}"),
+    };
+
+    Duration::try_from_secs_f64(number.parse::<f64>()? * multiplier)
+        .with_context(|| format!("Invalid duration: {arg}"))
+}
+
+//Parses sizes like 4096, 500k, 1.5g, 8GB (binary units)
+pub fn parse_size(arg: &str) -> Result<u64> {
+    let (number, unit) = split_unit(arg);
+    let unit = unit.to_ascii_lowercase();
+    let exponent = match unit.strip_suffix('b').unwrap_or(&unit) {
+        "" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        _ => bail!("Invalid size unit: {arg}"),
+    };
+
+    let size = number.parse::<f64>()? * 1024_f64.powi(exponent);
+    ensure!(size.is_finite() && size >= 1.0, "Invalid size: {arg}");
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(size as u64)
+}
+
+//Splits arguments on whitespace, quotes keep an argument with spaces together
+pub fn split_args(arg: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+
+    let mut chars = arg.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                in_arg = true;
+            }
+            (Some(q), c) if c == q => quote = None,
+            //only quotes and backslashes are escaped, so Windows paths work unchanged
+            (Some('"'), '\\') => match chars.next() {
+                Some(c @ ('"' | '\\')) => current.push(c),
+                Some(c) => {
+                    current.push('\\');
+                    current.push(c);
+                }
+                None => current.push('\\'),
+            },
+            (None, c) if c.is_whitespace() => {
+                if mem::take(&mut in_arg) {
+                    args.push(mem::take(&mut current));
+                }
+            }
+            (_, c) => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    ensure!(quote.is_none(), "Unterminated quote in {arg}");
+    if in_arg {
+        args.push(current);
+    }
+
+    Ok(args)
+}
+
+//Quotes an argument so split_args() gives it back unchanged
+pub fn quote_arg(arg: &str) -> Cow<'_, str> {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        return Cow::Borrowed(arg);
     }
 
-    Ok((main, http, hls, output))
+    Cow::Owned(format!(
+        r#""{}""#,
+        arg.replace('\\', r"\\").replace('"', r#"\""#)
+    ))
+}
+
+//Parsing happens before the logger is set up, so warnings are logged once it is
+pub fn deferred_warnings() -> Vec<String> {
+    mem::take(&mut *WARNINGS.lock().expect("Warnings mutex poisoned"))
+}
+
+fn warn_later(warning: String) {
+    WARNINGS
+        .lock()
+        .expect("Warnings mutex poisoned")
+        .push(warning);
+}
+
+fn split_unit(arg: &str) -> (&str, &str) {
+    let arg = arg.trim();
+    arg.split_at(
+        arg.find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(arg.len()),
+    )
 }
 
+#[derive(Clone)]
+#[allow(clippy::struct_field_names, reason = "pico_args parser")]
 pub struct Parser {
     parser: Arguments,
+    prefs: Option<String>,
     config: Option<String>,
+    config_dir: Option<PathBuf>,
 }
 
 impl Parser {
+    const MAX_INCLUDE_DEPTH: usize = 4;
+
     pub fn parse<T: FromStr>(&mut self, dst: &mut T, key: &'static str) -> Result<()>
     where
         <T as FromStr>::Err: Display + Send + Sync + Error + 'static,
     {
         let arg = self.parser.opt_value_from_str(key)?;
-        Ok(self.resolve(dst, arg, key, T::from_str)?)
+        self.resolve(dst, arg, key, T::from_str)
     }
 
     pub fn parse_free(&mut self, dst: &mut Option<String>, cfg_key: &'static str) -> Result<()> {
@@ -57,7 +209,7 @@ impl Parser {
 
     pub fn parse_switch(&mut self, dst: &mut bool, key: &'static str) -> Result<()> {
         let arg = self.parser.contains(key).then_some(true);
-        Ok(self.resolve(dst, arg, key, bool::from_str)?)
+        self.resolve(dst, arg, key, bool::from_str)
     }
 
     pub fn parse_switch_or(
@@ -67,7 +219,7 @@ impl Parser {
         key2: &'static str,
     ) -> Result<()> {
         let arg = (self.parser.contains(key1) || self.parser.contains(key2)).then_some(true);
-        Ok(self.resolve(dst, arg, key2, bool::from_str)?)
+        self.resolve(dst, arg, key2, bool::from_str)
     }
 
     pub fn parse_fn<T>(
@@ -115,41 +267,90 @@ impl Parser {
         self.resolve(dst, arg, key, Self::cow_string_impl)
     }
 
-    pub fn parse_cow_string_cfg(
-        &mut self,
-        dst: &mut Cow<'static, str>,
-        key: &'static str,
-        cfg_key: &'static str,
-    ) -> Result<()> {
-        let arg = self.parser.opt_value_from_fn(key, Self::cow_string_impl)?;
-        self.resolve(dst, arg, cfg_key, Self::cow_string_impl)
-    }
-
     fn resolve<T, E>(
         &self,
         dst: &mut T,
         val: Option<T>,
         key: &'static str,
         f: fn(_: &str) -> Result<T, E>,
-    ) -> Result<(), E> {
-        //unwrap arg or try to get arg from config file
+    ) -> Result<()>
+    where
+        anyhow::Error: From<E>,
+    {
+        //unwrap arg or try to get arg from channel prefs, then from config file
         if let Some(val) = val {
             *dst = val;
-        } else if let Some(cfg) = &self.config {
+        } else {
             let key = key.trim_start_matches('-');
-            if let Some(val) = cfg
-                .lines()
-                .find(|l| l.starts_with(key))
-                .and_then(|l| l.split_once('='))
-                .and_then(|(k, v)| k.eq(key).then_some(v))
+            if let Some(val) = [&self.prefs, &self.config]
+                .into_iter()
+                .flatten()
+                .find_map(|cfg| Self::lookup(cfg, key))
             {
-                *dst = f(val)?;
+                *dst = f(&Self::expand_env(val, key)?)?;
             }
         }
 
         Ok(())
     }
 
+    fn lookup<'a>(cfg: &'a str, key: &str) -> Option<&'a str> {
+        cfg.lines()
+            .find(|l| l.starts_with(key))
+            .and_then(|l| l.split_once('='))
+            .and_then(|(k, v)| k.eq(key).then_some(v))
+    }
+
+    //Expands $VAR and ${VAR} in config values, $$ is a literal $
+    fn expand_env<'a>(val: &'a str, key: &str) -> Result<Cow<'a, str>> {
+        if !val.contains('$') {
+            return Ok(Cow::Borrowed(val));
+        }
+
+        let mut expanded = String::with_capacity(val.len());
+        let mut rest = val;
+        while let Some(position) = rest.find('$') {
+            expanded.push_str(&rest[..position]);
+            rest = &rest[position + 1..];
+
+            let (name, remaining) = if let Some(remaining) = rest.strip_prefix('$') {
+                expanded.push('$');
+                rest = remaining;
+                continue;
+            } else if let Some(remaining) = rest.strip_prefix('{') {
+                let end = remaining.find('}').with_context(|| {
+                    format!("Unterminated environment variable in config key {key}")
+                })?;
+
+                (&remaining[..end], &remaining[end + 1..])
+            } else {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+
+                (&rest[..end], &rest[end..])
+            };
+
+            if name.is_empty() {
+                ensure!(
+                    !rest.starts_with('{'),
+                    "Empty environment variable in config key {key}",
+                );
+
+                expanded.push('$');
+                continue;
+            }
+
+            expanded.push_str(&env::var(name).with_context(|| {
+                format!("Environment variable {name} in config key {key} is not set")
+            })?);
+            rest = remaining;
+        }
+        expanded.push_str(rest);
+
+        Ok(Cow::Owned(expanded))
+    }
+
     #[allow(clippy::unnecessary_wraps, reason = "function pointer")]
     fn opt_string_impl(arg: &str) -> Result<Option<String>> {
         Ok(Some(arg.to_owned()))
@@ -202,33 +403,786 @@ impl Parser {
             process::exit(0);
         }
 
+        //only the literal form, --version takes no value otherwise
+        if parser.contains("--version=json") {
+            println!("{}", Self::version_json());
+            process::exit(0);
+        }
+
         if parser.contains("-V") || parser.contains("--version") {
-            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"),);
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
             process::exit(0);
         }
 
+        //hidden, development builds only
+        #[cfg(feature = "testserver")]
+        if let Some(spec) = parser.opt_value_from_str::<_, String>("--dev-serve")? {
+            crate::testserver::serve(&spec)?;
+            process::exit(0);
+        }
+
+        let no_config = parser.contains("--no-config");
+        let explicit_path = parser.opt_value_from_str::<_, String>("-c")?;
+        let explicit = explicit_path.is_some();
+        let path = match explicit_path {
+            Some(path) => Some(path),
+            None if no_config => Self::default_config_path().ok(),
+            None => Some(Self::default_config_path()?),
+        };
+
+        let config_dir = path
+            .as_deref()
+            .and_then(|p| Path::new(p).parent())
+            .map(Path::to_path_buf);
+
+        let config = match path {
+            Some(path) if !no_config => Self::load_config(&path, explicit)?,
+            _ => None,
+        };
+
         Ok(Self {
-            config: {
-                if parser.contains("--no-config") {
-                    None
-                } else {
-                    let path = match parser.opt_value_from_str("-c")? {
-                        Some(path) => path,
-                        None => Self::default_config_path()?,
-                    };
+            parser,
+            prefs: None,
+            config,
+            config_dir,
+        })
+    }
 
-                    if Path::new(&path).try_exists()? {
-                        Some(fs::read_to_string(path).context("Failed to read config file")?)
+    //The default config is optional, a config passed with -c has to exist
+    fn load_config(path: &str, explicit: bool) -> Result<Option<String>> {
+        let exists = Path::new(path)
+            .try_exists()
+            .with_context(|| format!("Failed to access config file {path}"))?;
+
+        if !exists {
+            ensure!(!explicit, "Config file not found: {path}");
+            return Ok(None);
+        }
+
+        Self::read_config(Path::new(path), &mut Vec::new())
+            .context("Failed to read config file")
+            .map(Some)
+    }
+
+    //Merges include=PATH and include?=PATH lines into the config, resolve() takes the first
+    //match so the including file goes first and later includes before earlier ones
+    fn read_config(path: &Path, chain: &mut Vec<PathBuf>) -> Result<String> {
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        let cycle = chain.contains(&path);
+        chain.push(path.clone());
+        ensure!(
+            !cycle,
+            "Config include cycle: {}",
+            Self::include_chain(chain)
+        );
+        ensure!(
+            chain.len() <= Self::MAX_INCLUDE_DEPTH + 1,
+            "Config includes are nested deeper than {}: {}",
+            Self::MAX_INCLUDE_DEPTH,
+            Self::include_chain(chain),
+        );
+
+        let config = Self::read_text(&path)?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut merged = String::with_capacity(config.len());
+        let mut included = Vec::new();
+        for line in config.lines() {
+            let (value, optional) = match line.split_once('=') {
+                Some(("include", value)) => (value, false),
+                Some(("include?", value)) => (value, true),
+                _ => {
+                    merged.push_str(line);
+                    merged.push('\n');
+                    continue;
+                }
+            };
+
+            let include = dir.join(&*Self::expand_env(value, "include")?);
+            if optional && !include.try_exists()? {
+                continue;
+            }
+
+            included.push(
+                Self::read_config(&include, chain)
+                    .with_context(|| format!("Failed to include {value} in {}", path.display()))?,
+            );
+        }
+
+        for config in included.iter().rev() {
+            merged.push_str(config);
+        }
+
+        chain.pop();
+        Ok(merged)
+    }
+
+    //Notepad saves as UTF-16 in some locales, files with a byte order mark are converted
+    fn read_text(path: &Path) -> Result<String> {
+        let bytes = fs::read(path).map_err(|e| {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                anyhow!("Permission denied reading {}", path.display())
+            } else {
+                anyhow!(e).context(format!("Failed to read {}", path.display()))
+            }
+        })?;
+
+        let utf16 = match bytes.as_slice() {
+            [0xff, 0xfe, rest @ ..] => Some((rest, true)),
+            [0xfe, 0xff, rest @ ..] => Some((rest, false)),
+            _ => None,
+        };
+
+        if let Some((rest, little_endian)) = utf16 {
+            ensure!(
+                rest.len() % 2 == 0,
+                "{} is not valid UTF-16 (odd number of bytes), save it as UTF-8",
+                path.display(),
+            );
+
+            let units = rest
+                .chunks_exact(2)
+                .map(|c| {
+                    if little_endian {
+                        u16::from_le_bytes([c[0], c[1]])
                     } else {
-                        None
+                        u16::from_be_bytes([c[0], c[1]])
                     }
+                })
+                .collect::<Vec<_>>();
+
+            return String::from_utf16(&units).with_context(|| {
+                format!("{} is not valid UTF-16, save it as UTF-8", path.display())
+            });
+        }
+
+        let mut text = String::from_utf8(bytes).map_err(|e| {
+            anyhow!(
+                "{} is not UTF-8 (invalid byte at offset {}), save it as UTF-8",
+                path.display(),
+                e.utf8_error().valid_up_to(),
+            )
+        })?;
+
+        //byte order mark some editors put in front of UTF-8 too
+        if text.starts_with('\u{feff}') {
+            text.remove(0);
+        }
+
+        Ok(text)
+    }
+
+    fn include_chain(chain: &[PathBuf]) -> String {
+        chain
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>()
+            .join(" -> ")
+    }
+
+    //for wrappers that need to know what the installed binary supports
+    fn version_json() -> String {
+        let features = [
+            ("colors", cfg!(feature = "colors")),
+            ("debug-logging", cfg!(feature = "debug-logging")),
+            ("testserver", cfg!(feature = "testserver")),
+        ];
+
+        let options = Self::usage_options(include_str!("usage"))
+            .iter()
+            .map(|o| format!(r#""{o}""#))
+            .collect::<Vec<_>>();
+
+        format!(
+            r#"{{"name":"{}","version":"{}","git_hash":"{}","build_date":"{}","target":"{}","features":[{}],"options":[{}]}}"#,
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+            env!("BUILD_GIT_HASH"),
+            env!("BUILD_DATE"),
+            env!("BUILD_TARGET"),
+            features
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| format!(r#""{name}""#))
+                .collect::<Vec<_>>()
+                .join(","),
+            options.join(","),
+        )
+    }
+
+    //Options are listed in the usage text, so it stays in sync with the parser.
+    //Only option headers count, descriptions are indented further and mention other options
+    fn usage_options(usage: &str) -> Vec<&str> {
+        const MAX_HEADER_INDENT: usize = 6;
+
+        let mut options = usage
+            .lines()
+            .filter(|l| l.len() - l.trim_start().len() <= MAX_HEADER_INDENT)
+            .filter(|l| l.trim_start().starts_with('-'))
+            .flat_map(|l| l.split([' ', ',']))
+            .filter(|w| w.starts_with("--"))
+            .collect::<Vec<_>>();
+        options.sort_unstable();
+        options.dedup();
+
+        options
+    }
+
+    fn parse_sessions(self, specs: &[String]) -> Result<Vec<Session>> {
+        //options left on the command line apply to every session
+        let shared = self.parser.finish();
+        specs
+            .iter()
+            .map(|spec| {
+                let mut args = shared.clone();
+                let name = Self::session_args(spec, &mut args)?;
+
+                let session_parser = Self {
+                    parser: Arguments::from_vec(args),
+                    prefs: None,
+                    config: self.config.clone(),
+                    config_dir: self.config_dir.clone(),
+                };
+                session_parser
+                    .parse_session(Some(name.clone()))
+                    .with_context(|| format!("Invalid session {name}"))
+            })
+            .collect()
+    }
+
+    //Prefs depend on the channel, which is only known once the free args are parsed,
+    //so the session is parsed again with the channel's prefs if it has any
+    fn parse_session(self, name: Option<String>) -> Result<Session> {
+        let session = self.clone().parse_session_args(name.clone())?;
+        let Some(prefs) = Prefs::new(session.hls.cache_dir(), self.config_dir.as_deref()) else {
+            ensure!(
+                !session.hls.save_prefs(),
+                "--save-prefs needs --playlist-cache-dir or a config directory",
+            );
+
+            session.hls.validate()?;
+            return Ok(session);
+        };
+
+        //prefs are per channel, which of a channel list is played is only known later
+        let single = session.hls.channels().len() == 1;
+        ensure!(
+            single || !session.hls.save_prefs(),
+            "--save-prefs needs a single channel",
+        );
+
+        let session = match single.then(|| prefs.load(session.hls.channel())).flatten() {
+            Some(options) => Self {
+                prefs: Some(options),
+                ..self
+            }
+            .parse_session_args(name)?,
+            None => session,
+        };
+
+        if session.hls.save_prefs() {
+            let mut options = Vec::new();
+            if let Some(quality) = session.hls.quality_arg() {
+                options.push(("quality", quality));
+            }
+            if let Some(args) = session.output.player.args() {
+                options.push(("player-args", args));
+            }
+
+            prefs
+                .save(session.hls.channel(), &options)
+                .context("Failed to save prefs")?;
+        }
+
+        session.hls.validate()?;
+        Ok(session)
+    }
+
+    fn parse_session_args(mut self, name: Option<String>) -> Result<Session> {
+        let mut hls = HlsArgs::default();
+        let mut output = OutputArgs::default();
+
+        output.parse(&mut self)?;
+        hls.parse(&mut self)?; //must be last because it parses the free args
+
+        if let Some(arg) = self.finish() {
+            bail!("Unrecognized argument: {arg}");
+        }
+
+        for (quality, sink) in hls.renditions() {
+            ensure!(
+                output.has_sink(*sink),
+                "Quality {quality} is sent to the {}, which isn't set",
+                match sink {
+                    Sink::Player => "player (-p)",
+                    Sink::Recorder => "recording (-r)",
+                },
+            );
+        }
+
+        Ok(Session { name, hls, output })
+    }
+
+    //Turns "channel=foo quality=best record=foo.ts" into command line arguments, returns the name
+    fn session_args(spec: &str, args: &mut Vec<OsString>) -> Result<String> {
+        let mut name = None;
+        let mut channel = None;
+        let mut quality = None;
+        let mut options = Vec::<OsString>::new();
+        for arg in spec.split_whitespace() {
+            let (key, value) = match arg.split_once('=') {
+                Some((key, value)) => (key.trim_start_matches('-'), Some(value)),
+                None => (arg.trim_start_matches('-'), None),
+            };
+
+            match (key, value) {
+                ("name", Some(value)) => name = Some(value.to_owned()),
+                ("channel", Some(value)) => channel = Some(value.to_owned()),
+                ("quality", Some(value)) => quality = Some(value.to_owned()),
+                ("record", Some(value)) => options.extend(["-r".into(), value.into()]),
+                ("player", Some(value)) => options.extend(["-p".into(), value.into()]),
+                (key, value) => {
+                    options.push(format!("--{key}").into());
+                    options.extend(value.map(OsString::from));
                 }
-            },
-            parser,
-        })
+            }
+        }
+
+        let channel = channel.with_context(|| format!("Missing channel in session: {spec}"))?;
+        let quality = quality.with_context(|| format!("Missing quality in session: {spec}"))?;
+        let name = name.unwrap_or_else(|| channel.clone());
+
+        //free args first so unknown keys are reported instead of taken as the channel
+        args.extend([channel.into(), quality.into()]);
+        args.extend(options);
+
+        Ok(name)
     }
 
     fn finish(self) -> Option<String> {
         self.parser.finish().into_iter().next()?.into_string().ok()
     }
 }
+
+//Command line arguments without a config file, for tests that parse options
+#[cfg(test)]
+impl Parser {
+    pub fn from_args(args: &[&str]) -> Self {
+        Self {
+            parser: Arguments::from_vec(args.iter().map(OsString::from).collect()),
+            prefs: None,
+            config: None,
+            config_dir: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{logger::Stdout, temp_dir::TempDir};
+
+    fn read(dir: &TempDir, name: &str) -> Result<String> {
+        Parser::read_config(&dir.join(name), &mut Vec::new())
+    }
+
+    #[test]
+    fn usage_options_are_headers_only() {
+        let options = Parser::usage_options(include_str!("usage"));
+
+        assert!(options.contains(&"--version"));
+        assert!(options.contains(&"--player-file-initial"));
+        assert!(!options.iter().any(|o| o.contains('=')));
+        assert!(options.windows(2).all(|w| w[0] < w[1]), "sorted and unique");
+    }
+
+    #[test]
+    fn includes_are_overridden_by_the_including_file() {
+        let dir = TempDir::with_files(
+            "config-precedence",
+            &[
+                (
+                    "config",
+                    b"quality=best\ninclude=first\ninclude=second\nplayer=mpv\n",
+                ),
+                ("first", b"quality=720p\nclient-id=first\nrecord=first.ts\n"),
+                ("second", b"client-id=second\nplayer-args=--second\n"),
+            ],
+        );
+
+        let config = read(&dir, "config").unwrap();
+        let lookup = |key| Parser::lookup(&config, key);
+        assert_eq!(lookup("quality"), Some("best"));
+        assert_eq!(lookup("player"), Some("mpv"));
+        assert_eq!(lookup("client-id"), Some("second"), "later include wins");
+        assert_eq!(lookup("player-args"), Some("--second"));
+        assert_eq!(lookup("record"), Some("first.ts"));
+        assert!(!config.contains("include"));
+    }
+
+    #[test]
+    fn includes_are_relative_to_the_including_file() {
+        let dir = TempDir::with_files(
+            "config-relative",
+            &[
+                ("config", b"include=sub/nested\n"),
+                ("sub/nested", b"include=leaf\n"),
+                ("sub/leaf", b"quality=best\n"),
+            ],
+        );
+
+        let config = read(&dir, "config").unwrap();
+        assert_eq!(Parser::lookup(&config, "quality"), Some("best"));
+    }
+
+    #[test]
+    fn missing_include() {
+        let dir = TempDir::with_files(
+            "config-missing",
+            &[
+                ("required", b"include=missing\n"),
+                ("optional", b"include?=missing\nquality=best\n"),
+            ],
+        );
+
+        let error = format!("{:#}", read(&dir, "required").unwrap_err());
+        assert!(error.contains("Failed to include missing"), "{error}");
+
+        let config = read(&dir, "optional").unwrap();
+        assert_eq!(Parser::lookup(&config, "quality"), Some("best"));
+    }
+
+    #[test]
+    fn include_cycle() {
+        let dir = TempDir::with_files(
+            "config-cycle",
+            &[
+                ("a", b"include=b\n"),
+                ("b", b"include=a\n"),
+                ("self", b"include=self\n"),
+            ],
+        );
+
+        for name in ["a", "self"] {
+            let error = format!("{:#}", read(&dir, name).unwrap_err());
+            assert!(error.contains("Config include cycle"), "{error}");
+        }
+    }
+
+    #[test]
+    fn include_depth() {
+        let files = (0..=Parser::MAX_INCLUDE_DEPTH + 1)
+            .map(|i| (i.to_string(), format!("include={}\n", i + 1)))
+            .collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(name, contents)| (name.as_str(), contents.as_bytes()))
+            .collect::<Vec<_>>();
+        let dir = TempDir::with_files("config-depth", &files);
+
+        let error = format!("{:#}", read(&dir, "0").unwrap_err());
+        assert!(error.contains("nested deeper than"), "{error}");
+    }
+
+    #[test]
+    fn missing_config() {
+        let dir = TempDir::new("config-load");
+        let path = dir.path_str("missing");
+
+        let error = Parser::load_config(&path, true).unwrap_err().to_string();
+        assert_eq!(error, format!("Config file not found: {path}"));
+        assert!(Parser::load_config(&path, false).unwrap().is_none());
+
+        //exists but can't be read
+        let dir = dir.to_string_lossy().into_owned();
+        let error = format!("{:#}", Parser::load_config(&dir, false).unwrap_err());
+        assert!(error.contains(&format!("Failed to read {dir}")), "{error}");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unreadable_config() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::with_files("config-unreadable", &[("config", b"quality=best\n")]);
+        let path = dir.path_str("config");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o000)).unwrap();
+
+        //root reads it anyway
+        if fs::read(&path).is_ok() {
+            return;
+        }
+
+        let error = format!("{:#}", Parser::load_config(&path, true).unwrap_err());
+        assert!(
+            error.contains(&format!("Permission denied reading {path}")),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn config_encodings() {
+        let utf16 = |big_endian: bool| {
+            let mut bytes = if big_endian {
+                vec![0xfe, 0xff]
+            } else {
+                vec![0xff, 0xfe]
+            };
+            for unit in "quality=best\r\nplayer=mpv\r\n".encode_utf16() {
+                bytes.extend(if big_endian {
+                    unit.to_be_bytes()
+                } else {
+                    unit.to_le_bytes()
+                });
+            }
+
+            bytes
+        };
+        let (little, big) = (utf16(false), utf16(true));
+        let dir = TempDir::with_files(
+            "config-encodings",
+            &[
+                ("utf16le", &little),
+                ("utf16be", &big),
+                ("odd", &little[..little.len() - 1]),
+                ("bom", b"\xef\xbb\xbfquality=best\n"),
+                ("latin1", b"quality=best\nplayer=caf\xe9\n"),
+            ],
+        );
+
+        for name in ["utf16le", "utf16be", "bom"] {
+            let config = read(&dir, name).unwrap();
+            assert_eq!(Parser::lookup(&config, "quality"), Some("best"), "{name}");
+        }
+
+        let error = read(&dir, "odd").unwrap_err().to_string();
+        assert!(error.contains("odd number of bytes"), "{error}");
+
+        let error = read(&dir, "latin1").unwrap_err().to_string();
+        assert!(
+            error.contains("is not UTF-8 (invalid byte at offset 23)"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn env_expansion() {
+        env::set_var("TWITCH_HLS_CLIENT_TEST_DIR", "/home/test");
+        let expand = |value| Parser::expand_env(value, "record").map(Cow::into_owned);
+
+        assert_eq!(
+            expand("${TWITCH_HLS_CLIENT_TEST_DIR}x").unwrap(),
+            "/home/testx"
+        );
+        assert_eq!(
+            expand("$TWITCH_HLS_CLIENT_TEST_DIR/rec.ts").unwrap(),
+            "/home/test/rec.ts"
+        );
+        assert_eq!(expand("$$HOME costs $$5").unwrap(), "$HOME costs $5");
+        assert_eq!(expand("a $ b$").unwrap(), "a $ b$");
+        assert_eq!(expand("$-1").unwrap(), "$-1");
+        assert!(matches!(
+            Parser::expand_env("plain", "record"),
+            Ok(Cow::Borrowed("plain"))
+        ));
+    }
+
+    #[test]
+    fn env_expansion_errors() {
+        let error = |value| Parser::expand_env(value, "record").unwrap_err().to_string();
+
+        assert_eq!(
+            error("$TWITCH_HLS_CLIENT_TEST_UNSET/rec.ts"),
+            "Environment variable TWITCH_HLS_CLIENT_TEST_UNSET in config key record is not set",
+        );
+        assert_eq!(
+            error("${TWITCH_HLS_CLIENT_TEST_DIR"),
+            "Unterminated environment variable in config key record",
+        );
+        assert_eq!(
+            error("${}"),
+            "Empty environment variable in config key record"
+        );
+    }
+
+    fn stdout(args: &[&str]) -> Result<Stdout> {
+        parse_with(Parser::from_args(args)).map(|(main, ..)| main.stdout)
+    }
+
+    #[test]
+    fn stdout_routing() {
+        assert_eq!(stdout(&["channel", "best"]).unwrap(), Stdout::Logs);
+        assert_eq!(
+            stdout(&["channel", "--print-streams"]).unwrap(),
+            Stdout::Data("--print-streams"),
+        );
+        assert_eq!(
+            stdout(&["--summary", "json", "channel", "best"]).unwrap(),
+            Stdout::Data("--summary=json"),
+        );
+
+        let error = stdout(&["--check", "--summary", "json", "channel", "best"]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--check and --summary=json both write to stdout, use only one of them",
+        );
+
+        //every session listing its streams is still one mode
+        assert_eq!(
+            stdout(&[
+                "--print-streams",
+                "--session",
+                "channel=a quality=best",
+                "--session",
+                "channel=b quality=best",
+            ])
+            .unwrap(),
+            Stdout::Data("--print-streams"),
+        );
+    }
+
+    //single session parsed with the given config
+    fn session(args: &[&str], config: Option<&str>) -> Result<Session> {
+        let parser = Parser {
+            config: config.map(str::to_owned),
+            ..Parser::from_args(args)
+        };
+
+        parse_with(parser).map(|(_, _, mut sessions)| sessions.remove(0))
+    }
+
+    #[test]
+    fn prefs_precedence() {
+        let dir = TempDir::with_files(
+            "prefs-precedence",
+            &[("prefs", b"channel quality=audio_only\n")],
+        );
+        let dir = dir.to_str().unwrap();
+        let config = Some("quality=720p\n");
+        let quality = |args: &[&str], config| {
+            session(&[&["--playlist-cache-dir", dir], args].concat(), config)
+                .unwrap()
+                .hls
+                .quality_arg()
+        };
+
+        assert_eq!(quality(&["channel", "480p"], config).unwrap(), "480p");
+        assert_eq!(quality(&["channel"], config).unwrap(), "audio_only");
+        assert_eq!(quality(&["Channel"], config).unwrap(), "audio_only");
+        assert_eq!(quality(&["other"], config).unwrap(), "720p");
+        assert_eq!(quality(&["other", "best"], None).unwrap(), "best");
+    }
+
+    #[test]
+    fn save_prefs() {
+        let dir = TempDir::new("save-prefs");
+        let dir = dir.to_str().unwrap();
+        let cached =
+            |args: &[&str]| session(&[&["--playlist-cache-dir", dir], args].concat(), None);
+
+        let saved = cached(&["--save-prefs", "channel", "720p", "-a", "--title 'a b' -"]).unwrap();
+
+        //picked up on the next start without them
+        let loaded = cached(&["channel"]).unwrap();
+        assert_eq!(loaded.hls.quality_arg().unwrap(), "720p");
+        assert_eq!(loaded.output.player.args(), saved.output.player.args());
+
+        assert!(session(&["--save-prefs", "channel", "720p"], None)
+            .unwrap_err()
+            .to_string()
+            .starts_with("--save-prefs needs"));
+        assert_eq!(
+            cached(&["--save-prefs", "a,b", "720p"])
+                .unwrap_err()
+                .to_string(),
+            "--save-prefs needs a single channel",
+        );
+    }
+
+    fn session_args(spec: &str) -> Result<(String, Vec<String>)> {
+        let mut args = vec![OsString::from("--shared")];
+        let name = Parser::session_args(spec, &mut args)?;
+
+        Ok((
+            name,
+            args.into_iter().map(|a| a.into_string().unwrap()).collect(),
+        ))
+    }
+
+    #[test]
+    fn session_specs() {
+        let (name, args) = session_args(
+            "name=main channel=foo quality=best record=foo.ts player=mpv --no-low-latency",
+        )
+        .unwrap();
+        assert_eq!(name, "main");
+        assert_eq!(
+            args,
+            [
+                "--shared",
+                "foo",
+                "best",
+                "-r",
+                "foo.ts",
+                "-p",
+                "mpv",
+                "--no-low-latency",
+            ],
+        );
+
+        //name defaults to the channel, keys can be given like options
+        let (name, args) = session_args("--channel=bar quality=720p codecs=av1").unwrap();
+        assert_eq!(name, "bar");
+        assert_eq!(args, ["--shared", "bar", "720p", "--codecs", "av1"]);
+
+        assert_eq!(
+            session_args("quality=best").unwrap_err().to_string(),
+            "Missing channel in session: quality=best",
+        );
+        assert_eq!(
+            session_args("channel=foo").unwrap_err().to_string(),
+            "Missing quality in session: channel=foo",
+        );
+    }
+
+    #[test]
+    fn sessions_are_independent() {
+        let (.., sessions) = parse_with(Parser::from_args(&[
+            "-p",
+            "mpv",
+            "--session",
+            "channel=foo quality=best record=foo.ts",
+            "--session",
+            "name=second channel=bar quality=720p",
+        ]))
+        .unwrap();
+
+        let [foo, bar] = &sessions[..] else {
+            panic!("expected two sessions");
+        };
+        assert_eq!(foo.name.as_deref(), Some("foo"));
+        assert_eq!(foo.hls.channel(), "foo");
+        assert_eq!(foo.hls.quality_arg().unwrap(), "best");
+        assert!(foo.output.has_sink(Sink::Recorder));
+        assert_eq!(bar.name.as_deref(), Some("second"));
+        assert_eq!(bar.hls.channel(), "bar");
+        assert_eq!(bar.hls.quality_arg().unwrap(), "720p");
+        assert!(!bar.output.has_sink(Sink::Recorder));
+
+        //shared options apply to every session
+        assert!(foo.output.has_sink(Sink::Player) && bar.output.has_sink(Sink::Player));
+
+        let error = parse_with(Parser::from_args(&[
+            "--session",
+            "channel=foo quality=best bogus=1",
+        ]))
+        .unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "Invalid session foo: Unrecognized argument: --bogus",
+        );
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use super::{quote_arg, split_args};
+
+//Per-channel options, one channel per line: channel quality=audio_only player-args="--mute"
+pub struct Prefs {
+    path: PathBuf,
+}
+
+impl Prefs {
+    const FILE_NAME: &'static str = "prefs";
+
+    //Kept next to the playlist cache if there is one, otherwise next to the config file
+    pub fn new(cache_dir: Option<&str>, config_dir: Option<&Path>) -> Option<Self> {
+        let dir = cache_dir.map(Path::new).or(config_dir)?;
+        Some(Self {
+            path: dir.join(Self::FILE_NAME),
+        })
+    }
+
+    //Options of the channel as config lines, corrupt lines are skipped with a warning
+    pub fn load(&self, channel: &str) -> Option<String> {
+        let prefs = match fs::read_to_string(&self.path) {
+            Ok(prefs) => prefs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                super::warn_later(format!("Failed to read {}: {e}", self.path.display()));
+                return None;
+            }
+        };
+
+        let mut options = None;
+        for (number, line) in prefs.lines().enumerate() {
+            match Self::parse_line(line) {
+                Ok(Some((name, line_options))) if name == channel => options = Some(line_options),
+                Ok(_) => (),
+                Err(e) => super::warn_later(format!(
+                    "Skipping line {} of {}: {e}",
+                    number + 1,
+                    self.path.display(),
+                )),
+            }
+        }
+
+        options
+    }
+
+    //Replaces the channel's line, other lines are kept as they are
+    pub fn save(&self, channel: &str, options: &[(&str, String)]) -> Result<()> {
+        let prefs = match fs::read_to_string(&self.path) {
+            Ok(prefs) => prefs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::default(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", self.path.display()))
+            }
+        };
+
+        let mut line = channel.to_owned();
+        for (key, value) in options {
+            //values go through the same $VAR expansion as the config
+            line.push(' ');
+            line.push_str(&quote_arg(&format!("{key}={}", value.replace('$', "$$"))));
+        }
+
+        let mut saved = String::with_capacity(prefs.len() + line.len());
+        for prefs_line in prefs.lines() {
+            if !Self::parse_line(prefs_line).is_ok_and(|p| p.is_some_and(|(n, _)| n == channel)) {
+                saved.push_str(prefs_line);
+                saved.push('\n');
+            }
+        }
+        saved.push_str(&line);
+        saved.push('\n');
+
+        //written to a temporary file first so a crash can't leave the file half written
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, saved)
+            .and_then(|()| fs::rename(&temp, &self.path))
+            .with_context(|| format!("Failed to write {}", self.path.display()))
+    }
+
+    fn parse_line(line: &str) -> Result<Option<(String, String)>> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let mut words = split_args(line)?.into_iter();
+        let channel = words.next().unwrap_or_default().to_lowercase();
+
+        let mut options = String::new();
+        for word in words {
+            let (key, value) = word
+                .split_once('=')
+                .with_context(|| format!("Option must be KEY=VALUE: {word}"))?;
+
+            options.push_str(key.trim_start_matches('-'));
+            options.push('=');
+            options.push_str(value);
+            options.push('\n');
+        }
+
+        Ok(Some((channel, options)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::deferred_warnings, *};
+    use crate::temp_dir::TempDir;
+
+    //Prefs in a directory of their own
+    fn prefs(name: &str, contents: &str) -> (TempDir, Prefs) {
+        let dir = TempDir::new(&format!("prefs-{name}"));
+        if !contents.is_empty() {
+            dir.write(Prefs::FILE_NAME, contents);
+        }
+
+        let prefs = Prefs::new(None, Some(&dir)).unwrap();
+        (dir, prefs)
+    }
+
+    #[test]
+    fn location() {
+        let config_dir = Path::new("config");
+        let path = |cache_dir| Prefs::new(cache_dir, Some(config_dir)).unwrap().path;
+
+        assert_eq!(path(Some("cache")), Path::new("cache").join("prefs"));
+        assert_eq!(path(None), config_dir.join("prefs"));
+        assert!(Prefs::new(None, None).is_none());
+    }
+
+    #[test]
+    fn lines() {
+        assert!(Prefs::parse_line("").unwrap().is_none());
+        assert!(Prefs::parse_line("  # comment").unwrap().is_none());
+        assert_eq!(
+            Prefs::parse_line(r#"Channel quality=audio_only --player-args="--mute --title 'a b'""#)
+                .unwrap()
+                .unwrap(),
+            (
+                "channel".to_owned(),
+                "quality=audio_only\nplayer-args=--mute --title 'a b'\n".to_owned()
+            ),
+        );
+
+        assert!(Prefs::parse_line("channel audio_only").is_err());
+        assert!(Prefs::parse_line("channel quality=\"best").is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_dir, prefs) = prefs("round-trip", "");
+        assert!(prefs.load("channel").is_none());
+
+        let player_args = r#"--title "a $b" C:\mpv"#.to_owned();
+        prefs
+            .save(
+                "channel",
+                &[
+                    ("quality", "720p60".to_owned()),
+                    ("player-args", player_args.clone()),
+                ],
+            )
+            .unwrap();
+        prefs
+            .save("other", &[("quality", "audio_only".to_owned())])
+            .unwrap();
+
+        assert_eq!(
+            prefs.load("channel").unwrap(),
+            format!(
+                "quality=720p60\nplayer-args={}\n",
+                player_args.replace('$', "$$")
+            ),
+        );
+        assert_eq!(prefs.load("other").unwrap(), "quality=audio_only\n");
+    }
+
+    #[test]
+    fn save_replaces_the_channel_line() {
+        let (dir, prefs) = prefs(
+            "replace",
+            "# favorites\nchannel quality=best\nbroken\nother quality=480p\n",
+        );
+
+        prefs
+            .save("channel", &[("quality", "audio_only".to_owned())])
+            .unwrap();
+
+        //everything else is kept as it was, even lines that don't parse
+        assert_eq!(
+            fs::read_to_string(dir.join(Prefs::FILE_NAME)).unwrap(),
+            "# favorites\nbroken\nother quality=480p\nchannel quality=audio_only\n",
+        );
+        assert!(!dir.join("prefs.tmp").exists());
+    }
+
+    #[test]
+    fn corrupt_lines_are_skipped() {
+        let (_dir, prefs) = prefs("corrupt", "channel quality\nchannel quality=best\n");
+
+        assert_eq!(prefs.load("channel").unwrap(), "quality=best\n");
+        assert!(deferred_warnings()
+            .iter()
+            .any(|w| w.starts_with("Skipping line 1 of ")
+                && w.ends_with(": Option must be KEY=VALUE: quality")));
+    }
+}
@@ -5,6 +5,7 @@ pub const PLAYER_VERSION: &str = "1.33.0-rc.5";
 
 pub const TWITCH_GQL_ENDPOINT: &str = "https://gql.twitch.tv/gql";
 pub const TWITCH_OAUTH_ENDPOINT: &str = "https://id.twitch.tv/oauth2/validate";
+pub const TWITCH_SPADE_ENDPOINT: &str = "https://spade.twitch.tv/track";
 pub const TWITCH_HLS_BASE: &str = "https://usher.ttvnw.net/api/channel/hls/";
 
 pub const DEFAULT_CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
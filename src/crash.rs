@@ -0,0 +1,112 @@
+use std::{
+    cell::RefCell,
+    fmt::Write,
+    panic::{self, Location},
+    sync::Arc,
+    time::Instant,
+};
+
+use crate::logger;
+
+thread_local! {
+    //what this thread was doing, for the panic hook
+    static CONTEXT: RefCell<Context> = RefCell::new(Context::default());
+}
+
+//Stream of the session and the last URL requested, copied to threads spawned through
+//logger::spawn so the worker reports the stream it belongs to
+#[derive(Default, Clone)]
+pub struct Context {
+    stream: Option<Arc<Stream>>,
+    url: String,
+}
+
+struct Stream {
+    channel: String,
+    quality: Option<String>,
+    started: Instant,
+}
+
+pub fn context() -> Context {
+    CONTEXT.with_borrow(Clone::clone)
+}
+
+pub fn set_context(context: Context) {
+    CONTEXT.set(context);
+}
+
+pub fn set_stream(channel: &str, quality: Option<&str>) {
+    CONTEXT.with_borrow_mut(|c| {
+        c.stream = Some(Arc::new(Stream {
+            channel: channel.to_owned(),
+            quality: quality.map(ToOwned::to_owned),
+            started: Instant::now(),
+        }));
+    });
+}
+
+//called for every playlist reload and segment, the buffer is reused
+pub fn set_url(url: &str) {
+    CONTEXT.with_borrow_mut(|c| url.clone_into(&mut c.url));
+}
+
+//Prints what the client was doing and the recent log after the default panic message,
+//bare backtraces of release builds are mostly <unknown> frames
+pub fn install_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default(info);
+        eprint!("{}", report(info.location()));
+    }));
+}
+
+fn report(location: Option<&Location<'_>>) -> String {
+    let mut report = format!(
+        "\n{} {} crashed",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+    );
+    if let Some(location) = location {
+        let _ = write!(report, " at {location}");
+    }
+
+    //try_with, the hook can run while the thread-local is being destroyed
+    let _ = CONTEXT.try_with(|c| {
+        let Ok(context) = c.try_borrow() else {
+            return;
+        };
+
+        if let Some(stream) = &context.stream {
+            let _ = write!(
+                report,
+                "\n  Stream: {} ({})\n  Running for: {}s",
+                stream.channel,
+                stream.quality.as_deref().unwrap_or("unknown quality"),
+                stream.started.elapsed().as_secs(),
+            );
+        }
+
+        if !context.url.is_empty() {
+            let _ = write!(report, "\n  Last URL: {}", logger::redact(&context.url));
+        }
+    });
+
+    match logger::recent() {
+        Some(recent) if !recent.is_empty() => {
+            let _ = write!(report, "\n  Last {} log lines:", recent.len());
+            for line in recent {
+                let _ = write!(report, "\n    {line}");
+            }
+        }
+        Some(_) => (),
+        None => report.push_str("\n  Log lines unavailable, the logger was busy"),
+    }
+
+    let _ = writeln!(
+        report,
+        "\nPlease report this at {}/issues with everything above",
+        env!("CARGO_PKG_REPOSITORY"),
+    );
+
+    report
+}
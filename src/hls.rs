@@ -1,62 +1,389 @@
 mod cache;
+mod heartbeat;
 mod master_playlist;
 mod media_playlist;
+mod quality;
+mod random;
 pub mod segment;
 
-pub use master_playlist::fetch_playlist;
-pub use media_playlist::MediaPlaylist;
+pub use heartbeat::Heartbeat;
+pub use master_playlist::{
+    check_live, fail_over_channel, fetch_playlist, refetch_playlist, select_channel, Variants,
+};
+pub use media_playlist::{MediaPlaylist, StaleError};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use log::warn;
 use std::{
     borrow::Cow,
     fmt::{self, Display, Formatter},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
 };
 
+use quality::Constraint;
+use segment::{SmallSegments, SmallSegmentsAction};
+
 use crate::{
-    args::{Parse, Parser},
-    http::{StatusError, Url},
+    args::{self, Parse, Parser},
+    http::{Scheme, StatusError, Url},
+    output::Sink,
 };
 
-#[derive(Debug)]
-pub struct OfflineError;
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OfflineError {
+    ChannelOffline,
+    EndOfStream,
+    //direct is whether Twitch itself was reachable, None if it wasn't checked
+    ProxiesUnavailable {
+        servers: usize,
+        direct: Option<bool>,
+    },
+}
 
 impl std::error::Error for OfflineError {}
 
 impl Display for OfflineError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Stream is offline or unavailable")
+        match self {
+            Self::ChannelOffline => write!(f, "Stream is offline or unavailable"),
+            Self::EndOfStream => write!(f, "Stream ended"),
+            Self::ProxiesUnavailable { servers, direct } => {
+                match servers {
+                    1 => write!(f, "The playlist proxy failed")?,
+                    _ => write!(f, "All {servers} playlist proxies failed")?,
+                }
+
+                match direct {
+                    Some(true) => write!(
+                        f,
+                        ", direct Twitch connectivity: OK (so the proxies are likely down)"
+                    ),
+                    Some(false) => write!(
+                        f,
+                        ", direct Twitch connectivity also failed (check your network/DNS)"
+                    ),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl OfflineError {
+    pub const fn exit_code(self) -> i32 {
+        match self {
+            Self::EndOfStream => 0,
+            Self::ChannelOffline => 2,
+            Self::ProxiesUnavailable { .. } => 4,
+        }
+    }
+}
+
+//Container of the segments, only known once a variant's media playlist is fetched
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Container {
+    #[default]
+    Any,
+    Ts,
+    Fmp4,
+}
+
+impl Display for Container {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Any => write!(f, "any"),
+            Self::Ts => write!(f, "ts"),
+            Self::Fmp4 => write!(f, "fmp4"),
+        }
+    }
+}
+
+impl Container {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "any" => Ok(Self::Any),
+            "ts" => Ok(Self::Ts),
+            "fmp4" => Ok(Self::Fmp4),
+            _ => bail!("Invalid container: {arg} (must be any, ts or fmp4)"),
+        }
+    }
+
+    //fMP4 playlists have an init segment (EXT-X-MAP)
+    const fn of(playlist: &MediaPlaylist) -> Self {
+        if playlist.header.is_some() {
+            Self::Fmp4
+        } else {
+            Self::Ts
+        }
     }
 }
 
+//Channels that are played directly from Twitch instead of the playlist proxy
+//when the auth token is entitled to ad-free playback
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PreferAuth {
+    Channels(Vec<String>),
+    All,
+    Subscribed,
+}
+
+impl PreferAuth {
+    #[allow(clippy::unnecessary_wraps, reason = "function pointer")]
+    fn new(arg: &str) -> Result<Option<Self>> {
+        Ok(Some(match arg {
+            "all" => Self::All,
+            "subscribed" => Self::Subscribed,
+            _ => Self::Channels(arg.split(',').map(str::to_lowercase).collect()),
+        }))
+    }
+
+    fn applies_to(&self, channel: &str) -> bool {
+        match self {
+            Self::Channels(channels) => channels.iter().any(|c| c == channel),
+            Self::All | Self::Subscribed => true,
+        }
+    }
+
+    //turbo and other ad-free entitlements aren't tied to the channel
+    const fn accepts(&self, entitlement: Entitlement) -> bool {
+        match self {
+            Self::Channels(_) | Self::All => !matches!(entitlement, Entitlement::None),
+            Self::Subscribed => matches!(entitlement, Entitlement::Subscriber),
+        }
+    }
+}
+
+//Playlist URLs given with --force-playlist-url, the first that works is played
+//and the next ones are fallbacks for when it stops working
 #[derive(Debug)]
+struct ForcedUrls {
+    urls: Vec<Url>,
+    active: AtomicUsize,
+}
+
+impl ForcedUrls {
+    fn new(arg: &str) -> Result<Option<Self>> {
+        let urls = arg.split(',').map(Url::from).collect::<Vec<_>>();
+        for (position, url) in urls.iter().enumerate() {
+            ensure!(
+                url.scheme != Scheme::Unknown && url.host().is_ok_and(|h| !h.is_empty()),
+                "Forced playlist URL {} is not an http(s) URL",
+                position + 1,
+            );
+        }
+
+        Ok(Some(Self {
+            urls,
+            active: AtomicUsize::default(),
+        }))
+    }
+
+    //In order at startup, after a failure starting with the one after the active URL
+    //and ending with the active URL itself, which may have recovered by then
+    fn order(&self, failed: bool) -> impl Iterator<Item = (usize, &Url)> {
+        let start = if failed {
+            self.active.load(Ordering::Relaxed) + 1
+        } else {
+            0
+        };
+
+        (start..start + self.urls.len())
+            .map(|i| i % self.urls.len())
+            .map(|i| (i, &self.urls[i]))
+    }
+
+    //true if another URL was active before
+    fn set_active(&self, index: usize) -> bool {
+        self.active.swap(index, Ordering::Relaxed) != index
+    }
+
+    fn has_fallbacks(&self) -> bool {
+        self.urls.len() > 1
+    }
+}
+
+//Channels given as a comma separated list in order of preference, the first live one
+//is played and with --channel-failover the next live one once it ends
+#[derive(Debug, Default)]
+struct Channels {
+    names: Vec<String>,
+    active: AtomicUsize,
+}
+
+impl Channels {
+    fn new(arg: &str) -> Result<Self> {
+        let names = arg
+            .to_lowercase()
+            .split(',')
+            .map(|c| c.trim().replace("twitch.tv/", ""))
+            .collect::<Vec<_>>();
+        ensure!(
+            names.iter().all(|c| !c.is_empty()),
+            "Empty channel name in channel list: {arg}",
+        );
+
+        Ok(Self {
+            names,
+            active: AtomicUsize::default(),
+        })
+    }
+
+    fn set_active(&self, index: usize) {
+        self.active.store(index, Ordering::Relaxed);
+    }
+
+    fn active(&self) -> &str {
+        self.names
+            .get(self.active.load(Ordering::Relaxed))
+            .map_or("", String::as_str)
+    }
+}
+
+//Ad-free playback the access token was issued with
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Entitlement {
+    None,
+    Subscriber,
+    Turbo,
+    AdFree,
+}
+
+impl Display for Entitlement {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::None => write!(f, "no ad-free entitlement"),
+            Self::Subscriber => write!(f, "subscribed"),
+            Self::Turbo => write!(f, "turbo"),
+            Self::AdFree => write!(f, "ad-free"),
+        }
+    }
+}
+
+//Only the length and a short prefix are shown in debug output
+pub struct AuthToken(String);
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("AuthToken")
+            .field("len", &self.0.len())
+            .field("prefix", &self.0.get(..4).unwrap_or_default())
+            .finish()
+    }
+}
+
+impl AuthToken {
+    const MIN_LEN: usize = 20;
+    const MAX_LEN: usize = 64;
+
+    //Accepts "OAuth <token>", quoted values and full cookie strings
+    #[allow(clippy::unnecessary_wraps, reason = "function pointer")]
+    fn new(arg: &str) -> Result<Option<Self>> {
+        const COOKIE_KEY: &str = "auth-token=";
+        const PREFIX: &str = "oauth ";
+
+        let trim = |s: &str| s.trim().trim_matches(['"', '\'']).trim().to_owned();
+
+        let mut token = trim(arg);
+        if let Some(start) = token.find(COOKIE_KEY) {
+            let cookie = &token[start + COOKIE_KEY.len()..];
+            token = trim(cookie.split(';').next().unwrap_or_default());
+        }
+
+        if token
+            .get(..PREFIX.len())
+            .is_some_and(|p| p.eq_ignore_ascii_case(PREFIX))
+        {
+            token = trim(&token[PREFIX.len()..]);
+        }
+
+        Ok(Some(Self(token)))
+    }
+
+    fn validate(&self) {
+        if !self.is_plausible() {
+            warn!("Auth token doesn't look valid, Twitch may ignore it");
+        }
+    }
+
+    fn is_plausible(&self) -> bool {
+        (Self::MIN_LEN..=Self::MAX_LEN).contains(&self.0.len())
+            && self.0.chars().all(|c| c.is_ascii_alphanumeric())
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools, reason = "command line switches")]
 pub struct Args {
     servers: Option<Vec<Url>>,
     print_streams: bool,
     no_low_latency: bool,
     client_id: Option<String>,
-    auth_token: Option<String>,
+    auth_token: Option<AuthToken>,
     codecs: Cow<'static, str>,
     never_proxy: Option<Vec<String>>,
+    prefer_auth: Option<PreferAuth>,
+    usher_params: Option<Vec<(String, String)>>,
+    platform: Option<String>,
+    os_name: Option<String>,
     playlist_cache_dir: Option<String>,
-    force_playlist_url: Option<Url>,
-    channel: String,
+    playlist_cache_max_entries: usize,
+    force_playlist_url: Option<ForcedUrls>,
+    allow_suppressed: bool,
+    watch_heartbeat: bool,
+    exclude_clusters: Option<Vec<String>>,
+    prefer_clusters: Option<Vec<String>>,
+    cluster_attempts: u32,
+    container: Container,
+    no_codec_fallback: bool,
+    max_segment_duration: Duration,
+    min_poll_interval: Duration,
+    poll_jitter: Option<u32>,
+    min_segment_size: u64,
+    min_segment_size_action: SmallSegmentsAction,
+    save_prefs: bool,
+    channels: Channels,
+    wait: Option<Duration>,
+    channel_failover: bool,
     quality: Option<String>,
+    renditions: Vec<(String, Sink)>,
 }
 
 impl Default for Args {
     fn default() -> Self {
         Self {
             codecs: "av1,h265,h264".into(),
+            playlist_cache_max_entries: 100,
+            cluster_attempts: 5,
+            container: Container::default(),
+            no_codec_fallback: bool::default(),
+            max_segment_duration: Duration::from_secs(30),
+            min_poll_interval: Duration::from_secs(1),
+            poll_jitter: Option::default(),
+            min_segment_size: u64::default(),
+            min_segment_size_action: SmallSegmentsAction::default(),
+            save_prefs: bool::default(),
             servers: Option::default(),
             print_streams: bool::default(),
             no_low_latency: bool::default(),
             client_id: Option::default(),
             auth_token: Option::default(),
             never_proxy: Option::default(),
+            prefer_auth: Option::default(),
+            usher_params: Option::default(),
+            platform: Option::default(),
+            os_name: Option::default(),
             playlist_cache_dir: Option::default(),
             force_playlist_url: Option::default(),
-            channel: String::default(),
+            allow_suppressed: bool::default(),
+            watch_heartbeat: bool::default(),
+            exclude_clusters: Option::default(),
+            prefer_clusters: Option::default(),
+            channels: Channels::default(),
+            wait: Option::default(),
+            channel_failover: bool::default(),
             quality: Option::default(),
+            renditions: Vec::default(),
         }
     }
 }
@@ -67,46 +394,467 @@ impl Parse for Args {
         parser.parse_switch(&mut self.print_streams, "--print-streams")?;
         parser.parse_switch(&mut self.no_low_latency, "--no-low-latency")?;
         parser.parse_opt_string(&mut self.client_id, "--client-id")?;
-        parser.parse_opt_string(&mut self.auth_token, "--auth-token")?;
+        parser.parse_fn(&mut self.auth_token, "--auth-token", AuthToken::new)?;
         parser.parse_cow_string(&mut self.codecs, "--codecs")?;
         parser.parse_fn(&mut self.never_proxy, "--never-proxy", Self::split_comma)?;
+        parser.parse_fn(&mut self.prefer_auth, "--prefer-auth-for", PreferAuth::new)?;
+        parser.parse_fn(
+            &mut self.usher_params,
+            "--usher-param",
+            Self::parse_usher_params,
+        )?;
+        parser.parse_opt_string(&mut self.platform, "--platform")?;
+        parser.parse_opt_string(&mut self.os_name, "--os-name")?;
         parser.parse_opt_string(&mut self.playlist_cache_dir, "--playlist-cache-dir")?;
-        parser.parse_fn(&mut self.force_playlist_url, "--force-playlist-url", |a| {
-            Ok(Some(a.to_owned().into()))
+        parser.parse(
+            &mut self.playlist_cache_max_entries,
+            "--playlist-cache-max-entries",
+        )?;
+        parser.parse_fn(
+            &mut self.force_playlist_url,
+            "--force-playlist-url",
+            ForcedUrls::new,
+        )?;
+
+        parser.parse_switch(&mut self.allow_suppressed, "--allow-suppressed")?;
+        parser.parse_switch(&mut self.watch_heartbeat, "--watch-heartbeat")?;
+        parser.parse_fn(
+            &mut self.exclude_clusters,
+            "--exclude-clusters",
+            Self::split_comma,
+        )?;
+        parser.parse_fn(
+            &mut self.prefer_clusters,
+            "--prefer-clusters",
+            Self::split_comma,
+        )?;
+        parser.parse(&mut self.cluster_attempts, "--cluster-attempts")?;
+        parser.parse_fn(&mut self.container, "--container", Container::new)?;
+        parser.parse_switch(&mut self.no_codec_fallback, "--no-codec-fallback")?;
+        parser.parse_fn(
+            &mut self.max_segment_duration,
+            "--max-segment-duration",
+            Self::parse_max_duration,
+        )?;
+        parser.parse_fn(
+            &mut self.min_poll_interval,
+            "--min-poll-interval",
+            args::parse_duration,
+        )?;
+        parser.parse_fn(&mut self.poll_jitter, "--poll-jitter", |a| {
+            let jitter = a.parse()?;
+            ensure!(jitter <= 50, "Poll jitter must be at most 50%");
+
+            Ok(Some(jitter))
         })?;
+        parser.parse_fn(
+            &mut self.min_segment_size,
+            "--min-segment-size",
+            args::parse_size,
+        )?;
+        parser.parse_fn(
+            &mut self.min_segment_size_action,
+            "--min-segment-size-action",
+            SmallSegmentsAction::new,
+        )?;
+        parser.parse_switch(&mut self.save_prefs, "--save-prefs")?;
+        parser.parse_fn(&mut self.wait, "--wait", |a| {
+            let wait = args::parse_duration(a)?;
+            ensure!(!wait.is_zero(), "Invalid wait interval: {a}");
 
-        self.channel = parser
-            .parse_free_required()
-            .context("Missing channel argument")?
-            .to_lowercase()
-            .replace("twitch.tv/", "");
+            Ok(Some(wait))
+        })?;
+        parser.parse_switch(&mut self.channel_failover, "--channel-failover")?;
+
+        self.channels = Channels::new(
+            &parser
+                .parse_free_required()
+                .context("Missing channel argument")?,
+        )?;
 
         parser.parse_free(&mut self.quality, "quality")?;
         if self.print_streams {
             self.quality = None;
         }
 
-        if let Some(never_proxy) = &self.never_proxy {
-            if never_proxy.iter().any(|a| a.eq(&self.channel)) {
-                self.servers = None;
-            }
+        if let Some(quality) = self.quality.as_ref().filter(|q| q.contains(':')) {
+            self.renditions = Self::parse_renditions(quality)?;
+            self.quality = Some(self.renditions[0].0.clone());
         }
 
+        ensure!(
+            !self.channel_failover || self.renditions.is_empty(),
+            "--channel-failover can't be used with two qualities",
+        );
+
         Ok(())
     }
 }
 
 impl Args {
+    //the access token is only valid for the query it was signed with
+    const SIGNED_USHER_PARAMS: [&str; 2] = ["sig", "token"];
+
+    //the quality can come from the channel's prefs, so it's checked once they are applied
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.quality.is_some() || self.print_streams || self.force_playlist_url.is_some(),
+            "Missing quality argument (use --print-streams to list the available streams)",
+        );
+
+        for (key, value) in self.usher_overrides() {
+            ensure!(
+                !Self::SIGNED_USHER_PARAMS.contains(&key),
+                "Usher parameter {key} is signed by the access token and can't be overridden",
+            );
+            ensure!(
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "Invalid usher parameter name: {key}",
+            );
+            ensure!(
+                !value.contains(['&', '#', '?']) && !value.contains(char::is_whitespace),
+                "Usher parameter {key} must be URL encoded: {value}",
+            );
+        }
+
+        Ok(())
+    }
+
+    //--platform and --os-name first, so --usher-param wins if both set the same key
+    fn usher_overrides(&self) -> impl Iterator<Item = (&str, &str)> {
+        let shorthands = [("platform", &self.platform), ("os_name", &self.os_name)]
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value.as_deref()?)));
+
+        shorthands.chain(
+            self.usher_params
+                .iter()
+                .flatten()
+                .map(|(key, value)| (key.as_str(), value.as_str())),
+        )
+    }
+
+    pub fn has_fallback_urls(&self) -> bool {
+        self.force_playlist_url
+            .as_ref()
+            .is_some_and(ForcedUrls::has_fallbacks)
+    }
+
+    //a playlist that fails with an I/O error can be played from another forced URL
+    pub fn can_fail_over(&self, error: &anyhow::Error) -> bool {
+        self.has_fallback_urls() && error.downcast_ref::<std::io::Error>().is_some()
+    }
+
+    pub fn channel(&self) -> &str {
+        self.channels.active()
+    }
+
+    pub fn channels(&self) -> &[String] {
+        &self.channels.names
+    }
+
+    //--never-proxy applies to the channel being played, which can change with a channel list
+    fn servers(&self) -> Option<&Vec<Url>> {
+        self.servers.as_ref().filter(|_| {
+            !self
+                .never_proxy
+                .as_ref()
+                .is_some_and(|n| n.iter().any(|c| c == self.channel()))
+        })
+    }
+
+    pub fn quality(&self) -> Option<&str> {
+        self.quality.as_deref()
+    }
+
+    pub const fn print_streams(&self) -> bool {
+        self.print_streams
+    }
+
+    pub const fn low_latency(&self) -> bool {
+        !self.no_low_latency
+    }
+
+    //proxies are shared by many clients, so their polls are spread out unless disabled
+    pub fn poll_jitter(&self) -> u32 {
+        const PROXY_JITTER: u32 = 15;
+
+        match self.poll_jitter {
+            Some(jitter) => jitter,
+            None if self.servers().is_some() => PROXY_JITTER,
+            None => 0,
+        }
+    }
+
+    pub const fn small_segments(&self) -> SmallSegments {
+        SmallSegments::new(self.min_segment_size, self.min_segment_size_action)
+    }
+
+    pub fn cache_dir(&self) -> Option<&str> {
+        self.playlist_cache_dir.as_deref()
+    }
+
+    //fMP4 streams whose init segment fails fall back to MPEG-TS, unless only fMP4 is wanted
+    pub fn codec_fallback(&self) -> bool {
+        !self.no_codec_fallback && self.container != Container::Fmp4
+    }
+
+    pub const fn save_prefs(&self) -> bool {
+        self.save_prefs
+    }
+
+    //quality as it would be passed on the command line
+    pub fn quality_arg(&self) -> Option<String> {
+        if self.renditions.is_empty() {
+            return self.quality.clone();
+        }
+
+        Some(
+            self.renditions
+                .iter()
+                .map(|(quality, sink)| format!("{quality}:{}", sink.arg()))
+                .collect::<Vec<_>>()
+                .join(","),
+        )
+    }
+
+    //qualities mapped to outputs, empty unless two qualities are played at once
+    pub fn renditions(&self) -> &[(String, Sink)] {
+        &self.renditions
+    }
+
+    //"best:record,audio_only:player"
+    fn parse_renditions(arg: &str) -> Result<Vec<(String, Sink)>> {
+        let renditions = arg
+            .split(',')
+            .map(|rendition| {
+                let (quality, sink) = rendition
+                    .split_once(':')
+                    .with_context(|| format!("Quality must be QUALITY:OUTPUT: {rendition}"))?;
+
+                ensure!(!quality.is_empty(), "Missing quality in {rendition}");
+                Constraint::new(quality)?;
+
+                Ok((quality.to_owned(), Sink::new(sink)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(
+            renditions.len() == 2,
+            "Exactly two qualities can be played at once: {arg}",
+        );
+        ensure!(
+            renditions[0].1 != renditions[1].1,
+            "Qualities must be sent to different outputs: {arg}",
+        );
+
+        Ok(renditions)
+    }
+
     #[allow(clippy::unnecessary_wraps, reason = "function pointer")]
     fn split_comma<T: for<'a> From<&'a str>>(arg: &str) -> Result<Option<Vec<T>>> {
         Ok(Some(arg.split(',').map(T::from).collect()))
     }
+
+    //"platform=android,supported_codecs=h265,h264", a part without = continues the previous value
+    fn parse_usher_params(arg: &str) -> Result<Option<Vec<(String, String)>>> {
+        let mut params = Vec::<(String, String)>::new();
+        for part in arg.split(',') {
+            if let Some((key, value)) = part.split_once('=') {
+                params.push((key.to_owned(), value.to_owned()));
+            } else {
+                let (_, value) = params
+                    .last_mut()
+                    .with_context(|| format!("Usher parameter must be KEY=VALUE: {part}"))?;
+
+                value.push(',');
+                value.push_str(part);
+            }
+        }
+
+        Ok(Some(params))
+    }
+
+    //zero would reload the playlist in a busy loop
+    fn parse_max_duration(arg: &str) -> Result<Duration> {
+        let duration = args::parse_duration(arg)?;
+        ensure!(
+            duration >= Duration::from_secs(1),
+            "Maximum segment duration must be at least 1s",
+        );
+
+        Ok(duration)
+    }
 }
 
-fn map_if_offline(error: anyhow::Error) -> anyhow::Error {
+//404 means something different depending on which endpoint returned it
+fn map_if_offline(error: anyhow::Error, offline: OfflineError) -> anyhow::Error {
     if StatusError::is_not_found(&error) {
-        return OfflineError.into();
+        return offline.into();
     }
 
     error
 }
+
+//Value of an attribute in a playlist tag like #EXT-X-STREAM-INF,
+//quoted values like CODECS="avc1.64002A,mp4a.40.2" can contain commas
+fn attribute<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let mut rest = if line.starts_with('#') {
+        line.split_once(':')?.1
+    } else {
+        line
+    };
+
+    loop {
+        let (name, value) = rest.split_once('=')?;
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let (value, remaining) = quoted.split_once('"')?;
+                (value, remaining.split_once(',').map_or("", |(_, r)| r))
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+
+        if name.trim() == key {
+            return Some(value);
+        }
+
+        rest = remaining;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_attributes() {
+        let line = r#"#EXT-X-STREAM-INF:BANDWIDTH=6000000,CODECS="avc1.64002A,mp4a.40.2",RESOLUTION=1920x1080,VIDEO="chunked",FRAME-RATE=60.000"#;
+
+        assert_eq!(attribute(line, "BANDWIDTH"), Some("6000000"));
+        assert_eq!(attribute(line, "CODECS"), Some("avc1.64002A,mp4a.40.2"));
+        assert_eq!(attribute(line, "RESOLUTION"), Some("1920x1080"));
+        assert_eq!(attribute(line, "VIDEO"), Some("chunked"));
+        assert_eq!(attribute(line, "FRAME-RATE"), Some("60.000"));
+        assert_eq!(attribute(line, "AUDIO"), None);
+
+        //a quoted value that looks like an attribute isn't one
+        let line = r#"#EXT-X-TWITCH-INFO:NODE="a,CLUSTER=fake",CLUSTER="real""#;
+        assert_eq!(attribute(line, "CLUSTER"), Some("real"));
+
+        //without the tag, as the quality parser passes it
+        assert_eq!(
+            attribute(r#"CODECS="av01.0.08M.08,mp4a.40.2""#, "CODECS"),
+            Some("av01.0.08M.08,mp4a.40.2")
+        );
+        assert_eq!(attribute(r#"CODECS="unterminated"#, "CODECS"), None);
+    }
+
+    #[test]
+    fn offline_errors() {
+        let proxies = |servers, direct| OfflineError::ProxiesUnavailable { servers, direct };
+
+        assert_eq!(OfflineError::EndOfStream.exit_code(), 0);
+        assert_eq!(OfflineError::ChannelOffline.exit_code(), 2);
+        assert_eq!(proxies(3, Some(true)).exit_code(), 4);
+
+        assert_eq!(OfflineError::EndOfStream.to_string(), "Stream ended");
+        assert_eq!(
+            OfflineError::ChannelOffline.to_string(),
+            "Stream is offline or unavailable"
+        );
+        assert_eq!(proxies(1, None).to_string(), "The playlist proxy failed");
+        assert_eq!(
+            proxies(3, Some(true)).to_string(),
+            "All 3 playlist proxies failed, direct Twitch connectivity: OK (so the proxies are likely down)"
+        );
+        assert!(proxies(2, Some(false))
+            .to_string()
+            .ends_with("direct Twitch connectivity also failed (check your network/DNS)"));
+
+        //only 404s are mapped, anything else is kept as is
+        let error = map_if_offline(anyhow::anyhow!("timed out"), OfflineError::ChannelOffline);
+        assert!(error.downcast_ref::<OfflineError>().is_none());
+    }
+
+    const TOKEN: &str = "0123456789abcdefghijklmnopqrst";
+
+    fn token(arg: &str) -> String {
+        AuthToken::new(arg).unwrap().unwrap().0
+    }
+
+    #[test]
+    fn auth_token_normalization() {
+        assert_eq!(token(TOKEN), TOKEN);
+        assert_eq!(token(&format!("OAuth {TOKEN}")), TOKEN);
+        assert_eq!(token(&format!("oauth   {TOKEN}")), TOKEN);
+        assert_eq!(token(&format!(" \"{TOKEN}\" ")), TOKEN);
+        assert_eq!(token(&format!("'OAuth {TOKEN}'")), TOKEN);
+        assert_eq!(token(&format!("\"OAuth \"{TOKEN}\"\"")), TOKEN);
+        assert_eq!(
+            token(&format!(
+                "unique_id=abc; auth-token={TOKEN}; persistent=xyz"
+            )),
+            TOKEN,
+        );
+        assert_eq!(token(&format!("auth-token=\"{TOKEN}\"")), TOKEN);
+        //only a prefix is stripped
+        assert_eq!(token(&format!("{TOKEN}OAuth ")), format!("{TOKEN}OAuth"));
+    }
+
+    #[test]
+    fn auth_token_validation() {
+        let plausible = |arg: &str| AuthToken::new(arg).unwrap().unwrap().is_plausible();
+
+        assert!(plausible(TOKEN));
+        assert!(plausible(&format!("OAuth {TOKEN}")));
+        assert!(plausible(&"a".repeat(AuthToken::MIN_LEN)));
+        assert!(plausible(&"a".repeat(AuthToken::MAX_LEN)));
+
+        assert!(!plausible(""));
+        assert!(!plausible(&"a".repeat(AuthToken::MIN_LEN - 1)));
+        assert!(!plausible(&"a".repeat(AuthToken::MAX_LEN + 1)));
+        assert!(!plausible(&format!("{TOKEN}-")));
+        assert!(!plausible(&format!("Bearer {TOKEN}")));
+        //a cookie string without the token
+        assert!(!plausible(&format!("unique_id={TOKEN}; persistent=xyz")));
+    }
+
+    #[test]
+    fn auth_token_debug() {
+        assert_eq!(
+            format!("{:?}", AuthToken(TOKEN.to_owned())),
+            "AuthToken { len: 30, prefix: \"0123\" }",
+        );
+        assert_eq!(
+            format!("{:?}", AuthToken("ab".to_owned())),
+            "AuthToken { len: 2, prefix: \"\" }",
+        );
+    }
+
+    #[test]
+    fn prefer_auth() {
+        let prefer_auth = |arg: &str| PreferAuth::new(arg).unwrap().unwrap();
+
+        let channels = prefer_auth("Channel,other");
+        assert!(channels.applies_to("channel") && channels.applies_to("other"));
+        assert!(!channels.applies_to("third"));
+        assert!(prefer_auth("all").applies_to("third"));
+        assert!(prefer_auth("subscribed").applies_to("third"));
+
+        for arg in ["channel", "all"] {
+            let policy = prefer_auth(arg);
+            assert!(!policy.accepts(Entitlement::None));
+            assert!(policy.accepts(Entitlement::Subscriber));
+            assert!(policy.accepts(Entitlement::Turbo));
+            assert!(policy.accepts(Entitlement::AdFree));
+        }
+
+        //turbo isn't a subscription to the channel
+        let subscribed = prefer_auth("subscribed");
+        assert!(subscribed.accepts(Entitlement::Subscriber));
+        assert!(!subscribed.accepts(Entitlement::Turbo));
+        assert!(!subscribed.accepts(Entitlement::AdFree));
+        assert!(!subscribed.accepts(Entitlement::None));
+    }
+}
@@ -1,12 +1,14 @@
 use std::{
-    fs::{self, File, ReadDir},
+    fs::{self, File, Metadata, ReadDir},
+    hash::{DefaultHasher, Hash, Hasher},
     io::{Read, Write},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
     time::Duration,
 };
 
 use anyhow::{bail, Result};
-use log::{debug, error};
+use log::{debug, error, info};
 
 use crate::http::{Agent, Connection, Url};
 
@@ -17,17 +19,39 @@ pub struct Cache {
 impl Cache {
     const MAGIC: &str = concat!(env!("CARGO_PKG_NAME"), "\n");
 
-    pub fn new(dir: &Option<String>, channel: &str, quality: &Option<String>) -> Option<Self> {
+    pub fn new(
+        dir: &Option<String>,
+        max_entries: usize,
+        channel: &str,
+        quality: &Option<String>,
+    ) -> Option<Self> {
         let (dir, quality) = dir.as_ref().zip(quality.as_ref())?;
+        Self::open(dir, max_entries, &format!("{channel}-{quality}"))
+    }
+
+    //A token belongs to the credentials it was fetched with, so they're part of the name.
+    //The hash may change with the Rust version, that only costs a cache miss
+    pub fn new_token(
+        dir: &Option<String>,
+        max_entries: usize,
+        channel: &str,
+        client_id: Option<&str>,
+        auth_token: Option<&str>,
+    ) -> Option<Self> {
+        let mut hasher = DefaultHasher::new();
+        (client_id, auth_token).hash(&mut hasher);
+
+        let name = format!("{channel}-{:016x}.token", hasher.finish());
+        Self::open(dir.as_ref()?, max_entries, &name)
+    }
+
+    fn open(dir: &str, max_entries: usize, name: &str) -> Option<Self> {
+        static SWEPT: AtomicBool = AtomicBool::new(false);
 
         match Self::read_dir(dir) {
             Ok(iter) => {
-                for entry in iter {
-                    let Ok(entry) = entry else {
-                        continue;
-                    };
-
-                    Self::remove_if_stale(&entry.path());
+                if !SWEPT.swap(true, Ordering::Relaxed) {
+                    Self::sweep(iter, max_entries);
                 }
             }
             Err(e) => {
@@ -37,7 +61,7 @@ impl Cache {
         }
 
         Some(Self {
-            path: format!("{dir}/{channel}-{quality}").into(),
+            path: format!("{dir}/{name}").into(),
         })
     }
 
@@ -66,6 +90,29 @@ impl Cache {
         }
     }
 
+    //contents of the cache if it was written within max_age
+    pub fn get_recent(&self, max_age: Duration) -> Option<String> {
+        let mut file = Self::check_magic(&self.path)?;
+        let age = file.metadata().ok()?.modified().ok()?.elapsed().ok()?;
+        if age >= max_age {
+            return None;
+        }
+
+        let mut string = String::new();
+        file.read_to_string(&mut string).ok()?;
+
+        Some(string)
+    }
+
+    pub fn replace(&self, contents: &str) {
+        debug!("Replacing cache: {}", self.path.display());
+
+        let file = File::create(&self.path);
+        if let Err(e) = file.and_then(|mut f| write!(f, "{}{contents}", Self::MAGIC)) {
+            error!("Failed to write cache: {e}");
+        }
+    }
+
     fn read_dir(dir: &str) -> Result<ReadDir> {
         let metadata = fs::metadata(dir)?;
         if !metadata.is_dir() || metadata.permissions().readonly() {
@@ -94,17 +141,138 @@ impl Cache {
         }
     }
 
-    fn remove_if_stale(path: &Path) -> Option<()> {
+    //Removes stale entries and then the oldest entries until there are at most max_entries
+    fn sweep(iter: ReadDir, max_entries: usize) {
         const FOURTY_EIGHT_HOURS: Duration = Duration::from_secs(48 * 60 * 60);
 
-        Self::check_magic(path)?;
+        let mut entries = Vec::new();
+        let mut foreign = 0;
+        let mut stale = 0;
+        for entry in iter {
+            let Ok(entry) = entry else {
+                continue;
+            };
+
+            let path = entry.path();
+            let Some(modified) = Self::check_magic(&path)
+                .and_then(|f| f.metadata().ok())
+                .filter(Metadata::is_file)
+                .and_then(|m| m.modified().ok())
+            else {
+                foreign += 1;
+                continue;
+            };
+
+            if modified.elapsed().is_ok_and(|e| e >= FOURTY_EIGHT_HOURS) {
+                Self::remove_cache(&path);
+                stale += 1;
+            } else {
+                entries.push((modified, path));
+            }
+        }
+
+        if foreign > 0 {
+            debug!("Playlist cache directory contains {foreign} unrelated files");
+        }
+
+        let excess = entries.len().saturating_sub(max_entries);
+        if excess > 0 {
+            entries.sort_unstable_by_key(|(modified, _)| *modified);
+            for (_, path) in &entries[..excess] {
+                Self::remove_cache(path);
+            }
+        }
 
-        let metadata = fs::metadata(path).ok()?;
-        let modified = metadata.modified().ok().and_then(|t| t.elapsed().ok())?;
-        if metadata.is_file() && modified >= FOURTY_EIGHT_HOURS {
-            Self::remove_cache(path);
+        if stale > 0 || excess > 0 {
+            info!("Cleaned playlist cache: removed {stale} stale and {excess} excess entries");
         }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    //file last written hours ago
+    fn file(dir: &TempDir, name: &str, contents: &str, hours: u64) {
+        let file = File::create(dir.join(name)).unwrap();
+        (&file).write_all(contents.as_bytes()).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(hours * 60 * 60))
+            .unwrap();
+    }
+
+    fn entry(dir: &TempDir, name: &str, hours: u64) {
+        file(
+            dir,
+            name,
+            &format!("{}http://127.0.0.1/{name}", Cache::MAGIC),
+            hours,
+        );
+    }
+
+    fn sweep(dir: &TempDir, max_entries: usize) -> Vec<String> {
+        Cache::sweep(fs::read_dir(dir).unwrap(), max_entries);
+        dir.entries()
+    }
+
+    #[test]
+    fn token_name_depends_on_credentials() {
+        let temp = TempDir::new("cache-token");
+        let dir = Some(temp.to_string_lossy().into_owned());
+        let path = |client_id, auth_token| {
+            Cache::new_token(&dir, 100, "channel", client_id, auth_token)
+                .unwrap()
+                .path
+        };
+
+        let anonymous = path(None, None);
+        assert_eq!(anonymous, path(None, None));
+        assert_ne!(anonymous, path(None, Some("token")));
+        assert_ne!(anonymous, path(Some("client"), None));
+        assert_ne!(path(None, Some("token")), path(None, Some("other")));
+        assert_ne!(path(Some("token"), None), path(None, Some("token")));
+    }
+
+    #[test]
+    fn stale_entries_are_swept() {
+        let dir = TempDir::new("cache-stale");
+        entry(&dir, "a-best", 1);
+        entry(&dir, "b-best", 47);
+        entry(&dir, "c-best", 49);
+        entry(&dir, "d-best", 24 * 30);
+
+        assert_eq!(sweep(&dir, 100), ["a-best", "b-best"]);
+    }
+
+    #[test]
+    fn entries_are_capped() {
+        let dir = TempDir::new("cache-capped");
+        for (name, hours) in [("a", 5), ("b", 1), ("c", 4), ("d", 2), ("e", 3), ("f", 50)] {
+            entry(&dir, name, hours);
+        }
+
+        //the stale one goes first, then the oldest down to the cap
+        assert_eq!(sweep(&dir, 3), ["b", "d", "e"]);
+        assert_eq!(sweep(&dir, 3), ["b", "d", "e"]);
+        assert_eq!(sweep(&dir, 0), [] as [&str; 0]);
+    }
+
+    #[test]
+    fn foreign_files_are_left_alone() {
+        let dir = TempDir::new("cache-foreign");
+        file(&dir, "notes.txt", "not a cache entry", 24 * 30);
+        file(&dir, "empty", "", 100);
+        file(&dir, "truncated", &Cache::MAGIC[..4], 100);
+        fs::create_dir(dir.join("subdir")).unwrap();
+        entry(&dir, "a", 1);
+        entry(&dir, "b", 2);
 
-        Some(())
+        assert_eq!(
+            sweep(&dir, 1),
+            ["a", "empty", "notes.txt", "subdir", "truncated"]
+        );
     }
 }
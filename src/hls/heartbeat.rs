@@ -0,0 +1,159 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+
+use super::random;
+use crate::{
+    constants,
+    http::{Agent, Method},
+    json, logger,
+};
+
+//Periodic minute-watched events like the web player sends, so watch time is counted
+pub struct Heartbeat {
+    active: Arc<AtomicBool>,
+}
+
+impl Heartbeat {
+    const INTERVAL: Duration = Duration::from_secs(60);
+    const MAX_JITTER_MS: u64 = 5000;
+
+    //token is the value of the playback access token, which carries the channel and user IDs
+    pub fn spawn(token: &str, broadcast_id: &str, channel: &str, agent: &Agent) -> Result<Self> {
+        let channel_id =
+            json::field(token, "channel_id").context("Missing channel ID in access token")?;
+        let user_id = json::field(token, "user_id")
+            .filter(|id| *id != "null")
+            .context("Access token isn't tied to a user, check --auth-token")?;
+
+        let body = encode(&format!(
+            "[{{\
+                \"event\":\"minute-watched\",\
+                \"properties\":{{\
+                    \"broadcast_id\":\"{broadcast_id}\",\
+                    \"channel\":\"{channel}\",\
+                    \"channel_id\":\"{channel_id}\",\
+                    \"hidden\":false,\
+                    \"live\":true,\
+                    \"location\":\"channel\",\
+                    \"logged_in\":true,\
+                    \"muted\":false,\
+                    \"player\":\"site\",\
+                    \"user_id\":{user_id}\
+                }}\
+            }}]",
+        ));
+
+        let active = Arc::new(AtomicBool::default());
+        logger::spawn("heartbeat", {
+            let active = active.clone();
+            let agent = agent.clone();
+            move || Self::run(&active, &body, &agent)
+        })
+        .context("Failed to spawn heartbeat")?;
+
+        info!("Watch heartbeat active for channel {channel}");
+        Ok(Self { active })
+    }
+
+    //paused during ad breaks and while the playlist is stalled
+    pub fn set_active(&self, active: bool) {
+        self.active.store(active, Ordering::Relaxed);
+    }
+
+    fn run(active: &Arc<AtomicBool>, body: &str, agent: &Agent) {
+        let mut request = agent.text();
+        let url = constants::TWITCH_SPADE_ENDPOINT.into();
+        loop {
+            thread::sleep(Self::INTERVAL + Self::jitter(body));
+            if Arc::strong_count(active) == 1 {
+                return;
+            }
+
+            if !active.load(Ordering::Relaxed) {
+                debug!("Playback inactive, skipping watch heartbeat");
+                continue;
+            }
+
+            let result = request.text_fmt(
+                Method::Post,
+                &url,
+                format_args!(
+                    "Content-Type: application/x-www-form-urlencoded\r\n\
+                     Content-Length: {content_length}\r\n\
+                     \r\n\
+                     data={body}",
+                    content_length = "data=".len() + body.len(),
+                ),
+            );
+
+            match result {
+                Ok(_) => debug!("Sent watch heartbeat"),
+                Err(e) => error!("Failed to send watch heartbeat: {e}"),
+            }
+        }
+    }
+
+    //the body carries the channel, so sessions of different channels don't line up
+    fn jitter(body: &str) -> Duration {
+        Duration::from_millis(random::number(body) % Self::MAX_JITTER_MS)
+    }
+}
+
+//base64, percent-encoded for use in a form body
+fn encode(input: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+    let mut encoded = String::with_capacity(input.len() * 2);
+    for chunk in input.as_bytes().chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |bits, (i, b)| bits | u32::from(*b) << (16 - i * 8));
+
+        for i in 0..4 {
+            if i > chunk.len() {
+                encoded.push_str("%3D");
+                continue;
+            }
+
+            match (bits >> (18 - i * 6)) & 0x3f {
+                62 => encoded.push_str("%2B"),
+                63 => encoded.push_str("%2F"),
+                sextet => encoded.push(ALPHABET[sextet as usize] as char),
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(encode(""), "");
+        assert_eq!(encode("f"), "Zg%3D%3D");
+        assert_eq!(encode("fo"), "Zm8%3D");
+        assert_eq!(encode("foo"), "Zm9v");
+        assert_eq!(encode("foobar"), "Zm9vYmFy");
+        assert_eq!(encode(r#"[{"a":1}]"#), "W3siYSI6MX1d");
+    }
+
+    #[test]
+    fn form_unsafe_characters_are_escaped() {
+        assert_eq!(encode(">>>"), "Pj4%2B");
+        assert_eq!(encode("???"), "Pz8%2F");
+        assert_eq!(encode("ü"), "w7w%3D");
+    }
+}
@@ -1,79 +1,649 @@
 use std::{
     borrow::Cow,
+    collections::VecDeque,
     fmt::{self, Display, Formatter},
     ops::{Deref, DerefMut},
     str::{self, Utf8Error},
+    sync::{atomic::Ordering, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use getrandom::getrandom;
-use log::{debug, error, info};
+use anyhow::{ensure, Context, Result};
+use log::{debug, error, info, warn};
 
-use super::{cache::Cache, map_if_offline, Args, OfflineError};
+use super::{
+    attribute,
+    cache::Cache,
+    map_if_offline,
+    quality::{Constraint, Variant},
+    random, Args, Container, Entitlement, ForcedUrls, Heartbeat, MediaPlaylist, OfflineError,
+};
 
 use crate::{
     constants,
-    http::{Agent, Connection, Method, StatusError, Url},
+    http::{Agent, Connection, Headers, Method, StatusError, Url},
+    logger,
 };
 
-pub fn fetch_playlist(mut args: Args, agent: &Agent) -> Result<Option<Connection>> {
-    if let Some(url) = args.force_playlist_url.take() {
-        info!("Using forced playlist URL");
-        return Ok(Some(Connection::new(url, agent.text())));
+//An explicit quality that matches none of the streams, unlike a missing one this is an error
+#[derive(Debug)]
+struct QualityNotFoundError {
+    quality: String,
+    available: Vec<String>,
+}
+
+impl std::error::Error for QualityNotFoundError {}
+
+impl Display for QualityNotFoundError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "No stream matches {}, available streams: {}",
+            self.quality,
+            self.available.join(", "),
+        )
+    }
+}
+
+//Variant playlists in the order they should be tried
+pub struct Variants {
+    first: Option<Connection>,
+    candidates: VecDeque<(String, Url)>,
+    cache: Option<Cache>,
+    heartbeat: Option<Heartbeat>,
+    second: Option<Box<Self>>,
+    container: Container,
+    max_segment_duration: Duration,
+    min_poll_interval: Duration,
+    multivariant_url: Option<Url>,
+    agent: Agent,
+}
+
+impl Variants {
+    fn new(first: Option<Connection>, cache: Option<Cache>, args: &Args, agent: &Agent) -> Self {
+        Self {
+            first,
+            candidates: VecDeque::default(),
+            cache,
+            heartbeat: None,
+            second: None,
+            container: args.container,
+            max_segment_duration: args.max_segment_duration,
+            min_poll_interval: args.min_poll_interval,
+            multivariant_url: None,
+            agent: agent.clone(),
+        }
     }
 
-    let cache = Cache::new(&args.playlist_cache_dir, &args.channel, &args.quality);
-    if let Some(conn) = cache.as_ref().and_then(|c| c.get(agent)) {
+    pub fn next(&mut self) -> Option<(Option<String>, Connection)> {
+        if let Some(conn) = self.first.take() {
+            return Some((None, conn));
+        }
+
+        let (name, url) = self.candidates.pop_front()?;
+        Some((Some(name), Connection::new(url, self.agent.playlist())))
+    }
+
+    pub const fn multivariant_url(&self) -> Option<&Url> {
+        self.multivariant_url.as_ref()
+    }
+
+    pub fn take_heartbeat(&mut self) -> Option<Heartbeat> {
+        self.heartbeat.take()
+    }
+
+    pub const fn set_container(&mut self, container: Container) {
+        self.container = container;
+    }
+
+    //variants of the second quality when two are played at once
+    pub fn take_second(&mut self) -> Option<Self> {
+        self.second.take().map(|second| *second)
+    }
+
+    //returns the name of the quality that was opened if known
+    pub fn open(mut self) -> Result<(Option<String>, MediaPlaylist)> {
+        let mut mismatched = Vec::new();
+        while let Some((name, conn)) = self.next() {
+            let url = conn.url.clone();
+            let name_or_unknown = name.as_deref().unwrap_or("<unknown>");
+            match MediaPlaylist::new(conn, self.max_segment_duration, self.min_poll_interval) {
+                //the container is only known from the media playlist, so every candidate
+                //that doesn't match costs one request
+                Ok(playlist)
+                    if self.container != Container::Any
+                        && Container::of(&playlist) != self.container =>
+                {
+                    info!(
+                        "Quality {name_or_unknown} is {}, trying the next one for --container {}",
+                        Container::of(&playlist),
+                        self.container,
+                    );
+                    mismatched.push(name_or_unknown.to_owned());
+                }
+                Ok(playlist) => {
+                    if let Some(first) = mismatched.first() {
+                        info!(
+                            "Using {name_or_unknown} instead of {first}, it's {}",
+                            self.container,
+                        );
+                    }
+
+                    if let Some(name) = &name {
+                        info!("Using quality: {name}");
+                    }
+
+                    if let Some(cache) = &self.cache {
+                        cache.create(&url);
+                    }
+
+                    return Ok((name, playlist));
+                }
+                Err(e) if is_unavailable(&e) => {
+                    error!("Quality {name_or_unknown} is unavailable: {e}");
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        ensure!(
+            mismatched.is_empty(),
+            "No stream is {} (--container), tried {}",
+            self.container,
+            mismatched.join(", "),
+        );
+
+        Err(OfflineError::ChannelOffline.into())
+    }
+}
+
+//None if the streams were listed instead of choosing one (--print-streams)
+pub fn fetch_playlist(args: &Args, agent: &Agent) -> Result<Option<Variants>> {
+    if let Some(forced) = &args.force_playlist_url {
+        return fetch_forced_playlists(forced, false, args, agent);
+    }
+
+    //The container and cluster of a cached URL aren't known without fetching it, so they
+    //can't be preferred, and listing streams needs the multivariant playlist
+    let cache = playlist_cache(args);
+    let bypass_cache = args.container != Container::Any
+        || args.print_streams
+        || args.exclude_clusters.is_some()
+        || args.prefer_clusters.is_some();
+    if let Some(conn) = cache
+        .as_ref()
+        .filter(|_| !bypass_cache)
+        .and_then(|c| c.get(agent))
+    {
         info!("Using cached playlist URL");
-        return Ok(Some(conn));
+        return Ok(Some(Variants::new(Some(conn), None, args, agent)));
+    }
+
+    let watch_heartbeat = args.watch_heartbeat && args.auth_token.is_some();
+    if args.watch_heartbeat && !watch_heartbeat {
+        error!("Watch heartbeat requires an auth token, disabling");
     }
 
-    info!("Fetching playlist for channel {}", &args.channel);
-    let playlist = if let Some(servers) = &args.servers {
-        fetch_proxy_playlist(
+    fetch_variants(args, cache, watch_heartbeat, agent)
+}
+
+//Fetches the multivariant playlist again when the variant that was playing went away,
+//the cached URL is replaced instead of used and the heartbeat keeps running
+pub fn refetch_playlist(args: &Args, agent: &Agent) -> Result<Option<Variants>> {
+    if let Some(forced) = &args.force_playlist_url {
+        return fetch_forced_playlists(forced, true, args, agent);
+    }
+
+    fetch_variants(args, playlist_cache(args), false, agent)
+}
+
+fn playlist_cache(args: &Args) -> Option<Cache> {
+    //a cached URL is only for one quality
+    Cache::new(
+        &args.playlist_cache_dir,
+        args.playlist_cache_max_entries,
+        args.channel(),
+        &args.quality,
+    )
+    .filter(|_| args.renditions.is_empty())
+}
+
+fn fetch_variants(
+    args: &Args,
+    cache: Option<Cache>,
+    watch_heartbeat: bool,
+    agent: &Agent,
+) -> Result<Option<Variants>> {
+    info!("Fetching playlist for channel {}", args.channel());
+    let entitled = args
+        .servers()
+        .as_ref()
+        .and_then(|_| check_prefer_auth(args, agent));
+
+    let (playlist, token) = match args.servers() {
+        Some(servers) if entitled.is_none() => {
+            let playlist = fetch_proxy_playlist(
+                !args.no_low_latency,
+                servers,
+                &args.codecs,
+                args.channel(),
+                agent,
+            )?;
+
+            (playlist, None)
+        }
+        _ => {
+            let (playlist, token) = fetch_twitch(args, entitled, agent)?;
+            (playlist, Some(token))
+        }
+    };
+
+    let (playlist, url) = fetch_cluster(playlist, args, || match &token {
+        Some(token) => fetch_twitch_playlist(token, args, agent),
+        None => Ok(fetch_proxy_playlist(
             !args.no_low_latency,
-            servers,
+            args.servers().map(Vec::as_slice).unwrap_or_default(),
             &args.codecs,
-            &args.channel,
+            args.channel(),
             agent,
-        )?
+        )?),
+    })?;
+
+    let info = TwitchInfo::new(&playlist);
+    if let Some(info) = &info {
+        info.log();
+        if info.suppress {
+            ensure!(
+                args.allow_suppressed,
+                "Channel is suppressing playback or hosting other content \
+                 (use --allow-suppressed to play anyway)",
+            );
+
+            info!("Channel is suppressing playback or hosting other content, continuing anyway");
+        }
+    }
+
+    let Some(candidates) = choose_stream(&playlist, args.quality.as_deref())? else {
+        let source = args
+            .servers()
+            .is_some()
+            .then(|| format!("{}://{}", url.scheme, url.host().unwrap_or("<unknown>")));
+
+        print_streams(&playlist, source.as_deref());
+        return Ok(None);
+    };
+
+    //the weaver is connected to while the rest of the startup runs
+    if let Some((_, url)) = candidates.front() {
+        agent.preconnect(url);
+    }
+
+    let mut variants = Variants::new(None, cache, args, agent);
+    variants.candidates = candidates;
+    variants.multivariant_url = Some(url);
+    variants.second = choose_second(&playlist, args, agent)?;
+
+    if watch_heartbeat {
+        variants.heartbeat = start_heartbeat(token.as_ref(), info.as_ref(), args.channel(), agent);
+    }
+
+    Ok(Some(variants))
+}
+
+//Fetching the playlist again gets a new cluster assigned, so bad clusters can be avoided
+fn fetch_cluster(
+    mut playlist: (String, Url),
+    args: &Args,
+    mut refetch: impl FnMut() -> Result<(String, Url)>,
+) -> Result<(String, Url)> {
+    if args.exclude_clusters.is_none() && args.prefer_clusters.is_none() {
+        return Ok(playlist);
+    }
+
+    let matches = |list: &Option<Vec<String>>, cluster: &str| {
+        list.as_ref()
+            .is_some_and(|l| l.iter().any(|c| c.eq_ignore_ascii_case(cluster)))
+    };
+
+    let mut attempt = 0;
+    loop {
+        let Some(cluster) = TwitchInfo::new(&playlist.0).and_then(|i| i.cluster) else {
+            debug!("Playlist has no cluster, can't choose one");
+            return Ok(playlist);
+        };
+
+        let reason = if matches(&args.exclude_clusters, cluster) {
+            "excluded"
+        } else if args.prefer_clusters.is_some() && !matches(&args.prefer_clusters, cluster) {
+            "not preferred"
+        } else {
+            info!("Using cluster {cluster}");
+            return Ok(playlist);
+        };
+
+        if attempt == args.cluster_attempts {
+            warn!("No acceptable cluster after {attempt} attempts, using {cluster} ({reason})");
+            return Ok(playlist);
+        }
+
+        attempt += 1;
+        info!(
+            "Got {cluster}, {reason}, retrying ({attempt}/{})",
+            args.cluster_attempts,
+        );
+        playlist = refetch()?;
+    }
+}
+
+//Playlist, its URL and the access token it was fetched with,
+//a token that was just fetched is used as is
+fn fetch_twitch(
+    args: &Args,
+    fetched: Option<AccessToken>,
+    agent: &Agent,
+) -> Result<((String, Url), AccessToken)> {
+    let token_cache = token_cache(args);
+    let fetch_token = || fetch_access_token(args, token_cache.as_ref(), agent);
+    let fetch = |token: &AccessToken| fetch_twitch_playlist(token, args, agent);
+
+    if let Some(token) = fetched {
+        return Ok((fetch(&token)?, token));
+    }
+
+    let Some(token) = token_cache
+        .as_ref()
+        .and_then(|c| AccessToken::from_cache(&c.get_recent(AccessToken::CACHE_TTL)?))
+    else {
+        let token = fetch_token()?;
+        return Ok((fetch(&token)?, token));
+    };
+
+    info!("Using cached access token");
+    match fetch(&token) {
+        Ok(playlist) => Ok((playlist, token)),
+        Err(e) if StatusError::is_forbidden(&e) => {
+            info!("Cached access token was rejected, fetching new one");
+            let token = fetch_token()?;
+            Ok((fetch(&token)?, token))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn token_cache(args: &Args) -> Option<Cache> {
+    Cache::new_token(
+        &args.playlist_cache_dir,
+        args.playlist_cache_max_entries,
+        args.channel(),
+        args.client_id.as_deref(),
+        args.auth_token.as_ref().map(|t| t.0.as_str()),
+    )
+}
+
+fn fetch_access_token(args: &Args, cache: Option<&Cache>, agent: &Agent) -> Result<AccessToken> {
+    //usher comes next, its connection is opened while waiting for the token
+    agent.preconnect(&constants::TWITCH_HLS_BASE.into());
+
+    let response = fetch_twitch_gql(
+        args.client_id.clone(),
+        args.auth_token.as_ref().map(|t| {
+            t.validate();
+            t.0.clone()
+        }),
+        args.channel(),
+        &gql_body(args.channel()),
+        agent,
+    )?;
+
+    let token = AccessToken::new(&response)?;
+    if let Some(cache) = cache {
+        cache.replace(&token.to_string());
+    }
+
+    Ok(token)
+}
+
+//With --prefer-auth-for the proxy is only used if the auth token isn't entitled to
+//ad-free playback, the token is returned to play from Twitch if it is
+fn check_prefer_auth(args: &Args, agent: &Agent) -> Option<AccessToken> {
+    let prefer_auth = args
+        .prefer_auth
+        .as_ref()
+        .filter(|p| p.applies_to(args.channel()))?;
+
+    if args.auth_token.is_none() {
+        error!("--prefer-auth-for requires an auth token, using the playlist proxy");
+        return None;
+    }
+
+    let token_cache = token_cache(args);
+    let token = match fetch_access_token(args, token_cache.as_ref(), agent) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to check entitlement, using the playlist proxy: {e}");
+            return None;
+        }
+    };
+
+    let entitlement = token.entitlement();
+    if prefer_auth.accepts(entitlement) {
+        info!("Auth token is {entitlement}, not using the playlist proxy (--prefer-auth-for)");
+        Some(token)
     } else {
-        let response = fetch_twitch_gql(
-            args.client_id.take(),
-            args.auth_token.take(),
-            &args.channel,
-            agent,
-        )?;
+        info!("Auth token is {entitlement} for this channel, using the playlist proxy");
+        None
+    }
+}
 
-        fetch_twitch_playlist(
-            &response,
-            !args.no_low_latency,
-            &args.codecs,
-            &args.channel,
-            agent,
-        )?
+fn start_heartbeat(
+    token: Option<&AccessToken>,
+    info: Option<&TwitchInfo>,
+    channel: &str,
+    agent: &Agent,
+) -> Option<Heartbeat> {
+    let Some(token) = token else {
+        error!("Watch heartbeat unavailable when using playlist proxies");
+        return None;
     };
 
-    let Some(url) = choose_stream(&playlist, &args.quality, args.print_streams) else {
-        print_streams(&playlist);
+    let Some(broadcast_id) = info.and_then(|i| i.broadcast_id) else {
+        error!("Watch heartbeat unavailable: missing broadcast ID");
+        return None;
+    };
+
+    Heartbeat::spawn(&token.token, broadcast_id, channel, agent)
+        .map_err(|e| error!("Watch heartbeat unavailable: {e}"))
+        .ok()
+}
+
+//Forced URLs are usually media playlists, but may be a multivariant playlist copied from elsewhere
+//Plays the first forced URL that returns a playlist, failed is set when refetching
+//because the active one stopped working
+fn fetch_forced_playlists(
+    forced: &ForcedUrls,
+    failed: bool,
+    args: &Args,
+    agent: &Agent,
+) -> Result<Option<Variants>> {
+    let mut order = forced.order(failed).peekable();
+    loop {
+        let (index, url) = order.next().context("Missing forced playlist URL")?;
+        match fetch_forced_playlist(url.clone(), args, agent) {
+            Ok(variants) => {
+                if forced.set_active(index) {
+                    info!("Switched to forced playlist URL {}", index + 1);
+                }
+
+                return Ok(variants);
+            }
+            Err(e) if order.peek().is_some() => {
+                error!("Forced playlist URL {} failed: {e}", index + 1);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn fetch_forced_playlist(url: Url, args: &Args, agent: &Agent) -> Result<Option<Variants>> {
+    info!("Using forced playlist URL");
+    let mut conn = Connection::new(url, agent.playlist());
+    let (is_playlist, multivariant) = conn
+        .text()
+        .map(|(playlist, _)| {
+            (
+                playlist.trim_start().starts_with("#EXTM3U"),
+                is_multivariant(playlist),
+            )
+        })
+        .map_err(|e| map_if_offline(e, OfflineError::ChannelOffline))?;
+
+    ensure!(is_playlist, "Forced playlist URL didn't return a playlist");
+
+    if !multivariant {
+        ensure!(
+            args.renditions.is_empty(),
+            "Forced playlist URL is a variant playlist, it can't be played in two qualities",
+        );
+
+        return Ok(Some(Variants::new(Some(conn), None, args, agent)));
+    }
+
+    ensure!(
+        args.quality.is_some() || args.print_streams,
+        "Forced playlist URL is a multivariant playlist, \
+         pass a variant playlist URL or a quality argument",
+    );
+
+    let playlist = conn.request.take();
+    if let Some(info) = TwitchInfo::new(&playlist) {
+        info.log();
+    }
+
+    let Some(candidates) = choose_stream(&playlist, args.quality.as_deref())? else {
+        print_streams(&playlist, Some("forced playlist URL"));
         return Ok(None);
     };
 
-    if let Some(cache) = &cache {
-        cache.create(&url);
+    info!(
+        "Forced playlist URL was a multivariant playlist, selected {}",
+        candidates.front().map_or("<unknown>", |(name, _)| name),
+    );
+
+    let mut variants = Variants::new(None, None, args, agent);
+    variants.candidates = candidates;
+    variants.multivariant_url = Some(conn.url);
+    variants.second = choose_second(&playlist, args, agent)?;
+
+    Ok(Some(variants))
+}
+
+//Checks if the channel is live with one GQL request, None for forced playlist URLs
+//or if the answer isn't known
+pub fn check_live(args: &Args, agent: &Agent) -> Option<bool> {
+    if args.force_playlist_url.is_some() {
+        return None;
+    }
+
+    let response = fetch_twitch_gql(
+        args.client_id.clone(),
+        None,
+        args.channel(),
+        &live_body(args.channel()),
+        agent,
+    )
+    .inspect_err(|e| debug!("Live check failed: {e}"))
+    .ok()?;
+
+    //a channel that doesn't exist has no user
+    if response.contains(r#""stream":{"#) {
+        Some(true)
+    } else if response.contains(r#""stream":null"#) || response.contains(r#""user":null"#) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+//Chooses the first live channel of a channel list with the live check, which is cheaper
+//than fetching playlists. A channel whose state isn't known is played, the playlist fetch
+//decides. With --wait the list is checked again until one is live
+pub fn select_channel(args: &Args, agent: &Agent) -> Result<()> {
+    let channels = &args.channels;
+    if channels.names.len() < 2 && args.wait.is_none() {
+        return Ok(());
+    }
+
+    //only the first round is logged while waiting
+    for round in 0.. {
+        for (index, channel) in channels.names.iter().enumerate() {
+            channels.set_active(index);
+            match check_live(args, agent) {
+                Some(false) if round > 0 => debug!("Channel {channel} is offline"),
+                Some(false) => info!("Channel {channel} is offline"),
+                live if channels.names.len() > 1 => {
+                    let unknown = if live.is_none() {
+                        " (live state unknown)"
+                    } else {
+                        ""
+                    };
+                    info!("Playing channel {channel}{unknown}");
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            }
+        }
+
+        channels.set_active(0);
+        let Some(wait) = args.wait else {
+            break;
+        };
+
+        if round == 0 {
+            info!("No channel is live, checking every {}s", wait.as_secs_f64());
+        }
+        thread::sleep(wait);
+    }
+
+    Err(OfflineError::ChannelOffline.into())
+}
+
+//With --channel-failover, switches to the first live channel of the list other than
+//the one that ended. Only a confirmed live channel is switched to
+pub fn fail_over_channel(args: &Args, agent: &Agent) -> bool {
+    let channels = &args.channels;
+    if !args.channel_failover || channels.names.len() < 2 {
+        return false;
+    }
+
+    let ended = channels.active.load(Ordering::Relaxed);
+    for (index, channel) in channels.names.iter().enumerate() {
+        if index == ended {
+            continue;
+        }
+
+        channels.set_active(index);
+        if check_live(args, agent) == Some(true) {
+            info!(
+                "Channel {} ended, switching to channel {channel}",
+                channels.names[ended],
+            );
+            return true;
+        }
     }
 
-    Ok(Some(Connection::new(url, agent.text())))
+    channels.set_active(ended);
+    debug!("No other channel of the list is live");
+    false
 }
 
 fn fetch_twitch_gql(
     client_id: Option<String>,
     auth_token: Option<String>,
     channel: &str,
+    body: &str,
     agent: &Agent,
 ) -> Result<String> {
-    const GQL_LEN_WITHOUT_CHANNEL: usize = 249;
-
     let mut client_id_buf = ArrayString::<30>::new();
     let client_id = choose_client_id(&mut client_id_buf, client_id, &auth_token, agent)?;
 
@@ -88,103 +658,223 @@ fn fetch_twitch_gql(
              {auth_token_head}{auth_token}{auth_token_tail}\
              Content-Length: {content_length}\r\n\
              \r\n\
-             {{\
-                \"extensions\":{{\
-                    \"persistedQuery\":{{\
-                        \"sha256Hash\":\"0828119ded1c13477966434e15800ff57ddacf13ba1911c129dc2200705b0712\",\
-                        \"version\":1\
-                    }}\
-                }},\
-                \"operationName\":\"PlaybackAccessToken\",\
-                \"variables\":{{\
-                    \"isLive\":true,\
-                    \"isVod\":false,\
-                    \"login\":\"{channel}\",\
-                    \"playerType\":\"site\",\
-                    \"vodID\":\"\"\
-                }}\
-             }}",
-             device_id = ArrayString::<32>::random()?,
-             content_length = GQL_LEN_WITHOUT_CHANNEL + channel.len(),
-             auth_token_head = if auth_token.is_some() { "Authorization: OAuth " } else { "" },
-             auth_token_tail = if auth_token.is_some() { "\r\n" } else { "" },
-             auth_token = auth_token.unwrap_or_default(),
-        )
+             {body}",
+            device_id = ArrayString::<32>::random(channel),
+            content_length = body.len(),
+            auth_token_head = if auth_token.is_some() {
+                "Authorization: OAuth "
+            } else {
+                ""
+            },
+            auth_token_tail = if auth_token.is_some() { "\r\n" } else { "" },
+            auth_token = auth_token.unwrap_or_default(),
+        ),
     )?;
 
     let mut response = request.take();
     response.retain(|c| c != '\\');
 
-    debug!("GQL response: {response}");
+    if logger::is_debug() {
+        debug!("GQL response: {}", logger::redact_dump(&response));
+    }
+
     Ok(response)
 }
 
-fn fetch_twitch_playlist(
-    gql_response: &str,
-    low_latency: bool,
-    codecs: &str,
-    channel: &str,
-    agent: &Agent,
-) -> Result<String> {
+//PlaybackAccessToken query, formatted before the request so its length is measured
+fn gql_body(channel: &str) -> String {
+    format!(
+        "{{\
+            \"extensions\":{{\
+                \"persistedQuery\":{{\
+                    \"sha256Hash\":\"0828119ded1c13477966434e15800ff57ddacf13ba1911c129dc2200705b0712\",\
+                    \"version\":1\
+                }}\
+            }},\
+            \"operationName\":\"PlaybackAccessToken\",\
+            \"variables\":{{\
+                \"isLive\":true,\
+                \"isVod\":false,\
+                \"login\":\"{channel}\",\
+                \"playerType\":\"site\",\
+                \"vodID\":\"\"\
+            }}\
+         }}"
+    )
+}
+
+fn live_body(channel: &str) -> String {
+    format!(r#"{{"query":"query{{user(login:\"{channel}\"){{stream{{id}}}}}}"}}"#)
+}
+
+//Attributes of the #EXT-X-TWITCH-INFO line in the master playlist
+struct TwitchInfo<'a> {
+    suppress: bool,
+    cluster: Option<&'a str>,
+    manifest_cluster: Option<&'a str>,
+    broadcast_id: Option<&'a str>,
+}
+
+impl<'a> TwitchInfo<'a> {
+    fn new(playlist: &'a str) -> Option<Self> {
+        let line = playlist
+            .lines()
+            .find_map(|l| l.strip_prefix("#EXT-X-TWITCH-INFO:"))?;
+
+        Some(Self {
+            suppress: attribute(line, "SUPPRESS") == Some("true"),
+            cluster: attribute(line, "CLUSTER").or_else(|| attribute(line, "MANIFEST-CLUSTER")),
+            manifest_cluster: attribute(line, "MANIFEST-CLUSTER"),
+            broadcast_id: attribute(line, "BROADCAST-ID"),
+        })
+    }
+
+    fn log(&self) {
+        if let Some(broadcast_id) = self.broadcast_id {
+            info!("Broadcast ID: {broadcast_id}");
+        }
+
+        debug!(
+            "Suppress: {}, cluster: {}, manifest cluster: {}",
+            self.suppress,
+            self.cluster.unwrap_or("<unknown>"),
+            self.manifest_cluster.unwrap_or("<unknown>"),
+        );
+    }
+}
+
+//Signature and token from the PlaybackAccessToken GQL response
+struct AccessToken {
+    signature: String,
+    token: String,
+}
+
+impl Display for AccessToken {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}\n{}", self.signature, self.token)
+    }
+}
+
+impl AccessToken {
+    const CACHE_TTL: Duration = Duration::from_secs(10 * 60);
+
+    fn new(gql_response: &str) -> Result<Self> {
+        const SIGNATURE_LEN: usize = 40;
+        const SIGNATURE: &str = r#""signature":""#;
+
+        let start = gql_response
+            .find(SIGNATURE)
+            .context("Failed to find signature in GQL response")?
+            + SIGNATURE.len();
+
+        let signature = gql_response
+            .get(start..start + SIGNATURE_LEN)
+            .context("Invalid signature in GQL response")?
+            .to_owned();
+
+        let start = gql_response
+            .find(r#"{"adblock""#)
+            .ok_or(OfflineError::ChannelOffline)?;
+        let end = gql_response
+            .find(r#"","signature""#)
+            .ok_or(OfflineError::ChannelOffline)?;
+
+        Ok(Self {
+            signature,
+            token: gql_response
+                .get(start..end)
+                .context("Invalid token in GQL response")?
+                .to_owned(),
+        })
+    }
+
+    //Fields of the token JSON, the GQL response is unescaped so it's plain JSON:
+    //{"adblock":false,...,"hide_ads":false,...,"show_ads":true,"subscriber":false,"turbo":false,...}
+    fn entitlement(&self) -> Entitlement {
+        let field = |name: &str| {
+            let start = self.token.find(&format!(r#""{name}":"#))? + name.len() + 3;
+            let value = &self.token[start..];
+            if value.starts_with("true") {
+                Some(true)
+            } else if value.starts_with("false") {
+                Some(false)
+            } else {
+                None
+            }
+        };
+
+        if field("subscriber") == Some(true) {
+            Entitlement::Subscriber
+        } else if field("turbo") == Some(true) {
+            Entitlement::Turbo
+        } else if field("hide_ads") == Some(true) || field("show_ads") == Some(false) {
+            Entitlement::AdFree
+        } else {
+            Entitlement::None
+        }
+    }
+
+    fn from_cache(cached: &str) -> Option<Self> {
+        let (signature, token) = cached.split_once('\n')?;
+        Some(Self {
+            signature: signature.to_owned(),
+            token: token.to_owned(),
+        })
+    }
+}
+
+fn fetch_twitch_playlist(token: &AccessToken, args: &Args, agent: &Agent) -> Result<(String, Url)> {
     let url = format!(
-        "{base_url}{channel}.m3u8\
-        ?acmb=e30%3D\
-        &allow_source=true\
-        &allow_audio_only=true\
-        &cdm=wv\
-        &fast_bread={low_latency}\
-        &playlist_include_framerate=true\
-        &player_backend=mediaplayer\
-        &reassignments_supported=true\
-        &supported_codecs={codecs}\
-        &transcode_mode=cbr_v1\
-        &p={p}\
-        &play_session_id={play_session_id}\
-        &sig={sig}\
-        &token={token}\
-        &player_version={player_version}\
-        &warp={low_latency}\
-        &browser_family=firefox\
-        &browser_version={browser_version}\
-        &os_name=Windows\
-        &os_version=NT+10.0\
-        &platform=web",
-        base_url = constants::TWITCH_HLS_BASE,
-        p = {
-            let mut buf = [0u8; 4];
-            getrandom(&mut buf)?;
-
-            u32::from_be_bytes(buf) % 9_999_999
-        },
-        play_session_id = ArrayString::<32>::random()?,
-        sig = {
-            const SIGNATURE_LEN: usize = 40;
-            const TOKEN: &str = r#""signature":""#;
-
-            let start = gql_response
-                .find(TOKEN)
-                .context("Failed to find signature in GQL response")?
-                + TOKEN.len();
-
-            &gql_response
-                .get(start..start + SIGNATURE_LEN)
-                .context("Invalid signature in GQL response")?
-        },
-        token = {
-            let start = gql_response.find(r#"{"adblock""#).ok_or(OfflineError)?;
-            let end = gql_response.find(r#"","signature""#).ok_or(OfflineError)?;
-
-            &gql_response[start..end]
-        },
-        player_version = constants::PLAYER_VERSION,
-        browser_version = &constants::USER_AGENT[(constants::USER_AGENT.len() - 5)..],
+        "{}{}.m3u8?{}",
+        constants::TWITCH_HLS_BASE,
+        args.channel(),
+        usher_query(token, args),
     )
     .into();
 
     let mut request = agent.text();
-    request.text(Method::Get, &url).map_err(map_if_offline)?;
+    request
+        .text(Method::Get, &url)
+        .map_err(|e| map_if_offline(e, OfflineError::ChannelOffline))?;
+
+    Ok((request.take(), url))
+}
+
+//what the web player sends, with --platform, --os-name and --usher-param applied
+fn usher_query(token: &AccessToken, args: &Args) -> UsherQuery {
+    let low_latency = !args.no_low_latency;
+    let mut query = UsherQuery::default();
+    query.add("acmb", "e30%3D");
+    query.add("allow_source", true);
+    query.add("allow_audio_only", true);
+    query.add("cdm", "wv");
+    query.add("fast_bread", low_latency);
+    query.add("playlist_include_framerate", true);
+    query.add("player_backend", "mediaplayer");
+    query.add("reassignments_supported", true);
+    query.add("supported_codecs", &args.codecs);
+    query.add("transcode_mode", "cbr_v1");
+    query.add("p", random::number(args.channel()) % 9_999_999);
+    query.add("play_session_id", ArrayString::<32>::random(args.channel()));
+    query.add("sig", &token.signature);
+    query.add("token", &token.token);
+    query.add("player_version", constants::PLAYER_VERSION);
+    query.add("warp", low_latency);
+    query.add("browser_family", "firefox");
+    query.add(
+        "browser_version",
+        &constants::USER_AGENT[(constants::USER_AGENT.len() - 5)..],
+    );
+    query.add("os_name", "Windows");
+    query.add("os_version", "NT+10.0");
+    query.add("platform", "web");
+
+    for (key, value) in args.usher_overrides() {
+        debug!("Overriding usher parameter: {key}={value}");
+        query.set(key, value);
+    }
 
-    Ok(request.take())
+    query
 }
 
 fn fetch_proxy_playlist(
@@ -193,16 +883,18 @@ fn fetch_proxy_playlist(
     codecs: &str,
     channel: &str,
     agent: &Agent,
-) -> Result<String, OfflineError> {
+) -> Result<(String, Url), OfflineError> {
+    let mut offline = false;
+    let mut url = Url::default();
     let mut request = agent.text();
-    for server in servers {
+    for server in ProxyHints::order(servers) {
         info!(
             "Using playlist proxy: {}://{}",
             server.scheme,
             server.host().unwrap_or("<unknown>"),
         );
 
-        let url = format!(
+        url = format!(
             "{}?allow_source=true\
             &allow_audio_only=true\
             &fast_bread={low_latency}\
@@ -213,62 +905,202 @@ fn fetch_proxy_playlist(
         )
         .into();
 
-        match request.text(Method::Get, &url) {
-            Ok(_) => break,
-            Err(e) if StatusError::is_not_found(&e) => error!("Server returned stream offline"),
+        let result = request.text(Method::Get, &url).map(|_| ());
+        ProxyHints::update(server, request.headers());
+        match result {
+            Ok(()) => break,
+            Err(e) if StatusError::is_not_found(&e) => {
+                error!("Server returned stream offline");
+                offline = true;
+            }
             Err(e) => error!("{e}"),
         }
     }
 
     let playlist = request.take();
     if playlist.is_empty() {
-        return Err(OfflineError);
+        if offline {
+            return Err(OfflineError::ChannelOffline);
+        }
+
+        return Err(OfflineError::ProxiesUnavailable {
+            servers: servers.len(),
+            direct: Some(probe_twitch(agent)),
+        });
     }
 
-    Ok(playlist)
+    Ok((playlist, url))
 }
 
-fn choose_stream(playlist: &str, quality: &Option<String>, should_print: bool) -> Option<Url> {
-    debug!("Master playlist:\n{playlist}");
-    let (Some(quality), false) = (quality, should_print) else {
-        return None;
-    };
+//Tells whether the proxies or the network are to blame when every proxy failed
+fn probe_twitch(agent: &Agent) -> bool {
+    const TIMEOUT: Duration = Duration::from_secs(2);
 
-    let mut iter = playlist_iter(playlist);
-    if quality == "best" {
-        return Some(iter.next()?.1.into());
+    info!("Checking direct Twitch connectivity...");
+    match agent.probe(&constants::TWITCH_HLS_BASE.into(), TIMEOUT) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Twitch connectivity check failed: {e}");
+            false
+        }
     }
+}
 
-    iter.find(|(name, _)| name == quality)
-        .map(|(_, url)| url.into())
+//Response headers of playlist proxies, some report a donation link and their rate limit
+struct ProxyHints {
+    donations_logged: Vec<String>,
+    //servers with no requests left, tried last until the limit resets
+    rate_limited: Vec<(String, Instant)>,
 }
 
-fn playlist_iter(playlist: &str) -> impl Iterator<Item = (&str, &str)> {
-    playlist
-        .lines()
-        .filter(|l| l.starts_with("#EXT-X-MEDIA"))
-        .zip(playlist.lines().filter(|l| l.starts_with("http")))
-        .filter_map(|(line, url)| {
-            Some((
-                line.split_once("NAME=\"")
-                    .map(|s| s.1.split('"'))
-                    .and_then(|mut s| s.next())
-                    .map(|s| s.strip_suffix(" (source)").unwrap_or(s))?,
-                url,
-            ))
-        })
+static PROXY_HINTS: Mutex<ProxyHints> = Mutex::new(ProxyHints {
+    donations_logged: Vec::new(),
+    rate_limited: Vec::new(),
+});
+
+impl ProxyHints {
+    const DEFAULT_RESET: Duration = Duration::from_secs(60);
+
+    fn order(servers: &[Url]) -> Vec<&Url> {
+        let mut hints = PROXY_HINTS.lock().expect("Proxy hints mutex poisoned");
+        hints
+            .rate_limited
+            .retain(|(_, reset)| Instant::now() < *reset);
+
+        let mut servers = servers.iter().collect::<Vec<_>>();
+        servers.sort_by_key(|s| hints.rate_limited.iter().any(|(l, _)| l == s.as_str()));
+        drop(hints);
+
+        servers
+    }
+
+    fn update(server: &Url, headers: &Headers) {
+        let mut hints = PROXY_HINTS.lock().expect("Proxy hints mutex poisoned");
+        if let Some(donate) = headers.get("x-donate-to") {
+            if !hints.donations_logged.iter().any(|s| s == server.as_str()) {
+                info!("Playlist proxy donation link: {donate}");
+                hints.donations_logged.push(server.to_string());
+            }
+        }
+
+        if let Some(cache) = headers.get("x-cache") {
+            debug!("Playlist proxy cache: {cache}");
+        }
+
+        let remaining = headers
+            .get("ratelimit-remaining")
+            .or_else(|| headers.get("x-ratelimit-remaining"));
+        let Some(remaining) = remaining else {
+            return;
+        };
+
+        let reset = headers
+            .get("ratelimit-reset")
+            .or_else(|| headers.get("x-ratelimit-reset"))
+            .and_then(|r| r.parse().ok())
+            .map_or(Self::DEFAULT_RESET, Duration::from_secs);
+        debug!(
+            "Playlist proxy rate limit: {remaining} of {} remaining, resets in {}s",
+            headers.get("ratelimit-limit").unwrap_or("?"),
+            reset.as_secs(),
+        );
+
+        if remaining.parse() == Ok(0) {
+            warn!("Playlist proxy rate limit reached, trying other servers first");
+            hints.rate_limited.retain(|(s, _)| s != server.as_str());
+            hints
+                .rate_limited
+                .push((server.to_string(), Instant::now() + reset));
+        }
+    }
 }
 
-fn print_streams(playlist: &str) {
-    let mut iter = playlist_iter(playlist);
-    if let Some((name, _)) = iter.next() {
-        print!("Available streams: {name} (best)");
+//Returns the chosen variant followed by the lower and then higher qualities to fall back to
+//None if no quality was given, the streams are listed instead
+fn choose_stream(playlist: &str, quality: Option<&str>) -> Result<Option<VecDeque<(String, Url)>>> {
+    if logger::is_debug() {
+        debug!("Master playlist:\n{}", logger::redact_dump(playlist));
     }
 
-    for (name, _) in iter {
-        print!(", {name}");
+    let Some(quality) = quality else {
+        return Ok(None);
+    };
+
+    let variants = Variant::parse_all(playlist);
+    let not_found = || QualityNotFoundError {
+        quality: quality.to_owned(),
+        available: variants.iter().map(ToString::to_string).collect(),
+    };
+
+    let position = if quality.eq_ignore_ascii_case("best") {
+        0
+    } else if let Some(position) = Variant::find(&variants, quality) {
+        position
+    } else if let Some(constraint) = Constraint::new(quality)? {
+        //best variants come first, fall back to the next best that still matches
+        let candidates = variants
+            .iter()
+            .filter(|v| v.name != "audio_only" && constraint.matches(v))
+            .map(|v| (v.name.to_owned(), v.url.into()))
+            .collect::<VecDeque<_>>();
+
+        if candidates.is_empty() {
+            return Err(not_found().into());
+        }
+
+        return Ok(Some(candidates));
+    } else {
+        //shortened names like 1080 for 1080p60, tried last so 1080p stays a constraint
+        Variant::find_partial(&variants, quality)?.ok_or_else(not_found)?
+    };
+
+    let chosen = variants.get(position).ok_or_else(not_found)?;
+
+    let mut candidates = VecDeque::with_capacity(variants.len());
+    candidates.push_back((chosen.name.to_owned(), chosen.url.into()));
+
+    candidates.extend(
+        variants[position + 1..]
+            .iter()
+            .chain(&variants[..position])
+            .filter(|v| v.name != "audio_only")
+            .map(|v| (v.name.to_owned(), v.url.into())),
+    );
+
+    Ok(Some(candidates))
+}
+
+//The second quality is chosen from the same multivariant playlist as the first
+fn choose_second(playlist: &str, args: &Args, agent: &Agent) -> Result<Option<Box<Variants>>> {
+    let Some((quality, _)) = args.renditions.get(1) else {
+        return Ok(None);
+    };
+
+    let candidates = choose_stream(playlist, Some(quality))?.context("Missing second quality")?;
+
+    let mut variants = Variants::new(None, None, args, agent);
+    variants.candidates = candidates;
+
+    Ok(Some(Box::new(variants)))
+}
+
+fn is_multivariant(playlist: &str) -> bool {
+    playlist.lines().any(|l| l.starts_with("#EXT-X-STREAM-INF"))
+}
+
+fn is_unavailable(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<OfflineError>().is_some() || StatusError::is_forbidden(error)
+}
+
+fn print_streams(playlist: &str, source: Option<&str>) {
+    let variants = Variant::parse_all(playlist);
+    match source {
+        Some(source) => println!("Available streams (from {source}):"),
+        None => println!("Available streams:"),
+    }
+    for (i, variant) in variants.iter().enumerate() {
+        println!("  {variant}{}", if i == 0 { " (best)" } else { "" });
     }
-    println!();
 }
 
 fn choose_client_id<'a>(
@@ -302,6 +1134,38 @@ fn choose_client_id<'a>(
     }
 }
 
+//Query string of the usher request, kept as pairs so single parameters can be overridden
+#[derive(Default)]
+struct UsherQuery(Vec<(String, String)>);
+
+impl Display for UsherQuery {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("&")?;
+            }
+            write!(f, "{key}={value}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl UsherQuery {
+    fn add(&mut self, key: &str, value: impl Display) {
+        self.0.push((key.to_owned(), value.to_string()));
+    }
+
+    //replaces the value in place, unknown keys are appended
+    fn set(&mut self, key: &str, value: &str) {
+        if let Some((_, current)) = self.0.iter_mut().find(|(k, _)| k == key) {
+            value.clone_into(current);
+        } else {
+            self.add(key, value);
+        }
+    }
+}
+
 struct ArrayString<const N: usize>([u8; N]);
 
 impl<const N: usize> Deref for ArrayString<{ N }> {
@@ -333,22 +1197,341 @@ impl<const N: usize> ArrayString<{ N }> {
         Self([0u8; N])
     }
 
-    fn random() -> Result<Self> {
-        const ALPHANUMERIC: &[u8] = b"0123456789\
-                                      ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                                      abcdefghijklmnopqrstuvwxyz";
+    fn random(salt: &str) -> Self {
+        Self(random::alphanumeric(salt))
+    }
 
-        let mut buf = [0u8; N];
-        getrandom(&mut buf)?;
+    const fn as_str(&self) -> Result<&str, Utf8Error> {
+        str::from_utf8(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        args::{Parse, Parser},
+        hls::quality::MULTIVARIANT,
+    };
+
+    fn chosen(quality: &str) -> Vec<String> {
+        choose_stream(MULTIVARIANT, Some(quality))
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|(name, url)| {
+                assert!(url.ends_with(".m3u8"));
+                name
+            })
+            .collect()
+    }
+
+    fn not_found(quality: &str) -> String {
+        choose_stream(MULTIVARIANT, Some(quality))
+            .unwrap_err()
+            .downcast::<QualityNotFoundError>()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn no_quality() {
+        assert!(choose_stream(MULTIVARIANT, None).unwrap().is_none());
+    }
 
-        for r in &mut buf {
-            *r = ALPHANUMERIC[(*r as usize) % ALPHANUMERIC.len()];
+    #[test]
+    fn by_name() {
+        //the rest follow as fallbacks, lower qualities first
+        assert_eq!(chosen("720p30"), ["720p30", "480p", "1080p60", "720p60"],);
+        assert_eq!(chosen("best"), ["1080p60", "720p60", "720p30", "480p"],);
+        assert_eq!(chosen("source"), chosen("best"));
+        assert_eq!(chosen(" 720P30"), chosen("720p30"));
+        assert_eq!(chosen("Audio"), chosen("audio_only"));
+        assert_eq!(
+            chosen("audio_only"),
+            ["audio_only", "1080p60", "720p60", "720p30", "480p"],
+        );
+
+        let (_, url) = &choose_stream(MULTIVARIANT, Some("720p60"))
+            .unwrap()
+            .unwrap()[0];
+        assert_eq!(url.as_str(), "https://example.com/720p60.m3u8");
+    }
+
+    #[test]
+    fn partial() {
+        assert_eq!(chosen("1080"), ["1080p60", "720p60", "720p30", "480p"],);
+        assert!(choose_stream(MULTIVARIANT, Some("720"))
+            .unwrap_err()
+            .to_string()
+            .contains("matches more than one stream"));
+    }
+
+    #[test]
+    fn constraints() {
+        //a plain name wins over the constraint it also spells
+        assert_eq!(chosen("480p"), ["480p", "1080p60", "720p60", "720p30"]);
+        assert_eq!(chosen("720p"), ["720p60", "720p30"]);
+        assert_eq!(chosen("best<=720p30"), ["720p30", "480p"]);
+        //audio only is never a fallback for a video constraint
+        assert_eq!(chosen("<=2mbps"), ["480p"]);
+    }
+
+    #[test]
+    fn fallback_to_next_matching() {
+        assert_eq!(chosen("best<=1000p"), ["720p60", "720p30", "480p"]);
+        assert_eq!(chosen("<=5mbps"), ["720p60", "720p30", "480p"]);
+    }
+
+    #[test]
+    fn nothing_matches() {
+        assert_eq!(
+            not_found("<=360p"),
+            "No stream matches <=360p, available streams: 1080p60 1080p 60fps 8000kbps, \
+             720p60 720p 60fps 3400kbps, 720p30 720p 30fps 2300kbps, 480p 480p 30fps 1400kbps, \
+             audio_only 160kbps",
+        );
+        assert!(not_found("1080p30").starts_with("No stream matches 1080p30, "));
+        assert!(not_found("360").starts_with("No stream matches 360, "));
+        assert!(choose_stream(MULTIVARIANT, Some("<=abc")).is_err());
+    }
+
+    //sanitized token of a PlaybackAccessToken response, with the given entitlement fields
+    fn gql_response(entitlement: &str) -> String {
+        let token = format!(
+            r#"{{"adblock":false,"authorization":{{"forbidden":false,"reason":""}},"blackout_enabled":false,"channel":"channel","channel_id":123456789,"chansub":{{"restricted_bitrates":[],"view_until":1924905600}},"ci_gb":false,"geoblock_reason":"","device_id":"0123456789abcdef","expires":1714570496,"extended_history_allowed":false,"game":"","https_required":true,"mature":false,"partner":false,"platform":"web","player_type":"site","private":{{"allowed_to_view":true}},"privileged":false,"role":"",{entitlement},"user_id":12345678,"user_ip":"203.0.113.1","version":2}}"#
+        );
+
+        //as Twitch sends it, then unescaped like fetch_twitch_gql does
+        let mut response = format!(
+            r#"{{"data":{{"streamPlaybackAccessToken":{{"value":"{}","signature":"{}","__typename":"PlaybackAccessToken"}}}},"extensions":{{"durationMilliseconds":42,"operationName":"PlaybackAccessToken_Template","requestID":"01HXYZ"}}}}"#,
+            token.replace('"', r#"\""#),
+            "0123456789abcdef0123456789abcdef01234567",
+        );
+        response.retain(|c| c != '\\');
+
+        response
+    }
+
+    fn entitlement(fields: &str) -> Entitlement {
+        AccessToken::new(&gql_response(fields))
+            .unwrap()
+            .entitlement()
+    }
+
+    #[test]
+    fn access_token() {
+        let token = AccessToken::new(&gql_response(
+            r#""hide_ads":false,"server_ads":true,"show_ads":true,"subscriber":false,"turbo":false"#,
+        ))
+        .unwrap();
+        assert_eq!(token.signature, "0123456789abcdef0123456789abcdef01234567");
+        assert!(token.token.starts_with(r#"{"adblock":false,"#));
+        assert!(token.token.ends_with(r#""version":2}"#));
+
+        let cached = AccessToken::from_cache(&token.to_string()).unwrap();
+        assert_eq!(cached.signature, token.signature);
+        assert_eq!(cached.token, token.token);
+
+        //a channel that doesn't exist or is offline has no token
+        let offline = r#"{"data":{"streamPlaybackAccessToken":{"value":null,"signature":"0123456789abcdef0123456789abcdef01234567"}}}"#;
+        assert!(AccessToken::new(offline)
+            .err()
+            .unwrap()
+            .downcast_ref::<OfflineError>()
+            .is_some());
+        assert!(AccessToken::new(r#"{"errors":[]}"#).is_err());
+    }
+
+    #[test]
+    fn entitlements() {
+        assert!(matches!(
+            entitlement(
+                r#""hide_ads":false,"server_ads":true,"show_ads":true,"subscriber":false,"turbo":false"#
+            ),
+            Entitlement::None,
+        ));
+        assert!(matches!(
+            entitlement(
+                r#""hide_ads":true,"server_ads":false,"show_ads":false,"subscriber":true,"turbo":false"#
+            ),
+            Entitlement::Subscriber,
+        ));
+        assert!(matches!(
+            entitlement(
+                r#""hide_ads":true,"server_ads":false,"show_ads":false,"subscriber":false,"turbo":true"#
+            ),
+            Entitlement::Turbo,
+        ));
+        //Prime Gaming and other ad-free benefits only show in the ad fields
+        assert!(matches!(
+            entitlement(
+                r#""hide_ads":true,"server_ads":false,"show_ads":false,"subscriber":false,"turbo":false"#
+            ),
+            Entitlement::AdFree,
+        ));
+        assert!(matches!(
+            entitlement(r#""server_ads":true,"show_ads":false"#),
+            Entitlement::AdFree,
+        ));
+    }
+
+    fn usher_args(options: &[&str]) -> Args {
+        let mut args = Args::default();
+        let mut parser = Parser::from_args(&[options, &["channel", "best"]].concat());
+        args.parse(&mut parser).unwrap();
+
+        args
+    }
+
+    //the parameters without the random ones
+    fn query(args: &Args) -> String {
+        let token = AccessToken {
+            signature: "0123456789abcdef0123456789abcdef01234567".to_owned(),
+            token: r#"{"adblock":false}"#.to_owned(),
+        };
+
+        usher_query(&token, args)
+            .0
+            .iter()
+            .filter(|(key, _)| !matches!(key.as_str(), "p" | "play_session_id"))
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    #[test]
+    fn usher_query_builder() {
+        let mut query = UsherQuery::default();
+        query.add("a", 1);
+        query.add("b", true);
+        query.set("a", "2");
+        query.set("c", "x%2Cy");
+        assert_eq!(query.to_string(), "a=2&b=true&c=x%2Cy");
+        assert!(UsherQuery::default().to_string().is_empty());
+    }
+
+    #[test]
+    fn usher_query_defaults() {
+        let defaults = query(&usher_args(&[]));
+        assert!(defaults.starts_with("acmb=e30%3D&allow_source=true&allow_audio_only=true&"));
+        assert!(defaults
+            .contains(r#"&sig=0123456789abcdef0123456789abcdef01234567&token={"adblock":false}&"#));
+        assert!(defaults.contains("&fast_bread=true&"));
+        assert!(defaults.ends_with("&os_name=Windows&os_version=NT+10.0&platform=web"));
+
+        let args = usher_args(&["--no-low-latency"]);
+        assert!(query(&args).contains("&fast_bread=false&"));
+        assert!(query(&args).contains("&warp=false&"));
+
+        let random = usher_query(
+            &AccessToken {
+                signature: String::new(),
+                token: String::new(),
+            },
+            &args,
+        );
+        for key in ["p", "play_session_id"] {
+            assert!(random.0.iter().any(|(k, v)| k == key && !v.is_empty()));
         }
+    }
+
+    #[test]
+    fn usher_query_overrides() {
+        let args = usher_args(&[
+            "--platform",
+            "android",
+            "--os-name",
+            "Android",
+            "--usher-param",
+            "os_name=Linux,player_backend=exoplayer,supported_codecs=av1,h264,new_param=1",
+        ]);
+        args.validate().unwrap();
 
-        Ok(Self(buf))
+        //in place, so only the values change
+        let expected = query(&usher_args(&[]))
+            .replace("os_name=Windows", "os_name=Linux")
+            .replace("platform=web", "platform=android")
+            .replace("player_backend=mediaplayer", "player_backend=exoplayer")
+            .replace(
+                "supported_codecs=av1,h265,h264",
+                "supported_codecs=av1,h264",
+            )
+            + "&new_param=1";
+        assert_eq!(query(&args), expected);
     }
 
-    const fn as_str(&self) -> Result<&str, Utf8Error> {
-        str::from_utf8(&self.0)
+    #[test]
+    fn signed_usher_params_are_protected() {
+        for param in ["sig=x", "token=x", "bad key=x", "platform=a&b=c", "=x"] {
+            let args = usher_args(&["--usher-param", param]);
+            assert!(args.validate().is_err(), "{param}");
+        }
+
+        assert!(Args::parse_usher_params("missing_value").is_err());
+    }
+
+    //Keys of a JSON object, checking its braces, brackets and strings are balanced
+    fn json_keys(json: &str) -> Vec<String> {
+        let mut depth = Vec::new();
+        let mut keys = Vec::new();
+        let mut last = String::new();
+        let mut string: Option<String> = None;
+        let mut escaped = false;
+        for c in json.chars() {
+            match (&mut string, c) {
+                (Some(current), _) if escaped => {
+                    current.push(c);
+                    escaped = false;
+                }
+                (Some(_), '\\') => escaped = true,
+                (Some(_), '"') => last = string.take().unwrap(),
+                (Some(current), _) => current.push(c),
+                (None, '"') => string = Some(String::new()),
+                (None, '{' | '[') => depth.push(c),
+                (None, '}') => assert_eq!(depth.pop(), Some('{'), "{json}"),
+                (None, ']') => assert_eq!(depth.pop(), Some('['), "{json}"),
+                (None, ':') => keys.push(std::mem::take(&mut last)),
+                (None, _) => (),
+            }
+        }
+
+        assert!(depth.is_empty() && string.is_none(), "{json}");
+        keys
+    }
+
+    #[test]
+    fn gql_bodies() {
+        let body = gql_body("channel");
+        assert!(body.starts_with('{') && body.ends_with('}'));
+        assert!(!body.contains(char::is_whitespace));
+        assert_eq!(
+            json_keys(&body),
+            [
+                "extensions",
+                "persistedQuery",
+                "sha256Hash",
+                "version",
+                "operationName",
+                "variables",
+                "isLive",
+                "isVod",
+                "login",
+                "playerType",
+                "vodID",
+            ],
+        );
+        assert!(body.contains(r#""login":"channel""#));
+        assert!(body.contains(r#""operationName":"PlaybackAccessToken""#));
+
+        //Content-Length is measured, so it follows the channel name
+        for channel in ["a", "channel", "a_very_long_channel_name_25"] {
+            assert_eq!(gql_body(channel).len(), gql_body("").len() + channel.len());
+        }
+
+        let body = live_body("channel");
+        assert_eq!(json_keys(&body), ["query"]);
+        assert!(body.contains(r#"user(login:\"channel\")"#));
     }
 }
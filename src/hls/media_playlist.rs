@@ -1,10 +1,13 @@
 use std::{
     collections::{vec_deque::IterMut, VecDeque},
     env,
+    fmt::{self, Display, Formatter},
+    mem,
+    time::{Duration as StdDuration, Instant},
 };
 
-use anyhow::{ensure, Context, Result};
-use log::debug;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, warn};
 
 use super::{
     map_if_offline,
@@ -13,41 +16,256 @@ use super::{
 };
 
 use crate::{
-    http::{Connection, Url},
-    logger,
+    crash,
+    http::{Connection, StatusError, Url},
+    logger, path_template,
 };
 
+//The variant stopped working while the stream may still be live, e.g. after an encoder change
+#[derive(Debug)]
+pub enum StaleError {
+    Failing(u32),
+    Stalled(StdDuration),
+    Expired,
+}
+
+impl std::error::Error for StaleError {}
+
+impl Display for StaleError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Failing(failures) => write!(f, "Playlist failed {failures} times in a row"),
+            Self::Stalled(elapsed) => {
+                write!(f, "Playlist hasn't updated in {}s", elapsed.as_secs())
+            }
+            Self::Expired => f.write_str("Playlist URL expired"),
+        }
+    }
+}
+
+//Why a new encoding session is believed to have started, e.g. after the broadcaster reconnected
+#[derive(Debug)]
+pub struct Restart(Vec<&'static str>);
+
+impl Display for Restart {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(&self.0.join(", "))
+    }
+}
+
+//Indicators of a restarted stream, each compared with what earlier reloads showed.
+//Several indicators of one reload are a single restart
+#[derive(Default)]
+struct StreamEpoch {
+    target_duration: Option<String>,
+    map: Option<String>,
+    program_date_time: Option<f64>,
+    indicators: Vec<&'static str>,
+}
+
+impl StreamEpoch {
+    //content segments don't jump in time within one session, ads are cut in between
+    const MAX_PROGRAM_DATE_TIME_GAP: f64 = 30.0;
+
+    fn indicate(&mut self, indicator: &'static str) {
+        if !self.indicators.contains(&indicator) {
+            debug!("Restart indicator: {indicator}");
+            self.indicators.push(indicator);
+        }
+    }
+
+    fn target_duration(&mut self, value: &str) {
+        match &self.target_duration {
+            Some(old) if old == value => return,
+            Some(_) => self.indicate("target duration changed"),
+            None => (),
+        }
+
+        self.target_duration = Some(value.to_owned());
+    }
+
+    //returns true if the init segment has to be delivered
+    fn map(&mut self, uri: &str) -> bool {
+        match &self.map {
+            Some(old) if old == uri => return false,
+            Some(_) => self.indicate("init segment changed"),
+            None => (),
+        }
+
+        self.map = Some(uri.to_owned());
+        true
+    }
+
+    fn program_date_time(&mut self, value: &str) {
+        let Some(time) = path_template::parse_timestamp(value) else {
+            return;
+        };
+
+        if self
+            .program_date_time
+            .is_some_and(|old| (time - old).abs() > Self::MAX_PROGRAM_DATE_TIME_GAP)
+        {
+            self.indicate("program date time jumped");
+        }
+
+        self.program_date_time = Some(time);
+    }
+
+    fn take(&mut self) -> Option<Restart> {
+        (!self.indicators.is_empty()).then(|| Restart(mem::take(&mut self.indicators)))
+    }
+}
+
+//Where the playlist text comes from. Connection polls the playlist over HTTP, anything
+//else can feed the playlist scripted bodies without a server
+pub trait PlaylistSource: Send {
+    //returns the effective URL too, for resolving relative entries after a redirect
+    fn fetch(&mut self) -> Result<(&str, &Url)>;
+
+    fn url(&self) -> &Url;
+
+    //drops the connection, the next fetch reconnects
+    fn reset(&mut self);
+}
+
+impl PlaylistSource for Connection {
+    fn fetch(&mut self) -> Result<(&str, &Url)> {
+        self.text()
+    }
+
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn reset(&mut self) {
+        self.request.reset();
+    }
+}
+
+//Serves predefined playlist bodies and injected errors, one per reload
+#[cfg(test)]
+pub struct ScriptedSource {
+    script: VecDeque<Result<String>>,
+    body: String,
+    url: Url,
+}
+
+#[cfg(test)]
+impl ScriptedSource {
+    pub fn new(script: impl IntoIterator<Item = Result<String>>) -> Self {
+        Self {
+            script: script.into_iter().collect(),
+            body: String::new(),
+            url: "http://127.0.0.1/v/0.m3u8".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl PlaylistSource for ScriptedSource {
+    fn fetch(&mut self) -> Result<(&str, &Url)> {
+        self.body = self.script.pop_front().context("Playlist script ended")??;
+        Ok((&self.body, &self.url))
+    }
+
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn reset(&mut self) {}
+}
+
+//Twitch-like playlist text for scripted sources. "live" and "ad" are two second segments
+//and "prefetch" a prefetch segment, each named after its sequence number. Other entries
+//are copied as lines
+#[cfg(test)]
+pub fn fixture(sequence: usize, entries: &[&str]) -> String {
+    let mut text = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{sequence}\n"
+    );
+    let mut sequence = sequence;
+    for entry in entries {
+        let segment = match *entry {
+            "live" => format!("#EXTINF:2.000,live\nseg{sequence}.ts\n"),
+            "ad" => format!("#EXTINF:2.000,Amazon|8675309\nad{sequence}.ts\n"),
+            "prefetch" => format!("#EXT-X-TWITCH-PREFETCH:seg{sequence}.ts\n"),
+            line => {
+                text.push_str(line);
+                text.push('\n');
+                continue;
+            }
+        };
+
+        text.push_str(&segment);
+        sequence += 1;
+    }
+
+    text
+}
+
 pub struct MediaPlaylist {
-    pub header: Option<Url>, //used for av1/hevc streams
+    pub header: Option<Url>, //used for av1/hevc streams, set again when the init segment changes
 
-    conn: Connection,
+    source: Box<dyn PlaylistSource>,
     segments: VecDeque<Segment>,
+    max_duration: StdDuration,
+    min_poll: StdDuration,
     debug_log_playlist: bool,
 
     sequence: usize,
     added: usize,
+
+    updated: Option<Instant>,
+    failures: u32,
+    resumed: bool,
+
+    epoch: StreamEpoch,
+    restart: Option<Restart>,
 }
 
 impl MediaPlaylist {
-    pub fn new(conn: Connection) -> Result<Self> {
+    pub fn new(
+        source: impl PlaylistSource + 'static,
+        max_duration: StdDuration,
+        min_poll: StdDuration,
+    ) -> Result<Self> {
         let mut playlist = Self {
-            conn,
+            source: Box::new(source),
             segments: VecDeque::with_capacity(16),
+            max_duration,
+            min_poll,
             debug_log_playlist: logger::is_debug() && env::var_os("DEBUG_NO_PLAYLIST").is_none(),
             header: Option::default(),
             sequence: usize::default(),
             added: usize::default(),
+            updated: Option::default(),
+            failures: u32::default(),
+            resumed: bool::default(),
+            epoch: StreamEpoch::default(),
+            restart: Option::default(),
         };
 
         playlist.reload()?;
         Ok(playlist)
     }
 
+    //thresholds are conservative, a false positive refetches the multivariant playlist
+    const MAX_FAILURES: u32 = 3;
+    const STALL_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+    //a jump of more than this many playlists' worth of segments isn't the playlist
+    //moving on, the sequence was reset or is garbage
+    const MAX_SEQUENCE_JUMP: usize = 10;
+
     pub fn reload(&mut self) -> Result<()> {
         debug!("----------RELOADING----------");
-        let playlist = self.conn.text().map_err(map_if_offline)?;
+        crash::set_url(self.source.url());
+        let (playlist, base) = match self.source.fetch() {
+            Ok(text) => text,
+            Err(e) => return self.failed(e),
+        };
         if self.debug_log_playlist {
-            debug!("Playlist:\n{playlist}");
+            debug!("Playlist:\n{}", logger::redact_dump(playlist));
         }
 
         if playlist
@@ -55,12 +273,14 @@ impl MediaPlaylist {
             .next_back()
             .is_some_and(|l| l.starts_with("#EXT-X-ENDLIST"))
         {
-            return Err(OfflineError.into());
+            return Err(OfflineError::EndOfStream.into());
         }
 
         let mut prefetch_removed = Self::remove_prefetch(&mut self.segments);
         let mut prev_segment_count = self.segments.len();
         let mut total_segments = 0;
+        let mut program_date_time = None;
+        let mut map = None;
         let mut lines = playlist.lines();
         while let Some(line) = lines.next() {
             let Some(split) = line.split_once(':') else {
@@ -69,63 +289,162 @@ impl MediaPlaylist {
 
             match split.0 {
                 "#EXT-X-MEDIA-SEQUENCE" => {
-                    let sequence = split.1.parse()?;
-                    ensure!(sequence >= self.sequence, "Sequence went backwards");
-
-                    if sequence > 0 {
-                        let removed = sequence - self.sequence;
-                        if removed < self.segments.len() {
-                            self.segments.drain(..removed);
-                            prev_segment_count = self.segments.len();
-
-                            debug!("Segments removed: {removed}");
-                        } else {
-                            self.segments.clear();
-                            prev_segment_count = 0;
-                            prefetch_removed = 0;
-
-                            debug!("All segments removed");
-                        }
+                    let sequence = split
+                        .1
+                        .parse()
+                        .with_context(|| format!("Invalid playlist line: {line}"))?;
+                    if sequence < self.sequence {
+                        self.epoch.indicate("media sequence went backwards");
+                        self.segments.clear();
+                        prefetch_removed = 0;
+                    } else if sequence > 0
+                        && Self::remove_segments(&mut self.segments, sequence - self.sequence)
+                    {
+                        prefetch_removed = 0;
                     }
 
+                    prev_segment_count = self.segments.len();
                     self.sequence = sequence;
                 }
-                "#EXT-X-MAP" if self.header.is_none() => {
-                    let mut url = split
-                        .1
-                        .split_once('=')
-                        .context("Failed to parse segment header")?
-                        .1
-                        .to_owned();
-
-                    url.retain(|c| c != '"');
-                    self.header = Some(url.into());
+                "#EXT-X-TARGETDURATION" => self.epoch.target_duration(split.1),
+                "#EXT-X-MAP" => {
+                    map = Some(
+                        split
+                            .1
+                            .split_once('=')
+                            .context("Failed to parse segment header")?
+                            .1
+                            .trim_matches('"'),
+                    );
                 }
+                "#EXT-X-PROGRAM-DATE-TIME" => program_date_time = Some(split.1),
                 "#EXTINF" => {
                     total_segments += 1;
+                    let program_date_time = program_date_time.take();
                     if total_segments > prev_segment_count {
                         if let Some(url) = lines.next() {
-                            self.segments
-                                .push_back(Segment::Normal(split.1.parse()?, url.into()));
+                            let duration = split
+                                .1
+                                .parse::<Duration>()
+                                .with_context(|| format!("Invalid playlist line: {line}"))?;
+
+                            //ads have their own init segments and times
+                            if !duration.is_ad() || self.epoch.map.is_none() {
+                                if let Some(url) = map
+                                    .filter(|m| self.epoch.map(m))
+                                    .and_then(|m| entry_url(base, m, "segment header"))
+                                {
+                                    self.header = Some(url);
+                                }
+                            }
+                            match program_date_time {
+                                Some(time) if !duration.is_ad() => {
+                                    self.epoch.program_date_time(time);
+                                }
+                                _ => self.epoch.program_date_time = None,
+                            }
+
+                            //an invalid URL is left empty to keep the sequence numbers
+                            //in place, the handler skips it
+                            self.segments.push_back(Segment::Normal(
+                                duration.limit(self.max_duration),
+                                entry_url(base, url, "segment").unwrap_or_default(),
+                                program_date_time.map(str::to_owned),
+                            ));
                         }
                     }
                 }
                 "#EXT-X-TWITCH-PREFETCH" => {
                     total_segments += 1;
                     if total_segments > prev_segment_count {
-                        self.segments.push_back(Segment::Prefetch(split.1.into()));
+                        self.segments.push_back(Segment::Prefetch(
+                            entry_url(base, split.1, "prefetch segment").unwrap_or_default(),
+                        ));
                     }
                 }
-                _ => continue,
+                _ => (),
             }
         }
 
-        self.added = total_segments - (prev_segment_count + prefetch_removed);
-        debug!("Segments added: {}", self.added);
+        //a broken proxy can list fewer segments without moving the sequence on
+        self.finish(total_segments.saturating_sub(prev_segment_count + prefetch_removed))
+    }
 
+    fn finish(&mut self, added: usize) -> Result<()> {
+        self.added = added;
+        debug!("Segments added: {added}");
+
+        //Segments of the old session are useless, playback continues from the newest. That one
+        //was already dispatched if nothing was added, e.g. only the target duration changed
+        if let Some(restart) = self.epoch.take() {
+            self.restart = Some(restart);
+            if self.added > 0 {
+                self.added = self.segments.len();
+            }
+        }
+
+        self.failures = 0;
+        self.resumed = false;
+        if self.added > 0 || self.updated.is_none() {
+            self.updated = Some(Instant::now());
+        } else if let Some(elapsed) = self
+            .updated
+            .map(|t| t.elapsed())
+            .filter(|e| *e > Self::STALL_TIMEOUT)
+        {
+            return Err(StaleError::Stalled(elapsed).into());
+        }
+
+        Ok(())
+    }
+
+    //a variant that worked before may be gone because the renditions changed
+    fn failed(&mut self, error: anyhow::Error) -> Result<()> {
+        if self.updated.is_none() || error.downcast_ref::<StatusError>().is_none() {
+            return Err(map_if_offline(error, OfflineError::EndOfStream));
+        }
+
+        if self.resumed {
+            return Err(StaleError::Expired.into());
+        }
+
+        self.failures += 1;
+        if self.failures >= Self::MAX_FAILURES {
+            return Err(StaleError::Failing(self.failures).into());
+        }
+
+        error!("{error}, retrying...");
+        self.added = 0;
         Ok(())
     }
 
+    //the socket is long gone after a suspend and the URL may have expired,
+    //a failing reload is then refetched right away instead of retried
+    pub fn resume(&mut self) {
+        self.source.reset();
+        self.updated = Some(Instant::now());
+        self.resumed = true;
+    }
+
+    //set by the reload that found the stream restarted
+    pub fn take_restart(&mut self) -> Option<Restart> {
+        self.restart.take()
+    }
+
+    pub fn url(&self) -> &Url {
+        self.source.url()
+    }
+
+    //new segments showed up within the window
+    pub fn updated_within(&self, window: StdDuration) -> bool {
+        self.updated.is_some_and(|t| t.elapsed() < window)
+    }
+
+    //the last reload failed and is being retried
+    pub const fn is_failing(&self) -> bool {
+        self.failures > 0
+    }
+
     pub fn segments(&mut self) -> QueueRange<'_> {
         if self.added == 0 {
             QueueRange::Empty
@@ -136,20 +455,80 @@ impl MediaPlaylist {
         }
     }
 
+    //the previous segments all left the playlist, segments() then only returns the newest
+    pub fn all_added(&self) -> bool {
+        self.added > 0 && self.added == self.segments.len()
+    }
+
+    //every segment, for catching up instead of skipping to the newest
+    pub fn all_segments(&mut self) -> QueueRange<'_> {
+        QueueRange::Partial(self.segments.range_mut(..))
+    }
+
+    //media sequence number of the first segment returned by segments()
+    pub fn added_sequence(&self) -> usize {
+        self.sequence + self.segments.len() - self.added
+    }
+
+    pub fn newest_sequence(&self) -> usize {
+        self.sequence + self.segments.len().saturating_sub(1)
+    }
+
+    pub fn prefetch_count(&self) -> usize {
+        self.segments
+            .iter()
+            .filter(|s| matches!(s, Segment::Prefetch(_)))
+            .count()
+    }
+
+    //--min-poll-interval, capped at half the playlist so channels with sub-second segments
+    //get several per reload instead of falling out of the window
+    pub fn poll_floor(&self) -> StdDuration {
+        let window: StdDuration = self
+            .segments
+            .iter()
+            .filter_map(|s| match s {
+                Segment::Normal(duration, ..) => Some(duration.as_std()),
+                Segment::Prefetch(_) => None,
+            })
+            .sum();
+
+        self.min_poll.min(window / 2)
+    }
+
     pub fn last_duration(&self) -> Option<Duration> {
         self.segments
             .iter()
             .rev()
             .find_map(|s| match s {
-                Segment::Normal(duration, _) => Some(duration),
+                Segment::Normal(duration, ..) => Some(duration),
                 Segment::Prefetch(_) => None,
             })
             .copied()
     }
 
+    //returns true if all segments were removed
+    fn remove_segments(segments: &mut VecDeque<Segment>, removed: usize) -> bool {
+        if removed < segments.len() {
+            segments.drain(..removed);
+            debug!("Segments removed: {removed}");
+
+            return false;
+        }
+
+        if !segments.is_empty() && removed / Self::MAX_SEQUENCE_JUMP > segments.len() {
+            warn!("Media sequence jumped by {removed}, starting over");
+        } else {
+            debug!("All segments removed");
+        }
+
+        segments.clear();
+        true
+    }
+
     fn remove_prefetch(segments: &mut VecDeque<Segment>) -> usize {
         let before = segments.len();
-        segments.retain(|s| matches!(*s, Segment::Normal(_, _)));
+        segments.retain(|s| matches!(*s, Segment::Normal(..)));
 
         before - segments.len()
     }
@@ -160,3 +539,350 @@ pub enum QueueRange<'a> {
     Back(Option<&'a mut Segment>),
     Empty,
 }
+
+//A broken playlist, e.g. one whose URLs a proxy wrapped across two lines, can put a tag,
+//an empty line or a fragment where a URL is expected. Those are skipped with a warning
+//instead of being requested
+fn entry_url(base: &Url, entry: &str, kind: &str) -> Option<Url> {
+    let url = if entry.is_empty() || entry.starts_with('#') {
+        Err(anyhow!("Expected a URL"))
+    } else {
+        base.join(entry)
+            .and_then(|url| url.validate().map(|()| url))
+    };
+
+    match url {
+        Ok(url) => Some(url),
+        Err(e) => {
+            warn!(
+                "Skipping {kind} with invalid URL {:?}: {e}",
+                logger::redact(entry),
+            );
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::*;
+
+    struct Body {
+        sequence: usize,
+        count: usize,
+        target: u32,
+        map: Option<&'static str>,
+        //program date time of the first segment, in seconds
+        start: usize,
+    }
+
+    impl Default for Body {
+        fn default() -> Self {
+            Self {
+                sequence: 100,
+                count: 4,
+                target: 2,
+                map: None,
+                start: 200,
+            }
+        }
+    }
+
+    impl Body {
+        //segments are two seconds long
+        fn text(&self) -> String {
+            let mut text = format!(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{}\n",
+                self.target, self.sequence,
+            );
+            if let Some(map) = self.map {
+                let _ = writeln!(text, "#EXT-X-MAP:URI=\"{map}\"");
+            }
+
+            for i in 0..self.count {
+                let time = self.start + 2 * i;
+                let _ = write!(
+                    text,
+                    "#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:{:02}:{:02}.000Z\n\
+                     #EXTINF:2.000,live\n\
+                     seg{}.ts\n",
+                    time / 60,
+                    time % 60,
+                    self.sequence + i,
+                );
+            }
+
+            text
+        }
+
+        //the next reload, one segment later
+        fn next(&self) -> Self {
+            Self {
+                sequence: self.sequence + 1,
+                start: self.start + 2,
+                ..*self
+            }
+        }
+    }
+
+    fn playlist(bodies: &[&Body]) -> MediaPlaylist {
+        let source = ScriptedSource::new(bodies.iter().map(|b| Ok(b.text())));
+        MediaPlaylist::new(source, StdDuration::from_secs(10), StdDuration::ZERO).unwrap()
+    }
+
+    //URLs of the segments the handler would dispatch
+    fn added(playlist: &mut MediaPlaylist) -> Vec<String> {
+        let url = |s: &Segment| match s {
+            Segment::Normal(_, url, _) | Segment::Prefetch(url) => {
+                url.rsplit('/').next().unwrap().to_owned()
+            }
+        };
+
+        match playlist.segments() {
+            QueueRange::Partial(iter) => iter.map(|s| url(s)).collect(),
+            QueueRange::Back(segment) => segment.map(|s| url(s)).into_iter().collect(),
+            QueueRange::Empty => Vec::new(),
+        }
+    }
+
+    //The restart is reported once and playback continues from the newest segment,
+    //the reload after it is a normal one
+    fn assert_one_restart(first: &Body, restarted: &Body, indicators: &[&str]) {
+        let after = restarted.next();
+        let mut playlist = playlist(&[first, restarted, &after]);
+        assert!(playlist.take_restart().is_none());
+
+        playlist.reload().unwrap();
+        let restart = playlist.take_restart().expect("restart");
+        assert_eq!(restart.0, indicators);
+        let newest = format!("seg{}.ts", restarted.sequence + restarted.count - 1);
+        assert_eq!(added(&mut playlist), [newest]);
+        assert!(playlist.take_restart().is_none());
+
+        playlist.reload().unwrap();
+        assert!(playlist.take_restart().is_none());
+        let next = format!("seg{}.ts", after.sequence + after.count - 1);
+        assert_eq!(added(&mut playlist), [next]);
+    }
+
+    #[test]
+    fn sequence_went_backwards() {
+        let first = Body::default();
+        let restarted = Body {
+            sequence: 5,
+            start: first.start + 8,
+            ..first
+        };
+
+        assert_one_restart(&first, &restarted, &["media sequence went backwards"]);
+    }
+
+    #[test]
+    fn target_duration_changed() {
+        let first = Body::default();
+        let restarted = Body {
+            target: 4,
+            ..first.next()
+        };
+
+        assert_one_restart(&first, &restarted, &["target duration changed"]);
+    }
+
+    #[test]
+    fn init_segment_changed() {
+        let first = Body {
+            map: Some("init0.mp4"),
+            ..Body::default()
+        };
+        let restarted = Body {
+            map: Some("init1.mp4"),
+            ..first.next()
+        };
+
+        assert_one_restart(&first, &restarted, &["init segment changed"]);
+    }
+
+    #[test]
+    fn program_date_time_jumped() {
+        let first = Body::default();
+        let restarted = Body {
+            start: first.start + 600,
+            ..first.next()
+        };
+
+        assert_one_restart(&first, &restarted, &["program date time jumped"]);
+    }
+
+    #[test]
+    fn indicators_of_one_reload_are_one_restart() {
+        let first = Body {
+            map: Some("init0.mp4"),
+            ..Body::default()
+        };
+        let restarted = Body {
+            target: 4,
+            map: Some("init1.mp4"),
+            start: first.start + 600,
+            ..first.next()
+        };
+
+        assert_one_restart(
+            &first,
+            &restarted,
+            &[
+                "target duration changed",
+                "init segment changed",
+                "program date time jumped",
+            ],
+        );
+    }
+
+    #[test]
+    fn sequence_reset_with_new_init_segment() {
+        let first = Body {
+            map: Some("init0.mp4"),
+            ..Body::default()
+        };
+        let restarted = Body {
+            sequence: 0,
+            map: Some("init1.mp4"),
+            ..first.next()
+        };
+
+        assert_one_restart(
+            &first,
+            &restarted,
+            &["media sequence went backwards", "init segment changed"],
+        );
+    }
+
+    #[test]
+    fn restart_without_new_segments_dispatches_nothing() {
+        let first = Body::default();
+        let restarted = Body { target: 4, ..first };
+        let mut playlist = playlist(&[&first, &restarted]);
+
+        playlist.reload().unwrap();
+        assert!(playlist.take_restart().is_some());
+        assert!(added(&mut playlist).is_empty());
+    }
+
+    fn hostile(entries: &[&str]) -> String {
+        let source = ScriptedSource::new([Ok(fixture(100, entries))]);
+        let error = MediaPlaylist::new(source, StdDuration::from_secs(10), StdDuration::ZERO)
+            .err()
+            .unwrap();
+
+        format!("{error:#}")
+    }
+
+    #[test]
+    fn invalid_lines_are_named() {
+        assert_eq!(
+            hostile(&["live", "#EXTINF:NaN,live", "seg101.ts"]),
+            "Invalid playlist line: #EXTINF:NaN,live: Segment duration out of range: NaN",
+        );
+        assert!(hostile(&["live", "#EXTINF:-2.000,live", "seg101.ts"])
+            .starts_with("Invalid playlist line: #EXTINF:-2.000,live: "));
+        assert!(hostile(&["#EXT-X-MEDIA-SEQUENCE:-5", "live"])
+            .starts_with("Invalid playlist line: #EXT-X-MEDIA-SEQUENCE:-5: "));
+    }
+
+    #[test]
+    fn absurd_duration_is_limited() {
+        let source = ScriptedSource::new([Ok(fixture(
+            100,
+            &["live", "#EXTINF:20000.000,live", "seg101.ts"],
+        ))]);
+        let playlist =
+            MediaPlaylist::new(source, StdDuration::from_secs(30), StdDuration::ZERO).unwrap();
+
+        assert_eq!(
+            playlist.last_duration().unwrap().as_std(),
+            StdDuration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn sequence_jump_drops_old_segments() {
+        let first = Body::default();
+        let jumped = Body {
+            sequence: first.sequence + 3_000_000,
+            ..first.next()
+        };
+        let mut playlist = playlist(&[&first, &jumped]);
+
+        playlist.reload().unwrap();
+        assert!(playlist.take_restart().is_none());
+        assert!(playlist.all_added());
+        assert_eq!(playlist.newest_sequence(), 3_000_103);
+        assert_eq!(added(&mut playlist), ["seg3000103.ts"]);
+    }
+
+    #[test]
+    fn shrunk_playlist_adds_nothing() {
+        let first = Body::default();
+        let shrunk = Body { count: 2, ..first };
+        let mut playlist = playlist(&[&first, &shrunk, &first.next()]);
+
+        playlist.reload().unwrap();
+        assert!(added(&mut playlist).is_empty());
+
+        playlist.reload().unwrap();
+        assert_eq!(added(&mut playlist), ["seg104.ts"]);
+    }
+
+    #[test]
+    fn invalid_urls_are_skipped() {
+        //a proxy wrapped the URLs, leaving line breaks and fragments in the entries
+        let wrapped = fixture(
+            100,
+            &[
+                "#EXT-X-MAP:URI=\"init\r0.mp4\"",
+                "live",
+                "#EXTINF:2.000,live",
+                "https://video-edge.example/v1/seg\r101.ts",
+                "#EXTINF:2.000,live",
+                "seg 102.ts",
+                "#EXTINF:2.000,live",
+                "",
+                "seg103.ts",
+                "#EXTINF:2.000,live",
+                "#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000Z",
+                "#EXTINF:2.000,live",
+                "https:///seg105.ts",
+                "#EXT-X-TWITCH-PREFETCH:seg\t106.ts",
+                "#EXT-X-TWITCH-PREFETCH:seg107.ts",
+            ],
+        );
+        let source = ScriptedSource::new([Ok(wrapped)]);
+        let playlist =
+            MediaPlaylist::new(source, StdDuration::from_secs(10), StdDuration::ZERO).unwrap();
+
+        //skipped entries keep their place in the sequence
+        let urls = playlist
+            .segments
+            .iter()
+            .map(|s| match s {
+                Segment::Normal(_, url, _) | Segment::Prefetch(url) => url.to_string(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "http://127.0.0.1/v/seg100.ts",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "",
+                "http://127.0.0.1/v/seg107.ts",
+            ],
+        );
+        assert_eq!(playlist.newest_sequence(), 107);
+        assert!(playlist.header.is_none());
+    }
+}
@@ -0,0 +1,433 @@
+use std::fmt::{self, Display, Formatter};
+
+use anyhow::{bail, ensure, Context, Result};
+use log::info;
+
+use super::attribute;
+
+//Variant of the multivariant playlist with the attributes qualities are matched against
+pub struct Variant<'a> {
+    pub name: &'a str,
+    pub url: &'a str,
+
+    source: bool,
+    bandwidth: Option<u64>,
+    height: Option<u32>,
+    frame_rate: Option<u32>,
+}
+
+impl Display for Variant<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.name)?;
+        if let Some(height) = self.height {
+            write!(f, " {height}p")?;
+        }
+
+        if let Some(frame_rate) = self.frame_rate {
+            write!(f, " {frame_rate}fps")?;
+        }
+
+        if let Some(bandwidth) = self.bandwidth {
+            write!(f, " {}kbps", bandwidth / 1000)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Variant<'a> {
+    pub fn parse_all(playlist: &'a str) -> Vec<Self> {
+        playlist
+            .lines()
+            .filter(|l| l.starts_with("#EXT-X-MEDIA"))
+            .zip(
+                playlist
+                    .lines()
+                    .filter_map(|l| l.strip_prefix("#EXT-X-STREAM-INF:")),
+            )
+            .zip(playlist.lines().filter(|l| l.starts_with("http")))
+            .filter_map(|((media, stream_inf), url)| {
+                let name = media
+                    .split_once("NAME=\"")
+                    .map(|s| s.1.split('"'))
+                    .and_then(|mut s| s.next())?;
+
+                Some(Self {
+                    name: name.strip_suffix(" (source)").unwrap_or(name),
+                    url,
+                    source: name.ends_with(" (source)"),
+                    bandwidth: attribute(stream_inf, "BANDWIDTH").and_then(|b| b.parse().ok()),
+                    height: attribute(stream_inf, "RESOLUTION")
+                        .and_then(|r| r.split_once('x'))
+                        .and_then(|(_, h)| h.parse().ok()),
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                    frame_rate: attribute(stream_inf, "FRAME-RATE")
+                        .and_then(|f| f.parse::<f32>().ok())
+                        .map(|f| f.round() as u32),
+                })
+            })
+            .collect()
+    }
+
+    //Position of the stream a quality names, ignoring case and whitespace
+    pub fn find(variants: &[Self], quality: &str) -> Option<usize> {
+        let normalized = normalize(quality);
+        let position = match normalized.as_str() {
+            "source" => variants.iter().position(|v| v.source),
+            "audio" => variants.iter().position(|v| v.name == "audio_only"),
+            _ => variants
+                .iter()
+                .position(|v| normalize(v.name) == normalized),
+        }?;
+
+        if variants[position].name != quality {
+            info!("Quality {quality} matched {}", variants[position].name);
+        }
+
+        Some(position)
+    }
+
+    //Position of the only stream whose name starts with or contains the quality,
+    //prefixes win over matches in the middle ("60" is ambiguous, "1080" isn't)
+    pub fn find_partial(variants: &[Self], quality: &str) -> Result<Option<usize>> {
+        let normalized = normalize(quality);
+        let names = variants
+            .iter()
+            .map(|v| normalize(v.name))
+            .collect::<Vec<_>>();
+
+        let mut matches = Vec::new();
+        for matcher in [str::starts_with, str::contains] {
+            for (position, name) in names.iter().enumerate() {
+                //the same name can be listed more than once, e.g. in two containers
+                if matcher(name, normalized.as_str())
+                    && !matches.iter().any(|p: &usize| names[*p] == *name)
+                {
+                    matches.push(position);
+                }
+            }
+
+            if !matches.is_empty() {
+                break;
+            }
+        }
+
+        match matches.as_slice() {
+            [] => Ok(None),
+            [position] => {
+                info!("Quality {quality} matched {}", variants[*position].name);
+                Ok(Some(*position))
+            }
+            _ => bail!(
+                "Quality {quality} matches more than one stream: {}",
+                matches
+                    .iter()
+                    .map(|p| variants[*p].name)
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        }
+    }
+}
+
+//"1080P60  HDR" -> "1080p60 hdr"
+fn normalize(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+//Quality selector constraint like 1080p30, best<=720p60 or <=4mbps
+pub enum Constraint {
+    Exact {
+        height: u32,
+        frame_rate: Option<u32>,
+    },
+    AtMost {
+        height: Option<u32>,
+        frame_rate: Option<u32>,
+        bandwidth: Option<u64>,
+    },
+}
+
+impl Constraint {
+    //Ok(None) if the quality is a plain name rather than a constraint
+    pub fn new(quality: &str) -> Result<Option<Self>> {
+        let normalized = normalize(quality);
+        let Some(limit) = normalized
+            .strip_prefix("best<=")
+            .or_else(|| normalized.strip_prefix("<="))
+        else {
+            return Ok(Self::resolution(&normalized)
+                .map(|(height, frame_rate)| Self::Exact { height, frame_rate }));
+        };
+
+        if let Some(bandwidth) = Self::bandwidth(limit)? {
+            return Ok(Some(Self::AtMost {
+                height: None,
+                frame_rate: None,
+                bandwidth: Some(bandwidth),
+            }));
+        }
+
+        let (height, frame_rate) =
+            Self::resolution(limit).with_context(|| format!("Invalid quality: {quality}"))?;
+
+        Ok(Some(Self::AtMost {
+            height: Some(height),
+            frame_rate,
+            bandwidth: None,
+        }))
+    }
+
+    pub fn matches(&self, variant: &Variant) -> bool {
+        match *self {
+            Self::Exact { height, frame_rate } => {
+                variant.height == Some(height)
+                    && frame_rate.map_or(true, |f| variant.frame_rate == Some(f))
+            }
+            Self::AtMost {
+                height,
+                frame_rate,
+                bandwidth,
+            } => {
+                Self::at_most(variant.height, height)
+                    && Self::at_most(variant.frame_rate, frame_rate)
+                    && Self::at_most(variant.bandwidth, bandwidth)
+            }
+        }
+    }
+
+    fn at_most<T: PartialOrd>(value: Option<T>, limit: Option<T>) -> bool {
+        match (value, limit) {
+            (_, None) => true,
+            (Some(value), Some(limit)) => value <= limit,
+            (None, Some(_)) => false,
+        }
+    }
+
+    //1080p, 1080p30, 1080p@30
+    fn resolution(arg: &str) -> Option<(u32, Option<u32>)> {
+        let (height, frame_rate) = arg.split_once('p')?;
+        let height = height.parse().ok()?;
+
+        let frame_rate = frame_rate.strip_prefix('@').unwrap_or(frame_rate);
+        if frame_rate.is_empty() {
+            return Some((height, None));
+        }
+
+        Some((height, Some(frame_rate.parse().ok()?)))
+    }
+
+    //4mbps, 500kbps, 4000000bps
+    fn bandwidth(arg: &str) -> Result<Option<u64>> {
+        let lowercase = arg.to_ascii_lowercase();
+        let Some(number) = lowercase.strip_suffix("bps") else {
+            return Ok(None);
+        };
+
+        let (number, multiplier) = match number.chars().last() {
+            Some('k') => (&number[..number.len() - 1], 1e3),
+            Some('m') => (&number[..number.len() - 1], 1e6),
+            Some('g') => (&number[..number.len() - 1], 1e9),
+            Some(_) => (number, 1.0),
+            None => bail!("Invalid bandwidth: {arg}"),
+        };
+
+        let bandwidth = number
+            .parse::<f64>()
+            .with_context(|| format!("Invalid bandwidth: {arg}"))?
+            * multiplier;
+        ensure!(
+            bandwidth.is_finite() && bandwidth >= 1.0,
+            "Invalid bandwidth: {arg}",
+        );
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        Ok(Some(bandwidth as u64))
+    }
+}
+
+//Twitch-like multivariant playlist, best stream first
+#[cfg(test)]
+pub const MULTIVARIANT: &str = "#EXTM3U\n\
+    #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"1080p60 (source)\",AUTOSELECT=YES,DEFAULT=YES\n\
+    #EXT-X-STREAM-INF:BANDWIDTH=8000000,RESOLUTION=1920x1080,CODECS=\"avc1.64002A,mp4a.40.2\",VIDEO=\"chunked\",FRAME-RATE=60.000\n\
+    https://example.com/chunked.m3u8\n\
+    #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"720p60\",NAME=\"720p60\",AUTOSELECT=YES,DEFAULT=YES\n\
+    #EXT-X-STREAM-INF:BANDWIDTH=3400000,RESOLUTION=1280x720,CODECS=\"avc1.4D401F,mp4a.40.2\",VIDEO=\"720p60\",FRAME-RATE=60.000\n\
+    https://example.com/720p60.m3u8\n\
+    #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"720p30\",NAME=\"720p30\",AUTOSELECT=YES,DEFAULT=YES\n\
+    #EXT-X-STREAM-INF:BANDWIDTH=2300000,RESOLUTION=1280x720,CODECS=\"avc1.4D401F,mp4a.40.2\",VIDEO=\"720p30\",FRAME-RATE=29.970\n\
+    https://example.com/720p30.m3u8\n\
+    #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"480p30\",NAME=\"480p\",AUTOSELECT=YES,DEFAULT=YES\n\
+    #EXT-X-STREAM-INF:BANDWIDTH=1400000,RESOLUTION=852x480,CODECS=\"avc1.4D401F,mp4a.40.2\",VIDEO=\"480p30\",FRAME-RATE=30.000\n\
+    https://example.com/480p30.m3u8\n\
+    #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"audio_only\",NAME=\"audio_only\",AUTOSELECT=NO,DEFAULT=NO\n\
+    #EXT-X-STREAM-INF:BANDWIDTH=160000,CODECS=\"mp4a.40.2\",VIDEO=\"audio_only\"\n\
+    https://example.com/audio_only.m3u8\n";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names<'a>(variants: &[Variant<'a>], constraint: &Constraint) -> Vec<&'a str> {
+        variants
+            .iter()
+            .filter(|v| constraint.matches(v))
+            .map(|v| v.name)
+            .collect()
+    }
+
+    fn constraint(quality: &str) -> Constraint {
+        Constraint::new(quality).unwrap().unwrap()
+    }
+
+    #[test]
+    fn parse_all() {
+        let variants = Variant::parse_all(MULTIVARIANT);
+        assert_eq!(
+            variants.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "1080p60 1080p 60fps 8000kbps",
+                "720p60 720p 60fps 3400kbps",
+                "720p30 720p 30fps 2300kbps",
+                "480p 480p 30fps 1400kbps",
+                "audio_only 160kbps",
+            ],
+        );
+        assert!(variants[0].source && !variants[1].source);
+        assert_eq!(variants[2].url, "https://example.com/720p30.m3u8");
+    }
+
+    #[test]
+    fn find() {
+        let variants = Variant::parse_all(MULTIVARIANT);
+        assert_eq!(Variant::find(&variants, "720p30"), Some(2));
+        assert_eq!(Variant::find(&variants, " 720P30 "), Some(2));
+        assert_eq!(Variant::find(&variants, "source"), Some(0));
+        assert_eq!(Variant::find(&variants, "1080p60"), Some(0));
+        assert_eq!(Variant::find(&variants, "audio"), Some(4));
+        assert_eq!(Variant::find(&variants, "360p"), None);
+    }
+
+    #[test]
+    fn find_partial() {
+        let variants = Variant::parse_all(MULTIVARIANT);
+        assert_eq!(Variant::find_partial(&variants, "1080").unwrap(), Some(0));
+        assert_eq!(Variant::find_partial(&variants, "48").unwrap(), Some(3));
+        //"p30" only appears in the middle of a name
+        assert_eq!(Variant::find_partial(&variants, "p30").unwrap(), Some(2));
+        assert_eq!(Variant::find_partial(&variants, "360").unwrap(), None);
+
+        let error = Variant::find_partial(&variants, "720").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Quality 720 matches more than one stream: 720p60, 720p30",
+        );
+        assert!(Variant::find_partial(&variants, "60").is_err());
+    }
+
+    #[test]
+    fn find_partial_ignores_duplicates() {
+        let playlist = format!(
+            "{MULTIVARIANT}\
+             #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"480p30-mp4\",NAME=\"480p\"\n\
+             #EXT-X-STREAM-INF:BANDWIDTH=1400000,RESOLUTION=852x480\n\
+             https://example.com/480p30-mp4.m3u8\n",
+        );
+
+        let variants = Variant::parse_all(&playlist);
+        assert_eq!(variants.len(), 6);
+        assert_eq!(Variant::find_partial(&variants, "480").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn exact() {
+        let variants = Variant::parse_all(MULTIVARIANT);
+        assert_eq!(names(&variants, &constraint("720p")), ["720p60", "720p30"]);
+        assert_eq!(names(&variants, &constraint("720p30")), ["720p30"]);
+        assert_eq!(names(&variants, &constraint("720p@60")), ["720p60"]);
+        assert!(names(&variants, &constraint("1080p30")).is_empty());
+        assert!(Constraint::new("720").unwrap().is_none());
+        assert!(Constraint::new("audio_only").unwrap().is_none());
+    }
+
+    #[test]
+    fn at_most() {
+        let variants = Variant::parse_all(MULTIVARIANT);
+        assert_eq!(
+            names(&variants, &constraint("best<=720p")),
+            ["720p60", "720p30", "480p"],
+        );
+        assert_eq!(
+            names(&variants, &constraint("best<=720p30")),
+            ["720p30", "480p"],
+        );
+        assert_eq!(names(&variants, &constraint("<=480p")), ["480p"]);
+        assert!(names(&variants, &constraint("<=360p")).is_empty());
+    }
+
+    #[test]
+    fn bandwidth() {
+        let variants = Variant::parse_all(MULTIVARIANT);
+        assert_eq!(
+            names(&variants, &constraint("<=4mbps")),
+            ["720p60", "720p30", "480p", "audio_only"],
+        );
+        assert_eq!(
+            names(&variants, &constraint("best<=2300kbps")),
+            ["720p30", "480p", "audio_only"],
+        );
+        assert_eq!(
+            names(&variants, &constraint("<=1400000bps")),
+            ["480p", "audio_only"],
+        );
+        assert_eq!(
+            names(&variants, &constraint("<=1.5MBPS")),
+            ["480p", "audio_only"],
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        for quality in [
+            "best<=", "<=abc", "<=bps", "<=0kbps", "<=-1mbps", "<=720x", "<=p30",
+        ] {
+            assert!(Constraint::new(quality).is_err(), "{quality}");
+        }
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        assert_eq!(normalize(" 1080P60 \t HDR "), "1080p60 hdr");
+
+        let playlist = "#EXTM3U\n\
+            #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"1080p60 HDR (source)\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=9000000,RESOLUTION=1920x1080,FRAME-RATE=60.000\n\
+            https://example.com/chunked.m3u8\n\
+            #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"1080p60\",NAME=\"1080p60\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=6000000,RESOLUTION=1920x1080,FRAME-RATE=60.000\n\
+            https://example.com/1080p60.m3u8\n\
+            #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"audio_only\",NAME=\"Audio Only\"\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=160000\n\
+            https://example.com/audio_only.m3u8\n";
+
+        let variants = Variant::parse_all(playlist);
+        assert_eq!(variants[0].name, "1080p60 HDR");
+        assert_eq!(Variant::find(&variants, "1080P60   hdr"), Some(0));
+        assert_eq!(Variant::find(&variants, "1080p60"), Some(1));
+        assert_eq!(Variant::find(&variants, "SOURCE"), Some(0));
+        assert_eq!(Variant::find(&variants, "audio only"), Some(2));
+        assert_eq!(Variant::find(&variants, "1080p60hdr"), None);
+
+        //the shorter name is a prefix of both
+        let error = Variant::find_partial(&variants, "1080P").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Quality 1080P matches more than one stream: 1080p60 HDR, 1080p60",
+        );
+        assert_eq!(Variant::find_partial(&variants, "HDR").unwrap(), Some(0));
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Once,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use getrandom::getrandom;
+use log::debug;
+
+type Source = fn(&mut [u8]) -> Result<(), getrandom::Error>;
+
+//Random values for request parameters, they only have to differ between requests.
+//getrandom can fail in seccomp sandboxes and on some platforms, then the values are
+//derived from the time, PID and salt (usually the channel) instead
+pub fn number(salt: &str) -> u64 {
+    number_with(salt, getrandom)
+}
+
+//For timing only, skips getrandom since it's called for every playlist reload
+pub fn weak_number(salt: &str) -> u64 {
+    let mut buf = [0u8; 8];
+    fallback(&mut buf, salt);
+
+    u64::from_be_bytes(buf)
+}
+
+pub fn alphanumeric<const N: usize>(salt: &str) -> [u8; N] {
+    alphanumeric_with(salt, getrandom)
+}
+
+//the source is getrandom, tests make it fail
+fn fill_with(buf: &mut [u8], salt: &str, source: Source) {
+    static FALLBACK: Once = Once::new();
+
+    if let Err(e) = source(buf) {
+        FALLBACK.call_once(|| debug!("getrandom failed ({e}), using fallback RNG"));
+        fallback(buf, salt);
+    }
+}
+
+fn number_with(salt: &str, source: Source) -> u64 {
+    let mut buf = [0u8; 8];
+    fill_with(&mut buf, salt, source);
+
+    u64::from_be_bytes(buf)
+}
+
+fn alphanumeric_with<const N: usize>(salt: &str, source: Source) -> [u8; N] {
+    const ALPHANUMERIC: &[u8] = b"0123456789\
+                                  ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                  abcdefghijklmnopqrstuvwxyz";
+
+    let mut buf = [0u8; N];
+    fill_with(&mut buf, salt, source);
+    for r in &mut buf {
+        *r = ALPHANUMERIC[(*r as usize) % ALPHANUMERIC.len()];
+    }
+
+    buf
+}
+
+fn fallback(buf: &mut [u8], salt: &str) {
+    //calls within the resolution of the clock still get different values
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = DefaultHasher::new();
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    process::id().hash(&mut hasher);
+    thread::current().id().hash(&mut hasher);
+    salt.hash(&mut hasher);
+    COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+
+    for chunk in buf.chunks_mut(8) {
+        let value = hasher.finish();
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        value.hash(&mut hasher);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn unavailable(_buf: &mut [u8]) -> Result<(), getrandom::Error> {
+        Err(getrandom::Error::UNSUPPORTED)
+    }
+
+    //a source that works fills the buffer itself
+    #[allow(clippy::unnecessary_wraps, reason = "function pointer")]
+    fn constant(buf: &mut [u8]) -> Result<(), getrandom::Error> {
+        buf.fill(7);
+        Ok(())
+    }
+
+    #[test]
+    fn source_is_used() {
+        assert_eq!(number_with("channel", constant), 0x0707_0707_0707_0707);
+        assert_eq!(alphanumeric_with::<4>("channel", constant), *b"7777");
+    }
+
+    #[test]
+    fn fallback_values_differ() {
+        let numbers = (0..1000)
+            .map(|_| number_with("channel", unavailable))
+            .collect::<HashSet<_>>();
+        assert_eq!(numbers.len(), 1000);
+
+        //bytes past the first eight come from the next hash
+        for len in [13, 32] {
+            let tails = (0..1000)
+                .map(|_| {
+                    let mut buf = vec![0; len];
+                    fill_with(&mut buf, "channel", unavailable);
+                    buf.split_off(8)
+                })
+                .collect::<HashSet<_>>();
+            assert_eq!(tails.len(), 1000, "{len}");
+        }
+    }
+
+    #[test]
+    fn fallback_alphanumeric() {
+        let ids = (0..100)
+            .map(|_| alphanumeric_with::<32>("channel", unavailable))
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), 100);
+        assert!(ids.iter().flatten().all(u8::is_ascii_alphanumeric));
+    }
+
+    #[test]
+    fn weak_numbers_differ() {
+        assert_ne!(weak_number("channel"), weak_number("channel"));
+    }
+}
@@ -1,10 +1,174 @@
-use std::{cmp::Ordering, mem, str::FromStr, thread, time::Duration as StdDuration, time::Instant};
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    fmt::{self, Display, Formatter},
+    mem,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::Duration as StdDuration,
+    time::Instant,
+};
 
-use anyhow::{Context, Result};
-use log::{debug, info};
+use anyhow::{bail, ensure, Context, Result};
+use log::{debug, info, warn};
 
-use super::{media_playlist::QueueRange, MediaPlaylist};
-use crate::{http::Url, worker::Worker};
+use super::{
+    media_playlist::{QueueRange, Restart},
+    random, Heartbeat, MediaPlaylist,
+};
+use crate::{
+    http::Url,
+    logger::Condition,
+    output::{Marker, SizeLimit, Status, StatusState, Summary, Webhook},
+    worker::{Download, Worker},
+};
+
+#[derive(Debug)]
+pub enum LimitError {
+    Duration(StdDuration, u64),
+    Size(StdDuration, u64),
+}
+
+impl std::error::Error for LimitError {}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (limit, duration, written) = match self {
+            Self::Duration(duration, written) => ("Duration", duration, written),
+            Self::Size(duration, written) => ("Size", duration, written),
+        };
+
+        #[allow(clippy::cast_precision_loss)]
+        let written = *written as f64 / (1024.0 * 1024.0);
+        write!(
+            f,
+            "{limit} limit reached ({}s of media dispatched, {written:.1} MiB written)",
+            duration.as_secs(),
+        )
+    }
+}
+
+//The playlist keeps updating but every segment is tiny, e.g. a slate or black frames
+//after a broadcaster-side failure
+#[derive(Debug)]
+pub struct SmallSegmentsError {
+    count: u32,
+    min: u64,
+}
+
+impl std::error::Error for SmallSegmentsError {}
+
+impl Display for SmallSegmentsError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} segments in a row were smaller than {} bytes, the stream looks frozen",
+            self.count, self.min,
+        )
+    }
+}
+
+impl SmallSegmentsError {
+    //for supervising scripts, distinct from the --check and offline codes
+    pub const EXIT_CODE: i32 = 6;
+}
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SmallSegmentsAction {
+    #[default]
+    Warn,
+    Stop,
+}
+
+impl SmallSegmentsAction {
+    pub fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "warn" => Ok(Self::Warn),
+            "stop" => Ok(Self::Stop),
+            _ => bail!("Invalid min segment size action: {arg} (must be warn or stop)"),
+        }
+    }
+}
+
+//Consecutive downloads under --min-segment-size. Nothing is downloaded during ad breaks,
+//so they neither count nor reset it
+pub struct SmallSegments {
+    min: u64,
+    action: SmallSegmentsAction,
+    consecutive: u32,
+}
+
+impl SmallSegments {
+    //20 seconds of Twitch segments
+    const CONSECUTIVE: u32 = 10;
+
+    pub const fn new(min: u64, action: SmallSegmentsAction) -> Self {
+        Self {
+            min,
+            action,
+            consecutive: 0,
+        }
+    }
+
+    fn update(&mut self, bytes: u64) -> Result<()> {
+        if bytes >= self.min {
+            if self.consecutive >= Self::CONSECUTIVE {
+                info!("Segment sizes are back to normal ({bytes} bytes)");
+            }
+
+            self.consecutive = 0;
+            return Ok(());
+        }
+
+        self.consecutive += 1;
+        if self.consecutive != Self::CONSECUTIVE {
+            return Ok(());
+        }
+
+        let error = SmallSegmentsError {
+            count: self.consecutive,
+            min: self.min,
+        };
+        match self.action {
+            SmallSegmentsAction::Warn => {
+                warn!("{error}");
+                Ok(())
+            }
+            SmallSegmentsAction::Stop => Err(error.into()),
+        }
+    }
+}
+
+//Recording limits that apply to the whole session
+pub struct Limits {
+    duration: Option<StdDuration>,
+    size: Arc<SizeLimit>,
+
+    dispatched: StdDuration,
+}
+
+impl Limits {
+    pub const fn new(duration: Option<StdDuration>, size: Arc<SizeLimit>) -> Self {
+        Self {
+            duration,
+            size,
+            dispatched: StdDuration::ZERO,
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.duration.is_some_and(|d| self.dispatched >= d) {
+            return Err(LimitError::Duration(self.dispatched, self.size.written()).into());
+        }
+
+        if self.size.reached() {
+            return Err(LimitError::Size(self.dispatched, self.size.written()).into());
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Default, Copy, Clone, Debug)]
 pub struct Duration {
@@ -16,14 +180,20 @@ impl FromStr for Duration {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let secs: f32 = s
+            .split_once(',')
+            .context("Invalid segment duration")?
+            .0
+            .parse()
+            .context("Failed to parse segment duration")?;
+        ensure!(
+            secs.is_finite() && secs >= 0.0,
+            "Segment duration out of range: {secs}",
+        );
+
         Ok(Self {
             is_ad: s.contains('|'),
-            inner: StdDuration::try_from_secs_f32(
-                s.split_once(',')
-                    .map(|s| s.0.parse())
-                    .context("Invalid segment duration")??,
-            )
-            .context("Failed to parse segment duration")?,
+            inner: StdDuration::from_secs_f32(secs),
         })
     }
 }
@@ -47,96 +217,1337 @@ impl Duration {
         inner: StdDuration::from_secs(3),
     };
 
-    pub fn sleep(&self, elapsed: StdDuration) {
+    pub const fn is_ad(self) -> bool {
+        self.is_ad
+    }
+
+    pub const fn as_std(self) -> StdDuration {
+        self.inner
+    }
+
+    //broken proxies have served durations of hours, which would be slept through
+    pub fn limit(mut self, max: StdDuration) -> Self {
+        if self.inner > max {
+            warn!(
+                "Segment duration of {:.1}s is implausible, using {}s",
+                self.inner.as_secs_f32(),
+                max.as_secs(),
+            );
+            self.inner = max;
+        }
+
+        self
+    }
+
+    //time until the next playlist reload, floor is the shortest time between reloads
+    pub fn interval(&self, floor: StdDuration) -> StdDuration {
         if self.inner >= Self::MAX.inner {
-            self.sleep_half(elapsed);
-            return;
+            return self.half_interval(floor);
         }
 
-        Self::sleep_thread(self.inner, elapsed);
+        self.inner.max(floor)
+    }
+
+    pub fn half_interval(&self, floor: StdDuration) -> StdDuration {
+        (self.inner / 2).max(floor)
     }
+}
+
+//Time source of the pacing, tests run the handler on a fake one instead of sleeping
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
 
-    pub fn sleep_half(&self, elapsed: StdDuration) {
-        if let Some(half) = self.inner.checked_div(2) {
-            Self::sleep_thread(half, elapsed);
+    fn sleep(&self, duration: StdDuration);
+}
+
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: StdDuration) {
+        thread::sleep(duration);
+    }
+}
+
+//Sleeps between playlist reloads. With --poll-jitter the first reload is staggered by a random
+//part of the interval and every later one moved randomly by up to that percentage, so clients
+//polling the same proxy don't line up on segment boundaries. Together they stay within one interval
+struct Pacing {
+    clock: Box<dyn Clock>,
+    jitter: u32,
+    //seconds the jitter moved reloads so far, positive is later
+    drift: f64,
+    staggered: bool,
+}
+
+impl Pacing {
+    fn new() -> Self {
+        Self {
+            clock: Box::new(SystemClock),
+            jitter: 0,
+            drift: 0.0,
+            staggered: false,
         }
     }
 
-    fn sleep_thread(duration: StdDuration, elapsed: StdDuration) {
-        if let Some(sleep_time) = duration.checked_sub(elapsed) {
-            debug!("Sleeping thread for {:?}", sleep_time);
-            thread::sleep(sleep_time);
+    //time is when the reload started
+    fn sleep(&mut self, interval: StdDuration, time: Instant) {
+        let interval = self.jitter(interval);
+        let elapsed = self.clock.now().saturating_duration_since(time);
+
+        //a fetch taking more than twice the segment duration is most likely a clock jump
+        //or a stalled VM, skipping the sleep would reload and download in a burst
+        let sleep_time = if elapsed > interval * 2 {
+            debug!("Elapsed time {elapsed:?} is implausible for a {interval:?} segment, clamping");
+            interval / 2
+        } else if let Some(sleep_time) = interval.checked_sub(elapsed) {
+            sleep_time
+        } else {
+            return;
+        };
+
+        debug!("Sleeping thread for {:?}", sleep_time);
+        self.clock.sleep(sleep_time);
+    }
+
+    fn jitter(&mut self, interval: StdDuration) -> StdDuration {
+        if self.jitter == 0 {
+            return interval;
         }
+
+        let secs = interval.as_secs_f64();
+        let max = secs * f64::from(self.jitter) / 100.0;
+        if !self.staggered {
+            self.staggered = true;
+            return StdDuration::from_secs_f64(Self::fraction("stagger").mul_add(secs - max, secs));
+        }
+
+        //every reload lands up to max away from where it would be without jitter,
+        //the previous offset is undone so they can't add up
+        let target = Self::fraction("jitter").mul_add(2.0, -1.0) * max;
+        let offset = (target - self.drift).clamp(-max, max);
+        self.drift += offset;
+
+        StdDuration::from_secs_f64((secs + offset).max(0.0))
+    }
+
+    //0.0..1.0
+    fn fraction(salt: &str) -> f64 {
+        f64::from(u16::try_from(random::weak_number(salt) % 1000).unwrap_or_default()) / 1000.0
     }
 }
 
 #[derive(Debug)]
 pub enum Segment {
-    Normal(Duration, Url),
+    Normal(Duration, Url, Option<String>), //program date time
     Prefetch(Url),
 }
 
+//Reports whether the playlist serves prefetch segments, which is what makes low latency work
+struct LowLatency {
+    enabled: bool,
+    available: Option<bool>,
+    reloads_without: u32,
+
+    seen: u64,
+    dispatched: u64,
+}
+
+impl LowLatency {
+    //prefetch segments can show up a few reloads after the stream starts
+    const UNAVAILABLE_RELOADS: u32 = 5;
+
+    const fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            available: None,
+            reloads_without: 0,
+            seen: 0,
+            dispatched: 0,
+        }
+    }
+
+    fn update(&mut self, prefetch: usize) {
+        if !self.enabled {
+            return;
+        }
+
+        self.seen += prefetch as u64;
+        let available = if prefetch > 0 {
+            self.reloads_without = 0;
+            true
+        } else {
+            self.reloads_without += 1;
+            if self.reloads_without < Self::UNAVAILABLE_RELOADS {
+                return;
+            }
+
+            false
+        };
+
+        if self.available == Some(available) {
+            return;
+        }
+
+        if available {
+            info!("Low latency active");
+        } else {
+            info!("Low latency unavailable for this stream: no prefetch segments served");
+        }
+        debug!(
+            "Prefetch segments seen: {}, dispatched: {}",
+            self.seen, self.dispatched,
+        );
+
+        self.available = Some(available);
+    }
+}
+
+//Recent downloads reported by the worker, tells if it keeps up with the stream
+struct Throughput {
+    recent: VecDeque<Download>,
+}
+
+impl Throughput {
+    const WINDOW: usize = 10;
+
+    //a couple of downloads say more about the connection setup than the throughput
+    const MIN_DOWNLOADS: usize = 3;
+
+    fn add(&mut self, download: Download) {
+        debug!(
+            "Downloaded segment {} in {}ms: {} bytes, {}ms of media, {} retries{}",
+            download.sequence,
+            download.elapsed.as_millis(),
+            download.bytes,
+            download.duration.as_millis(),
+            download.retries,
+            if download.prefetch { ", prefetch" } else { "" },
+        );
+
+        if self.recent.len() == Self::WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(download);
+    }
+
+    //download time and media duration of the recent segments, None until there are enough
+    fn totals(&self) -> Option<(StdDuration, StdDuration)> {
+        if self.recent.len() < Self::MIN_DOWNLOADS {
+            return None;
+        }
+
+        Some((
+            self.recent.iter().map(|d| d.elapsed).sum(),
+            self.recent.iter().map(|d| d.duration).sum(),
+        ))
+    }
+}
+
 pub struct Handler {
     worker: Worker,
+    throughput: Throughput,
+    small_segments: SmallSegments,
+    last_sequence: Option<usize>,
     init: bool,
+    limits: Limits,
+    low_latency: LowLatency,
+    recent: VecDeque<String>,
+    heartbeat: Option<Heartbeat>,
+    webhook: Option<Webhook>,
+    summary: Arc<Summary>,
+    status: Option<Arc<Status>>,
+    pacing: Pacing,
+    in_ad_break: bool,
+    last_ad: Option<Instant>,
+    epoch: u32,
+
+    filtering_ads: Condition,
+    skipping: Condition,
+    unchanged: Condition,
 }
 
 impl Handler {
-    pub const fn new(worker: Worker) -> Self {
-        Self { worker, init: true }
+    const RECENT_SEGMENTS: usize = 20;
+
+    pub fn new(
+        worker: Worker,
+        limits: Limits,
+        small_segments: SmallSegments,
+        low_latency: bool,
+        heartbeat: Option<Heartbeat>,
+        webhook: Option<Webhook>,
+        summary: Arc<Summary>,
+    ) -> Self {
+        Self {
+            worker,
+            throughput: Throughput {
+                recent: VecDeque::new(),
+            },
+            small_segments,
+            last_sequence: None,
+            init: true,
+            limits,
+            low_latency: LowLatency::new(low_latency),
+            recent: VecDeque::new(),
+            heartbeat,
+            webhook,
+            summary,
+            status: None,
+            pacing: Pacing::new(),
+            in_ad_break: false,
+            last_ad: None,
+            epoch: 0,
+            filtering_ads: Condition::new("Filtering ad segment...", "Ad filtering"),
+            skipping: Condition::new(
+                "Failed to find next segment, skipping to newest...",
+                "Skipping to newest segment",
+            ),
+            unchanged: Condition::new("Playlist unchanged, retrying...", "Playlist unchanged"),
+        }
+    }
+
+    //the --status-file of the session, set after the writer it belongs to moved to the worker
+    pub fn set_status(&mut self, status: Option<Arc<Status>>) {
+        self.status = status;
+    }
+
+    #[cfg(test)]
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.pacing.clock = Box::new(clock);
+    }
+
+    //percentage of --poll-jitter, 0 keeps exact timing
+    pub const fn set_poll_jitter(&mut self, jitter: u32) {
+        self.pacing.jitter = jitter;
+    }
+
+    pub fn set_state(&self, state: StatusState) {
+        if let Some(status) = &self.status {
+            status.set_state(state);
+        }
+    }
+
+    //After --channel-failover, events and the status file report the new channel.
+    //The watch heartbeat of the old channel is replaced, dropping it stops its thread
+    pub fn set_channel(&mut self, channel: &str, heartbeat: Option<Heartbeat>) {
+        if let Some(webhook) = &self.webhook {
+            webhook.set_channel(channel);
+        }
+        if let Some(status) = &self.status {
+            status.set_channel(channel);
+        }
+
+        self.heartbeat = heartbeat;
     }
 
     pub fn process(&mut self, playlist: &mut MediaPlaylist, time: Instant) -> Result<()> {
+        self.limits.check()?;
+        self.low_latency.update(playlist.prefetch_count());
+        for download in self.worker.downloads() {
+            self.summary.retries(download.retries);
+            self.small_segments.update(download.bytes)?;
+            self.throughput.add(download);
+        }
+
+        if let Some(restart) = playlist.take_restart() {
+            self.restart(&restart, playlist.header.take())?;
+        }
+
         let last_duration = playlist
             .last_duration()
             .context("Failed to find last segment duration")?;
 
+        let floor = playlist.poll_floor();
+        self.set_ad_break(last_duration.is_ad)?;
+
         if last_duration.is_ad {
-            info!("Filtering ad segment...");
-            last_duration.sleep(time.elapsed());
+            self.last_ad = Some(Instant::now());
+            self.filtering_ads.occur();
+            self.count_ads(playlist);
+            self.set_watching(false);
+            self.pacing.sleep(last_duration.interval(floor), time);
 
             return Ok(());
         }
+        self.filtering_ads.end();
+
+        let (sequence, newest_sequence) = (playlist.added_sequence(), playlist.newest_sequence());
+        let mut segments = if !self.init && playlist.all_added() && self.can_catch_up(sequence) {
+            playlist.all_segments()
+        } else {
+            playlist.segments()
+        };
 
-        match playlist.segments() {
+        match segments {
             QueueRange::Partial(ref mut segments) => {
-                for segment in segments {
-                    debug!("Sending segment to worker:\n{segment:?}");
+                for (sequence, segment) in (sequence..).zip(segments) {
+                    debug!(
+                        "Sending segment to worker (epoch {}):\n{segment:?}",
+                        self.epoch
+                    );
                     match segment {
-                        Segment::Normal(_, url) | Segment::Prefetch(url) => {
-                            self.worker.url(mem::take(url))?;
+                        Segment::Normal(duration, url, program_date_time) => {
+                            self.dispatch(
+                                mem::take(url),
+                                sequence,
+                                *duration,
+                                program_date_time.take(),
+                                false,
+                            )?;
+                        }
+                        Segment::Prefetch(url) => {
+                            self.low_latency.dispatched += 1;
+                            self.dispatch(mem::take(url), sequence, last_duration, None, true)?;
                         }
                     }
                 }
 
-                last_duration.sleep(time.elapsed());
+                self.skipping.end();
+                self.unchanged.end();
+                self.set_watching(true);
+
+                self.pacing.sleep(last_duration.interval(floor), time);
                 self.init = false;
             }
             QueueRange::Back(newest) => {
                 if !self.init {
-                    info!("Failed to find next segment, skipping to newest...");
+                    self.skipping.occur();
+                    self.summary.skip();
+                    self.worker.marker(Marker::Discontinuity)?;
                 }
+                self.unchanged.end();
+                self.set_watching(true);
 
                 let newest = newest.context("Failed to find newest segment")?;
-                debug!("Sending newest segment to worker:\n{newest:?}");
+                debug!(
+                    "Sending newest segment to worker (epoch {}):\n{newest:?}",
+                    self.epoch,
+                );
 
                 match newest {
-                    Segment::Normal(duration, ref mut url) => {
-                        self.worker.url(mem::take(url))?;
-                        duration.sleep(time.elapsed());
+                    Segment::Normal(duration, ref mut url, ref mut program_date_time) => {
+                        self.dispatch(
+                            mem::take(url),
+                            newest_sequence,
+                            *duration,
+                            program_date_time.take(),
+                            false,
+                        )?;
+                        self.pacing.sleep(duration.interval(floor), time);
+                    }
+                    Segment::Prefetch(ref mut url) => {
+                        self.low_latency.dispatched += 1;
+                        self.dispatch(mem::take(url), newest_sequence, last_duration, None, true)?;
                     }
-                    Segment::Prefetch(ref mut url) => self.worker.url(mem::take(url))?,
                 }
             }
             QueueRange::Empty => {
                 if last_duration < Duration::MAX && !self.init {
-                    info!("Playlist unchanged, retrying...");
+                    self.unchanged.occur();
+                    self.set_watching(false);
                 }
 
-                last_duration.sleep_half(time.elapsed());
+                self.pacing.sleep(last_duration.half_interval(floor), time);
+            }
+        }
+
+        Ok(())
+    }
+
+    //an ad break was being filtered within the window
+    pub fn ads_within(&self, window: StdDuration) -> bool {
+        self.last_ad.is_some_and(|t| t.elapsed() < window)
+    }
+
+    fn set_ad_break(&mut self, is_ad: bool) -> Result<()> {
+        self.worker.set_ad_break(is_ad);
+        self.set_state(if is_ad {
+            StatusState::AdBreak
+        } else {
+            StatusState::Live
+        });
+        if is_ad == self.in_ad_break {
+            return Ok(());
+        }
+
+        self.in_ad_break = is_ad;
+        if let Some(webhook) = &self.webhook {
+            webhook.ad_break(is_ad);
+        }
+
+        self.worker.marker(if is_ad {
+            Marker::AdBreak
+        } else {
+            Marker::StreamResume
+        })
+    }
+
+    //The broadcaster's encoder started over, the segments already queued finish and
+    //playback continues from the newest segment of the new session
+    fn restart(&mut self, restart: &Restart, header: Option<Url>) -> Result<()> {
+        self.epoch += 1;
+        warn!("Stream restarted (epoch {}): {restart}", self.epoch);
+
+        self.init = true;
+        self.summary.restart();
+        if let Some(webhook) = &self.webhook {
+            webhook.restart(self.epoch);
+        }
+
+        self.worker.marker(Marker::Restart)?;
+        if let Some(header) = header {
+            self.worker.header(header)?;
+        }
+
+        Ok(())
+    }
+
+    //the next playlist is another rendition, its init segment has to be written first
+    pub fn reset(&mut self, header: Option<Url>) -> Result<()> {
+        self.init = true;
+        self.summary.reset();
+        self.worker.marker(Marker::Discontinuity)?;
+        if let Some(header) = header {
+            self.worker.header(header)?;
+        }
+
+        Ok(())
+    }
+
+    //skips to the newest segment on a fresh connection, the segment that was downloading
+    //before the suspend is abandoned
+    pub fn resync(&mut self) -> Result<()> {
+        self.init = true;
+        self.summary.reset();
+        self.worker.cancel();
+        self.worker.marker(Marker::Discontinuity)?;
+        self.worker.reconnect()
+    }
+
+    //The previous segments all left the playlist. That only means the worker fell behind
+    //if segments were missed in between or downloads are slower than realtime,
+    //otherwise the playlist moved on in one reload and none of the new segments are skipped.
+    fn can_catch_up(&self, sequence: usize) -> bool {
+        let contiguous = self.last_sequence.is_some_and(|s| sequence <= s + 1);
+        let Some((elapsed, duration)) = self.throughput.totals() else {
+            return false;
+        };
+
+        if elapsed >= duration {
+            debug!(
+                "Downloads are slower than realtime: {}ms for {}ms of media",
+                elapsed.as_millis(),
+                duration.as_millis(),
+            );
+            return false;
+        }
+
+        if contiguous {
+            debug!("Playlist moved past the previous segments, catching up");
+        }
+        contiguous
+    }
+
+    //ad segments are never dispatched, so they're counted when the playlist adds them
+    fn count_ads(&self, playlist: &mut MediaPlaylist) {
+        let segments = if playlist.all_added() {
+            playlist.all_segments()
+        } else {
+            playlist.segments()
+        };
+
+        if let QueueRange::Partial(segments) = segments {
+            for segment in segments {
+                if let Segment::Normal(duration, ..) = segment {
+                    if duration.is_ad {
+                        self.summary.ad(duration.inner);
+                    }
+                }
             }
         }
+    }
+
+    fn set_watching(&self, watching: bool) {
+        if let Some(heartbeat) = &self.heartbeat {
+            heartbeat.set_active(watching);
+        }
+    }
+
+    fn dispatch(
+        &mut self,
+        url: Url,
+        sequence: usize,
+        duration: Duration,
+        program_date_time: Option<String>,
+        prefetch: bool,
+    ) -> Result<()> {
+        self.last_sequence = Some(sequence);
+
+        //left empty by the playlist because the URL was invalid
+        if url.is_empty() {
+            return Ok(());
+        }
+
+        //prefetch segments reappear as normal segments, query strings can differ between the two
+        let path = url.split('?').next().unwrap_or_default();
+        if self.recent.iter().any(|p| p == path) {
+            debug!("Skipping already dispatched segment: {path}");
+            return Ok(());
+        }
+
+        if self.recent.len() == Self::RECENT_SEGMENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(path.to_owned());
+
+        self.limits.check()?;
+        self.worker
+            .url(url, sequence, duration.inner, program_date_time, prefetch)?;
+        self.limits.dispatched += duration.inner;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fmt::Write as _,
+        fs,
+        sync::{
+            atomic::{self, AtomicUsize},
+            Mutex,
+        },
+    };
+
+    use super::{
+        super::{
+            media_playlist::{fixture, PlaylistSource, ScriptedSource},
+            OfflineError,
+        },
+        *,
+    };
+    use crate::{
+        args::{Parse, Parser},
+        logger,
+        memory::Memory,
+        output::{Args as OutputArgs, SizeLimit, StreamEnv, Writer},
+        temp_dir::TempDir,
+        worker::{self, Jobs},
+    };
+
+    //Starts at an arbitrary instant and only moves when slept on or advanced
+    #[derive(Clone)]
+    struct FakeClock {
+        start: Instant,
+        elapsed: Arc<Mutex<StdDuration>>,
+        sleeps: Arc<Mutex<Vec<StdDuration>>>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.start + *self.elapsed.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: StdDuration) {
+            *self.elapsed.lock().unwrap() += duration;
+            self.sleeps.lock().unwrap().push(duration);
+        }
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self {
+                start: Instant::now(),
+                elapsed: Arc::default(),
+                sleeps: Arc::default(),
+            }
+        }
+
+        fn take_sleeps(&self) -> Vec<StdDuration> {
+            mem::take(&mut self.sleeps.lock().unwrap())
+        }
+    }
+
+    //A handler fed by scripted playlists, like the session's main loop
+    struct Session {
+        handler: Handler,
+        playlist: MediaPlaylist,
+        jobs: Jobs,
+        clock: FakeClock,
+    }
+
+    impl Session {
+        fn new(bodies: &[String]) -> Self {
+            let source = ScriptedSource::new(bodies.iter().cloned().map(Ok));
+            Self::with_source(source, StdDuration::ZERO, FakeClock::new())
+        }
+
+        fn with_source(
+            source: impl PlaylistSource + 'static,
+            min_poll: StdDuration,
+            clock: FakeClock,
+        ) -> Self {
+            let (worker, jobs) = Worker::recording(worker::DEFAULT_MAX_QUEUED);
+            let mut handler = Handler::new(
+                worker,
+                Limits::new(None, Arc::new(SizeLimit::default())),
+                SmallSegments::new(0, SmallSegmentsAction::Warn),
+                true,
+                None,
+                None,
+                Arc::new(Summary::new()),
+            );
+            handler.set_clock(clock.clone());
+
+            let playlist =
+                MediaPlaylist::new(source, StdDuration::from_secs(30), min_poll).unwrap();
+
+            Self {
+                handler,
+                playlist,
+                jobs,
+                clock,
+            }
+        }
+
+        //jobs of the first playlist, which was loaded by MediaPlaylist::new
+        fn start(&mut self) -> Vec<String> {
+            let time = self.clock.now();
+            self.handler.process(&mut self.playlist, time).unwrap();
+            self.jobs()
+        }
+
+        fn reload(&mut self) -> Result<Vec<String>> {
+            let time = self.clock.now();
+            self.playlist.reload()?;
+            self.handler.process(&mut self.playlist, time)?;
+            Ok(self.jobs())
+        }
+
+        //URLs relative to the playlist
+        fn jobs(&self) -> Vec<String> {
+            relative(&self.jobs.take())
+        }
+
+        //like start, but the jobs are also written to the writer as the worker would
+        fn start_writing(&mut self, writer: &mut Writer) -> Vec<String> {
+            let time = self.clock.now();
+            self.handler.process(&mut self.playlist, time).unwrap();
+            relative(&self.jobs.write(writer))
+        }
+
+        fn reload_writing(&mut self, writer: &mut Writer) -> Vec<String> {
+            self.playlist.reload().unwrap();
+            self.start_writing(writer)
+        }
+    }
+
+    fn relative(jobs: &[String]) -> Vec<String> {
+        jobs.iter()
+            .map(|j| j.replace("http://127.0.0.1/v/", ""))
+            .collect()
+    }
+
+    #[test]
+    fn steady_state() {
+        let mut session = Session::new(&[
+            fixture(10, &["live"; 4]),
+            fixture(11, &["live"; 4]),
+            fixture(12, &["live"; 4]),
+            fixture(12, &["live"; 4]),
+        ]);
+
+        //playback starts at the newest segment
+        assert_eq!(session.start(), ["seg13.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg14.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg15.ts"]);
+        assert_eq!(session.clock.take_sleeps(), [StdDuration::from_secs(2); 3]);
+
+        //an unchanged playlist is polled again after half a segment
+        assert!(session.reload().unwrap().is_empty());
+        assert_eq!(session.clock.take_sleeps(), [StdDuration::from_secs(1)]);
+    }
+
+    #[test]
+    fn prefetch_promotion() {
+        let mut session = Session::new(&[
+            fixture(10, &["live", "live", "prefetch", "prefetch"]),
+            fixture(11, &["live", "live", "prefetch", "prefetch"]),
+            fixture(12, &["live", "live", "live", "prefetch"]),
+        ]);
+
+        assert_eq!(session.start(), ["seg13.ts"]);
+        //the promoted prefetch segment was already dispatched
+        assert_eq!(session.reload().unwrap(), ["seg14.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg15.ts"]);
+    }
+
+    #[test]
+    fn ad_break() {
+        let mut session = Session::new(&[
+            fixture(10, &["live"; 4]),
+            fixture(11, &["live", "live", "live", "ad"]),
+            fixture(12, &["live", "live", "ad", "ad"]),
+            fixture(13, &["live", "ad", "ad", "live"]),
+            fixture(14, &["ad", "ad", "live", "live"]),
+        ]);
+
+        assert_eq!(session.start(), ["seg13.ts"]);
+        assert_eq!(session.reload().unwrap(), ["marker AdBreak"]);
+        assert!(session.reload().unwrap().is_empty());
+        assert_eq!(
+            session.reload().unwrap(),
+            ["marker StreamResume", "seg16.ts"],
+        );
+        assert_eq!(session.reload().unwrap(), ["seg17.ts"]);
+    }
+
+    #[test]
+    fn sequence_reset() {
+        let mut session = Session::new(&[
+            fixture(100, &["live"; 4]),
+            fixture(101, &["live"; 4]),
+            fixture(0, &["live"; 4]),
+            fixture(1, &["live"; 4]),
+        ]);
+
+        assert_eq!(session.start(), ["seg103.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg104.ts"]);
+        //playback continues from the newest segment of the new session
+        assert_eq!(session.reload().unwrap(), ["marker Restart", "seg3.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg4.ts"]);
+    }
+
+    #[test]
+    fn prefetch_promotion_across_sequence_reset() {
+        let mut session = Session::new(&[
+            fixture(100, &["live", "live", "prefetch", "prefetch"]),
+            fixture(0, &["live", "live", "prefetch", "prefetch"]),
+            fixture(1, &["live", "live", "live", "prefetch"]),
+            fixture(2, &["live", "live", "live", "prefetch"]),
+        ]);
+
+        assert_eq!(session.start(), ["seg103.ts"]);
+        //the prefetch segment of the old session is never promoted, the new one starts
+        //from its newest prefetch segment
+        assert_eq!(session.reload().unwrap(), ["marker Restart", "seg3.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg4.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg5.ts"]);
+    }
+
+    #[test]
+    fn endlist() {
+        let mut session = Session::new(&[
+            fixture(10, &["live"; 4]),
+            fixture(11, &["live", "live", "live", "live", "#EXT-X-ENDLIST"]),
+        ]);
+
+        assert_eq!(session.start(), ["seg13.ts"]);
+        let error = session.reload().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OfflineError>(),
+            Some(OfflineError::EndOfStream),
+        ));
+        assert!(session.jobs().is_empty());
+    }
+
+    #[test]
+    fn durations() {
+        let duration = "2.002,live".parse::<Duration>().unwrap();
+        assert_eq!(duration.as_std(), StdDuration::from_secs_f32(2.002));
+        assert!(!duration.is_ad());
+        assert!("2.000,Amazon|8675309".parse::<Duration>().unwrap().is_ad());
+        assert_eq!(
+            "0,".parse::<Duration>().unwrap().as_std(),
+            StdDuration::ZERO
+        );
+
+        for invalid in ["2.000", "abc,live", "-1.000,live", "NaN,live", "inf,live"] {
+            assert!(invalid.parse::<Duration>().is_err(), "{invalid}");
+        }
+
+        let max = StdDuration::from_secs(30);
+        let absurd = "20000.000,live".parse::<Duration>().unwrap();
+        assert_eq!(absurd.limit(max).as_std(), max);
+        assert_eq!(duration.limit(max), duration);
+    }
+
+    #[test]
+    fn absurd_duration_isnt_slept_through() {
+        let absurd = |sequence: usize| {
+            let url = format!("seg{}.ts", sequence + 3);
+            fixture(
+                sequence,
+                &["live", "live", "live", "#EXTINF:20000.000,live", &url],
+            )
+        };
+        let mut session = Session::new(&[absurd(10), absurd(11)]);
+
+        assert_eq!(session.start(), ["seg13.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg14.ts"]);
+        //long segments are polled at half their limited duration
+        assert_eq!(session.clock.take_sleeps(), [StdDuration::from_secs(15); 2]);
+    }
+
+    #[test]
+    fn sequence_jump() {
+        let mut session = Session::new(&[
+            fixture(10, &["live"; 4]),
+            fixture(3_000_010, &["live"; 4]),
+            fixture(3_000_011, &["live"; 4]),
+        ]);
+
+        assert_eq!(session.start(), ["seg13.ts"]);
+        //the new segments aren't caught up on
+        assert_eq!(session.reload().unwrap(), ["seg3000013.ts"]);
+        assert_eq!(session.reload().unwrap(), ["seg3000014.ts"]);
+    }
+
+    fn jittered(percent: u32) -> (Pacing, FakeClock) {
+        let clock = FakeClock::new();
+        let pacing = Pacing {
+            clock: Box::new(clock.clone()),
+            jitter: percent,
+            ..Pacing::new()
+        };
+
+        (pacing, clock)
+    }
+
+    #[test]
+    fn poll_jitter_bounds() {
+        let interval = StdDuration::from_secs(2);
+        let max = interval / 5;
+        let (mut pacing, clock) = jittered(20);
+
+        pacing.sleep(interval, clock.now());
+        let stagger = clock.take_sleeps()[0];
+        assert!(
+            stagger >= interval && stagger < StdDuration::from_millis(3600),
+            "{stagger:?}"
+        );
+
+        for _ in 0..200 {
+            pacing.sleep(interval, clock.now());
+        }
+        let sleeps = clock.take_sleeps();
+        for sleep in &sleeps {
+            assert!(
+                *sleep >= StdDuration::from_millis(1600) && *sleep <= interval + max,
+                "{sleep:?}"
+            );
+        }
+        assert!(sleeps.iter().any(|sleep| *sleep != sleeps[0]));
+
+        //the reloads as a whole stay within one jitter of the exact schedule
+        let total = sleeps.iter().sum::<StdDuration>();
+        let exact = interval * 200;
+        assert!(total.abs_diff(exact) <= max, "{total:?}");
+    }
+
+    #[test]
+    fn poll_jitter_drift_is_corrected() {
+        let interval = StdDuration::from_secs(2);
+        let max = 0.4;
+        let (mut pacing, clock) = jittered(20);
+        pacing.staggered = true;
+
+        //already a full jitter late, the next reload can only come earlier
+        pacing.drift = max;
+        pacing.sleep(interval, clock.now());
+        assert!(clock.take_sleeps()[0] <= interval);
+        assert!(pacing.drift <= max);
+
+        pacing.drift = -max;
+        pacing.sleep(interval, clock.now());
+        assert!(clock.take_sleeps()[0] >= interval);
+        assert!(pacing.drift >= -max);
+
+        for _ in 0..200 {
+            pacing.sleep(interval, clock.now());
+            assert!(pacing.drift.abs() <= max + f64::EPSILON, "{}", pacing.drift);
+        }
+    }
+
+    #[test]
+    fn no_jitter_is_exact() {
+        let interval = StdDuration::from_secs(2);
+        let (mut pacing, clock) = jittered(0);
+
+        let time = clock.now();
+        *clock.elapsed.lock().unwrap() += StdDuration::from_millis(500);
+        pacing.sleep(interval, time);
+        pacing.sleep(interval, clock.now());
+
+        //time spent fetching is taken off the sleep
+        assert_eq!(
+            clock.take_sleeps(),
+            [StdDuration::from_millis(1500), interval]
+        );
+    }
+
+    #[test]
+    fn invalid_urls_arent_dispatched() {
+        let wrapped = "seg\r13.ts";
+        let mut session = Session::new(&[
+            fixture(10, &["live", "live", "live", "#EXTINF:2.000,live", wrapped]),
+            fixture(
+                11,
+                &[
+                    "live",
+                    "live",
+                    "#EXTINF:2.000,live",
+                    wrapped,
+                    "#EXTINF:2.000,live",
+                    "seg14.ts",
+                ],
+            ),
+        ]);
+
+        assert!(session.start().is_empty());
+        assert_eq!(session.reload().unwrap(), ["seg14.ts"]);
+    }
+
+    //Live stream on the fake clock that was already running when the session started.
+    //Segments are listed once complete, durations repeat and the newest few are kept
+    struct LiveSource {
+        clock: FakeClock,
+        durations: Vec<StdDuration>,
+        //sequence of the newest segment in the last playlist fetched
+        listed: Arc<AtomicUsize>,
+        body: String,
+        url: Url,
+    }
+
+    impl LiveSource {
+        const WINDOW: usize = 6;
+        const AGE: StdDuration = StdDuration::from_secs(30);
+
+        fn new(clock: &FakeClock, durations: &[f64]) -> Self {
+            Self {
+                clock: clock.clone(),
+                durations: durations
+                    .iter()
+                    .map(|d| StdDuration::from_secs_f64(*d))
+                    .collect(),
+                listed: Arc::default(),
+                body: String::new(),
+                url: "http://127.0.0.1/v/0.m3u8".into(),
+            }
+        }
+
+        fn duration(&self, sequence: usize) -> StdDuration {
+            self.durations[sequence % self.durations.len()]
+        }
+
+        fn complete(&self) -> usize {
+            let now = *self.clock.elapsed.lock().unwrap() + Self::AGE;
+            let (mut complete, mut end) = (0, StdDuration::ZERO);
+            while end + self.duration(complete) <= now {
+                end += self.duration(complete);
+                complete += 1;
+            }
+
+            complete
+        }
+    }
+
+    impl PlaylistSource for LiveSource {
+        fn fetch(&mut self) -> Result<(&str, &Url)> {
+            let complete = self.complete();
+            let first = complete.saturating_sub(Self::WINDOW);
+            self.body = format!(
+                "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:6\n#EXT-X-MEDIA-SEQUENCE:{first}\n"
+            );
+            for sequence in first..complete {
+                let duration = self.duration(sequence).as_secs_f64();
+                let _ = write!(self.body, "#EXTINF:{duration:.3},live\nseg{sequence}.ts\n");
+            }
+
+            self.listed.store(complete - 1, atomic::Ordering::Relaxed);
+            Ok((&self.body, &self.url))
+        }
+
+        fn url(&self) -> &Url {
+            &self.url
+        }
+
+        fn reset(&mut self) {}
+    }
+
+    //Plays a minute of a live stream, returns the number of playlist requests. Every reload
+    //has to dispatch all of the new segments, without skipping any
+    fn play_live(durations: &[f64], min_poll: StdDuration) -> usize {
+        let clock = FakeClock::new();
+        let live = LiveSource::new(&clock, durations);
+        let listed = live.listed.clone();
+        let mut session = Session::with_source(live, min_poll, clock.clone());
+
+        let sequence = |job: &String| {
+            job.strip_prefix("seg")
+                .and_then(|j| j.strip_suffix(".ts"))
+                .and_then(|j| j.parse::<usize>().ok())
+                .unwrap_or_else(|| panic!("unexpected job {job}"))
+        };
+
+        let mut last = sequence(&session.start()[0]);
+        let mut requests = 1;
+        while *clock.elapsed.lock().unwrap() < StdDuration::from_secs(60) {
+            for job in session.reload().unwrap() {
+                assert_eq!(sequence(&job), last + 1, "skipped a segment");
+                last += 1;
+            }
+            assert_eq!(last, listed.load(atomic::Ordering::Relaxed), "fell behind");
+            requests += 1;
+        }
+
+        requests
+    }
+
+    #[test]
+    fn poll_intervals() {
+        let floor = StdDuration::from_secs(1);
+        let duration = |secs: &str| format!("{secs},live").parse::<Duration>().unwrap();
+
+        assert_eq!(duration("0.500").interval(floor), floor);
+        assert_eq!(duration("0.500").half_interval(floor), floor);
+        assert_eq!(duration("2.000").interval(floor), StdDuration::from_secs(2));
+        assert_eq!(duration("2.000").half_interval(floor), floor);
+        //long segments are polled twice as often, so the connection is kept alive
+        assert_eq!(duration("6.000").interval(floor), StdDuration::from_secs(3));
+        assert_eq!(
+            duration("0.500").interval(StdDuration::ZERO),
+            StdDuration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn fast_segments_are_polled_at_the_floor() {
+        let floor = StdDuration::from_secs(1);
+
+        //two segments a reload instead of one every half second
+        assert!(play_live(&[0.5], floor) <= 61);
+        assert!(play_live(&[0.5], StdDuration::ZERO) >= 120);
+
+        //the floor can't exceed half the playlist, or segments would leave it unseen.
+        //Six half second segments are polled every 1.5s
+        let requests = play_live(&[0.5], StdDuration::from_secs(10));
+        assert!((40..=41).contains(&requests), "{requests}");
+    }
+
+    #[test]
+    fn mixed_segment_durations() {
+        let floor = StdDuration::from_secs(1);
+
+        assert!(play_live(&[6.0], floor) <= 21);
+        assert!(play_live(&[0.5, 0.5, 0.5, 0.5, 6.0], floor) <= 61);
+        assert!(play_live(&[6.0, 0.5, 6.0, 0.5, 0.5, 2.0], floor) <= 61);
+    }
+
+    #[test]
+    fn clock_jumps_arent_caught_up_in_a_burst() {
+        let interval = StdDuration::from_secs(2);
+        let (mut pacing, clock) = jittered(0);
+
+        //stepped forward during the reload, it would otherwise not sleep at all
+        let time = clock.now();
+        *clock.elapsed.lock().unwrap() += StdDuration::from_secs(60 * 60);
+        pacing.sleep(interval, time);
+
+        //stepped back, the reload seems to have started in the future
+        let time = clock.now() + StdDuration::from_secs(60 * 60);
+        pacing.sleep(interval, time);
+
+        assert_eq!(clock.take_sleeps(), [interval / 2, interval]);
+
+        //the same in the handler, the segments aren't affected
+        let mut session = Session::new(&[fixture(10, &["live"; 4]), fixture(11, &["live"; 4])]);
+        assert_eq!(session.start(), ["seg13.ts"]);
+        session.clock.take_sleeps();
+
+        let time = session.clock.now();
+        *session.clock.elapsed.lock().unwrap() += StdDuration::from_secs(60 * 60);
+        session.playlist.reload().unwrap();
+        session
+            .handler
+            .process(&mut session.playlist, time)
+            .unwrap();
+        assert_eq!(session.jobs(), ["seg14.ts"]);
+        assert_eq!(session.clock.take_sleeps(), [StdDuration::from_secs(1)]);
+    }
+
+    //playlists that each add one segment
+    fn live(first: usize, count: usize) -> Vec<String> {
+        (first..first + count)
+            .map(|sequence| fixture(sequence, &["live"; 4]))
+            .collect()
+    }
+
+    //reports a download of the given size before each reload, returns what was logged about them
+    fn small_segments(session: &mut Session, sizes: &[u64]) -> Result<Vec<(log::Level, String)>> {
+        let mut logged = Vec::new();
+        for (sequence, bytes) in sizes.iter().enumerate() {
+            session.jobs.downloaded(sequence, *bytes);
+            let (result, records) = logger::capture(|| session.reload());
+            logged.extend(records.into_iter().filter(|(_, r)| r.contains("bytes")));
+            result?;
+        }
+
+        Ok(logged)
+    }
+
+    #[test]
+    fn small_segments_warn() {
+        let mut session = Session::new(&live(10, 13));
+        session.handler.small_segments = SmallSegments::new(1000, SmallSegmentsAction::Warn);
+        session.start();
+
+        let logged = small_segments(&mut session, &[100; 11]).unwrap();
+        assert_eq!(
+            logged,
+            [(
+                log::Level::Warn,
+                "10 segments in a row were smaller than 1000 bytes, the stream looks frozen"
+                    .to_owned()
+            )],
+        );
+
+        let logged = small_segments(&mut session, &[5000]).unwrap();
+        assert_eq!(
+            logged,
+            [(
+                log::Level::Info,
+                "Segment sizes are back to normal (5000 bytes)".to_owned()
+            )],
+        );
+    }
+
+    #[test]
+    fn small_segments_stop() {
+        let mut session = Session::new(&live(10, 20));
+        session.handler.small_segments = SmallSegments::new(1000, SmallSegmentsAction::Stop);
+        session.start();
+
+        //a normal one in between starts the count over
+        small_segments(&mut session, &[100, 100, 5000]).unwrap();
+        small_segments(&mut session, &[100; 9]).unwrap();
+
+        let error = small_segments(&mut session, &[100]).unwrap_err();
+        assert!(error.is::<SmallSegmentsError>());
+        assert_eq!(SmallSegmentsError::EXIT_CODE, 6);
+    }
+
+    #[test]
+    fn ad_breaks_dont_count_as_small_segments() {
+        let mut bodies = live(10, 6);
+        bodies.extend([
+            fixture(16, &["live", "live", "live", "ad"]),
+            fixture(17, &["live", "live", "ad", "ad"]),
+            fixture(18, &["live", "ad", "ad", "ad"]),
+            fixture(19, &["ad", "ad", "ad", "live"]),
+        ]);
+        bodies.extend(live(20, 5));
+        let mut session = Session::new(&bodies);
+        session.handler.small_segments = SmallSegments::new(1000, SmallSegmentsAction::Stop);
+        session.start();
+
+        small_segments(&mut session, &[100; 5]).unwrap();
+
+        //nothing is downloaded during the break, so the count neither grows nor resets
+        let jobs = (0..3)
+            .flat_map(|_| session.reload().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(jobs, ["marker AdBreak"]);
+        assert_eq!(
+            session.reload().unwrap(),
+            ["marker StreamResume", "seg22.ts"]
+        );
+
+        small_segments(&mut session, &[100; 4]).unwrap();
+        assert!(small_segments(&mut session, &[100])
+            .unwrap_err()
+            .is::<SmallSegmentsError>());
+    }
+
+    #[test]
+    fn chapter_offsets_match_written_content() {
+        let dir = TempDir::new("chapter-offsets");
+        let mut args = OutputArgs::default();
+        args.parse(&mut Parser::from_args(&[
+            "-r",
+            &dir.path_str("recording.ts"),
+            "--overwrite",
+            "--chapters",
+            &dir.path_str("chapters.csv"),
+        ]))
+        .unwrap();
+        let mut writer = Writer::new(
+            &args,
+            None,
+            &StreamEnv::new("", "channel", None, None),
+            Arc::new(Summary::new()),
+            Arc::new(Memory::new(None)),
+        )
+        .unwrap();
+
+        let mut session = Session::new(&[
+            fixture(10, &["live"; 4]),
+            fixture(11, &["live", "live", "live", "ad"]),
+            fixture(12, &["live", "live", "ad", "ad"]),
+            fixture(14, &["ad", "ad", "live", "live"]),
+            fixture(15, &["ad", "live", "live", "live"]),
+            fixture(0, &["live"; 4]),
+        ]);
+        let mut jobs = session.start_writing(&mut writer);
+        for _ in 0..5 {
+            jobs.extend(session.reload_writing(&mut writer));
+        }
+        drop(writer);
+        assert_eq!(
+            jobs,
+            [
+                "seg13.ts",
+                "marker AdBreak",
+                "marker StreamResume",
+                "seg16.ts",
+                "seg17.ts",
+                "seg18.ts",
+                "marker Restart",
+                "seg3.ts",
+            ],
+        );
+
+        //ads aren't written, so a marker is after every two second segment written before it
+        let mut written = 0;
+        let mut expected = Vec::new();
+        for job in &jobs {
+            if job.starts_with("marker ") {
+                expected.push(format!("{}.000", written * 2));
+            } else {
+                written += 1;
+            }
+        }
+
+        let csv = fs::read_to_string(dir.join("chapters.csv")).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("offset,wall_time,type"));
+        let (offsets, types): (Vec<_>, Vec<_>) = lines
+            .map(|l| {
+                let fields = l.split(',').collect::<Vec<_>>();
+                (fields[0].to_owned(), fields[2])
+            })
+            .unzip();
+        assert_eq!(offsets, expected);
+        assert_eq!(offsets, ["2.000", "2.000", "8.000"]);
+        assert_eq!(types, ["ad_break", "stream_resume", "stream_restart"]);
+
+        let recording = fs::read_to_string(dir.join("recording.ts")).unwrap();
+        assert_eq!(recording.matches(".ts").count(), written);
+        assert!(!recording.contains("/ad"));
+    }
+}
@@ -1,36 +1,64 @@
 mod decoder;
+mod headers;
+mod preconnect;
+mod rate_limit;
 mod request;
+mod resolver;
+#[cfg(test)]
+pub mod scripted;
 mod tls_stream;
+mod trace;
 mod url;
 
+pub use headers::Headers;
+use preconnect::Preconnector;
+use rate_limit::RateLimiter;
+use request::Transport;
 pub use request::{Request, TextRequest};
+use resolver::Resolver;
+use tls_stream::NoVerification;
+use trace::Tracer;
 pub use url::{Scheme, Url};
 
 use std::{
     borrow::Cow,
-    fmt::{self, Display, Formatter},
+    fmt::{self, Display, Formatter, Write as _},
     io::{self, Write},
+    net::IpAddr,
     sync::Arc,
     time::Duration,
 };
 
-use anyhow::Result;
-use log::debug;
-use rustls::{ClientConfig, RootCertStore};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use log::{debug, info, warn};
+use rustls::{
+    pki_types::{
+        pem::{self, PemObject},
+        CertificateDer,
+    },
+    ClientConfig, RootCertStore,
+};
 
 use crate::{
     args::{Parse, Parser},
-    constants,
+    constants, logger,
+    path_template::PathTemplate,
 };
 
+//The start of the body is kept for API requests, it usually explains the error
 #[derive(Debug)]
-pub struct StatusError(u16, Url);
+pub struct StatusError(u16, Url, Option<String>);
 
 impl std::error::Error for StatusError {}
 
 impl Display for StatusError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "Status code {} on {}", self.0, self.1)
+        write!(f, "Status code {} on {}", self.0, logger::redact(&self.1))?;
+        if let Some(body) = &self.2 {
+            write!(f, ": {}", logger::redact(body))?;
+        }
+
+        Ok(())
     }
 }
 
@@ -38,17 +66,175 @@ impl StatusError {
     pub fn is_not_found(error: &anyhow::Error) -> bool {
         error
             .downcast_ref::<Self>()
-            .is_some_and(|Self(code, _)| *code == 404)
+            .is_some_and(|Self(code, ..)| *code == 404)
+    }
+
+    pub fn is_unauthorized(error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<Self>()
+            .is_some_and(|Self(code, ..)| *code == 401)
+    }
+
+    pub fn is_forbidden(error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<Self>()
+            .is_some_and(|Self(code, ..)| *code == 403)
+    }
+}
+
+#[derive(Debug)]
+pub struct RedirectError(u16, Url);
+
+impl std::error::Error for RedirectError {}
+
+impl Display for RedirectError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Status code {} redirecting to {}",
+            self.0,
+            logger::redact(&self.1),
+        )
+    }
+}
+
+impl RedirectError {
+    fn location(error: anyhow::Error) -> Result<Url> {
+        error.downcast::<Self>().map(|Self(_, url)| url)
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidContentError {
+    start: String,
+    content_type: String,
+    url: Url,
+}
+
+impl std::error::Error for InvalidContentError {}
+
+impl Display for InvalidContentError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Unexpected content (type: {}, starts with: {}) on {}",
+            self.content_type, self.start, self.url,
+        )
+    }
+}
+
+impl InvalidContentError {
+    pub fn is_invalid_content(error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+            .is_some_and(|e| e.downcast_ref::<Self>().is_some())
+    }
+
+    fn new(start: &[u8], content_type: String, url: Url) -> Self {
+        Self {
+            start: start.iter().take(16).fold(String::new(), |mut s, b| {
+                let _ = write!(s, "{b:02x}");
+                s
+            }),
+            content_type,
+            url,
+        }
+    }
+}
+
+//A body that claimed gzip failed to decode, downloading it uncompressed may work
+#[derive(Debug)]
+pub struct DecodeError(io::Error);
+
+impl std::error::Error for DecodeError {}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Failed to decode gzipped body: {}", self.0)
+    }
+}
+
+impl DecodeError {
+    pub fn is_decode_error(error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+            .is_some_and(|e| e.downcast_ref::<Self>().is_some())
+    }
+
+    //keeps the kind so a truncated body is still retried like any other connection error
+    fn wrap(error: io::Error) -> io::Error {
+        match error.kind() {
+            io::ErrorKind::InvalidInput
+            | io::ErrorKind::InvalidData
+            | io::ErrorKind::UnexpectedEof => io::Error::new(error.kind(), Self(error)),
+            _ => error,
+        }
+    }
+}
+
+//The body was abandoned through the cancel flag of the request, with the bytes written before
+#[derive(Debug)]
+pub struct CancelledError(u64);
+
+impl std::error::Error for CancelledError {}
+
+impl Display for CancelledError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Download cancelled after {} bytes", self.0)
+    }
+}
+
+impl CancelledError {
+    pub fn is_cancelled(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<Self>().is_some()
+    }
+}
+
+//progress of a large or slow response body
+pub struct Progress<'a> {
+    pub context: &'a str,
+    pub received: u64,
+    pub total: Option<u64>,
+}
+
+impl Display for Progress<'_> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        #[allow(clippy::cast_precision_loss)]
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+
+        write!(f, "Downloading {}: {:.1}", self.context, mib(self.received))?;
+        if let Some(total) = self.total {
+            write!(f, "/{:.1}", mib(total))?;
+        }
+
+        f.write_str(" MiB")
     }
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::struct_excessive_bools, reason = "command line switches")]
 pub struct Args {
     force_https: bool,
     force_ipv4: bool,
+    no_content_check: bool,
     retries: u64,
+    api_retries: Option<u64>,
+    playlist_retries: Option<u64>,
+    segment_retries: Option<u64>,
     timeout: Duration,
+    api_rate_limit: u32,
     user_agent: Cow<'static, str>,
+    segment_user_agent: Option<String>,
+    header: Option<String>,
+    segment_header: Option<String>,
+    doh: Option<Url>,
+    doh_fallback: bool,
+    bogus_ips: Vec<IpAddr>,
+    ca_file: Option<String>,
+    insecure_skip_verify: bool,
+    trace_http: Option<PathTemplate>,
 }
 
 impl Default for Args {
@@ -56,9 +242,23 @@ impl Default for Args {
         Self {
             retries: 3,
             timeout: Duration::from_secs(10),
+            api_rate_limit: 30,
             user_agent: constants::USER_AGENT.into(),
             force_https: bool::default(),
             force_ipv4: bool::default(),
+            no_content_check: bool::default(),
+            api_retries: Option::default(),
+            playlist_retries: Option::default(),
+            segment_retries: Option::default(),
+            segment_user_agent: Option::default(),
+            header: Option::default(),
+            segment_header: Option::default(),
+            doh: Option::default(),
+            doh_fallback: bool::default(),
+            bogus_ips: Vec::default(),
+            ca_file: Option::default(),
+            insecure_skip_verify: bool::default(),
+            trace_http: Option::default(),
         }
     }
 }
@@ -67,16 +267,101 @@ impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
         parser.parse_switch(&mut self.force_https, "--force-https")?;
         parser.parse_switch(&mut self.force_ipv4, "--force-ipv4")?;
+        parser.parse_switch(&mut self.no_content_check, "--no-content-check")?;
         parser.parse(&mut self.retries, "--http-retries")?;
+        parser.parse_fn(&mut self.api_retries, "--api-retries", |a| {
+            Ok(Some(a.parse()?))
+        })?;
+        parser.parse_fn(&mut self.playlist_retries, "--playlist-retries", |a| {
+            Ok(Some(a.parse()?))
+        })?;
+        parser.parse_fn(&mut self.segment_retries, "--segment-retries", |a| {
+            Ok(Some(a.parse()?))
+        })?;
         parser.parse_fn(&mut self.timeout, "--http-timeout", |a| {
             Ok(Duration::try_from_secs_f64(a.parse()?)?)
         })?;
+        parser.parse(&mut self.api_rate_limit, "--api-rate-limit")?;
         parser.parse_cow_string(&mut self.user_agent, "--user-agent")?;
+        parser.parse_opt_string(&mut self.segment_user_agent, "--segment-user-agent")?;
+        parser.parse_fn(&mut self.header, "--header", Self::parse_header)?;
+        parser.parse_fn(
+            &mut self.segment_header,
+            "--segment-header",
+            Self::parse_header,
+        )?;
+        parser.parse_fn(&mut self.doh, "--doh", |a| Ok(Some(a.into())))?;
+        parser.parse_switch(&mut self.doh_fallback, "--doh-fallback")?;
+        parser.parse_fn(&mut self.bogus_ips, "--bogus-ips", |a| {
+            a.split(',')
+                .map(|ip| {
+                    ip.trim()
+                        .parse()
+                        .context("Invalid IP address in --bogus-ips")
+                })
+                .collect()
+        })?;
+        parser.parse_opt_string(&mut self.ca_file, "--ca-file")?;
+        parser.parse_switch(&mut self.insecure_skip_verify, "--insecure-skip-verify")?;
+        parser.parse_fn(&mut self.trace_http, "--trace-http", |a| {
+            let dir = PathTemplate::new(a)?;
+            ensure!(
+                !dir.is_per_stream(),
+                "--trace-http is shared by all channels, it can't contain {{channel}} or {{quality}}",
+            );
+
+            Ok(Some(dir))
+        })?;
 
         Ok(())
     }
 }
 
+impl Args {
+    fn parse_header(arg: &str) -> Result<Option<String>> {
+        let (name, value) = arg.split_once(':').context("Header must be NAME:VALUE")?;
+        ensure!(
+            !name.trim().is_empty() && !arg.contains(['\r', '\n']),
+            "Invalid header: {arg}",
+        );
+
+        Ok(Some(format!("{}: {}\r\n", name.trim(), value.trim())))
+    }
+
+    //every class falls back to --http-retries
+    const fn retries(&self, profile: Profile) -> u64 {
+        let retries = match profile {
+            Profile::Api => self.api_retries,
+            Profile::Playlist => self.playlist_retries,
+            Profile::Segment => self.segment_retries,
+        };
+
+        match retries {
+            Some(retries) => retries,
+            None => self.retries,
+        }
+    }
+}
+
+//Requests for segments can look different from API and playlist requests,
+//and every class of request has its own retry budget
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Profile {
+    Api,
+    Playlist,
+    Segment,
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Api => f.write_str("api"),
+            Self::Playlist => f.write_str("playlist"),
+            Self::Segment => f.write_str("segment"),
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum Method {
     Get,
@@ -96,38 +381,123 @@ impl Display for Method {
 pub struct Agent {
     args: Arc<Args>,
     tls_config: Arc<ClientConfig>,
+    resolver: Arc<Resolver>,
+    preconnector: Arc<Preconnector>,
+    rate_limiter: Arc<RateLimiter>,
+    tracer: Option<Arc<Tracer>>,
 }
 
 impl Agent {
     pub fn new(args: Args) -> Result<Self> {
+        let tls_config = if args.insecure_skip_verify {
+            warn!("TLS certificate verification is DISABLED, connections can be intercepted and modified by anyone on the network");
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification::new()))
+                .with_no_client_auth()
+        } else {
+            ClientConfig::builder()
+                .with_root_certificates(Arc::new(Self::load_roots(args.ca_file.as_deref())?))
+                .with_no_client_auth()
+        };
+
+        let tracer = match &args.trace_http {
+            Some(dir) => Some(Arc::new(Tracer::new(dir)?)),
+            None => None,
+        };
+
+        Ok(Self {
+            rate_limiter: Arc::new(RateLimiter::new(args.api_rate_limit)),
+            args: Arc::new(args),
+            tls_config: Arc::new(tls_config),
+            resolver: Arc::default(),
+            preconnector: Arc::default(),
+            tracer,
+        })
+    }
+
+    fn load_roots(ca_file: Option<&str>) -> Result<RootCertStore> {
+        let certs = if let Some(path) = ca_file {
+            CertificateDer::pem_file_iter(path)
+                .and_then(Iterator::collect)
+                .map_err(|e| match e {
+                    pem::Error::Io(e) => anyhow!("Failed to load CA file {path}: {e}"),
+                    e => anyhow!("Failed to parse CA file {path}: {e:?}"),
+                })?
+        } else {
+            rustls_native_certs::load_native_certs()?
+        };
+
         let mut roots = RootCertStore::empty();
-        for cert in rustls_native_certs::load_native_certs()? {
+        for cert in certs {
             //Ignore parsing errors, OS can have broken certs
             if let Err(e) = roots.add(cert) {
                 debug!("Invalid certificate: {e}");
             }
         }
 
-        Ok(Self {
-            args: Arc::new(args),
-            tls_config: Arc::new(
-                ClientConfig::builder()
-                    .with_root_certificates(Arc::new(roots))
-                    .with_no_client_auth(),
-            ),
-        })
+        if roots.is_empty() {
+            match ca_file {
+                Some(path) => bail!("No valid CA certificates found in {path}"),
+                None => bail!(
+                    "No CA certificates found in the system trust store, \
+                     install or mount a CA bundle or use --ca-file"
+                ),
+            }
+        }
+
+        debug!("Loaded {} CA certificates", roots.len());
+        Ok(roots)
+    }
+
+    fn user_agent(&self, profile: Profile) -> &str {
+        match (profile, &self.args.segment_user_agent) {
+            (Profile::Segment, Some(user_agent)) => user_agent,
+            _ => &self.args.user_agent,
+        }
+    }
+
+    fn header(&self, profile: Profile) -> &str {
+        match profile {
+            Profile::Api | Profile::Playlist => self.args.header.as_deref(),
+            Profile::Segment => self.args.segment_header.as_deref(),
+        }
+        .unwrap_or_default()
     }
 
     pub fn text(&self) -> TextRequest {
-        TextRequest::new(self.clone())
+        TextRequest::new(Profile::Api, self.clone())
+    }
+
+    //for polling media playlists
+    pub fn playlist(&self) -> TextRequest {
+        TextRequest::new(Profile::Playlist, self.clone())
     }
 
     pub fn binary<W: Write>(&self, writer: W) -> Request<W> {
-        Request::new(writer, self.clone())
+        Request::new(writer, Profile::Segment, self.clone())
+    }
+
+    //API requests delayed by --api-rate-limit so far and how long they waited in total
+    pub fn rate_limited(&self) -> (u64, Duration) {
+        self.rate_limiter.throttled()
+    }
+
+    //Opens a connection in the background for a request to the URL's host that comes next,
+    //the request uses it instead of connecting then
+    pub fn preconnect(&self, url: &Url) {
+        self.preconnector.start(url, self);
+    }
+
+    //Only connects to the URL's host, with a TLS handshake for HTTPS. Bounded by the
+    //timeout instead of --http-timeout and never retried
+    pub fn probe(&self, url: &Url, timeout: Duration) -> Result<()> {
+        Transport::with_timeout(url, url.host()?, self, timeout)?.handshake()?;
+        Ok(())
     }
 
     pub fn exists(&self, url: &Url) -> Option<TextRequest> {
-        let mut request = self.binary(io::sink());
+        let mut request = Request::new(io::sink(), Profile::Playlist, self.clone());
 
         request
             .call(Method::Get, url)
@@ -147,7 +517,84 @@ impl Connection {
         Self { url, request }
     }
 
-    pub fn text(&mut self) -> Result<&str> {
-        self.request.text(Method::Get, &self.url)
+    //returns the effective URL too, for resolving relative entries after a redirect
+    pub fn text(&mut self) -> Result<(&str, &Url)> {
+        let location = match self.request.text(Method::Get, &self.url) {
+            Ok(_) => None,
+            Err(e) => Some(RedirectError::location(e)?),
+        };
+
+        //follow a single redirect and poll the new location directly from now on
+        if let Some(url) = location {
+            self.update_url(url);
+            self.request.text(Method::Get, &self.url)?;
+        }
+
+        Ok((self.request.as_str(), &self.url))
+    }
+
+    pub fn update_url(&mut self, url: Url) {
+        match (self.url.host(), url.host()) {
+            (Ok(old), Ok(new)) if old != new => info!(
+                "Weaver moved: {} -> {}",
+                Self::short_host(old),
+                Self::short_host(new),
+            ),
+            _ => debug!("Playlist URL changed: {}", logger::redact(&url)),
+        }
+
+        self.url = url;
+    }
+
+    //video-weaver.jfk04.hls.ttvnw.net -> jfk04
+    fn short_host(host: &str) -> &str {
+        host.strip_prefix("video-weaver.")
+            .and_then(|h| h.split('.').next())
+            .unwrap_or(host)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::args::Parser;
+    use scripted::{Reply, ScriptedServer};
+
+    fn agent(args: &[&str]) -> Agent {
+        let mut http_args = Args::default();
+        http_args.parse(&mut Parser::from_args(args)).unwrap();
+        http_args.insecure_skip_verify = true;
+
+        Agent::new(http_args).unwrap()
+    }
+
+    //requests sent for one call when every attempt fails
+    fn attempts(agent: &Agent, profile: Profile) -> usize {
+        let server = ScriptedServer::new((0..10).map(|_| Reply::Cut(Vec::new())));
+        let url = server.url("path");
+        let result = match profile {
+            Profile::Api => agent.text().text(Method::Get, &url).map(drop),
+            Profile::Playlist => agent.playlist().text(Method::Get, &url).map(drop),
+            Profile::Segment => agent.binary(io::sink()).call(Method::Get, &url),
+        };
+        assert!(result.is_err());
+
+        server.requests().len()
+    }
+
+    #[test]
+    fn retries_per_profile() {
+        let agent = agent(&["--api-retries", "0", "--segment-retries", "2"]);
+        assert_eq!(attempts(&agent, Profile::Api), 1);
+        assert_eq!(attempts(&agent, Profile::Playlist), 4);
+        assert_eq!(attempts(&agent, Profile::Segment), 3);
+    }
+
+    #[test]
+    fn retries_fall_back_to_http_retries() {
+        let agent = agent(&["--http-retries", "1", "--playlist-retries", "0"]);
+        assert_eq!(attempts(&agent, Profile::Api), 2);
+        assert_eq!(attempts(&agent, Profile::Playlist), 1);
+        assert_eq!(attempts(&agent, Profile::Segment), 2);
     }
 }
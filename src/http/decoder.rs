@@ -1,21 +1,33 @@
-use std::io::{self, Read};
+use std::io::{self, Chain, Cursor, Read, Take};
 
-use anyhow::{bail, Result};
 use chunked_transfer::Decoder as ChunkDecoder;
 use flate2::read::GzDecoder;
-use log::debug;
+use log::{debug, warn};
+
+use super::DecodeError;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+//the first bytes of a body are read to check them, then read again
+type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
 
 enum Encoding<R: Read> {
     Unencoded(R, u64),
+    CloseDelimited(R),
     Chunked(ChunkDecoder<R>),
-    ChunkedGzip(GzDecoder<ChunkDecoder<R>>),
-    Gzip(GzDecoder<R>),
+    ChunkedGzip(GzDecoder<Peeked<ChunkDecoder<R>>>),
+    Gzip(GzDecoder<Peeked<Take<R>>>),
+
+    //misconfigured servers label plain bodies as gzip
+    MislabeledChunked(Peeked<ChunkDecoder<R>>),
+    Mislabeled(Peeked<Take<R>>),
 }
 
 pub struct Decoder<R: Read> {
     is_gzipped: bool,
     is_chunked: bool,
     content_length: Option<u64>,
+    closes_connection: bool,
 
     kind: Option<Encoding<R>>,
     consumed: u64,
@@ -26,13 +38,18 @@ impl<R: Read> Read for Decoder<R> {
         match self.kind.as_mut().expect("Missing encoding") {
             Encoding::Unencoded(reader, length) => {
                 let consumed = reader.take(*length - self.consumed).read(buf)?;
+                if consumed == 0 && self.consumed < *length && !buf.is_empty() {
+                    //connection closed mid-body
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
                 self.consumed += consumed as u64;
 
                 Ok(consumed)
             }
+            Encoding::CloseDelimited(reader) => reader.read(buf),
             Encoding::Chunked(reader) => reader.read(buf),
             Encoding::ChunkedGzip(reader) => {
-                let consumed = reader.read(buf)?;
+                let consumed = reader.read(buf).map_err(DecodeError::wrap)?;
                 if consumed == 0 {
                     //Gzip decoder doesn't consume trailing bytes in chunk decoder
                     io::copy(&mut reader.get_mut(), &mut io::sink())?;
@@ -40,16 +57,19 @@ impl<R: Read> Read for Decoder<R> {
 
                 Ok(consumed)
             }
-            Encoding::Gzip(reader) => reader.read(buf),
+            Encoding::Gzip(reader) => reader.read(buf).map_err(DecodeError::wrap),
+            Encoding::MislabeledChunked(reader) => reader.read(buf),
+            Encoding::Mislabeled(reader) => reader.read(buf),
         }
     }
 }
 
 impl<R: Read> Decoder<R> {
-    pub fn new(headers: &str) -> Self {
+    pub fn new(headers: &str, code: u16) -> Self {
         let mut content_length = None;
         let mut is_chunked = false;
         let mut is_gzipped = false;
+        let mut closes_connection = false;
 
         for line in headers.lines() {
             let mut split = line.split_whitespace();
@@ -63,42 +83,264 @@ impl<R: Read> Decoder<R> {
                 is_chunked = split.next().is_some_and(|h| h == "chunked");
             } else if key.eq_ignore_ascii_case("content-length:") {
                 content_length = split.next().and_then(|h| h.parse().ok());
+            } else if key.eq_ignore_ascii_case("connection:") {
+                closes_connection = split
+                    .next()
+                    .is_some_and(|h| h.eq_ignore_ascii_case("close"));
             }
         }
 
+        if matches!(code, 100..=199 | 204 | 304) {
+            content_length = Some(0);
+            is_chunked = false;
+            is_gzipped = false;
+        }
+
+        //bodies without a length end when the server closes the connection
+        closes_connection |= !is_chunked && content_length.is_none();
+
         Self {
             is_gzipped,
             is_chunked,
             content_length,
+            closes_connection,
             kind: Option::default(),
             consumed: u64::default(),
         }
     }
 
-    pub fn set_reader(&mut self, reader: R) -> Result<()> {
+    pub const fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    pub const fn is_gzipped(&self) -> bool {
+        self.is_gzipped
+    }
+
+    //the connection can't be reused after the response
+    pub const fn closes_connection(&self) -> bool {
+        self.closes_connection
+    }
+
+    pub fn set_reader(&mut self, reader: R) -> io::Result<()> {
         let kind = match (self.is_chunked, self.is_gzipped) {
-            (true, true) => {
-                debug!("Body is chunked and gzipped");
-                Encoding::ChunkedGzip(GzDecoder::new(ChunkDecoder::new(reader)))
-            }
+            (true, true) => match Self::peek(ChunkDecoder::new(reader))? {
+                (true, reader) => {
+                    debug!("Body is chunked and gzipped");
+                    Encoding::ChunkedGzip(GzDecoder::new(reader))
+                }
+                (false, reader) => Encoding::MislabeledChunked(reader),
+            },
             (true, false) => {
                 debug!("Body is chunked");
                 Encoding::Chunked(ChunkDecoder::new(reader))
             }
             (false, true) => {
-                debug!("Body is gzipped");
-                Encoding::Gzip(GzDecoder::new(reader))
+                let reader = reader.take(self.content_length.unwrap_or(u64::MAX));
+                match Self::peek(reader)? {
+                    (true, reader) => {
+                        debug!("Body is gzipped");
+                        Encoding::Gzip(GzDecoder::new(reader))
+                    }
+                    (false, reader) => Encoding::Mislabeled(reader),
+                }
             }
-            (false, false) => match self.content_length {
-                Some(length) => {
+            (false, false) => {
+                if let Some(length) = self.content_length {
                     debug!("Content length: {length}");
                     Encoding::Unencoded(reader, length)
+                } else {
+                    debug!("Body is delimited by connection close");
+                    Encoding::CloseDelimited(reader)
                 }
-                None => bail!("Failed to resolve encoding of HTTP response"),
-            },
+            }
         };
 
         self.kind = Some(kind);
         Ok(())
     }
+
+    //true if the body starts like gzip, empty bodies aren't worth a warning
+    fn peek<T: Read>(mut reader: T) -> io::Result<(bool, Peeked<T>)> {
+        let mut magic = Vec::with_capacity(GZIP_MAGIC.len());
+        (&mut reader)
+            .take(GZIP_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+
+        let is_gzip = magic.is_empty() || magic == GZIP_MAGIC;
+        if !is_gzip {
+            warn!("Body is labeled as gzip but isn't gzipped, passing it through");
+        }
+
+        Ok((is_gzip, Cursor::new(magic).chain(reader)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    //MPEG-TS starts with the sync byte, not the gzip magic
+    const BODY: &[u8] = b"\x47\x40\x00\x10 transport stream";
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn chunked(body: &[u8]) -> Vec<u8> {
+        let mut chunked = Vec::new();
+        for chunk in body.chunks(7) {
+            write!(chunked, "{:x}\r\n", chunk.len()).unwrap();
+            chunked.extend_from_slice(chunk);
+            chunked.extend_from_slice(b"\r\n");
+        }
+        chunked.extend_from_slice(b"0\r\n\r\n");
+
+        chunked
+    }
+
+    //the decoded body and whatever the decoder left unread on the connection
+    fn decode(headers: &str, raw: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        decode_status(200, headers, raw)
+    }
+
+    fn decode_status(code: u16, headers: &str, raw: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut connection = Cursor::new(raw.to_vec());
+        let mut decoder = Decoder::new(headers, code);
+        decoder.set_reader(&mut connection)?;
+
+        let mut body = Vec::new();
+        decoder.read_to_end(&mut body)?;
+
+        let mut rest = Vec::new();
+        connection.read_to_end(&mut rest)?;
+        Ok((body, rest))
+    }
+
+    #[test]
+    fn gzip_body() {
+        let gzipped = gzip(BODY);
+        let mut raw = gzipped.clone();
+        raw.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
+
+        let headers = format!(
+            "Content-Encoding: gzip\r\nContent-Length: {}\r\n",
+            gzipped.len()
+        );
+        let (body, rest) = decode(&headers, &raw).unwrap();
+        assert_eq!(body, BODY);
+        assert_eq!(rest, b"HTTP/1.1 200 OK\r\n");
+
+        let mut raw = chunked(&gzipped);
+        raw.extend_from_slice(b"next");
+        let headers = "Content-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n";
+        let (body, rest) = decode(headers, &raw).unwrap();
+        assert_eq!(body, BODY);
+        assert_eq!(rest, b"next");
+    }
+
+    #[test]
+    fn mislabeled_gzip_is_passed_through() {
+        let headers = format!(
+            "Content-Encoding: gzip\r\nContent-Length: {}\r\n",
+            BODY.len()
+        );
+        let (body, rest) = decode(&headers, &[BODY, b"next"].concat()).unwrap();
+        assert_eq!(body, BODY);
+        assert_eq!(rest, b"next");
+
+        let mut raw = chunked(BODY);
+        raw.extend_from_slice(b"next");
+        let headers = "Content-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n";
+        let (body, rest) = decode(headers, &raw).unwrap();
+        assert_eq!(body, BODY);
+        assert_eq!(rest, b"next");
+
+        //too short to tell
+        let (body, _) = decode("Content-Encoding: gzip\r\nContent-Length: 1\r\n", b"\x47").unwrap();
+        assert_eq!(body, b"\x47");
+    }
+
+    #[test]
+    fn broken_gzip_is_a_decode_error() {
+        let mut gzipped = gzip(BODY);
+        //the checksum in the trailer
+        let crc = gzipped.len() - 8;
+        gzipped[crc] ^= 0xff;
+
+        let headers = format!(
+            "Content-Encoding: gzip\r\nContent-Length: {}\r\n",
+            gzipped.len()
+        );
+        let error = decode(&headers, &gzipped).unwrap_err();
+        assert!(DecodeError::is_decode_error(&error.into()));
+
+        let truncated = gzip(BODY);
+        let headers = format!(
+            "Content-Encoding: gzip\r\nContent-Length: {}\r\n",
+            truncated.len()
+        );
+        let error = decode(&headers, &truncated[..truncated.len() / 2]).unwrap_err();
+        assert!(DecodeError::is_decode_error(&error.into()));
+    }
+
+    #[test]
+    fn zero_length() {
+        let decoder = Decoder::<&[u8]>::new("Content-Length: 0\r\n", 200);
+        assert_eq!(decoder.content_length(), Some(0));
+        assert!(!decoder.closes_connection());
+
+        let (body, rest) = decode("Content-Length: 0\r\n", b"HTTP/1.1 200 OK\r\n").unwrap();
+        assert!(body.is_empty());
+        assert_eq!(rest, b"HTTP/1.1 200 OK\r\n");
+    }
+
+    #[test]
+    fn close_delimited() {
+        let decoder = Decoder::<&[u8]>::new("Content-Type: video/mp2t\r\n", 200);
+        assert_eq!(decoder.content_length(), None);
+        assert!(decoder.closes_connection());
+
+        let (body, rest) = decode("Content-Type: video/mp2t\r\n", BODY).unwrap();
+        assert_eq!(body, BODY);
+        assert!(rest.is_empty());
+
+        //a length still bounds the body, but the connection goes away after it
+        let headers = format!("Connection: close\r\nContent-Length: {}\r\n", BODY.len());
+        assert!(Decoder::<&[u8]>::new(&headers, 200).closes_connection());
+        let (body, _) = decode(&headers, BODY).unwrap();
+        assert_eq!(body, BODY);
+
+        let chunked = "Transfer-Encoding: chunked\r\n";
+        assert!(!Decoder::<&[u8]>::new(chunked, 200).closes_connection());
+    }
+
+    #[test]
+    fn bodiless_statuses() {
+        //whatever the headers say, these responses end with them
+        let headers =
+            "Content-Length: 10\r\nTransfer-Encoding: chunked\r\nContent-Encoding: gzip\r\n";
+        for code in [100, 101, 204, 304] {
+            let decoder = Decoder::<&[u8]>::new(headers, code);
+            assert_eq!(decoder.content_length(), Some(0), "{code}");
+            assert!(!decoder.is_gzipped());
+            assert!(!decoder.closes_connection());
+
+            let (body, rest) = decode_status(code, headers, b"HTTP/1.1 200 OK\r\n").unwrap();
+            assert!(body.is_empty());
+            assert_eq!(rest, b"HTTP/1.1 200 OK\r\n");
+        }
+    }
+
+    #[test]
+    fn truncated_body() {
+        let error = decode("Content-Length: 10\r\n", b"1234").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
 }
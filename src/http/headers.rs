@@ -0,0 +1,71 @@
+//Header fields of a response, names are matched case-insensitively
+#[derive(Default, Debug, Clone)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    //header block as received, starting with the status line
+    pub fn parse(block: &str) -> Self {
+        Self(
+            block
+                .lines()
+                .skip(1)
+                .filter_map(|l| l.split_once(':'))
+                .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+                .collect(),
+        )
+    }
+
+    //first field with the name, repeated fields aren't combined
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_case_insensitive() {
+        let headers =
+            Headers::parse("HTTP/1.1 200 OK\r\nContent-Type: video/mp2t\r\nx-CACHE: HIT\r\n");
+        assert_eq!(headers.get("content-type"), Some("video/mp2t"));
+        assert_eq!(headers.get("CONTENT-TYPE"), Some("video/mp2t"));
+        assert_eq!(headers.get("X-Cache"), Some("HIT"));
+        assert_eq!(headers.get("content-length"), None);
+    }
+
+    #[test]
+    fn whitespace_is_trimmed() {
+        let headers = Headers::parse(
+            "HTTP/1.1 302 Found\r\nLocation:https://a.b:8080/x?y=z \r\n  Age :\t 5\t\r\nEmpty:\r\n",
+        );
+        assert_eq!(headers.get("location"), Some("https://a.b:8080/x?y=z"));
+        assert_eq!(headers.get("age"), Some("5"));
+        assert_eq!(headers.get("empty"), Some(""));
+    }
+
+    #[test]
+    fn first_duplicate_wins() {
+        let headers =
+            Headers::parse("HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nset-cookie: b=2\r\nVia: 1\r\n");
+        assert_eq!(headers.get("set-cookie"), Some("a=1"));
+        assert_eq!(headers.get("via"), Some("1"));
+    }
+
+    #[test]
+    fn lines_without_colon_are_skipped() {
+        let headers =
+            Headers::parse("HTTP/1.1 200 OK: fine\r\ngarbage\r\n\r\nServer: test\nAge 5\r\n");
+        assert_eq!(headers.get("server"), Some("test"));
+        assert_eq!(headers.get("age"), None);
+        assert_eq!(headers.get("garbage"), None);
+
+        //the status line isn't a field, even with a colon in its reason
+        assert_eq!(headers.get("HTTP/1.1 200 OK"), None);
+        assert!(Headers::parse("").get("server").is_none());
+    }
+}
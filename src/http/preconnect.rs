@@ -0,0 +1,100 @@
+use std::{
+    sync::{Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::debug;
+
+use super::{request::Transport, Agent, Scheme, Url};
+
+//Connections opened on another thread while the current request is still running,
+//a connection that is never used is dropped once it's stale
+#[derive(Default)]
+pub struct Preconnector {
+    pending: Mutex<Vec<Pending>>,
+}
+
+struct Pending {
+    host: String,
+    scheme: Scheme,
+    started: Instant,
+    handle: JoinHandle<Result<(Transport, Duration)>>,
+}
+
+impl Preconnector {
+    //servers close connections that never sent a request sooner than idle keep-alive ones
+    const MAX_AGE: Duration = Duration::from_secs(10);
+
+    pub fn start(&self, url: &Url, agent: &Agent) {
+        let Ok(host) = url.host() else {
+            return;
+        };
+
+        let mut pending = self.lock();
+        pending.retain(|p| p.started.elapsed() < Self::MAX_AGE);
+        if pending
+            .iter()
+            .any(|p| p.host == host && p.scheme == url.scheme)
+        {
+            return;
+        }
+
+        debug!("Pre-connecting to {host}");
+        let (thread_url, thread_host, agent) = (url.clone(), host.to_owned(), agent.clone());
+        let spawned = thread::Builder::new()
+            .name("preconnect".to_owned())
+            .spawn(move || {
+                let start = Instant::now();
+                let mut transport = Transport::new(&thread_url, &thread_host, &agent)?;
+                transport.handshake()?;
+
+                Ok((transport, start.elapsed()))
+            });
+
+        match spawned {
+            Ok(handle) => pending.push(Pending {
+                host: host.to_owned(),
+                scheme: url.scheme,
+                started: Instant::now(),
+                handle,
+            }),
+            Err(e) => debug!("Failed to spawn pre-connect thread: {e}"),
+        }
+    }
+
+    //Waits for a connection that is still being opened, failures fall back to connecting normally
+    pub fn take(&self, url: &Url, host: &str) -> Option<Transport> {
+        let pending = {
+            let mut pending = self.lock();
+            pending.retain(|p| p.started.elapsed() < Self::MAX_AGE);
+            let position = pending
+                .iter()
+                .position(|p| p.host == host && p.scheme == url.scheme)?;
+
+            pending.swap_remove(position)
+        };
+
+        let waiting = Instant::now();
+        match pending.handle.join() {
+            Ok(Ok((transport, connect_time))) => {
+                debug!(
+                    "Using pre-connected socket to {host}, saved {}ms of {}ms",
+                    connect_time.saturating_sub(waiting.elapsed()).as_millis(),
+                    connect_time.as_millis(),
+                );
+                Some(transport)
+            }
+            Ok(Err(e)) => {
+                debug!("Pre-connecting to {host} failed: {e}");
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Pending>> {
+        self.pending.lock().expect("Pre-connect mutex poisoned")
+    }
+}
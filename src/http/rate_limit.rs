@@ -0,0 +1,154 @@
+use std::{
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+//Hosts of the Twitch API, many requests to them from one address or token can get it blocked.
+//Playlists and segments come from other hosts and are never limited.
+const LIMITED_HOSTS: [&str; 3] = ["gql.twitch.tv", "id.twitch.tv", "usher.ttvnw.net"];
+
+//Token bucket shared by every session, requests over the limit are delayed instead of dropped
+pub struct RateLimiter {
+    per_minute: u32,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    //negative while requests are waiting for their turn
+    tokens: f64,
+    updated: Instant,
+
+    throttled: u64,
+    delayed: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32) -> Self {
+        Self {
+            per_minute,
+            bucket: Mutex::new(Bucket {
+                tokens: f64::from(per_minute),
+                updated: Instant::now(),
+                throttled: u64::default(),
+                delayed: Duration::default(),
+            }),
+        }
+    }
+
+    pub fn wait(&self, host: &str) {
+        if self.per_minute == 0 || !LIMITED_HOSTS.contains(&host) {
+            return;
+        }
+
+        let Some(delay) = self.reserve(Instant::now()) else {
+            return;
+        };
+
+        let (throttled, delayed) = self.throttled();
+        info!(
+            "API rate limit of {}/min reached, delaying request to {host} by {:.1}s \
+             ({throttled} delayed, {:.1}s in total)",
+            self.per_minute,
+            delay.as_secs_f64(),
+            delayed.as_secs_f64(),
+        );
+
+        thread::sleep(delay);
+    }
+
+    pub fn throttled(&self) -> (u64, Duration) {
+        let bucket = self.lock();
+        (bucket.throttled, bucket.delayed)
+    }
+
+    //Takes a token at the given time, returns how long the request has to wait for it
+    fn reserve(&self, now: Instant) -> Option<Duration> {
+        let per_second = f64::from(self.per_minute) / 60.0;
+        let mut bucket = self.lock();
+
+        let refilled = now.saturating_duration_since(bucket.updated).as_secs_f64() * per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(f64::from(self.per_minute));
+        bucket.updated = bucket.updated.max(now);
+        bucket.tokens -= 1.0;
+
+        if bucket.tokens >= 0.0 {
+            return None;
+        }
+
+        let delay = Duration::from_secs_f64(-bucket.tokens / per_second);
+        bucket.throttled += 1;
+        bucket.delayed += delay;
+        drop(bucket);
+
+        Some(delay)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Bucket> {
+        self.bucket.lock().expect("Rate limiter mutex poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_delay() {
+        let limiter = RateLimiter::new(30);
+        let now = Instant::now();
+
+        for _ in 0..30 {
+            assert_eq!(limiter.reserve(now), None);
+        }
+
+        //half a token a second, waiting requests queue up behind each other
+        assert_eq!(limiter.reserve(now), Some(Duration::from_secs(2)));
+        assert_eq!(limiter.reserve(now), Some(Duration::from_secs(4)));
+        assert_eq!(limiter.throttled(), (2, Duration::from_secs(6)));
+
+        //a token refilled, but the two waiting requests are owed theirs first
+        let later = now + Duration::from_secs(2);
+        assert_eq!(limiter.reserve(later), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn refill_is_capped() {
+        let limiter = RateLimiter::new(30);
+        let now = Instant::now();
+        for _ in 0..30 {
+            limiter.reserve(now);
+        }
+
+        //idle for ten minutes still allows only one burst
+        let later = now + Duration::from_secs(10 * 60);
+        for _ in 0..30 {
+            assert_eq!(limiter.reserve(later), None);
+        }
+        assert_eq!(limiter.reserve(later), Some(Duration::from_secs(2)));
+
+        //a time before the last request doesn't take tokens back
+        assert_eq!(limiter.reserve(now), Some(Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn only_api_hosts_are_limited() {
+        let limiter = RateLimiter::new(1);
+        for _ in 0..10 {
+            limiter.wait("video-weaver.fra05.hls.ttvnw.net");
+            limiter.wait("proxy.example.com");
+        }
+
+        assert_eq!(limiter.reserve(Instant::now()), None);
+        assert_eq!(limiter.throttled(), (0, Duration::ZERO));
+
+        //0 turns the limit off
+        let limiter = RateLimiter::new(0);
+        for _ in 0..10 {
+            limiter.wait("gql.twitch.tv");
+        }
+        assert_eq!(limiter.throttled(), (0, Duration::ZERO));
+    }
+}
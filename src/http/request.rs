@@ -7,9 +7,13 @@ use std::{
         Read, Write,
     },
     mem,
-    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    net::{SocketAddr, TcpStream},
     str,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, ensure, Context, Result};
@@ -18,114 +22,337 @@ use log::{debug, error};
 use super::{
     decoder::Decoder,
     tls_stream::{TlsStream, TLS_MAX_FRAG_SIZE},
-    Agent, Method, Scheme, StatusError, Url,
+    trace::Tee,
+    Agent, CancelledError, Headers, InvalidContentError, Method, Profile, Progress, RedirectError,
+    Scheme, StatusError, Url,
 };
 
+use crate::logger;
+
+//enough for two MPEG-TS sync bytes
+const CONTENT_CHECK_LEN: usize = 189;
+
+const MAX_HEADERS_LEN: usize = 64 * 1024;
+
+//bodies smaller than this never report progress
+const PROGRESS_MIN_LEN: u64 = 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+//error bodies up to this size are read so the connection can be reused,
+//the start of it is kept for API errors
+const MAX_ERROR_BODY: usize = 64 * 1024;
+const ERROR_SNIPPET_LEN: usize = 300;
+
+//servers close idle keep-alive connections, reconnect instead of timing out on a dead one
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct Request<W: Write> {
     writer: W,
 
     stream: Option<BufReader<Transport>>,
     scheme: Scheme,
     hash: u64,
+    last_used: Option<Instant>,
+    response_started: bool,
+    headers: Headers,
+
+    //body bytes of the current call that reached the writer, an interrupted body resumes from here
+    written: u64,
+    resumable: bool,
+    //drops what the writer got of an interrupted body, a retry then starts over
+    restart: Option<fn(&mut W)>,
+    keep_alive: bool,
+    accept_encoding: &'static str,
 
     decoded_buf: Box<[u8]>,
     retries: u64,
+    retried: u64,
+    context: Option<String>,
+    content_check: Option<fn(&[u8]) -> bool>,
+    progress: Option<fn(&Progress)>,
+    cancel: Option<Arc<AtomicBool>>,
+    profile: Profile,
     agent: Agent,
 }
 
 impl<W: Write> Request<W> {
-    pub fn new(writer: W, agent: Agent) -> Self {
+    pub fn new(writer: W, profile: Profile, agent: Agent) -> Self {
         Self {
             writer,
             decoded_buf: vec![0u8; TLS_MAX_FRAG_SIZE].into_boxed_slice(),
-            retries: agent.args.retries,
+            retries: agent.args.retries(profile),
+            retried: u64::default(),
+            context: Option::default(),
+            content_check: Option::default(),
+            progress: Option::default(),
+            cancel: Option::default(),
+            profile,
             agent,
             stream: Option::default(),
             scheme: Scheme::default(),
             hash: u64::default(),
+            last_used: Option::default(),
+            response_started: bool::default(),
+            headers: Headers::default(),
+            written: u64::default(),
+            resumable: bool::default(),
+            restart: Option::default(),
+            keep_alive: bool::default(),
+            accept_encoding: "gzip",
         }
     }
 
     pub fn into_text_request(self) -> TextRequest {
-        let mut request = self.agent.text();
-        request.0.stream = self.stream;
-        request.0.scheme = self.scheme;
-        request.0.hash = self.hash;
+        let request = TextRequest::new(self.profile, self.agent.clone());
+        TextRequest(self.hand_over(request.0))
+    }
+
+    //keeps using the connection for requests written elsewhere
+    pub fn hand_over<V: Write>(self, mut request: Request<V>) -> Request<V> {
+        request.stream = self.stream;
+        request.scheme = self.scheme;
+        request.hash = self.hash;
+        request.last_used = self.last_used;
 
         request
     }
 
+    pub fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    //drop the connection, the last response may not have been fully read.
+    //A cancellation that came in between calls is meant for the old connection
+    pub fn reset(&mut self) {
+        self.stream = None;
+        if let Some(cancel) = &self.cancel {
+            cancel.store(false, Ordering::Relaxed);
+        }
+    }
+
+    //body bytes of the last call
+    pub const fn written(&self) -> u64 {
+        self.written
+    }
+
+    //retries the last call needed, downloading again uncompressed counts as one
+    pub const fn retried(&self) -> u64 {
+        self.retried
+    }
+
+    //extra information included in retry logs
+    pub fn set_context(&mut self, context: String) {
+        self.context = Some(context);
+    }
+
+    //check the start of every response body before it is written
+    pub fn set_content_check(&mut self, check: fn(&[u8]) -> bool) {
+        if !self.agent.args.no_content_check {
+            self.content_check = Some(check);
+        }
+    }
+
+    //called at most once per second while a large body is downloading slowly
+    pub fn set_progress(&mut self, progress: fn(&Progress)) {
+        self.progress = Some(progress);
+    }
+
+    //overrides the retries of the profile, for requests that must not be sent twice
+    pub fn set_retries(&mut self, retries: u64) {
+        self.retries = retries;
+    }
+
+    //setting the flag stops the body of the current call at the next chunk, or of the next
+    //call if none is running. The flag is cleared once the cancellation took effect
+    pub fn set_cancel(&mut self, cancel: Arc<AtomicBool>) {
+        self.cancel = Some(cancel);
+    }
+
     pub fn call(&mut self, method: Method, url: &Url) -> Result<()> {
+        self.written = 0;
+        self.retried = 0;
         self.call_impl(method, url, None)
     }
 
+    //Downloads a body that failed to decode again without compression,
+    //bytes that already reached the writer are skipped
+    pub fn call_uncompressed(&mut self, method: Method, url: &Url) -> Result<()> {
+        self.accept_encoding = "identity";
+        self.retried += 1;
+        let result = self.call_impl(method, url, None);
+        self.accept_encoding = "gzip";
+
+        result
+    }
+
     fn call_impl(&mut self, method: Method, url: &Url, args: Option<Arguments>) -> Result<()> {
+        //a line break would end the request line early and desync the connection
+        ensure!(
+            !url.contains(['\r', '\n']),
+            "Refusing request for a URL with a line break",
+        );
+
         let host = url.host()?;
+        self.agent.rate_limiter.wait(host);
+
         let hash = Self::hash_host(host);
-        if self.stream.is_none() || self.hash != hash || self.scheme != url.scheme {
-            self.connect(url, host, hash)?;
+        if self.stream.is_some() && self.last_used.is_some_and(|t| t.elapsed() > IDLE_TIMEOUT) {
+            debug!("Dropping idle connection");
+            self.stream = None;
+        }
+
+        let mut reused = self.stream.is_some() && self.hash == hash && self.scheme == url.scheme;
+        if !reused {
+            //a connection opened ahead may have been closed by the server like an idle one
+            reused = self.connect(url, host, hash)?;
         }
 
+        self.resumable = false;
+
         let mut retries = 0;
         loop {
             match self.converse(method, url, args) {
                 Ok(()) => break,
-                Err(e) if retries < self.retries => {
-                    match e.downcast_ref::<io::Error>() {
-                        Some(i) if i.kind() == Other => return Err(e),
-                        Some(_) => (),
-                        _ => return Err(e),
-                    }
+                //server closed an idle keep-alive connection, replay once without using a retry
+                Err(e) if reused && !self.response_started && Self::is_io_error(&e) => {
+                    debug!("Connection closed before response ({e}), reconnecting...");
+                    reused = false;
+
+                    self.connect(url, host, hash)?;
+                }
+                Err(e) if retries < self.retries && Self::is_io_error(&e) => {
+                    retries += 1;
 
                     //Don't log first error
-                    if retries > 0 {
-                        error!("http: {e}, retrying...");
+                    let context = self.context.as_deref().unwrap_or("request");
+                    if retries > 1 {
+                        error!(
+                            "http: {e} ({context}), {} retry {retries}/{}...",
+                            self.profile, self.retries,
+                        );
                     } else {
-                        debug!("got {e}");
+                        debug!(
+                            "got {e} ({context}), {} retry {retries}/{}",
+                            self.profile, self.retries,
+                        );
                     }
-                    retries += 1;
+                    self.retried += 1;
+                    reused = false;
 
                     self.connect(url, host, hash)?;
                 }
-                Err(e) => return Err(e),
+                //the body of an error response was read, the connection is still usable
+                Err(e) if self.keep_alive && e.is::<StatusError>() => {
+                    self.last_used = Some(Instant::now());
+                    return Err(e);
+                }
+                Err(e) => {
+                    //response may not have been fully read, don't reuse the connection
+                    self.stream = None;
+                    return Err(e);
+                }
             }
         }
 
+        if !self.keep_alive {
+            debug!("Server closes the connection after the response");
+            self.stream = None;
+        }
+
+        self.last_used = Some(Instant::now());
         self.writer.flush()?;
         Ok(())
     }
 
     fn converse(&mut self, method: Method, url: &Url, args: Option<Arguments>) -> Result<()> {
+        self.response_started = false;
+        self.headers = Headers::default();
+        if let Some(restart) = self.restart.filter(|_| self.written > 0) {
+            restart(&mut self.writer);
+            self.written = 0;
+        }
+
+        //ranges of gzipped responses don't map to decoded bytes, those are downloaded again
+        let resume = self.written > 0 && self.resumable && args.is_none();
         let mut stream = self.stream.as_mut().expect("Missing stream");
+        let mut trace = self
+            .agent
+            .tracer
+            .as_ref()
+            .and_then(|t| t.start(method, url));
+
+        let mut request = Tee::new(stream.get_mut(), trace.as_mut());
         write!(
-            stream.get_mut(),
+            request,
             "{method} /{path} HTTP/1.1\r\n\
              Host: {host}\r\n\
              User-Agent: {user_agent}\r\n\
              Accept: */*\r\n\
              Accept-Language: en-US\r\n\
-             Accept-Encoding: gzip\r\n\
+             Accept-Encoding: {encoding}\r\n\
              Connection: keep-alive\r\n\
+             {range_head}{range}{range_tail}\
+             {header}\
              {args}",
             path = url.path()?,
-            host = url.host()?,
-            user_agent = &self.agent.args.user_agent,
-            args = args.unwrap_or(format_args!("\r\n")),
+            host = url.host_header()?,
+            user_agent = self.agent.user_agent(self.profile),
+            encoding = self.accept_encoding,
+            range_head = if resume { "Range: bytes=" } else { "" },
+            range = if resume {
+                self.written.to_string()
+            } else {
+                String::new()
+            },
+            range_tail = if resume { "-\r\n" } else { "" },
+            header = self.agent.header(self.profile),
+            args = args.unwrap_or_else(|| format_args!("\r\n")),
         )?;
-        stream.get_mut().flush()?;
+        request.flush()?;
+        if let Some(trace) = &trace {
+            trace.finish_request();
+        }
 
-        let (headers, headers_len) = loop {
+        //headers may arrive across many reads, collect them until the terminator
+        let mut headers_buf = Vec::with_capacity(1024);
+        loop {
             let buf = stream.fill_buf()?;
             if buf.is_empty() {
                 return Err(io::Error::from(UnexpectedEof).into());
             }
+            self.response_started = true;
 
-            if let Some(mut position) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
-                position += 4; //pass \r\n\r\n
-                break (str::from_utf8(&buf[..position])?, position);
+            let searched = headers_buf.len().saturating_sub(3);
+            headers_buf.extend_from_slice(buf);
+            if let Some(position) = headers_buf[searched..]
+                .windows(4)
+                .position(|w| w == b"\r\n\r\n")
+            {
+                let end = searched + position + 4; //pass \r\n\r\n
+                let consumed = buf.len() - (headers_buf.len() - end);
+
+                headers_buf.truncate(end);
+                stream.consume(consumed);
+                break;
             }
-        };
-        debug!("Response:\n{headers}");
+
+            let consumed = buf.len();
+            stream.consume(consumed);
+            ensure!(
+                headers_buf.len() <= MAX_HEADERS_LEN,
+                "Response headers exceeded {}KB",
+                MAX_HEADERS_LEN / 1024,
+            );
+        }
+
+        let headers = str::from_utf8(&headers_buf)?;
+        if logger::is_debug() {
+            debug!("Response:\n{}", logger::redact_dump(headers));
+        }
+        if let Some(trace) = &mut trace {
+            trace.response_headers(headers);
+        }
+        self.headers = Headers::parse(headers);
 
         let code = headers
             .split_whitespace()
@@ -133,35 +360,192 @@ impl<W: Write> Request<W> {
             .and_then(|s| s.parse().ok())
             .context("Failed to parse HTTP status code")?;
 
-        if code != 200 {
-            return Err(StatusError(code, url.clone()).into());
+        if matches!(code, 301 | 302 | 303 | 307 | 308) {
+            if let Some(location) = self.headers.get("location") {
+                return Err(RedirectError(code, url.join(location)?).into());
+            }
         }
 
-        let mut decoder = Decoder::new(headers);
-        stream.consume(headers_len);
-        decoder.set_reader(&mut stream)?;
+        let mut decoder = Decoder::new(headers, code);
+        self.keep_alive = !decoder.closes_connection();
+
+        let resumed = resume && code == 206;
+        if resumed {
+            let start = self
+                .headers
+                .get("content-range")
+                .and_then(|r| r.strip_prefix("bytes "))
+                .and_then(|r| r.split('-').next())
+                .and_then(|s| s.parse().ok());
+
+            if start != Some(self.written) || decoder.is_gzipped() {
+                self.resumable = false;
+                return Err(io::Error::new(
+                    InvalidData,
+                    "Partial response doesn't match the interrupted download",
+                )
+                .into());
+            }
+
+            debug!("Resuming download at byte {}", self.written);
+        } else if !matches!(code, 200 | 204) {
+            decoder.set_reader(Tee::new(&mut stream, trace.as_mut()))?;
+            let (body, drained) = Self::drain_error(&mut decoder, &mut self.decoded_buf);
+            self.keep_alive &= drained;
+
+            //segment and playlist errors stay terse
+            let snippet = (self.profile == Profile::Api)
+                .then(|| Self::error_snippet(&body))
+                .flatten();
+            return Err(StatusError(code, url.clone(), snippet).into());
+        }
+
+        //written bytes are dropped if the body starts over
+        let mut skip = if resumed { 0 } else { self.written };
+        if skip > 0 {
+            debug!("Downloading again, skipping {skip} already written bytes");
+        }
+        self.resumable = !decoder.is_gzipped();
+
+        let mut progress = ProgressReporter::new(
+            self.progress,
+            self.context.as_deref().unwrap_or("response"),
+            decoder.content_length(),
+        );
+        let content_type = self.content_check.filter(|_| !resumed).map(|_| {
+            self.headers
+                .get("content-type")
+                .unwrap_or("<none>")
+                .to_owned()
+        });
+
+        decoder.set_reader(Tee::new(&mut stream, trace.as_mut()))?;
+
+        if let (Some(check), Some(content_type)) = (self.content_check, content_type) {
+            //buffer the start of the body so it can be checked before reaching the writer
+            let mut filled = 0;
+            while filled < CONTENT_CHECK_LEN {
+                let consumed = decoder.read(&mut self.decoded_buf[filled..])?;
+                if consumed == 0 {
+                    break;
+                }
+
+                filled += consumed;
+            }
+
+            let start = &self.decoded_buf[..filled];
+            if !check(start) {
+                return Err(io::Error::new(
+                    InvalidData,
+                    InvalidContentError::new(start, content_type, url.clone()),
+                )
+                .into());
+            }
+
+            Self::write_body(&mut self.writer, start, &mut skip, &mut self.written)?;
+            progress.update(filled);
+        }
 
         loop {
+            //the rest of the body is left unread, the connection is dropped with the error
+            if self
+                .cancel
+                .as_ref()
+                .is_some_and(|c| c.swap(false, Ordering::Relaxed))
+            {
+                return Err(CancelledError(self.written).into());
+            }
+
             let consumed = decoder.read(&mut self.decoded_buf)?;
             if consumed == 0 {
                 break Ok(());
             }
 
-            self.writer.write_all(&self.decoded_buf[..consumed])?;
+            Self::write_body(
+                &mut self.writer,
+                &self.decoded_buf[..consumed],
+                &mut skip,
+                &mut self.written,
+            )?;
+            progress.update(consumed);
         }
     }
 
-    fn connect(&mut self, url: &Url, host: &str, hash: u64) -> Result<()> {
-        debug!("Connecting to {host}...");
+    //Reads the rest of an error response, returns the start of the body and whether
+    //it was read to the end so the connection can be reused
+    fn drain_error(decoder: &mut impl Read, buf: &mut [u8]) -> (Vec<u8>, bool) {
+        let mut body = Vec::new();
+        let mut drained = 0;
+        loop {
+            match decoder.read(buf) {
+                Ok(0) => return (body, true),
+                Ok(consumed) => {
+                    //one byte more than the snippet shows it was cut
+                    let kept = consumed.min((ERROR_SNIPPET_LEN + 1).saturating_sub(body.len()));
+                    body.extend_from_slice(&buf[..kept]);
 
-        self.stream = Some(BufReader::with_capacity(
-            TLS_MAX_FRAG_SIZE,
-            Transport::new(url, host, &self.agent)?,
-        ));
+                    drained += consumed;
+                    if drained > MAX_ERROR_BODY {
+                        return (body, false);
+                    }
+                }
+                Err(_) => return (body, false),
+            }
+        }
+    }
+
+    //the start of an error body as one line of printable text
+    fn error_snippet(body: &[u8]) -> Option<String> {
+        let cut = body.len() > ERROR_SNIPPET_LEN;
+        let snippet = String::from_utf8_lossy(&body[..body.len().min(ERROR_SNIPPET_LEN)])
+            .split(|c: char| c.is_whitespace() || c.is_control())
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        match (snippet.is_empty(), cut) {
+            (true, _) => None,
+            (false, true) => Some(format!("{snippet}...")),
+            (false, false) => Some(snippet),
+        }
+    }
+
+    fn write_body(writer: &mut W, buf: &[u8], skip: &mut u64, written: &mut u64) -> Result<()> {
+        let skipped = buf.len().min(usize::try_from(*skip).unwrap_or(usize::MAX));
+        *skip -= skipped as u64;
+
+        let buf = &buf[skipped..];
+        if !buf.is_empty() {
+            writer.write_all(buf)?;
+            *written += buf.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    //errors caused by the connection rather than by the response
+    fn is_io_error(error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() != Other)
+    }
+
+    //returns whether the connection was opened ahead by Agent::preconnect
+    fn connect(&mut self, url: &Url, host: &str, hash: u64) -> Result<bool> {
+        let preconnected = self.agent.preconnector.take(url, host);
+        let opened_ahead = preconnected.is_some();
+        let transport = if let Some(transport) = preconnected {
+            transport
+        } else {
+            debug!("Connecting to {host} ({} profile)...", self.profile);
+            Transport::new(url, host, &self.agent)?
+        };
+
+        self.stream = Some(BufReader::with_capacity(TLS_MAX_FRAG_SIZE, transport));
         self.scheme = url.scheme;
         self.hash = hash;
 
-        Ok(())
+        Ok(opened_ahead)
     }
 
     fn hash_host(host: &str) -> u64 {
@@ -172,17 +556,85 @@ impl<W: Write> Request<W> {
     }
 }
 
+struct ProgressReporter<'a> {
+    callback: Option<fn(&Progress)>,
+    context: &'a str,
+    total: Option<u64>,
+
+    received: u64,
+    start: Instant,
+    last_report: Option<Instant>,
+}
+
+impl<'a> ProgressReporter<'a> {
+    fn new(callback: Option<fn(&Progress)>, context: &'a str, total: Option<u64>) -> Self {
+        Self {
+            //known small bodies are never slow enough to be worth reporting
+            callback: callback.filter(|_| total.map_or(true, |t| t >= PROGRESS_MIN_LEN)),
+            context,
+            total,
+            received: u64::default(),
+            start: Instant::now(),
+            last_report: Option::default(),
+        }
+    }
+
+    fn update(&mut self, consumed: usize) {
+        let Some(callback) = self.callback else {
+            return;
+        };
+
+        self.received += consumed as u64;
+        if self.received < PROGRESS_MIN_LEN
+            || self.start.elapsed() < PROGRESS_INTERVAL
+            || self
+                .last_report
+                .is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+
+        callback(&Progress {
+            context: self.context,
+            received: self.received,
+            total: self.total,
+        });
+        self.last_report = Some(Instant::now());
+    }
+}
+
 pub struct TextRequest(Request<StringWriter>);
 
 impl TextRequest {
-    pub fn new(agent: Agent) -> Self {
-        Self(Request::new(StringWriter::default(), agent))
+    //a playlist may have changed since an interrupted response, so it isn't resumed
+    pub fn new(profile: Profile, agent: Agent) -> Self {
+        let mut request = Request::new(StringWriter::default(), profile, agent);
+        request.restart = Some(|writer| writer.0.clear());
+
+        Self(request)
+    }
+
+    pub fn reset(&mut self) {
+        self.0.reset();
+    }
+
+    pub fn set_retries(&mut self, retries: u64) {
+        self.0.set_retries(retries);
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0.writer.0
     }
 
     pub fn take(&mut self) -> String {
         mem::take(&mut self.0.writer.0)
     }
 
+    //headers of the last response, empty if none was received
+    pub const fn headers(&self) -> &Headers {
+        &self.0.headers
+    }
+
     pub fn text(&mut self, method: Method, url: &Url) -> Result<&str> {
         self.text_impl(method, url, None)
     }
@@ -193,13 +645,14 @@ impl TextRequest {
 
     fn text_impl(&mut self, method: Method, url: &Url, data: Option<Arguments>) -> Result<&str> {
         self.0.writer.0.clear();
+        self.0.written = 0;
         self.0.call_impl(method, url, data)?;
 
         Ok(&self.0.writer.0)
     }
 }
 
-enum Transport {
+pub enum Transport {
     Tls(Box<TlsStream>),
     Unencrypted(TcpStream),
 }
@@ -234,7 +687,11 @@ impl Write for Transport {
 }
 
 impl Transport {
-    fn new(url: &Url, host: &str, agent: &Agent) -> Result<Self> {
+    pub fn new(url: &Url, host: &str, agent: &Agent) -> Result<Self> {
+        Self::with_timeout(url, host, agent, agent.args.timeout)
+    }
+
+    pub fn with_timeout(url: &Url, host: &str, agent: &Agent, timeout: Duration) -> Result<Self> {
         if agent.args.force_https {
             ensure!(
                 url.scheme == Scheme::Https,
@@ -242,16 +699,19 @@ impl Transport {
             );
         }
 
-        let addrs = (host, url.port()?).to_socket_addrs()?;
+        let addrs = agent
+            .resolver
+            .resolve(host, url.port()?, agent)?
+            .into_iter();
         let sock = if agent.args.force_ipv4 {
-            Self::try_connect(addrs.filter(SocketAddr::is_ipv4), agent.args.timeout)?
+            Self::try_connect(addrs.filter(SocketAddr::is_ipv4), timeout)?
         } else {
-            Self::try_connect(addrs, agent.args.timeout)?
+            Self::try_connect(addrs, timeout)?
         };
 
         sock.set_nodelay(true)?;
-        sock.set_read_timeout(Some(agent.args.timeout))?;
-        sock.set_write_timeout(Some(agent.args.timeout))?;
+        sock.set_read_timeout(Some(timeout))?;
+        sock.set_write_timeout(Some(timeout))?;
 
         match url.scheme {
             Scheme::Http => Ok(Self::Unencrypted(sock)),
@@ -260,6 +720,13 @@ impl Transport {
         }
     }
 
+    pub fn handshake(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tls(stream) => stream.handshake(),
+            Self::Unencrypted(_) => Ok(()),
+        }
+    }
+
     fn try_connect(
         iter: impl Iterator<Item = SocketAddr>,
         timeout: Duration,
@@ -298,3 +765,439 @@ impl Write for StringWriter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, fmt::Write as _, io::Write as _};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::{
+        super::{
+            scripted::{self, Reply, ScriptedServer},
+            Args, DecodeError,
+        },
+        *,
+    };
+
+    //the response is cut off after the first half of its body
+    fn cut(status: &str, headers: &str, body: &[u8]) -> Reply {
+        let mut response = scripted::response(status, headers, body);
+        response.truncate(response.len() - body.len() / 2);
+
+        Reply::Cut(response)
+    }
+
+    fn segment(server: &ScriptedServer) -> Vec<u8> {
+        let mut request = scripted::agent().binary(Vec::new());
+        request.call(Method::Get, &server.url("1.ts")).unwrap();
+        assert_eq!(request.retried(), 1);
+
+        mem::take(request.writer_mut())
+    }
+
+    #[test]
+    fn segment_resumes_with_range() {
+        let server = ScriptedServer::new([
+            cut("200 OK", "", b"0123456789"),
+            Reply::Full(scripted::response(
+                "206 Partial Content",
+                "Content-Range: bytes 5-9/10\r\n",
+                b"56789",
+            )),
+        ]);
+
+        assert_eq!(segment(&server), b"0123456789");
+        assert!(server.requests()[1].contains("Range: bytes=5-\r\n"));
+    }
+
+    #[test]
+    fn segment_skips_written_bytes_of_full_response() {
+        let server = ScriptedServer::new([
+            cut("200 OK", "", b"0123456789"),
+            Reply::Full(scripted::response("200 OK", "", b"0123456789")),
+        ]);
+
+        assert_eq!(segment(&server), b"0123456789");
+    }
+
+    #[test]
+    fn mismatched_range_is_retried() {
+        let server = ScriptedServer::new([
+            cut("200 OK", "", b"0123456789"),
+            Reply::Full(scripted::response(
+                "206 Partial Content",
+                "Content-Range: bytes 2-9/10\r\n",
+                b"23456789",
+            )),
+            Reply::Full(scripted::response("200 OK", "", b"0123456789")),
+        ]);
+
+        let mut request = scripted::agent().binary(Vec::new());
+        request.call(Method::Get, &server.url("1.ts")).unwrap();
+        assert_eq!(request.writer_mut(), b"0123456789");
+        assert!(!server.requests()[2].contains("Range:"));
+    }
+
+    #[test]
+    fn text_starts_over() {
+        let old = b"#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:100\n#EXTINF:2.000,live\nseg100.ts\n";
+        let new = b"#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:101\n#EXTINF:2.000,live\nseg101.ts\n";
+        let server = ScriptedServer::new([
+            cut("200 OK", "", old),
+            Reply::Full(scripted::response("200 OK", "", new)),
+        ]);
+
+        let mut request = scripted::agent().playlist();
+        let text = request.text(Method::Get, &server.url("v/0.m3u8")).unwrap();
+        assert_eq!(text.as_bytes(), new);
+        assert!(!server.requests()[1].contains("Range:"));
+    }
+
+    #[test]
+    fn undecodable_body_is_downloaded_uncompressed() {
+        let body = b"0123456789";
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(body).unwrap();
+        let mut gzipped = encoder.finish().unwrap();
+        //the checksum in the trailer, the body decodes before it's checked
+        let crc = gzipped.len() - 8;
+        gzipped[crc] ^= 0xff;
+
+        let server = ScriptedServer::new([
+            Reply::Full(scripted::response(
+                "200 OK",
+                "Content-Encoding: gzip\r\n",
+                &gzipped,
+            )),
+            Reply::Full(scripted::response("200 OK", "", body)),
+        ]);
+
+        //not retried as a connection error first
+        let mut request = agent(0).binary(Vec::new());
+        let url = server.url("1.ts");
+        let error = request.call(Method::Get, &url).unwrap_err();
+        assert!(DecodeError::is_decode_error(&error));
+
+        request.call_uncompressed(Method::Get, &url).unwrap();
+        assert_eq!(request.writer_mut(), body);
+        assert_eq!(request.retried(), 1);
+
+        let requests = server.requests();
+        assert!(requests[0].contains("Accept-Encoding: gzip\r\n"));
+        assert!(requests[1].contains("Accept-Encoding: identity\r\n"));
+    }
+
+    #[test]
+    fn api_error_body_is_shown_and_drained() {
+        let error = br#"{"error":"Bad Request","status":400,"message":"The \"Client-ID\" header is invalid"}"#;
+        let server = ScriptedServer::new([
+            Reply::Full(scripted::response(
+                "400 Bad Request",
+                "Content-Type: application/json\r\n",
+                error,
+            )),
+            Reply::Full(scripted::response("200 OK", "", b"ok")),
+        ]);
+
+        let mut request = scripted::agent().text();
+        let url = server.url("gql");
+        let message = request.text(Method::Get, &url).unwrap_err().to_string();
+        assert_eq!(
+            message,
+            format!(
+                "Status code 400 on {url}: {}",
+                String::from_utf8_lossy(error)
+            ),
+        );
+
+        //the body was read, so the connection is still in sync
+        assert_eq!(request.text(Method::Get, &url).unwrap(), "ok");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn chunked_error_body_is_drained() {
+        let server = ScriptedServer::new([
+            Reply::Full(
+                b"HTTP/1.1 403 Forbidden\r\nTransfer-Encoding: chunked\r\n\r\n\
+                  7\r\ndenied \r\n6\r\nregion\r\n0\r\n\r\n"
+                    .to_vec(),
+            ),
+            Reply::Full(scripted::response("200 OK", "", b"ok")),
+        ]);
+
+        let mut request = scripted::agent().text();
+        let url = server.url("gql");
+        let error = request.text(Method::Get, &url).unwrap_err();
+        assert!(error.to_string().ends_with(": denied region"));
+        assert!(StatusError::is_forbidden(&error));
+
+        assert_eq!(request.text(Method::Get, &url).unwrap(), "ok");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn segment_errors_are_terse() {
+        let server = ScriptedServer::new([
+            Reply::Full(scripted::response(
+                "404 Not Found",
+                "",
+                b"<html><body>Not Found</body></html>",
+            )),
+            Reply::Full(scripted::response("200 OK", "", b"segment")),
+        ]);
+
+        let mut request = scripted::agent().binary(Vec::new());
+        let url = server.url("1.ts");
+        let error = request.call(Method::Get, &url).unwrap_err();
+        assert_eq!(error.to_string(), format!("Status code 404 on {url}"));
+        assert!(StatusError::is_not_found(&error));
+
+        request.call(Method::Get, &url).unwrap();
+        assert_eq!(request.writer_mut(), b"segment");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn error_snippets() {
+        let snippet = Request::<Vec<u8>>::error_snippet;
+
+        assert_eq!(
+            snippet(b"{\n  \"error\": \"invalid client\"\r\n}\n").unwrap(),
+            r#"{ "error": "invalid client" }"#,
+        );
+        assert!(snippet(b"").is_none());
+        assert!(snippet(b" \r\n\t").is_none());
+
+        let long = "word ".repeat(ERROR_SNIPPET_LEN);
+        let cut = snippet(long.as_bytes()).unwrap();
+        assert!(cut.ends_with("word..."));
+        assert!(cut.len() <= ERROR_SNIPPET_LEN + 3);
+    }
+
+    #[test]
+    fn line_breaks_are_refused() {
+        let server = ScriptedServer::new([]);
+
+        let url = Url::from(format!("{}\r\nX-Injected: 1", server.url("1.ts")));
+        let error = scripted::agent()
+            .binary(Vec::new())
+            .call(Method::Get, &url)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Refusing request for a URL with a line break"
+        );
+        assert_eq!(server.connections(), 0);
+    }
+
+    #[test]
+    fn close_delimited_body_reconnects() {
+        let server = ScriptedServer::new([
+            Reply::Cut(b"HTTP/1.1 200 OK\r\n\r\nuntil the connection closes".to_vec()),
+            Reply::Full(scripted::response("200 OK", "", b"ok")),
+        ]);
+
+        let mut request = scripted::agent().text();
+        let url = server.url("playlist.m3u8");
+        assert_eq!(
+            request.text(Method::Get, &url).unwrap(),
+            "until the connection closes"
+        );
+        assert_eq!(request.text(Method::Get, &url).unwrap(), "ok");
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[test]
+    fn bodiless_response_keeps_connection() {
+        let server = ScriptedServer::new([
+            Reply::Full(b"HTTP/1.1 204 No Content\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec()),
+            Reply::Full(scripted::response("200 OK", "", b"ok")),
+        ]);
+
+        let mut request = scripted::agent().text();
+        let url = server.url("playlist.m3u8");
+        assert!(request.text(Method::Get, &url).unwrap().is_empty());
+        assert_eq!(request.text(Method::Get, &url).unwrap(), "ok");
+        assert_eq!(server.connections(), 1);
+    }
+
+    //Set-Cookie headers of about len bytes, like the large ones some servers send
+    fn large_headers(len: usize) -> String {
+        (0..len / 100).fold(String::new(), |mut headers, i| {
+            let _ = write!(headers, "Set-Cookie: cookie{i:04}={i:0>78}\r\n");
+            headers
+        })
+    }
+
+    #[test]
+    fn large_headers_in_small_reads() {
+        let headers = large_headers(10 * 1024);
+        let server = ScriptedServer::new([
+            Reply::Trickled(scripted::response("200 OK", &headers, b"body")),
+            Reply::Full(scripted::response("200 OK", "", b"ok")),
+        ]);
+
+        let mut request = scripted::agent().text();
+        let url = server.url("playlist.m3u8");
+        assert_eq!(request.text(Method::Get, &url).unwrap(), "body");
+        assert_eq!(request.headers().get("set-cookie").unwrap().len(), 89);
+
+        //nothing of the next response was taken for the headers
+        assert_eq!(request.text(Method::Get, &url).unwrap(), "ok");
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn headers_are_limited() {
+        let headers = large_headers(MAX_HEADERS_LEN + 1024);
+        let server =
+            ScriptedServer::new([Reply::Full(scripted::response("200 OK", &headers, b"body"))]);
+
+        let error = agent(0)
+            .text()
+            .text(Method::Get, &server.url("playlist.m3u8"))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Response headers exceeded 64KB");
+    }
+
+    fn agent(retries: u64) -> Agent {
+        Agent::new(Args {
+            retries,
+            insecure_skip_verify: true,
+            ..Args::default()
+        })
+        .unwrap()
+    }
+
+    fn get(request: &mut Request<Vec<u8>>, url: &Url) -> Result<Vec<u8>> {
+        request.call(Method::Get, url)?;
+        Ok(mem::take(request.writer_mut()))
+    }
+
+    #[test]
+    fn stale_connection_is_replayed_for_free() {
+        let server = ScriptedServer::new([
+            //closed by the server right after the response, like an idle keep-alive connection
+            Reply::Cut(scripted::response("200 OK", "", b"first")),
+            Reply::Full(scripted::response("200 OK", "", b"second")),
+        ]);
+
+        let mut request = agent(0).binary(Vec::new());
+        let url = server.url("1.ts");
+        assert_eq!(get(&mut request, &url).unwrap(), b"first");
+        assert_eq!(get(&mut request, &url).unwrap(), b"second");
+        assert_eq!(request.retried(), 0);
+        assert_eq!(server.connections(), 2);
+    }
+
+    #[test]
+    fn replay_is_only_free_once() {
+        let server = ScriptedServer::new([
+            Reply::Cut(scripted::response("200 OK", "", b"first")),
+            Reply::Cut(Vec::new()),
+            Reply::Full(scripted::response("200 OK", "", b"second")),
+        ]);
+
+        //the new connection closing too isn't a stale one
+        let mut request = agent(1).binary(Vec::new());
+        let url = server.url("1.ts");
+        assert_eq!(get(&mut request, &url).unwrap(), b"first");
+        assert_eq!(get(&mut request, &url).unwrap(), b"second");
+        assert_eq!(request.retried(), 1);
+        assert_eq!(server.connections(), 3);
+    }
+
+    #[test]
+    fn mid_response_failure_uses_a_retry() {
+        let truncated = || {
+            let mut response = scripted::response("200 OK", "", b"0123456789");
+            response.truncate(response.len() - 5);
+            Reply::Cut(response)
+        };
+        let server = ScriptedServer::new([
+            Reply::Full(scripted::response("200 OK", "", b"first")),
+            truncated(),
+            Reply::Full(scripted::response("200 OK", "", b"0123456789")),
+            truncated(),
+        ]);
+
+        let mut request = agent(1).binary(Vec::new());
+        let url = server.url("1.ts");
+        assert_eq!(get(&mut request, &url).unwrap(), b"first");
+        assert_eq!(get(&mut request, &url).unwrap(), b"0123456789");
+        assert_eq!(request.retried(), 1);
+        //the server serves one connection at a time
+        drop(request);
+
+        let mut request = agent(0).binary(Vec::new());
+        assert!(get(&mut request, &url).is_err());
+    }
+
+    thread_local! {
+        static REPORTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn report(progress: &Progress) {
+        REPORTS.with_borrow_mut(|r| r.push(progress.to_string()));
+    }
+
+    fn take_reports() -> Vec<String> {
+        REPORTS.with_borrow_mut(mem::take)
+    }
+
+    //as if the download started and was last reported that long ago
+    fn rewind(reporter: &mut ProgressReporter, secs: u64) {
+        let ago = Duration::from_secs(secs);
+        reporter.start = reporter.start.checked_sub(ago).unwrap();
+        reporter.last_report = reporter.last_report.and_then(|t| t.checked_sub(ago));
+    }
+
+    #[test]
+    fn progress_cadence() {
+        const MIB: usize = 1024 * 1024;
+        let total = Some(6 * MIB as u64 + MIB as u64 / 10);
+        let mut reporter = ProgressReporter::new(Some(report), "init segment", total);
+
+        //fast so far
+        reporter.update(2 * MIB);
+        assert!(take_reports().is_empty());
+
+        rewind(&mut reporter, 2);
+        reporter.update(MIB + MIB / 5);
+        assert_eq!(take_reports(), ["Downloading init segment: 3.2/6.1 MiB"]);
+
+        //at most once per interval
+        reporter.update(MIB);
+        assert!(take_reports().is_empty());
+        rewind(&mut reporter, 1);
+        reporter.update(MIB);
+        assert_eq!(take_reports(), ["Downloading init segment: 5.2/6.1 MiB"]);
+
+        //small bodies never report, unknown lengths do once they're large
+        let mut small = ProgressReporter::new(Some(report), "segment", Some(MIB as u64 - 1));
+        rewind(&mut small, 2);
+        small.update(MIB - 1);
+        assert!(take_reports().is_empty());
+
+        let mut unknown = ProgressReporter::new(Some(report), "segment", None);
+        rewind(&mut unknown, 2);
+        unknown.update(MIB / 2);
+        assert!(take_reports().is_empty());
+        unknown.update(MIB);
+        assert_eq!(take_reports(), ["Downloading segment: 1.5 MiB"]);
+    }
+
+    #[test]
+    fn fast_download_is_silent() {
+        let body = vec![0x47; 2 * 1024 * 1024];
+        let server = ScriptedServer::new([Reply::Full(scripted::response("200 OK", "", &body))]);
+
+        let mut request = scripted::agent().binary(Vec::new());
+        request.set_progress(report);
+        request.set_context("init segment".to_owned());
+        assert_eq!(get(&mut request, &server.url("init.mp4")).unwrap(), body);
+        assert!(take_reports().is_empty());
+    }
+}
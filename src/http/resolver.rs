@@ -0,0 +1,137 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{ensure, Context, Result};
+use log::debug;
+
+use super::{Agent, Method, Url};
+use crate::json;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const MAX_TTL: u64 = 60 * 60;
+
+//Resolves host names with the system resolver or a DNS over HTTPS endpoint
+#[derive(Default)]
+pub struct Resolver {
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl Resolver {
+    pub fn resolve(&self, host: &str, port: u16, agent: &Agent) -> Result<Vec<SocketAddr>> {
+        let Some(doh) = &agent.args.doh else {
+            return Self::system(host, port);
+        };
+
+        //the DoH server itself has to be resolved by the system to avoid recursion
+        if host.parse::<IpAddr>().is_ok() || doh.host().is_ok_and(|h| h == host) {
+            return Self::system(host, port);
+        }
+
+        let system = if agent.args.doh_fallback {
+            match Self::system(host, port) {
+                Ok(addrs) if !addrs.iter().any(|a| agent.args.bogus_ips.contains(&a.ip())) => {
+                    debug!("Resolved {host} using system resolver");
+                    return Ok(addrs);
+                }
+                Ok(addrs) => {
+                    debug!("System resolver returned a bogus address for {host}");
+                    Ok(addrs)
+                }
+                Err(e) => {
+                    debug!("System resolver failed for {host}: {e}");
+                    Err(e)
+                }
+            }
+        } else {
+            Err(io::Error::other("System resolver not used").into())
+        };
+
+        match self.doh(host, doh, agent) {
+            Ok(addrs) => {
+                debug!("Resolved {host} using DoH");
+                Ok(addrs
+                    .into_iter()
+                    .map(|a| SocketAddr::new(a, port))
+                    .collect())
+            }
+            Err(e) => {
+                debug!("DoH failed for {host}: {e}, using system resolver");
+                system.or_else(|_| Self::system(host, port))
+            }
+        }
+    }
+
+    fn system(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok((host, port)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve {host}"))?
+            .collect())
+    }
+
+    fn doh(&self, host: &str, doh: &Url, agent: &Agent) -> Result<Vec<IpAddr>> {
+        if let Some((addrs, expiry)) = self
+            .cache
+            .lock()
+            .expect("Resolver cache poisoned")
+            .get(host)
+        {
+            if *expiry > Instant::now() {
+                return Ok(addrs.clone());
+            }
+        }
+
+        let mut request = agent.text();
+        let mut addrs = Vec::new();
+        let mut ttl = MAX_TTL;
+        for kind in [TYPE_A, TYPE_AAAA] {
+            if kind == TYPE_AAAA && agent.args.force_ipv4 {
+                continue;
+            }
+
+            let separator = if doh.contains('?') { '&' } else { '?' };
+            let url: Url =
+                format!("{doh}{separator}name={host}&type={kind}&ct=application/dns-json").into();
+
+            let response = request.text(Method::Get, &url)?;
+            ensure!(
+                json::field(response, "Status") == Some("0"),
+                "DoH query for {host} failed",
+            );
+
+            let Some(answers) = json::field(response, "Answer") else {
+                continue;
+            };
+
+            for answer in answers.split('{').skip(1) {
+                if json::field(answer, "type").and_then(|t| t.parse().ok()) != Some(kind) {
+                    continue;
+                }
+
+                if let Some(addr) = json::field(answer, "data").and_then(|d| d.parse().ok()) {
+                    addrs.push(addr);
+                    if let Some(answer_ttl) =
+                        json::field(answer, "TTL").and_then(|t| t.parse().ok())
+                    {
+                        ttl = ttl.min(answer_ttl);
+                    }
+                }
+            }
+        }
+
+        ensure!(!addrs.is_empty(), "No addresses found for {host}");
+        debug!("DoH addresses for {host} (TTL {ttl}s): {addrs:?}");
+
+        self.cache.lock().expect("Resolver cache poisoned").insert(
+            host.to_owned(),
+            (addrs.clone(), Instant::now() + Duration::from_secs(ttl)),
+        );
+
+        Ok(addrs)
+    }
+}
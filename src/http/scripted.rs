@@ -0,0 +1,158 @@
+//HTTP server for tests, every request gets the next scripted reply no matter its path.
+//Connections are served one after another, like a request uses them
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{Agent, Args, Url};
+
+pub enum Reply {
+    //sent in full, the connection stays open
+    Full(Vec<u8>),
+    //sent, then the connection is closed
+    Cut(Vec<u8>),
+    //sent in full after a while, so the client can queue more work meanwhile
+    Delayed(Duration, Vec<u8>),
+    //sent one byte at a time, so the client reads it in many small pieces
+    Trickled(Vec<u8>),
+}
+
+pub struct ScriptedServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl ScriptedServer {
+    pub fn new(script: impl IntoIterator<Item = Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind scripted server");
+        let addr = listener
+            .local_addr()
+            .expect("Missing scripted server address");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let connections = Arc::new(AtomicUsize::new(0));
+
+        let mut script = script.into_iter().collect::<VecDeque<_>>();
+        thread::spawn({
+            let requests = requests.clone();
+            let connections = connections.clone();
+            move || {
+                for connection in listener.incoming() {
+                    let Ok(mut connection) = connection else {
+                        return;
+                    };
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    let mut reader = BufReader::new(connection.try_clone().unwrap());
+
+                    while let Some(request) = read_request(&mut reader) {
+                        requests.lock().unwrap().push(request);
+                        match script.pop_front() {
+                            Some(Reply::Full(reply)) => {
+                                if connection.write_all(&reply).is_err() {
+                                    break;
+                                }
+                            }
+                            Some(Reply::Delayed(delay, reply)) => {
+                                thread::sleep(delay);
+                                if connection.write_all(&reply).is_err() {
+                                    break;
+                                }
+                            }
+                            Some(Reply::Trickled(reply)) => {
+                                let _ = connection.set_nodelay(true);
+                                if reply
+                                    .chunks(1)
+                                    .any(|byte| connection.write_all(byte).is_err())
+                                {
+                                    break;
+                                }
+                            }
+                            Some(Reply::Cut(reply)) => {
+                                let _ = connection.write_all(&reply);
+                                break;
+                            }
+                            None => return,
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            connections,
+        }
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        format!("http://{}/{path}", self.addr).into()
+    }
+
+    //requests received so far, the head and body of each
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    //connections accepted so far, a reused connection isn't counted again
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+}
+
+//A complete response with a Content-Length matching the body
+pub fn response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\n\r\n",
+        body.len(),
+    )
+    .into_bytes();
+    response.extend_from_slice(body);
+
+    response
+}
+
+//plain HTTP only, the roots aren't needed
+pub fn agent() -> Agent {
+    Agent::new(Args {
+        insecure_skip_verify: true,
+        ..Args::default()
+    })
+    .unwrap()
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<String> {
+    let mut head = String::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        if line.trim().is_empty() {
+            head.push_str(&line);
+            break;
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or_default();
+            }
+        }
+        head.push_str(&line);
+    }
+
+    let mut body = Vec::new();
+    reader.take(content_length).read_to_end(&mut body).ok()?;
+    head.push_str(&String::from_utf8_lossy(&body));
+
+    Some(head)
+}
@@ -9,8 +9,14 @@ use std::{
 
 use anyhow::Result;
 use rustls::{
-    client::{ClientConnectionData, UnbufferedClientConnection},
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        ClientConnectionData, UnbufferedClientConnection,
+    },
+    crypto::{self, ring, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, ServerName, UnixTime},
     unbuffered::{ConnectionState, EncodeTlsData, UnbufferedStatus, WriteTraffic},
+    DigitallySignedStruct, SignatureScheme,
 };
 
 use super::Agent;
@@ -74,6 +80,41 @@ impl TlsStream {
         })
     }
 
+    //Completes the handshake without sending a request, otherwise it's done by the first write
+    pub fn handshake(&mut self) -> io::Result<()> {
+        loop {
+            let UnbufferedStatus { discard, state } =
+                self.conn.process_tls_records(self.incoming.used_mut());
+
+            let done = match state.map_err(|e| io::Error::new(InvalidData, e))? {
+                ConnectionState::WriteTraffic(_) | ConnectionState::ReadTraffic(_) => true,
+                ConnectionState::TransmitTlsData(state) => {
+                    self.outgoing.send(&mut self.sock)?;
+                    state.done();
+                    false
+                }
+                ConnectionState::EncodeTlsData(state) => {
+                    self.outgoing.encode(state)?;
+                    false
+                }
+                ConnectionState::BlockedHandshake => {
+                    self.incoming.recv(&mut self.sock)?;
+                    false
+                }
+                ConnectionState::Closed => return Err(io::Error::from(ConnectionReset)),
+                _ => unreachable!(),
+            };
+
+            if discard != 0 {
+                self.incoming.discard(discard);
+            }
+
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
     fn converse(
         &mut self,
         read: Option<&[u8]>,
@@ -210,3 +251,48 @@ impl State {
         self.used -= size;
     }
 }
+
+//Accepts any server certificate for --insecure-skip-verify, handshake signatures are still checked
+#[derive(Debug)]
+pub struct NoVerification(WebPkiSupportedAlgorithms);
+
+impl NoVerification {
+    pub fn new() -> Self {
+        Self(ring::default_provider().signature_verification_algorithms)
+    }
+}
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer,
+        _intermediates: &[CertificateDer],
+        _server_name: &ServerName,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, warn};
+
+use super::{Method, Url};
+use crate::{
+    logger,
+    path_template::{Fields, PathTemplate},
+};
+
+//Raw request and response dumps for --trace-http, one set of files per request on the wire
+pub struct Tracer {
+    dir: PathBuf,
+    sequence: AtomicU64,
+    written: AtomicU64,
+    stopped: AtomicBool,
+}
+
+impl Tracer {
+    const MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+    pub fn new(template: &PathTemplate) -> Result<Self> {
+        let dir = template.expand(&Fields::unbound());
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create trace directory {}", dir.display()))?;
+        warn!(
+            "Tracing HTTP traffic to {}, dumps can contain private data",
+            dir.display(),
+        );
+
+        Ok(Self {
+            dir,
+            sequence: AtomicU64::default(),
+            written: AtomicU64::default(),
+            stopped: AtomicBool::default(),
+        })
+    }
+
+    pub fn start(&self, method: Method, url: &Url) -> Option<Trace<'_>> {
+        if self.stopped.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        //the number is logged so the dumps can be matched with the debug log
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!("HTTP trace {sequence:06}: {method} {}", logger::redact(url));
+
+        Some(Trace {
+            tracer: self,
+            prefix: format!("{sequence:06}"),
+            request: Vec::with_capacity(1024),
+            body: None,
+        })
+    }
+
+    fn reserve(&self, len: usize) -> bool {
+        let written = self.written.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+        if written > Self::MAX_SIZE {
+            if !self.stopped.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "HTTP trace reached {} MiB, tracing stopped",
+                    Self::MAX_SIZE / 1024 / 1024
+                );
+            }
+
+            return false;
+        }
+
+        true
+    }
+
+    fn stop(&self, error: &io::Error) {
+        if !self.stopped.swap(true, Ordering::Relaxed) {
+            error!("Failed to write HTTP trace, tracing stopped: {error}");
+        }
+    }
+}
+
+pub struct Trace<'a> {
+    tracer: &'a Tracer,
+    prefix: String,
+    request: Vec<u8>,
+    body: Option<File>,
+}
+
+impl Trace<'_> {
+    pub fn finish_request(&self) {
+        let request = String::from_utf8_lossy(&self.request);
+        self.write_file("request.txt", logger::redact(&request).as_bytes());
+    }
+
+    //the body file is only created once headers were received
+    pub fn response_headers(&mut self, headers: &str) {
+        self.write_file("response.headers", logger::redact(headers).as_bytes());
+        match File::create(self.path("response.bin")) {
+            Ok(file) => self.body = Some(file),
+            Err(e) => self.tracer.stop(&e),
+        }
+    }
+
+    fn response_body(&mut self, buf: &[u8]) {
+        let Some(body) = &mut self.body else {
+            return;
+        };
+
+        if !self.tracer.reserve(buf.len()) {
+            self.body = None;
+            return;
+        }
+
+        if let Err(e) = body.write_all(buf) {
+            self.tracer.stop(&e);
+            self.body = None;
+        }
+    }
+
+    fn write_file(&self, name: &str, contents: &[u8]) {
+        if !self.tracer.reserve(contents.len()) {
+            return;
+        }
+
+        if let Err(e) = fs::write(self.path(name), contents) {
+            self.tracer.stop(&e);
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.tracer.dir.join(format!("{}-{name}", self.prefix))
+    }
+}
+
+//Passes reads and writes through, copying them to the trace if there is one
+pub struct Tee<'a, 'b, T> {
+    inner: T,
+    trace: Option<&'a mut Trace<'b>>,
+}
+
+impl<T: Read> Read for Tee<'_, '_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let consumed = self.inner.read(buf)?;
+        if let Some(trace) = &mut self.trace {
+            trace.response_body(&buf[..consumed]);
+        }
+
+        Ok(consumed)
+    }
+}
+
+impl<T: Write> Write for Tee<'_, '_, T> {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        unreachable!();
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if let Some(trace) = &mut self.trace {
+            trace.request.extend_from_slice(buf);
+        }
+
+        self.inner.write_all(buf)
+    }
+}
+
+impl<'a, 'b, T> Tee<'a, 'b, T> {
+    pub const fn new(inner: T, trace: Option<&'a mut Trace<'b>>) -> Self {
+        Self { inner, trace }
+    }
+}
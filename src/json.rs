@@ -0,0 +1,151 @@
+use std::fmt::Write;
+
+//Quoted JSON string, for the JSON written by hand by the webhook, status file and summary
+pub fn string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str(r#"\""#),
+            '\\' => quoted.push_str(r"\\"),
+            '\n' => quoted.push_str(r"\n"),
+            '\r' => quoted.push_str(r"\r"),
+            '\t' => quoted.push_str(r"\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, r"\u{:04x}", u32::from(c));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+pub fn optional(value: Option<&str>) -> String {
+    value.map_or_else(|| "null".to_owned(), string)
+}
+
+//Value of the first field named key at any depth, for the JSON read by hand from DoH and
+//access tokens. Strings are returned without quotes and with escapes kept, objects and arrays whole
+pub fn field<'a>(json: &'a str, key: &str) -> Option<&'a str> {
+    let mut start = 0;
+    while let Some(quote) = json[start..].find('"') {
+        let quote = start + quote;
+        let end = string_end(json, quote)?;
+        let rest = json[end + 1..].trim_start();
+        if let Some(value) = rest.strip_prefix(':') {
+            if &json[quote + 1..end] == key {
+                return value_of(value.trim_start());
+            }
+        }
+
+        start = end + 1;
+    }
+
+    None
+}
+
+fn value_of(value: &str) -> Option<&str> {
+    match value.chars().next()? {
+        '"' => Some(&value[1..string_end(value, 0)?]),
+        '{' | '[' => {
+            let mut depth = 0;
+            let mut i = 0;
+            while i < value.len() {
+                match value.as_bytes()[i] {
+                    b'"' => i = string_end(value, i)?,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Some(&value[..=i]);
+                        }
+                    }
+                    _ => (),
+                }
+                i += 1;
+            }
+
+            None
+        }
+        _ => Some(value.split([',', '}', ']']).next()?.trim()),
+    }
+}
+
+//index of the quote closing the string opened at start
+fn string_end(json: &str, start: usize) -> Option<usize> {
+    let mut escaped = false;
+    for (i, b) in json.bytes().enumerate().skip(start + 1) {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'"' => return Some(i),
+            _ => (),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        assert_eq!(string("channel"), r#""channel""#);
+        assert_eq!(string(r#"a"b\c"#), r#""a\"b\\c""#);
+        assert_eq!(string("line\nbreak\ttab"), r#""line\nbreak\ttab""#);
+        assert_eq!(string("\u{1}\u{7f}"), r#""\u0001\u007f""#);
+        assert_eq!(string("ünïcode"), r#""ünïcode""#);
+        assert_eq!(optional(None), "null");
+    }
+
+    #[test]
+    fn fields() {
+        let json = r#"{"Status":0,"TC":false,"name":"host","ttl": 30 ,"user_id":null}"#;
+        assert_eq!(field(json, "Status"), Some("0"));
+        assert_eq!(field(json, "TC"), Some("false"));
+        assert_eq!(field(json, "name"), Some("host"));
+        assert_eq!(field(json, "ttl"), Some("30"));
+        assert_eq!(field(json, "user_id"), Some("null"));
+        assert_eq!(field(json, "ttl"), field(&json.replace(' ', ""), "ttl"));
+    }
+
+    #[test]
+    fn escaped_strings() {
+        let json = r#"{"a":"x\"y,z\\","b":"\"b\":1","c":2}"#;
+        assert_eq!(field(json, "a"), Some(r#"x\"y,z\\"#));
+        assert_eq!(field(json, "c"), Some("2"));
+
+        //keys inside string values aren't fields
+        assert_eq!(field(json, "b"), Some(r#"\"b\":1"#));
+        assert_eq!(field(r#"{"a":"\"c\":1","c":2}"#, "c"), Some("2"));
+    }
+
+    #[test]
+    fn nested_values() {
+        let json = r#"{"Status":0,"Answer":[{"type":5,"data":"a.b."},{"type":1,"data":"1.2.3.4","TTL":60}],"x":{"y":{"z":"}"}},"last":true}"#;
+        assert_eq!(
+            field(json, "Answer"),
+            Some(r#"[{"type":5,"data":"a.b."},{"type":1,"data":"1.2.3.4","TTL":60}]"#)
+        );
+        assert_eq!(field(json, "x"), Some(r#"{"y":{"z":"}"}}"#));
+        assert_eq!(field(json, "z"), Some("}"));
+        assert_eq!(field(json, "type"), Some("5"));
+        assert_eq!(field(json, "TTL"), Some("60"));
+        assert_eq!(field(json, "last"), Some("true"));
+    }
+
+    #[test]
+    fn missing_fields() {
+        let json = r#"{"channel_id":"1","values":["user_id"]}"#;
+        assert_eq!(field(json, "user_id"), None);
+        assert_eq!(field(json, "channel"), None);
+        assert_eq!(field("", "a"), None);
+        assert_eq!(field(r#"{"a":"unterminated"#, "a"), None);
+        assert_eq!(field(r#"{"a":{"b":1"#, "a"), None);
+        assert_eq!(field(r#"{"a":"#, "a"), None);
+    }
+}
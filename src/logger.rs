@@ -1,16 +1,163 @@
 use std::{
+    any::Any,
+    borrow::Cow,
+    cell::RefCell,
+    collections::VecDeque,
     env,
+    fmt::{self, Write as _},
     io::{self, IsTerminal},
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, PoisonError, TryLockError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use anyhow::Result;
-use log::{Level, LevelFilter, Log, Metadata, Record};
+use anyhow::{bail, Result};
+use log::{debug, info, Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{crash, memory};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static DEBUG_FULL: AtomicBool = AtomicBool::new(false);
+
+//bytes of playlists, responses and headers formatted for debug logs since the last report
+static DUMPED: AtomicU64 = AtomicU64::new(0);
+static DUMP_REPORTED: Mutex<Option<Instant>> = Mutex::new(None);
+
+//last records of every level that reached the logger, printed by the panic hook
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+const RECENT_RECORDS: usize = 50;
+
+thread_local! {
+    //name of the session logging on this thread, set when running multiple sessions
+    static SESSION: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+//playlists longer than this are truncated in debug logs unless --debug-full is used
+const DUMP_MAX_LINES: usize = 30;
+
+//how often the bytes formatted for debug dumps are logged, growth means a dump regressed
+const DUMP_REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+//characters ending a secret value in a URL or header
+const URL_END: &[char] = &['&', '"', ' ', '\r', '\n'];
+
+//secrets that are masked in debug output, by the text preceding them and where they end
+const SECRETS: [(&str, SecretEnd); 6] = [
+    ("sig=", SecretEnd::Chars(URL_END)),
+    ("token=", SecretEnd::Chars(URL_END)),
+    ("play_session_id=", SecretEnd::Chars(URL_END)),
+    (r#""value":""#, SecretEnd::Str(r#"","signature""#)),
+    (r#""signature":""#, SecretEnd::Chars(&['"'])),
+    ("Authorization: ", SecretEnd::Chars(&['\r', '\n'])),
+];
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Quiet,
+}
+
+impl LogLevel {
+    pub fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "quiet" => Ok(Self::Quiet),
+            _ => bail!("Invalid log level: {arg}"),
+        }
+    }
+
+    const fn filter(self) -> LevelFilter {
+        match self {
+            Self::Error => LevelFilter::Error,
+            Self::Warn => LevelFilter::Warn,
+            Self::Info | Self::Quiet => LevelFilter::Info,
+        }
+    }
+}
+
+//Whether stdout carries logs or machine-readable output, decided once at startup
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Stdout {
+    #[default]
+    Logs,
+    Data(&'static str),
+}
+
+impl Stdout {
+    //the same mode can be claimed by every session
+    pub fn claim(&mut self, flag: &'static str) -> Result<()> {
+        match *self {
+            Self::Data(other) if other != flag => {
+                bail!("{other} and {flag} both write to stdout, use only one of them")
+            }
+            _ => *self = Self::Data(flag),
+        }
+
+        Ok(())
+    }
+}
+
+//Recurring condition that is logged once when it starts and summarized when it ends in quiet mode
+pub struct Condition {
+    message: &'static str,
+    name: &'static str,
+
+    start: Option<Instant>,
+    count: u64,
+}
+
+impl Condition {
+    pub const fn new(message: &'static str, name: &'static str) -> Self {
+        Self {
+            message,
+            name,
+            start: None,
+            count: 0,
+        }
+    }
+
+    pub fn occur(&mut self) {
+        if self.start.is_none() {
+            self.start = Some(Instant::now());
+            info!("{}", self.message);
+        } else if !is_quiet() {
+            info!("{}", self.message);
+        }
+
+        self.count += 1;
+    }
+
+    pub fn end(&mut self) {
+        let Some(start) = self.start.take() else {
+            return;
+        };
+
+        let count = mem::take(&mut self.count);
+        if is_quiet() && count > 1 {
+            info!(
+                "{} continued for {}s ({count} times)",
+                self.name,
+                start.elapsed().as_secs(),
+            );
+        }
+    }
+}
 
 pub struct Logger {
     #[allow(dead_code)]
     enable_debug: bool,
 
     enable_colors: bool,
+    stdout: Stdout,
 }
 
 impl Log for Logger {
@@ -20,14 +167,17 @@ impl Log for Logger {
 
     fn log(&self, record: &Record<'_>) {
         let level = record.level();
+        let session = SESSION.with_borrow(|s| s.as_ref().map(|s| format!("[{s}] ")));
+        let session = session.as_deref().unwrap_or_default();
+        remember(level, session, record.args());
         match level {
             #[cfg(feature = "debug-logging")]
-            Level::Error | Level::Info | Level::Debug if self.enable_debug => {
+            Level::Error | Level::Warn | Level::Info | Level::Debug if self.enable_debug => {
                 use std::time::{Duration, SystemTime};
 
                 let thread = std::thread::current();
-                println!(
-                    "{} {} ({}) {}: {}",
+                self.print(format_args!(
+                    "{} {} ({}) {}: {session}{}",
                     SystemTime::now()
                         .duration_since(SystemTime::UNIX_EPOCH)
                         .unwrap_or(Duration::ZERO)
@@ -36,10 +186,16 @@ impl Log for Logger {
                     thread.name().unwrap_or("<unknown>"),
                     record.module_path().unwrap_or("<unknown>"),
                     record.args()
+                ));
+            }
+            Level::Error | Level::Warn => {
+                eprintln!(
+                    "{} {session}{}",
+                    level_tag(level, self.enable_colors),
+                    record.args(),
                 );
             }
-            Level::Error => eprintln!("{} {}", level_tag(level, self.enable_colors), record.args()),
-            Level::Info => println!("{}", record.args()),
+            Level::Info => self.print(format_args!("{session}{}", record.args())),
             _ => (),
         }
     }
@@ -48,17 +204,28 @@ impl Log for Logger {
 }
 
 impl Logger {
-    pub fn init(enable_debug: bool) -> Result<()> {
+    pub fn init(
+        enable_debug: bool,
+        debug_full: bool,
+        log_level: LogLevel,
+        stdout: Stdout,
+    ) -> Result<()> {
         log::set_boxed_logger(Box::new(Self {
             enable_debug,
             enable_colors: env::var_os("NO_COLOR").is_none() && io::stdout().is_terminal(),
+            stdout,
         }))?;
 
-        log::set_max_level(if enable_debug {
+        //without debug logging compiled in, debug records would be formatted only to be dropped
+        log::set_max_level(if enable_debug && cfg!(feature = "debug-logging") {
             LevelFilter::Debug
-        } else {
+        } else if enable_debug {
             LevelFilter::Info
+        } else {
+            log_level.filter()
         });
+        QUIET.store(log_level == LogLevel::Quiet, Ordering::Relaxed);
+        DEBUG_FULL.store(debug_full, Ordering::Relaxed);
 
         #[cfg(not(feature = "debug-logging"))]
         if enable_debug {
@@ -67,6 +234,14 @@ impl Logger {
 
         Ok(())
     }
+
+    //errors and warnings always go to stderr
+    fn print(&self, args: fmt::Arguments<'_>) {
+        match self.stdout {
+            Stdout::Logs => println!("{args}"),
+            Stdout::Data(_) => eprintln!("{args}"),
+        }
+    }
 }
 
 #[cfg(feature = "debug-logging")]
@@ -79,12 +254,173 @@ pub const fn is_debug() -> bool {
     false
 }
 
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+//Masks playback tokens, signatures and session ids so debug logs can be shared
+pub fn redact(text: &str) -> Cow<'_, str> {
+    if env::var_os("DEBUG_NO_REDACT").is_some()
+        || !SECRETS.iter().any(|(prefix, _)| text.contains(prefix))
+    {
+        return Cow::Borrowed(text);
+    }
+
+    let mut redacted = text.to_owned();
+    for (prefix, end) in &SECRETS {
+        let mut masked = String::with_capacity(redacted.len());
+        let mut rest = redacted.as_str();
+        while let Some(position) = rest.find(prefix) {
+            let (head, tail) = rest.split_at(position + prefix.len());
+            let len = end.find(tail).unwrap_or(tail.len());
+
+            masked.push_str(head);
+            masked.push_str(&mask(&tail[..len]));
+            rest = &tail[len..];
+        }
+
+        masked.push_str(rest);
+        redacted = masked;
+    }
+
+    Cow::Owned(redacted)
+}
+
+//Redacts and truncates a playlist, response body or headers for debug logging.
+//Callers check is_debug() first, so nothing is formatted with debug logging off
+pub fn redact_dump(text: &str) -> Cow<'_, str> {
+    let dump = match text.match_indices('\n').nth(DUMP_MAX_LINES - 1) {
+        Some((end, _)) if !DEBUG_FULL.load(Ordering::Relaxed) && end + 1 < text.len() => {
+            let (head, tail) = text.split_at(end + 1);
+            let mut truncated = redact(head).into_owned();
+            let _ = write!(truncated, "... ({} more lines)", tail.lines().count());
+
+            Cow::Owned(truncated)
+        }
+        _ => redact(text),
+    };
+
+    count_dump(dump.len());
+    dump
+}
+
+fn count_dump(len: usize) {
+    DUMPED.fetch_add(len as u64, Ordering::Relaxed);
+
+    let mut reported = DUMP_REPORTED.lock().expect("Dump report mutex poisoned");
+    match *reported {
+        Some(last) if last.elapsed() >= DUMP_REPORT_INTERVAL => {
+            debug!(
+                "Formatted {} of debug dumps in the last {}s",
+                memory::format_size(DUMPED.swap(0, Ordering::Relaxed)),
+                last.elapsed().as_secs(),
+            );
+            *reported = Some(Instant::now());
+        }
+        Some(_) => (),
+        None => *reported = Some(Instant::now()),
+    }
+}
+
+enum SecretEnd {
+    Chars(&'static [char]),
+    Str(&'static str),
+}
+
+impl SecretEnd {
+    fn find(&self, text: &str) -> Option<usize> {
+        match self {
+            Self::Chars(chars) => text.find(*chars),
+            Self::Str(string) => text.find(string),
+        }
+    }
+}
+
+//keeps the first and last 4 characters of long secrets
+fn mask(secret: &str) -> String {
+    match (
+        secret.get(..4),
+        secret.get(secret.len().saturating_sub(4)..),
+    ) {
+        (Some(head), Some(tail)) if secret.len() > 12 => format!("{head}...{tail}"),
+        _ => "***".to_owned(),
+    }
+}
+
+//Buffers of the oldest records are reused, so this doesn't allocate once the ring is full
+fn remember(level: Level, session: &str, args: &fmt::Arguments<'_>) {
+    let mut recent = RECENT.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let mut line = if recent.len() == RECENT_RECORDS {
+        recent.pop_front().unwrap_or_default()
+    } else {
+        String::new()
+    };
+
+    line.clear();
+    let _ = write!(line, "{} {session}{args}", level_tag_no_color(level));
+    recent.push_back(line);
+}
+
+//Recent records, oldest first. Called from the panic hook, which can run while this
+//thread holds the lock (a panicking Display impl), so it never blocks for long
+pub fn recent() -> Option<Vec<String>> {
+    for _ in 0..10 {
+        match RECENT.try_lock() {
+            Ok(recent) => return Some(recent.iter().cloned().collect()),
+            Err(TryLockError::Poisoned(e)) => {
+                return Some(e.into_inner().iter().cloned().collect())
+            }
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+
+    None
+}
+
+pub fn set_session(name: &str) {
+    SESSION.set(Some(name.into()));
+}
+
+//Name for a thread that is part of the current session but logs separately
+pub fn sub_session(name: &str) -> String {
+    SESSION.with_borrow(|s| {
+        s.as_ref()
+            .map_or_else(|| name.to_owned(), |session| format!("{session}/{name}"))
+    })
+}
+
+//Spawns a named thread that logs with the session and crash context of the spawning thread
+pub fn spawn<F, T>(name: &str, f: F) -> io::Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let session = SESSION.with_borrow(Clone::clone);
+    let context = crash::context();
+    thread::Builder::new().name(name.to_owned()).spawn(move || {
+        SESSION.set(session);
+        crash::set_context(context);
+        f()
+    })
+}
+
+//Message of a caught panic, for threads that report panics as errors
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<unknown>")
+}
+
 fn level_tag_no_color(level: Level) -> &'static str {
     match level {
         Level::Error => "[ERROR]",
+        Level::Warn => "[WARN]",
         Level::Info => "[INFO]",
         Level::Debug => "[DEBUG]",
-        _ => unreachable!(),
+        Level::Trace => unreachable!(),
     }
 }
 
@@ -93,9 +429,10 @@ fn level_tag(level: Level, enable_colors: bool) -> &'static str {
     if enable_colors {
         match level {
             Level::Error => "\x1b[31m[ERROR]\x1b[0m", //red
+            Level::Warn => "\x1b[33m[WARN]\x1b[0m",   //yellow
             Level::Info => "\x1b[34m[INFO]\x1b[0m",   //blue
             Level::Debug => "\x1b[36m[DEBUG]\x1b[0m", //cyan
-            _ => unreachable!(),
+            Level::Trace => unreachable!(),
         }
     } else {
         level_tag_no_color(level)
@@ -106,3 +443,113 @@ fn level_tag(level: Level, enable_colors: bool) -> &'static str {
 fn level_tag(level: Level, _enable_colors: bool) -> &'static str {
     level_tag_no_color(level)
 }
+
+//Records logged on the calling thread while f runs, for tests of what gets logged.
+//Installed as the logger of the test binary, other threads and debug records
+//aren't captured so debug dumps stay off in every test
+#[cfg(test)]
+pub fn capture<T>(f: impl FnOnce() -> T) -> (T, Vec<(Level, String)>) {
+    use std::sync::Once;
+
+    thread_local! {
+        static CAPTURED: RefCell<Option<Vec<(Level, String)>>> = const { RefCell::new(None) };
+    }
+
+    struct Capture;
+
+    impl Log for Capture {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record<'_>) {
+            CAPTURED.with_borrow_mut(|captured| {
+                if let Some(captured) = captured {
+                    captured.push((record.level(), record.args().to_string()));
+                }
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_logger(&Capture).expect("Another logger is installed");
+        log::set_max_level(LevelFilter::Info);
+    });
+
+    CAPTURED.set(Some(Vec::new()));
+    let result = f();
+    (result, CAPTURED.take().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_url() {
+        let url = "https://usher.ttvnw.net/api/channel/hls/c.m3u8?sig=0123456789abcdef\
+                   &token=%7B%22adblock%22%3Afalse%7D&play_session_id=fedcba9876543210&p=1";
+        assert_eq!(
+            redact(url),
+            "https://usher.ttvnw.net/api/channel/hls/c.m3u8?sig=0123...cdef\
+             &token=%7B%...e%7D&play_session_id=fedc...3210&p=1",
+        );
+
+        //up to the end of the line, and short values are fully masked
+        assert_eq!(
+            redact("GET /c.m3u8?token=short HTTP/1.1\r\nsig=abc\n"),
+            "GET /c.m3u8?token=*** HTTP/1.1\r\nsig=***\n",
+        );
+        assert_eq!(redact("token=0123456789abcdef"), "token=0123...cdef");
+    }
+
+    #[test]
+    fn redact_gql() {
+        let body = r#"{"data":{"streamPlaybackAccessToken":{"value":"{\"adblock\":false,\"channel\":\"c\",\"expires\":1714566896}","signature":"0123456789abcdef0123456789abcdef01234567","__typename":"PlaybackAccessToken"}}}"#;
+        assert_eq!(
+            redact(body),
+            r#"{"data":{"streamPlaybackAccessToken":{"value":"{\"a...896}","signature":"0123...4567","__typename":"PlaybackAccessToken"}}}"#,
+        );
+
+        assert_eq!(
+            redact("POST /gql HTTP/1.1\r\nAuthorization: OAuth 0123456789abcdef\r\n\r\n"),
+            "POST /gql HTTP/1.1\r\nAuthorization: OAut...cdef\r\n\r\n",
+        );
+    }
+
+    #[test]
+    fn nothing_to_redact() {
+        let text = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n";
+        assert!(matches!(redact(text), Cow::Borrowed(t) if t == text));
+    }
+
+    #[test]
+    fn dump_truncation() {
+        let lines = |count: usize| {
+            (0..count).fold(String::new(), |mut text, i| {
+                let _ = writeln!(text, "seg{i}.ts?token=0123456789abcdef");
+                text
+            })
+        };
+
+        let dump = redact_dump(&lines(DUMP_MAX_LINES)).into_owned();
+        assert_eq!(dump.lines().count(), DUMP_MAX_LINES);
+        assert!(!dump.contains("more lines"));
+
+        let dump = redact_dump(&lines(DUMP_MAX_LINES + 5)).into_owned();
+        assert!(dump.starts_with("seg0.ts?token=0123...cdef\n"));
+        assert!(dump.ends_with(&format!(
+            "seg{}.ts?token=0123...cdef\n... (5 more lines)",
+            DUMP_MAX_LINES - 1,
+        )));
+        assert!(!dump.contains("456789ab"));
+
+        //a last line without a newline still counts
+        let mut text = lines(DUMP_MAX_LINES);
+        text.push_str("last");
+        assert!(redact_dump(&text).ends_with("... (1 more lines)"));
+    }
+}
@@ -1,92 +1,169 @@
 mod args;
 mod constants;
+mod crash;
 mod hls;
 mod http;
+mod json;
 mod logger;
+mod memory;
 mod output;
+mod path_template;
+mod session;
+#[cfg(test)]
+mod temp_dir;
+#[cfg(feature = "testserver")]
+mod testserver;
 mod worker;
 
-use std::{
-    io::{self, ErrorKind::Other},
-    time::Instant,
-};
+use std::{io, process, sync::Arc, time::Duration};
 
-use anyhow::Result;
-use log::{debug, info};
+use anyhow::{ensure, Context, Result};
+use log::{debug, error, warn};
 
 use args::{Parse, Parser};
-use hls::{segment::Handler, MediaPlaylist, OfflineError};
-use http::Agent;
-use logger::Logger;
-use output::{Player, Writer};
-use worker::Worker;
+use hls::{Args as HlsArgs, OfflineError};
+use http::{Agent, StatusError};
+use logger::{LogLevel, Logger, Stdout};
+use output::{Summary, SummaryFormat};
+use worker::SegmentCacheArgs;
 
 #[derive(Default, Debug)]
+#[allow(clippy::struct_excessive_bools, reason = "command line switches")]
 pub struct Args {
     debug: bool,
+    debug_full: bool,
+    log_level: LogLevel,
     passthrough: bool,
+    check: bool,
+    duration: Option<Duration>,
+    max_buffer_memory: Option<u64>,
+    max_queued_segments: Option<usize>,
+    segment_cache: SegmentCacheArgs,
+    summary: SummaryFormat,
+    stdout: Stdout,
 }
 
 impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
         parser.parse_switch_or(&mut self.debug, "-d", "--debug")?;
+        parser.parse_switch(&mut self.debug_full, "--debug-full")?;
+        parser.parse_fn(&mut self.log_level, "--log-level", LogLevel::new)?;
         parser.parse_switch(&mut self.passthrough, "--passthrough")?;
+        parser.parse_switch(&mut self.check, "--check")?;
+        parser.parse_fn(&mut self.duration, "--duration", |a| {
+            Ok(Some(args::parse_duration(a)?))
+        })?;
+        parser.parse_fn(&mut self.max_buffer_memory, "--max-buffer-memory", |a| {
+            Ok(Some(args::parse_size(a)?))
+        })?;
+        parser.parse_fn(
+            &mut self.max_queued_segments,
+            "--max-queued-segments",
+            |a| {
+                let max = a.parse()?;
+                ensure!(max > 0, "Invalid max queued segments: {a}");
+                Ok(Some(max))
+            },
+        )?;
+        self.segment_cache.parse(parser)?;
+        parser.parse_fn(&mut self.summary, "--summary", SummaryFormat::new)?;
 
         Ok(())
     }
 }
 
-fn main_loop(mut playlist: MediaPlaylist, mut handler: Handler) -> Result<()> {
-    handler.process(&mut playlist, Instant::now())?;
-    loop {
-        let time = Instant::now();
+//Exit codes for --check
+const CHECK_OFFLINE: i32 = OfflineError::ChannelOffline.exit_code();
+const CHECK_DENIED: i32 = 3;
+const CHECK_NETWORK: i32 = OfflineError::ProxiesUnavailable {
+    servers: 0,
+    direct: None,
+}
+.exit_code();
+const CHECK_FAILED: i32 = 5;
 
-        playlist.reload()?;
-        handler.process(&mut playlist, time)?;
-    }
+fn check(hls_args: &HlsArgs, agent: &Agent) -> i32 {
+    let result = hls::select_channel(hls_args, agent)
+        .and_then(|()| hls::fetch_playlist(hls_args, agent))
+        .and_then(|variants| {
+            variants
+                .context("No quality selected")?
+                .open()
+                .map(|(quality, _)| quality)
+        });
+
+    let (live, code) = match result {
+        Ok(quality) => {
+            println!(
+                "channel={} live=yes quality={}",
+                hls_args.channel(),
+                quality.as_deref().unwrap_or("unknown"),
+            );
+            return 0;
+        }
+        Err(e)
+            if matches!(
+                e.downcast_ref(),
+                Some(OfflineError::ProxiesUnavailable { .. })
+            ) =>
+        {
+            error!("{e}");
+            ("unreachable", CHECK_NETWORK)
+        }
+        Err(e) if e.downcast_ref::<OfflineError>().is_some() => ("no", CHECK_OFFLINE),
+        Err(e) if StatusError::is_forbidden(&e) || StatusError::is_unauthorized(&e) => {
+            error!("{e}");
+            ("denied", CHECK_DENIED)
+        }
+        Err(e) if e.downcast_ref::<io::Error>().is_some() => {
+            error!("{e}");
+            ("unreachable", CHECK_NETWORK)
+        }
+        Err(e) => {
+            error!("{e}");
+            ("unknown", CHECK_FAILED)
+        }
+    };
+
+    println!("channel={} live={live}", hls_args.channel());
+    code
 }
 
 fn main() -> Result<()> {
-    let (playlist, handler) = {
-        let (main_args, http_args, hls_args, mut output_args) = args::parse()?;
-
-        Logger::init(main_args.debug)?;
-        debug!("\n{main_args:#?}\n{http_args:#?}\n{hls_args:#?}\n{output_args:#?}");
+    let summary = Arc::new(Summary::new());
+    crash::install_hook();
+    let (main_args, http_args, mut sessions) = args::parse()?;
 
-        let agent = Agent::new(http_args)?;
-        let conn = match hls::fetch_playlist(hls_args, &agent) {
-            Ok(Some(conn)) => conn,
-            Ok(None) => return Ok(()),
-            Err(e) if e.downcast_ref::<OfflineError>().is_some() => {
-                info!("{e}, exiting...");
-                return Ok(());
-            }
-            Err(e) => return Err(e),
-        };
+    Logger::init(
+        main_args.debug,
+        main_args.debug_full,
+        main_args.log_level,
+        main_args.stdout,
+    )?;
+    for warning in args::deferred_warnings() {
+        warn!("{warning}");
+    }
+    debug!("\n{main_args:#?}\n{http_args:#?}\n{sessions:#?}");
 
-        if main_args.passthrough {
-            return Player::passthrough(&mut output_args.player, &conn.url);
+    let agent = Agent::new(http_args)?;
+    let result = if sessions.len() > 1 {
+        ensure!(!main_args.check, "--check can't be used with --session");
+        Ok(session::run_all(sessions, &main_args, &agent, &summary))
+    } else {
+        let session = sessions.pop().context("Missing channel argument")?;
+        if main_args.check {
+            process::exit(check(&session.hls, &agent));
         }
 
-        let mut playlist = MediaPlaylist::new(conn)?;
-        let worker = Worker::spawn(Writer::new(&output_args)?, playlist.header.take(), agent)?;
-
-        (playlist, Handler::new(worker))
+        session.run(&main_args, &agent, &summary)
     };
 
-    match main_loop(playlist, handler) {
-        Ok(()) => Ok(()),
-        Err(e) if e.downcast_ref::<OfflineError>().is_some() => {
-            info!("Stream ended, exiting...");
-            Ok(())
-        }
-        Err(e)
-            if e.downcast_ref::<io::Error>()
-                .is_some_and(|e| e.kind() == Other) =>
-        {
-            info!("Player closed, exiting...");
-            Ok(())
-        }
-        Err(e) => Err(e),
+    //printed for errors as well, before main returns them
+    let (throttled, delay) = agent.rate_limited();
+    summary.api_throttled(throttled, delay);
+    summary.print(main_args.summary);
+    match result? {
+        0 => Ok(()),
+        code => process::exit(code),
     }
 }
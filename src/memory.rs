@@ -0,0 +1,174 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+//Memory held by the queues and buffers of one session, read by the debug stats line and
+//compared against --max-buffer-memory. Shared by the pipelines of the session
+#[derive(Default)]
+pub struct Memory {
+    counters: [AtomicU64; Kind::ALL.len()],
+    max: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Player,
+    Replay,
+    Queue,
+    //queued segment URLs, a count and not bytes
+    QueuedSegments,
+}
+
+impl Kind {
+    const ALL: [Self; 4] = [
+        Self::Player,
+        Self::Replay,
+        Self::Queue,
+        Self::QueuedSegments,
+    ];
+    const BYTES: [Self; 3] = [Self::Player, Self::Replay, Self::Queue];
+    //What --max-buffer-memory limits, in the order it's evicted. The player buffer has its own
+    //limit (--player-buffer), a lagging player mustn't make the worker drop segments
+    const EVICTABLE: [Self; 2] = [Self::Replay, Self::Queue];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Player => "player",
+            Self::Replay => "replay",
+            Self::Queue => "queue",
+            Self::QueuedSegments => "queued",
+        }
+    }
+}
+
+impl Memory {
+    pub fn new(max: Option<u64>) -> Self {
+        Self {
+            max: max.unwrap_or_default(),
+            ..Self::default()
+        }
+    }
+
+    pub fn get(&self, kind: Kind) -> u64 {
+        self.counter(kind).load(Ordering::Relaxed)
+    }
+
+    pub fn evictable(&self) -> u64 {
+        Kind::EVICTABLE.iter().map(|k| self.get(*k)).sum()
+    }
+
+    pub fn over_limit(&self) -> bool {
+        self.max > 0 && self.evictable() > self.max
+    }
+
+    //"player=1.2 MiB replay=40.5 MiB queue=0.3 KiB queued=2"
+    pub fn summary(&self) -> String {
+        let bytes = Kind::BYTES
+            .iter()
+            .map(|k| format!("{}={}", k.name(), format_size(self.get(*k))))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let queued = Kind::QueuedSegments;
+        format!("{bytes} {}={}", queued.name(), self.get(queued))
+    }
+
+    const fn counter(&self, kind: Kind) -> &AtomicU64 {
+        &self.counters[kind as usize]
+    }
+}
+
+//One owner's share of a counter, whatever is still held is released when it's dropped
+pub struct Tracked {
+    memory: Arc<Memory>,
+    kind: Kind,
+    held: AtomicU64,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.memory
+            .counter(self.kind)
+            .fetch_sub(*self.held.get_mut(), Ordering::Relaxed);
+    }
+}
+
+impl Tracked {
+    pub fn new(memory: &Arc<Memory>, kind: Kind) -> Self {
+        Self {
+            memory: memory.clone(),
+            kind,
+            held: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self) -> u64 {
+        self.held.load(Ordering::Relaxed)
+    }
+
+    pub fn add(&self, len: usize) {
+        self.held.fetch_add(len as u64, Ordering::Relaxed);
+        self.memory
+            .counter(self.kind)
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, len: usize) {
+        self.held.fetch_sub(len as u64, Ordering::Relaxed);
+        self.memory
+            .counter(self.kind)
+            .fetch_sub(len as u64, Ordering::Relaxed);
+    }
+
+    //the memory of the session this share belongs to
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+}
+
+pub fn format_size(size: u64) -> String {
+    #[allow(clippy::cast_precision_loss)]
+    let size = size as f64;
+    if size >= 1024.0 * 1024.0 {
+        format!("{:.1} MiB", size / (1024.0 * 1024.0))
+    } else {
+        format!("{:.1} KiB", size / 1024.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_counted_separately() {
+        let first = Arc::new(Memory::new(Some(100)));
+        let second = Arc::new(Memory::new(Some(100)));
+
+        let replay = Tracked::new(&first, Kind::Replay);
+        replay.add(150);
+        assert!(first.over_limit());
+        assert!(!second.over_limit());
+
+        let queued = Tracked::new(&second, Kind::QueuedSegments);
+        queued.add(500);
+        assert_eq!(second.evictable(), 0, "a count, not bytes");
+
+        drop(replay);
+        assert_eq!(first.get(Kind::Replay), 0);
+        assert!(!first.over_limit());
+    }
+
+    #[test]
+    fn player_is_not_evictable() {
+        let memory = Arc::new(Memory::new(Some(100)));
+        let player = Tracked::new(&memory, Kind::Player);
+        player.add(1000);
+        assert!(!memory.over_limit());
+
+        let queue = Tracked::new(&memory, Kind::Queue);
+        queue.add(101);
+        assert!(memory.over_limit());
+    }
+}
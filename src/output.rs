@@ -1,37 +1,156 @@
+mod chapters;
 mod player;
 mod recorder;
+mod replay;
+mod stats;
+mod status;
+mod summary;
+mod ts;
+mod webhook;
 
-pub use player::Player;
+pub use chapters::Marker;
+pub use player::{PipeClosedError, Player, StreamEnv};
+pub use stats::Sink;
+pub use status::{State as StatusState, Status};
+pub use summary::{Format as SummaryFormat, Summary};
+pub use ts::is_mpegts;
+pub use webhook::Webhook;
 
-use std::io::{self, ErrorKind::Other, Write};
+use std::{
+    io::{self, ErrorKind::Other, Write},
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
 
-use anyhow::{bail, Result};
-use log::debug;
+use anyhow::{bail, ensure, Result};
+use log::{debug, error};
 
-use player::Args as PlayerArgs;
+use chapters::{Args as ChaptersArgs, Chapters};
+use player::{Args as PlayerArgs, PlayerLagError};
 use recorder::{Args as RecorderArgs, Recorder};
+use replay::{Args as ReplayArgs, Replay};
+use stats::SinkStats;
+use status::{Args as StatusArgs, StatusFile};
+use webhook::{Args as WebhookArgs, SegmentEvent};
 
-use crate::args::{Parse, Parser};
+use crate::{
+    args::{self, Parse, Parser},
+    memory::Memory,
+    path_template::Fields,
+};
 
 #[derive(Default, Debug)]
 pub struct Args {
     pub player: PlayerArgs,
     recorder: RecorderArgs,
+    chapters: ChaptersArgs,
+    replay: ReplayArgs,
+    pub webhook: WebhookArgs,
+    status: StatusArgs,
+    max_size: Option<u64>,
+    keepalive: Keepalive,
+    keepalive_in_recording: bool,
 }
 
 impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
         self.player.parse(parser)?;
         self.recorder.parse(parser)?;
+        self.chapters.parse(parser)?;
+        ensure!(
+            !self.chapters.is_set() || self.recorder.is_set(),
+            "--chapters needs a recording (-r)",
+        );
+        self.replay.parse(parser)?;
+        self.webhook.parse(parser)?;
+        self.status.parse(parser)?;
+        parser.parse_fn(&mut self.max_size, "--max-size", |a| {
+            Ok(Some(args::parse_size(a)?))
+        })?;
+        parser.parse_fn(
+            &mut self.keepalive,
+            "--keepalive-during-ads",
+            Keepalive::new,
+        )?;
+        parser.parse_switch(&mut self.keepalive_in_recording, "--keepalive-in-recording")?;
 
         Ok(())
     }
 }
 
-pub enum Writer {
-    Player(Player),
-    Recorder(Recorder),
-    Combined(Player, Recorder),
+impl Args {
+    pub const fn has_sink(&self, sink: Sink) -> bool {
+        match sink {
+            Sink::Player => self.player.is_set(),
+            Sink::Recorder => self.recorder.is_set(),
+        }
+    }
+
+    //Moves a sink into new args for a second pipeline, the other options are shared
+    pub fn split_off(&mut self, sink: Sink) -> Self {
+        let mut args = Self {
+            status: self.status.clone(),
+            max_size: self.max_size,
+            keepalive: self.keepalive,
+            keepalive_in_recording: self.keepalive_in_recording,
+            ..Self::default()
+        };
+
+        match sink {
+            Sink::Player => args.player = mem::take(&mut self.player),
+            Sink::Recorder => {
+                args.recorder = mem::take(&mut self.recorder);
+                args.chapters = mem::take(&mut self.chapters);
+            }
+        }
+
+        args
+    }
+}
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Keepalive {
+    #[default]
+    None,
+    NullPackets,
+}
+
+impl Keepalive {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "none" => Ok(Self::None),
+            "null-packets" => Ok(Self::NullPackets),
+            _ => bail!("Invalid keepalive mode: {arg}"),
+        }
+    }
+}
+
+pub struct Writer {
+    sinks: Sinks,
+    stats: SinkStats,
+    summary: Arc<Summary>,
+    memory: Arc<Memory>,
+    size_limit: Arc<SizeLimit>,
+
+    keepalive: Keepalive,
+    keepalive_in_recording: bool,
+
+    replay: Option<Replay>,
+    webhook: Option<Webhook>,
+    chapters: Option<Chapters>,
+    status: Option<StatusFile>,
+    in_header: bool,
+
+    //segment being written
+    sequence: usize,
+    segment_duration: Duration,
+    program_date_time: Option<String>,
+    segment_size: u64,
+    offset: Duration,
 }
 
 impl Write for Writer {
@@ -40,39 +159,469 @@ impl Write for Writer {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        debug!("Finished writing segment");
-        match self {
-            Self::Player(_) => Ok(()),
-            Self::Recorder(recorder) | Self::Combined(_, recorder) => recorder.flush(),
+        debug!(
+            "Finished writing segment, buffered {}",
+            self.memory.summary()
+        );
+        let result = match &mut self.sinks {
+            Sinks::Player(player) => self.stats.time(Sink::Player, || flush_player(player)),
+            Sinks::Recorder(recorder) => self.stats.time(Sink::Recorder, || recorder.flush()),
+            Sinks::Combined(player, recorder) => {
+                match self.stats.time(Sink::Player, || flush_player(player)) {
+                    Err(e) if !is_player_closed(&e) => Err(e),
+                    _ => self.stats.time(Sink::Recorder, || recorder.flush()),
+                }
+            }
+        };
+        if let Sinks::Player(player) | Sinks::Combined(player, _) = &mut self.sinks {
+            written(
+                &self.summary,
+                self.status.as_deref(),
+                Sink::Player,
+                player.take_sent(),
+            );
+        }
+
+        self.stats.finish_segment();
+        let in_header = mem::take(&mut self.in_header);
+        if let Some(replay) = &mut self.replay {
+            if in_header {
+                replay.finish_header();
+            } else {
+                replay.finish_segment(self.segment_duration);
+            }
+        }
+
+        let size = mem::take(&mut self.segment_size);
+        if !in_header {
+            if result.is_ok() && size > 0 {
+                self.summary.first_segment(size);
+                self.summary.segment(size, self.segment_duration);
+                if let Some(status) = &self.status {
+                    status.segment(self.segment_duration, self.program_date_time.as_deref());
+                }
+            }
+
+            if let (Some(webhook), Ok(())) = (&self.webhook, &result) {
+                webhook.segment(SegmentEvent {
+                    sequence: self.sequence,
+                    duration: self.segment_duration,
+                    size,
+                    program_date_time: self.program_date_time.take(),
+                    offset: self.offset,
+                });
+            }
+
+            self.offset += self.segment_duration;
         }
+
+        result
     }
 
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        match self {
-            Self::Player(player) => player.write_all(buf),
-            Self::Recorder(recorder) => recorder.write_all(buf),
-            Self::Combined(player, recorder) => {
-                if let Err(e) = player.write_all(buf) {
-                    match e.kind() {
-                        Other => (), //ignore player closed
-                        _ => return Err(e),
+        if !self.in_header {
+            match &mut self.sinks {
+                Sinks::Player(player) => player.ensure_spawned()?,
+                Sinks::Combined(player, _) => match player.ensure_spawned() {
+                    Err(e) if is_player_closed(&e) => {
+                        error!("Failed to open player, continuing the recording: {e}");
                     }
+                    result => result?,
+                },
+                Sinks::Recorder(_) => (),
+            }
+        }
+
+        self.size_limit.add(buf.len());
+        self.segment_size += buf.len() as u64;
+        if let Some(replay) = &mut self.replay {
+            replay.write(buf);
+        }
+
+        let written = |sink, len| {
+            written(&self.summary, self.status.as_deref(), sink, len);
+        };
+        match &mut self.sinks {
+            Sinks::Player(player) => {
+                self.stats.time(Sink::Player, || player.write_all(buf))?;
+                written(Sink::Player, player.take_sent());
+            }
+            Sinks::Recorder(recorder) => {
+                self.stats
+                    .time(Sink::Recorder, || recorder.write_all(buf))?;
+                written(Sink::Recorder, buf.len());
+            }
+            Sinks::Combined(player, recorder) => {
+                match self.stats.time(Sink::Player, || player.write_all(buf)) {
+                    Ok(()) => written(Sink::Player, player.take_sent()),
+                    Err(e) if is_player_closed(&e) => (),
+                    Err(e) => return Err(e),
                 }
 
-                recorder.write_all(buf)?;
-                Ok(())
+                self.stats
+                    .time(Sink::Recorder, || recorder.write_all(buf))?;
+                written(Sink::Recorder, buf.len());
             }
         }
+
+        Ok(())
     }
 }
 
 impl Writer {
-    pub fn new(args: &Args) -> Result<Self> {
-        match (Player::spawn(&args.player)?, Recorder::new(&args.recorder)?) {
-            (Some(player), Some(recorder)) => Ok(Self::Combined(player, recorder)),
-            (Some(player), None) => Ok(Self::Player(player)),
-            (None, Some(recorder)) => Ok(Self::Recorder(recorder)),
+    pub fn new(
+        args: &Args,
+        webhook: Option<Webhook>,
+        env: &StreamEnv,
+        summary: Arc<Summary>,
+        memory: Arc<Memory>,
+    ) -> Result<Self> {
+        let fields = Fields::new(env.channel(), env.quality());
+        let sinks = match (
+            Player::spawn(&args.player, env, &memory)?,
+            Recorder::new(&args.recorder, &fields)?,
+        ) {
+            (Some(player), Some(recorder)) => Sinks::Combined(player, recorder),
+            (Some(player), None) => Sinks::Player(player),
+            (None, Some(recorder)) => Sinks::Recorder(recorder),
             (None, None) => bail!("Player or recording must be set"),
+        };
+
+        summary.opened();
+        Ok(Self {
+            sinks,
+            stats: SinkStats::new(),
+            summary,
+            size_limit: Arc::new(SizeLimit {
+                max: args.max_size,
+                written: AtomicU64::default(),
+            }),
+            keepalive: args.keepalive,
+            keepalive_in_recording: args.keepalive_in_recording,
+            replay: Replay::new(&args.replay, &fields, &memory)?,
+            memory,
+            webhook,
+            chapters: Chapters::new(&args.chapters, &fields)?,
+            status: StatusFile::new(&args.status, &fields, env.channel(), env.quality())?,
+            in_header: bool::default(),
+            sequence: usize::default(),
+            segment_duration: Duration::default(),
+            program_date_time: Option::default(),
+            segment_size: u64::default(),
+            offset: Duration::default(),
+        })
+    }
+
+    //the next segment is the init segment of an fMP4 stream
+    pub fn start_header(&mut self) {
+        self.in_header = true;
+        if let Sinks::Player(player) | Sinks::Combined(player, _) = &mut self.sinks {
+            player.disable_psi();
         }
     }
+
+    pub fn set_segment(
+        &mut self,
+        sequence: usize,
+        duration: Duration,
+        program_date_time: Option<String>,
+    ) {
+        self.sequence = sequence;
+        self.segment_duration = duration;
+        self.program_date_time = program_date_time;
+    }
+
+    //the segment being written was abandoned, drops what the outputs still hold of it
+    pub fn discard_segment(&mut self) -> io::Result<()> {
+        self.segment_size = 0;
+        if let Some(replay) = &mut self.replay {
+            replay.discard_segment();
+        }
+
+        match &mut self.sinks {
+            Sinks::Player(player) => {
+                player.discard_segment();
+                Ok(())
+            }
+            Sinks::Recorder(recorder) => recorder.discard_segment(),
+            Sinks::Combined(player, recorder) => {
+                player.discard_segment();
+                recorder.discard_segment()
+            }
+        }
+    }
+
+    //marks the current position in the recording, after everything written so far
+    pub fn marker(&mut self, marker: Marker, time: SystemTime) {
+        if let Some(chapters) = &mut self.chapters {
+            chapters.write(marker, self.offset, time);
+        }
+    }
+
+    //keeps players that treat silence as end of stream fed during ad breaks (MPEG-TS only)
+    pub fn keepalive(&mut self) -> io::Result<()> {
+        if self.keepalive == Keepalive::None {
+            return Ok(());
+        }
+
+        let packets = ts::null_packets();
+        match &mut self.sinks {
+            //a delayed player is opened by the stream, not by keepalive packets
+            Sinks::Player(player) | Sinks::Combined(player, _) if player.is_spawned() => {
+                //not media, so not counted as written
+                let result = player.write_all(&packets).and_then(|()| player.flush());
+                player.take_sent();
+                if let Err(e) = result {
+                    match (is_player_closed(&e), &self.sinks) {
+                        (true, Sinks::Combined(..)) => (),
+                        _ => return Err(e),
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        match &mut self.sinks {
+            Sinks::Recorder(recorder) | Sinks::Combined(_, recorder)
+                if self.keepalive_in_recording =>
+            {
+                recorder.write_all(&packets)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    //time the outputs blocked writes since the last call
+    pub fn take_blocked(&mut self) -> Duration {
+        self.stats.take_blocked()
+    }
+
+    pub fn size_limit(&self) -> Arc<SizeLimit> {
+        self.size_limit.clone()
+    }
+
+    pub fn status(&self) -> Option<Arc<Status>> {
+        self.status.as_ref().map(StatusFile::status)
+    }
+}
+
+//Bytes written to the outputs, shared with the segment handler so it can stop at --max-size
+#[derive(Default)]
+pub struct SizeLimit {
+    max: Option<u64>,
+    written: AtomicU64,
+}
+
+impl SizeLimit {
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    pub fn reached(&self) -> bool {
+        self.max.is_some_and(|max| self.written() >= max)
+    }
+
+    fn add(&self, len: usize) {
+        self.written.fetch_add(len as u64, Ordering::Relaxed);
+    }
+}
+
+//Bytes that reached an output, a player's batched writes once they were sent
+fn written(summary: &Summary, status: Option<&Status>, sink: Sink, len: usize) {
+    if len == 0 {
+        return;
+    }
+
+    summary.first_byte();
+    summary.written(sink, len);
+    if let Some(status) = status {
+        status.written(sink, len);
+    }
+}
+
+//With a recording, a closed player doesn't end the stream. A player too slow for
+//--player-buffer-fatal still does
+fn is_player_closed(error: &io::Error) -> bool {
+    error.kind() == Other && !PlayerLagError::is_player_lag(error)
+}
+
+//sends the writes batched since the last segment, then checks the player is still reading
+fn flush_player(player: &mut Player) -> io::Result<()> {
+    player.flush()?;
+    player.check_alive()
+}
+
+enum Sinks {
+    Player(Player),
+    Recorder(Recorder),
+    Combined(Player, Recorder),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        args::{Parse, Parser},
+        logger,
+        temp_dir::TempDir,
+    };
+
+    pub fn writer(args: &[&str]) -> Writer {
+        writer_with(args, Arc::new(Summary::new()))
+    }
+
+    fn writer_with(args: &[&str], summary: Arc<Summary>) -> Writer {
+        let mut output_args = Args::default();
+        output_args.parse(&mut Parser::from_args(args)).unwrap();
+
+        Writer::new(
+            &output_args,
+            None,
+            &StreamEnv::new("", "channel", None, None),
+            summary,
+            Arc::new(Memory::new(None)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn failed_player_keeps_recording() {
+        let dir = TempDir::new("failed-player");
+        let recording = dir.join("recording.ts");
+        let player = dir.join("missing-player");
+
+        let mut writer = writer(&[
+            "-p",
+            player.to_str().unwrap(),
+            "--delay-player-spawn",
+            "-r",
+            recording.to_str().unwrap(),
+            "--overwrite",
+        ]);
+        assert!(matches!(writer.sinks, Sinks::Combined(..)));
+
+        let segment = [0x47; 188];
+        for _ in 0..2 {
+            writer.write_all(&segment).unwrap();
+            writer.flush().unwrap();
+        }
+        drop(writer);
+
+        assert_eq!(fs::read(&recording).unwrap(), [segment, segment].concat());
+    }
+
+    #[test]
+    fn keepalive_in_recording() {
+        let recorded = |name, args: &[&str]| {
+            let dir = TempDir::new(name);
+            let recording = dir.join("recording.ts");
+            let mut writer =
+                writer(&[&["-r", recording.to_str().unwrap(), "--overwrite"], args].concat());
+            writer.keepalive().unwrap();
+            drop(writer);
+
+            fs::read(recording).unwrap()
+        };
+
+        assert!(recorded("keepalive-off", &[]).is_empty());
+        assert!(recorded(
+            "keepalive-player",
+            &["--keepalive-during-ads", "null-packets"]
+        )
+        .is_empty());
+        assert_eq!(
+            recorded(
+                "keepalive-recording",
+                &[
+                    "--keepalive-during-ads",
+                    "null-packets",
+                    "--keepalive-in-recording"
+                ],
+            ),
+            ts::null_packets(),
+        );
+
+        assert_eq!(Keepalive::new("none").unwrap(), Keepalive::None);
+        assert_eq!(
+            Keepalive::new("null-packets").unwrap(),
+            Keepalive::NullPackets
+        );
+        assert!(Keepalive::new("silence").is_err());
+    }
+
+    #[test]
+    fn recording_is_flushed_per_segment() {
+        let dir = TempDir::new("segment-flush");
+        let recording = dir.join("recording.ts");
+        let mut writer = writer(&[
+            "-r",
+            recording.to_str().unwrap(),
+            "--overwrite",
+            "--record-buffer",
+            "1",
+        ]);
+        let read = || fs::read(&recording).unwrap();
+
+        writer.start_header();
+        writer.write_all(b"header").unwrap();
+        assert!(read().is_empty());
+        writer.flush().unwrap();
+        assert_eq!(read(), b"header");
+
+        writer.set_segment(1, Duration::from_secs(2), None);
+        writer.write_all(b"cut").unwrap();
+        writer.discard_segment().unwrap();
+        writer.write_all(b"segment").unwrap();
+        assert_eq!(read(), b"header");
+        writer.flush().unwrap();
+        assert_eq!(read(), b"headersegment");
+
+        writer.write_all(b"tail").unwrap();
+        drop(writer);
+        assert_eq!(read(), b"headersegmenttail");
+    }
+
+    #[test]
+    fn startup_is_logged_once() {
+        let dir = TempDir::new("startup");
+        let recording = dir.join("recording.ts");
+        let args = ["-r", recording.to_str().unwrap(), "--overwrite"];
+        let summary = Arc::new(Summary::new());
+        let segment = |writer: &mut Writer, data: &[u8]| {
+            writer.set_segment(1, Duration::from_secs(2), None);
+            writer.write_all(data).unwrap();
+            writer.flush().unwrap();
+        };
+
+        let ((), logged) = logger::capture(|| {
+            let mut writer = writer_with(&args, summary.clone());
+            //the init segment is output, but not a segment of the stream
+            writer.start_header();
+            writer.write_all(b"header").unwrap();
+            writer.flush().unwrap();
+            segment(&mut writer, b"segment");
+
+            //the worker was reset in the middle of a segment
+            writer.write_all(b"cut").unwrap();
+            writer.discard_segment().unwrap();
+            segment(&mut writer, b"after reset");
+            drop(writer);
+
+            //the pipeline of another rendition shares the summary
+            let mut writer = writer_with(&args, summary.clone());
+            segment(&mut writer, b"other rendition");
+        });
+
+        let startup = logged
+            .iter()
+            .filter_map(|(_, line)| line.strip_prefix("first-"))
+            .map(|line| line.split(' ').map(str::to_owned).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(startup.len(), 2, "{startup:?}");
+        assert_eq!(startup[0][0], "byte");
+        assert_eq!(startup[1][0], "segment");
+        assert_eq!(startup[1][2], "7");
+    }
 }
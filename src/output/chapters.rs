@@ -0,0 +1,218 @@
+use std::{
+    fs::File,
+    io::Write,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{error, info};
+
+use crate::{
+    args::{Parse, Parser},
+    path_template::{Fields, PathTemplate},
+};
+
+#[derive(Default, Debug)]
+pub struct Args {
+    path: Option<PathTemplate>,
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse_fn(&mut self.path, "--chapters", |a| {
+            Ok(Some(PathTemplate::new(a)?))
+        })
+    }
+}
+
+impl Args {
+    pub const fn is_set(&self) -> bool {
+        self.path.is_some()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Marker {
+    AdBreak,
+    StreamResume,
+    Discontinuity,
+    Restart,
+}
+
+impl Marker {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::AdBreak => "ad_break",
+            Self::StreamResume => "stream_resume",
+            Self::Discontinuity => "discontinuity",
+            Self::Restart => "stream_restart",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Format {
+    Csv,
+    FfMetadata,
+}
+
+//Points in the recording where content was cut, every marker is appended as it happens
+//so the file stays usable if the process dies mid-recording
+pub struct Chapters {
+    file: File,
+    format: Format,
+    failed: bool,
+}
+
+impl Chapters {
+    pub fn new(args: &Args, fields: &Fields) -> Result<Option<Self>> {
+        let Some(template) = &args.path else {
+            return Ok(None);
+        };
+
+        let path = template.expand(fields);
+        let format = if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("csv"))
+        {
+            Format::Csv
+        } else {
+            Format::FfMetadata
+        };
+
+        info!("Writing chapters to: {}", path.display());
+        let mut file = File::create(&path).context("Failed to create chapters file")?;
+        file.write_all(match format {
+            Format::Csv => b"offset,wall_time,type\n",
+            Format::FfMetadata => b";FFMETADATA1\n",
+        })
+        .context("Failed to write chapters file")?;
+
+        Ok(Some(Self {
+            file,
+            format,
+            failed: false,
+        }))
+    }
+
+    //offset is the duration of the content recorded before the marker
+    pub fn write(&mut self, marker: Marker, offset: Duration, time: SystemTime) {
+        let wall_time = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let line = match self.format {
+            Format::Csv => format!(
+                "{:.3},{wall_time:.3},{}\n",
+                offset.as_secs_f64(),
+                marker.name(),
+            ),
+            //chapters need an end, markers are zero length chapters
+            Format::FfMetadata => format!(
+                "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={0}\nEND={0}\ntitle={1} at {wall_time:.0}\n",
+                offset.as_millis(),
+                marker.name(),
+            ),
+        };
+
+        //the recording matters more than its chapters, so failures don't stop it
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            if !self.failed {
+                error!("Failed to write chapter marker: {e}");
+            }
+            self.failed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{output::tests::writer, temp_dir::TempDir};
+
+    fn written(dir: &TempDir, name: &str) -> String {
+        let mut args = Args::default();
+        args.parse(&mut Parser::from_args(&["--chapters", &dir.path_str(name)]))
+            .unwrap();
+
+        let mut chapters = Chapters::new(&args, &Fields::new("channel", None))
+            .unwrap()
+            .unwrap();
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+        chapters.write(Marker::AdBreak, Duration::from_millis(2500), time);
+        chapters.write(
+            Marker::StreamResume,
+            Duration::from_millis(2500),
+            time + Duration::from_secs(30),
+        );
+        chapters.write(Marker::Restart, Duration::from_secs(10), time);
+        drop(chapters);
+
+        fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        let dir = TempDir::new("chapters-csv");
+        let expected = "offset,wall_time,type\n\
+            2.500,1700000000.250,ad_break\n\
+            2.500,1700000030.250,stream_resume\n\
+            10.000,1700000000.250,stream_restart\n";
+        assert_eq!(written(&dir, "chapters.csv"), expected);
+        assert_eq!(written(&dir, "chapters.CSV"), expected);
+    }
+
+    #[test]
+    fn ffmetadata() {
+        let dir = TempDir::new("chapters-ffmetadata");
+        assert_eq!(
+            written(&dir, "chapters.txt"),
+            ";FFMETADATA1\n\
+            \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=2500\nEND=2500\ntitle=ad_break at 1700000000\n\
+            \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=2500\nEND=2500\ntitle=stream_resume at 1700000030\n\
+            \n[CHAPTER]\nTIMEBASE=1/1000\nSTART=10000\nEND=10000\ntitle=stream_restart at 1700000000\n",
+        );
+    }
+
+    #[test]
+    fn offsets_count_only_segments_written() {
+        let dir = TempDir::new("chapters-writer");
+        let mut writer = writer(&[
+            "-r",
+            &dir.path_str("recording.ts"),
+            "--overwrite",
+            "--chapters",
+            &dir.path_str("chapters.csv"),
+        ]);
+        let now = SystemTime::now();
+
+        writer.start_header();
+        writer.write_all(b"init").unwrap();
+        writer.flush().unwrap();
+        writer.marker(Marker::Discontinuity, now);
+
+        writer.set_segment(1, Duration::from_millis(2500), None);
+        writer.write_all(b"segment").unwrap();
+        writer.flush().unwrap();
+
+        //the cut segment is downloaded again, it's only counted once
+        writer.set_segment(2, Duration::from_secs(2), None);
+        writer.write_all(b"cut").unwrap();
+        writer.discard_segment().unwrap();
+        writer.write_all(b"segment").unwrap();
+        writer.flush().unwrap();
+        writer.marker(Marker::AdBreak, now);
+        drop(writer);
+
+        let offsets = fs::read_to_string(dir.join("chapters.csv"))
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|l| l.split(',').next().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(offsets, ["0.000", "4.500"]);
+    }
+}
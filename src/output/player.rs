@@ -1,14 +1,33 @@
 use std::{
-    borrow::Cow,
+    env,
     fmt::{self, Display, Formatter},
-    io::{self, ErrorKind::BrokenPipe, Write},
-    process::{Child, ChildStdin, Command, Stdio},
+    fs::{self, File},
+    io::{
+        self,
+        ErrorKind::{BrokenPipe, InvalidInput},
+        Write,
+    },
+    mem,
+    path::{Path, PathBuf},
+    process::{self, Child, ChildStdin, Command, ExitStatus, Stdio},
+    sync::{
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use anyhow::{bail, Context, Result};
-use log::{error, info};
+use log::{debug, error, info, warn};
 
-use crate::args::{Parse, Parser};
+use super::ts::Psi;
+use crate::{
+    args::{self, Parse, Parser},
+    logger,
+    memory::{Kind, Memory, Tracked},
+    path_template::{Fields, PathTemplate},
+};
 
 #[derive(Debug)]
 pub struct PipeClosedError;
@@ -21,21 +40,72 @@ impl Display for PipeClosedError {
     }
 }
 
+impl PipeClosedError {
+    pub fn is_pipe_closed(error: &anyhow::Error) -> bool {
+        error
+            .downcast_ref::<io::Error>()
+            .and_then(io::Error::get_ref)
+            .is_some_and(|e| e.downcast_ref::<Self>().is_some())
+    }
+}
+
+#[derive(Debug)]
+pub struct PlayerLagError;
+
+impl std::error::Error for PlayerLagError {}
+
+impl Display for PlayerLagError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Player buffer is full, player is too slow")
+    }
+}
+
+impl PlayerLagError {
+    pub fn is_player_lag(error: &io::Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|e| e.downcast_ref::<Self>().is_some())
+    }
+}
+
+#[allow(clippy::struct_excessive_bools, reason = "command line switches")]
 #[derive(Clone, Debug)]
 pub struct Args {
     path: Option<String>,
-    pargs: Cow<'static, str>,
+    pargs: Vec<String>,
     quiet: bool,
     no_kill: bool,
+    buffer_size: usize,
+    chunk_size: usize,
+    buffer_fatal: bool,
+    exit_policy: ExitPolicy,
+    env: bool,
+    ensure_psi: bool,
+    delay_spawn: bool,
+    file_mode: bool,
+    file_path: Option<PathTemplate>,
+    file_initial: u64,
+    keep_file: bool,
 }
 
 impl Default for Args {
     fn default() -> Self {
         Self {
-            pargs: "-".into(),
+            pargs: vec!["-".to_owned()],
+            buffer_size: 32 * 1024 * 1024,
+            chunk_size: 256 * 1024,
+            file_initial: 2 * 1024 * 1024,
             path: Option::default(),
             quiet: bool::default(),
             no_kill: bool::default(),
+            buffer_fatal: bool::default(),
+            exit_policy: ExitPolicy::default(),
+            env: bool::default(),
+            ensure_psi: bool::default(),
+            delay_spawn: bool::default(),
+            file_mode: bool::default(),
+            file_path: Option::default(),
+            keep_file: bool::default(),
         }
     }
 }
@@ -43,27 +113,178 @@ impl Default for Args {
 impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
         parser.parse_opt_string_cfg(&mut self.path, "-p", "player")?;
-        parser.parse_cow_string_cfg(&mut self.pargs, "-a", "player-args")?;
+        parser.parse_fn_cfg(&mut self.pargs, "-a", "player-args", args::split_args)?;
         parser.parse_switch_or(&mut self.quiet, "-q", "--quiet")?;
         parser.parse_switch(&mut self.no_kill, "--no-kill")?;
+        parser.parse_fn(&mut self.buffer_size, "--player-buffer", |a| {
+            a.parse::<usize>()?
+                .checked_mul(1024 * 1024)
+                .context("Player buffer size is too large")
+        })?;
+        parser.parse_fn(&mut self.chunk_size, "--player-chunk", |a| {
+            a.parse::<usize>()?
+                .checked_mul(1024)
+                .context("Player chunk size is too large")
+        })?;
+        parser.parse_switch(&mut self.buffer_fatal, "--player-buffer-fatal")?;
+        parser.parse_fn(
+            &mut self.exit_policy,
+            "--player-exit-policy",
+            ExitPolicy::new,
+        )?;
+        parser.parse_switch(&mut self.env, "--player-env")?;
+        parser.parse_switch(&mut self.ensure_psi, "--ensure-psi")?;
+        parser.parse_switch(&mut self.delay_spawn, "--delay-player-spawn")?;
+        parser.parse_switch(&mut self.file_mode, "--player-file-mode")?;
+        parser.parse_fn(&mut self.file_path, "--player-file", |a| {
+            Ok(Some(PathTemplate::new(a)?))
+        })?;
+        parser.parse_fn(
+            &mut self.file_initial,
+            "--player-file-initial",
+            args::parse_size,
+        )?;
+        parser.parse_switch(&mut self.keep_file, "--keep-player-file")?;
 
         Ok(())
     }
 }
 
+impl Args {
+    pub const fn is_set(&self) -> bool {
+        self.path.is_some()
+    }
+
+    //player arguments as they would be passed to -a, none if they are the default
+    pub fn args(&self) -> Option<String> {
+        (self.pargs != Self::default().pargs).then(|| {
+            self.pargs
+                .iter()
+                .map(|a| args::quote_arg(a))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+    }
+}
+
+//What to do when the player process exits while its stdin may still be open
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitPolicy {
+    Ignore,
+    #[default]
+    Stop,
+    Restart,
+}
+
+impl ExitPolicy {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "ignore" => Ok(Self::Ignore),
+            "stop" => Ok(Self::Stop),
+            "restart" => Ok(Self::Restart),
+            _ => bail!("Invalid player exit policy: {arg}"),
+        }
+    }
+}
+
+//The stream being played, passed to the player as THC_* environment variables.
+//Off unless --player-env is set, the URLs contain signed access tokens.
+#[derive(Default, Clone, Debug)]
+pub struct StreamEnv {
+    vars: Vec<(&'static str, String)>,
+}
+
+impl StreamEnv {
+    pub fn new(
+        playlist_url: &str,
+        channel: &str,
+        quality: Option<&str>,
+        multivariant_url: Option<&str>,
+    ) -> Self {
+        let mut vars = vec![
+            ("THC_PLAYLIST_URL", playlist_url.to_owned()),
+            ("THC_CHANNEL", channel.to_owned()),
+        ];
+
+        if let Some(quality) = quality {
+            vars.push(("THC_QUALITY", quality.to_owned()));
+        }
+
+        if let Some(multivariant_url) = multivariant_url {
+            vars.push(("THC_MULTIVARIANT_URL", multivariant_url.to_owned()));
+        }
+
+        Self { vars }
+    }
+
+    pub fn channel(&self) -> &str {
+        self.var("THC_CHANNEL").unwrap_or_default()
+    }
+
+    pub fn quality(&self) -> Option<&str> {
+        self.var("THC_QUALITY")
+    }
+
+    fn var(&self, name: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
 pub struct Player {
-    stdin: ChildStdin,
-    process: Child,
-    no_kill: bool,
+    pipe: Option<Pipe>,
+    //written instead of the pipe with --player-file-mode
+    file: Option<PlayerFile>,
+    //None until the first segment with --delay-player-spawn,
+    //or until the file has --player-file-initial bytes with --player-file-mode
+    process: Option<Child>,
+    //init segment written before the delayed player was spawned
+    pending: Vec<u8>,
+    //writes batched into --player-chunk sized blocks, sent when full and after every segment
+    chunk: Vec<u8>,
+    //bytes sent to the pipe since the last take_sent()
+    sent: usize,
+    exited: bool,
+    args: Args,
+    env: StreamEnv,
+    psi: Option<Psi>,
+    memory: Arc<Memory>,
+
+    lag: Lag,
+    liveness: Liveness,
+    restarted: Option<Instant>,
 }
 
 impl Drop for Player {
     fn drop(&mut self) {
-        if !self.no_kill {
-            if let Err(e) = self.process.kill() {
-                error!("Failed to kill player: {e}");
+        //can't wait too long for a player that stopped reading
+        const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+        if self.exited {
+            debug!("Player already exited");
+            return;
+        }
+
+        //let the player consume the buffered tail of the stream
+        if self.pipe.as_ref().is_some_and(|p| !p.handle.is_finished()) {
+            let _ = self.send_chunk();
+        }
+        if let Some(pipe) = self.pipe.take() {
+            debug!("Flushing player buffer");
+            if !pipe.finish(FLUSH_TIMEOUT) {
+                warn!("Player didn't read the rest of the stream in time");
             }
         }
+
+        if self.args.no_kill {
+            return;
+        }
+
+        if let Some(Err(e)) = self.process.as_mut().map(Child::kill) {
+            error!("Failed to kill player: {e}");
+        }
     }
 }
 
@@ -72,80 +293,1076 @@ impl Write for Player {
         unreachable!();
     }
 
+    //sends the batched writes, called after every segment
     fn flush(&mut self) -> io::Result<()> {
-        unreachable!();
+        self.send_chunk()
     }
 
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.stdin.write_all(buf).map_err(|error| {
-            if error.kind() == BrokenPipe {
-                let _ = self.process.try_wait(); //reap pid
-                return io::Error::other(PipeClosedError);
-            }
+        if let Some(psi) = &mut self.psi {
+            psi.scan(buf);
+        }
 
-            error
-        })
+        if self.process.is_none() && self.file.is_none() && !self.exited {
+            self.pending.extend_from_slice(buf);
+            return Ok(());
+        }
+
+        if self.chunk.len() + buf.len() > self.args.chunk_size {
+            self.send_chunk()?;
+        }
+
+        self.chunk.extend_from_slice(buf);
+        if self.chunk.len() >= self.args.chunk_size {
+            self.send_chunk()?;
+        }
+
+        Ok(())
     }
 }
 
 impl Player {
-    pub fn spawn(args: &Args) -> Result<Option<Self>> {
+    pub fn spawn(args: &Args, env: &StreamEnv, memory: &Arc<Memory>) -> Result<Option<Self>> {
         let Some(path) = &args.path else {
             return Ok(None);
         };
 
-        info!("Opening player: {path} {}", args.pargs);
+        let file = if args.file_mode {
+            let fields = Fields::new(env.channel(), env.quality());
+            Some(PlayerFile::create(args, &fields)?)
+        } else {
+            None
+        };
+
+        let env = if args.env {
+            env.clone()
+        } else {
+            StreamEnv::default()
+        };
+
+        let (process, pipe) = if file.is_some() {
+            info!(
+                "Opening player once {} KB of the stream are written",
+                args.file_initial / 1024
+            );
+            (None, None)
+        } else if args.delay_spawn {
+            info!("Opening player once the stream starts");
+            (None, None)
+        } else {
+            let (process, pipe) = Self::open(path, args, &env, None, memory)?;
+            (Some(process), pipe)
+        };
+
+        Ok(Some(Self {
+            pipe,
+            file,
+            process,
+            pending: Vec::default(),
+            chunk: Vec::with_capacity(args.chunk_size),
+            sent: usize::default(),
+            exited: bool::default(),
+            args: args.clone(),
+            env,
+            psi: args.ensure_psi.then(Psi::default),
+            memory: memory.clone(),
+            lag: Lag::default(),
+            liveness: Liveness::new(),
+            restarted: Option::default(),
+        }))
+    }
+
+    //Called after every segment, catches players that exited without closing the pipe,
+    //e.g. wrapper scripts whose child inherited stdin
+    pub fn check_alive(&mut self) -> io::Result<()> {
+        if self.exited || self.liveness.checked.elapsed() < Liveness::INTERVAL {
+            return Ok(());
+        }
+        self.liveness.checked = Instant::now();
+
+        //a player reading a file can't be stalled by it
+        match &self.pipe {
+            Some(pipe) => self.liveness.check_stalled(pipe.queued.get()),
+            None if self.file.is_some() => (),
+            None => return Ok(()),
+        }
+
+        let Some(status) = self.try_wait()? else {
+            return Ok(());
+        };
+
+        self.exited = true;
+        match self.args.exit_policy {
+            ExitPolicy::Ignore => {
+                warn!("Player exited ({status}) but its input is still open, ignoring");
+                Ok(())
+            }
+            ExitPolicy::Stop => {
+                info!("Player exited ({status})");
+
+                //not joined, whatever inherited stdin may never read from it again
+                self.pipe = None;
+                Err(io::Error::other(PipeClosedError))
+            }
+            ExitPolicy::Restart => {
+                warn!("Player exited ({status})");
+                self.restart()
+            }
+        }
+    }
+
+    //Opens a player delayed by --delay-player-spawn, called before the first segment
+    //that isn't an init segment. Ad breaks aren't written, so that's the stream starting
+    pub fn ensure_spawned(&mut self) -> io::Result<()> {
+        //a player that failed to open isn't retried, a recording goes on without it
+        if self.process.is_some() || self.file.is_some() || self.exited {
+            return Ok(());
+        }
+
+        self.launch()?;
+        let header = mem::take(&mut self.pending);
+        if header.is_empty() {
+            return Ok(());
+        }
+
+        debug!(
+            "Sending {} bytes of init segment to the player",
+            header.len()
+        );
+        self.write_all(&header)
+    }
+
+    //bytes that reached the player pipe, batched writes only count once they're sent
+    pub fn take_sent(&mut self) -> usize {
+        mem::replace(&mut self.sent, 0)
+    }
+
+    pub const fn is_spawned(&self) -> bool {
+        self.process.is_some()
+    }
+
+    pub fn passthrough(args: &mut Args, url: &str, env: &StreamEnv) -> Result<()> {
+        info!("Passing through playlist URL to player");
+        //the player reads the playlist itself, there's no stream to wait for
+        args.delay_spawn = false;
+        args.file_mode = false;
+        replace_input(&mut args.pargs, url);
+
+        let Some(mut player) = Self::spawn(args, env, &Arc::default())? else {
+            bail!("No player set");
+        };
+
+        player
+            .process
+            .as_mut()
+            .context("Missing player process")?
+            .wait()
+            .context("Failed to wait for player process")?;
+
+        Ok(())
+    }
+
+    //the pipe is None when the player reads the file at input instead of its stdin
+    fn open(
+        path: &str,
+        args: &Args,
+        env: &StreamEnv,
+        input: Option<&Path>,
+        memory: &Arc<Memory>,
+    ) -> Result<(Child, Option<Pipe>)> {
+        let mut pargs = args.pargs.clone();
+        if let Some(input) = input {
+            replace_input(&mut pargs, &input.to_string_lossy());
+        }
+
+        info!("Opening player: {path} {}", pargs.join(" "));
         let mut command = Command::new(path);
         command
-            .args(args.pargs.split_whitespace())
-            .stdin(Stdio::piped());
+            .args(&pargs)
+            .envs(env.vars.iter().map(|(k, v)| (k, v)))
+            .stdin(if input.is_some() {
+                Stdio::null()
+            } else {
+                Stdio::piped()
+            });
 
         if args.quiet {
             command.stdout(Stdio::null()).stderr(Stdio::null());
         }
 
         let mut process = command.spawn().context("Failed to open player")?;
+        if input.is_some() {
+            return Ok((process, None));
+        }
+
         let stdin = process
             .stdin
             .take()
             .context("Failed to open player stdin")?;
 
-        Ok(Some(Self {
-            stdin,
-            process,
-            no_kill: args.no_kill,
-        }))
+        Ok((process, Some(Pipe::spawn(stdin, memory)?)))
     }
 
-    pub fn passthrough(args: &mut Args, url: &str) -> Result<()> {
-        info!("Passing through playlist URL to player");
-        if args.pargs.split_whitespace().any(|a| a == "-") {
-            args.pargs = args
-                .pargs
-                .split_whitespace()
-                .map(|a| {
-                    if a == "-" {
-                        url.to_owned()
-                    } else {
-                        a.to_owned()
-                    }
-                })
-                .collect::<Vec<String>>()
-                .join(" ")
-                .into();
-        } else {
-            args.pargs = format!("{} {url}", args.pargs).into();
+    fn launch(&mut self) -> io::Result<()> {
+        let Some(path) = &self.args.path else {
+            return Err(io::Error::other(PipeClosedError));
+        };
+
+        let input = self.file.as_ref().map(|f| f.path.as_path());
+        let (process, pipe) = Self::open(path, &self.args, &self.env, input, &self.memory)
+            .map_err(|e| {
+                self.exited = true;
+                self.pending = Vec::new();
+                io::Error::other(format!("{e:#}"))
+            })?;
+        self.process = Some(process);
+        self.pipe = pipe;
+        self.liveness = Liveness::new();
+
+        Ok(())
+    }
+
+    fn restart(&mut self) -> io::Result<()> {
+        const MIN_INTERVAL: Duration = Duration::from_secs(10);
+
+        self.pipe = None;
+        if self.restarted.is_some_and(|t| t.elapsed() < MIN_INTERVAL) {
+            error!("Player exited again right after restarting, not restarting");
+            return Err(io::Error::other(PipeClosedError));
         }
 
-        let Some(mut player) = Self::spawn(args)? else {
-            bail!("No player set");
+        let Some(path) = &self.args.path else {
+            return Err(io::Error::other(PipeClosedError));
         };
 
-        player
-            .process
-            .wait()
-            .context("Failed to wait for player process")?;
+        if let Some(process) = self.process.as_mut().filter(|_| !self.exited) {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+
+        info!("Restarting player");
+        let input = self.file.as_ref().map(|f| f.path.as_path());
+        match Self::open(path, &self.args, &self.env, input, &self.memory) {
+            Ok((process, pipe)) => {
+                self.process = Some(process);
+                self.pipe = pipe;
+                self.exited = false;
+                self.lag = Lag::default();
+                self.liveness = Liveness::new();
+                self.restarted = Some(Instant::now());
+
+                //the new player starts mid-stream, the tables are repeated only every few seconds.
+                //A file is read from the start
+                let Some(packets) = self
+                    .psi
+                    .as_ref()
+                    .filter(|_| self.file.is_none())
+                    .and_then(Psi::packets)
+                else {
+                    return Ok(());
+                };
+
+                debug!("Sending PAT and PMT to the restarted player");
+                self.write_all(&packets)
+            }
+            Err(e) => {
+                error!("Failed to restart player: {e:#}");
+                Err(io::Error::other(PipeClosedError))
+            }
+        }
+    }
+
+    //Drops the batched part of the segment being written,
+    //earlier chunks of it were already sent and stay with the player
+    pub fn discard_segment(&mut self) {
+        self.chunk.clear();
+    }
+
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
 
+        if let Some(file) = &mut self.file {
+            let len = self.chunk.len();
+            let result = file.append(&self.chunk);
+            self.chunk.clear();
+            result?;
+
+            self.sent += len;
+            if self.process.is_none() && !self.exited && file.written >= self.args.file_initial {
+                return self.launch();
+            }
+
+            return Ok(());
+        }
+
+        let Some(pipe) = &self.pipe else {
+            self.chunk.clear();
+            return Err(io::Error::other(PipeClosedError));
+        };
+
+        if pipe.handle.is_finished() {
+            self.chunk.clear();
+            return self.pipe_closed();
+        }
+
+        let len = self.chunk.len();
+        let queued = usize::try_from(pipe.queued.get()).unwrap_or(usize::MAX);
+        self.lag.update(len, queued, self.args.buffer_size);
+
+        if queued + len > self.args.buffer_size {
+            self.chunk.clear();
+            if self.args.buffer_fatal {
+                return Err(io::Error::other(PlayerLagError));
+            }
+
+            self.lag.dropped(len);
+            return Ok(());
+        }
+
+        let chunk = mem::replace(&mut self.chunk, Vec::with_capacity(self.args.chunk_size));
+        pipe.queued.add(len);
+        if pipe.chunk_tx.send(chunk).is_err() {
+            return self.pipe_closed();
+        }
+
+        self.liveness.sent += len as u64;
+        self.sent += len;
         Ok(())
     }
+
+    //fMP4 has no PSI tables
+    pub fn disable_psi(&mut self) {
+        self.psi = None;
+    }
+
+    fn pipe_closed(&mut self) -> io::Result<()> {
+        let error = self.close_pipe();
+        let closed = error
+            .get_ref()
+            .is_some_and(|e| e.downcast_ref::<PipeClosedError>().is_some());
+
+        if closed && self.args.exit_policy == ExitPolicy::Restart {
+            return self.restart();
+        }
+
+        Err(error)
+    }
+
+    //Some Windows launchers (.bat, .lnk) make writes fail with ERROR_NO_DATA instead
+    //of a broken pipe, or with InvalidInput once the player exited
+    fn is_closed(&mut self, error: &io::Error) -> bool {
+        const ERROR_BROKEN_PIPE: i32 = 109;
+        const ERROR_NO_DATA: i32 = 232;
+
+        if error.kind() == BrokenPipe {
+            return true;
+        }
+
+        if !cfg!(windows) {
+            return false;
+        }
+
+        matches!(
+            error.raw_os_error(),
+            Some(ERROR_BROKEN_PIPE | ERROR_NO_DATA)
+        ) || (error.kind() == InvalidInput && self.try_wait().is_ok_and(|s| s.is_some()))
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.process.as_mut().map_or(Ok(None), Child::try_wait)
+    }
+
+    fn close_pipe(&mut self) -> io::Error {
+        let Some(pipe) = self.pipe.take() else {
+            return io::Error::other(PipeClosedError);
+        };
+
+        drop(pipe.chunk_tx);
+        match pipe.handle.join() {
+            Ok(Err(e)) if !self.is_closed(&e) => e,
+            Err(p) => io::Error::other(format!(
+                "Player pipe panicked: {}",
+                logger::panic_message(&*p)
+            )),
+            _ => {
+                //reap pid
+                self.exited = self.try_wait().is_ok_and(|s| s.is_some());
+                io::Error::other(PipeClosedError)
+            }
+        }
+    }
+}
+
+//Growing file read by the player with --player-file-mode, for players that can only open files.
+//Removed when the player is closed unless --keep-player-file is set
+struct PlayerFile {
+    file: File,
+    path: PathBuf,
+    written: u64,
+    keep: bool,
+}
+
+impl Drop for PlayerFile {
+    fn drop(&mut self) {
+        if self.keep {
+            info!("Kept player file: {}", self.path.display());
+        } else if let Err(e) = fs::remove_file(&self.path) {
+            error!("Failed to remove player file {}: {e}", self.path.display());
+        }
+    }
+}
+
+impl PlayerFile {
+    fn create(args: &Args, fields: &Fields) -> Result<Self> {
+        let (file, path) = args
+            .file_path
+            .as_ref()
+            .map_or_else(
+                || {
+                    let path =
+                        env::temp_dir().join(format!("twitch-hls-client-{}.ts", process::id()));
+                    File::create_new(&path).map(|f| (f, path))
+                },
+                |t| t.create_new(&t.expand(fields)),
+            )
+            .context("Failed to create player file")?;
+        info!("Writing player input to: {}", path.display());
+
+        Ok(Self {
+            file,
+            path,
+            written: u64::default(),
+            keep: args.keep_file,
+        })
+    }
+
+    fn append(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all(buf)?;
+        self.written += buf.len() as u64;
+
+        Ok(())
+    }
+}
+
+//Writes to the player's stdin on its own thread so a slow player can't stall the worker
+struct Pipe {
+    chunk_tx: Sender<Vec<u8>>,
+    handle: JoinHandle<io::Result<()>>,
+    queued: Arc<Tracked>,
+}
+
+impl Pipe {
+    fn spawn(mut stdin: ChildStdin, memory: &Arc<Memory>) -> Result<Self> {
+        let (chunk_tx, chunk_rx) = mpsc::channel::<Vec<u8>>();
+        let queued = Arc::new(Tracked::new(memory, Kind::Player));
+
+        let handle = logger::spawn("player", {
+            let queued = queued.clone();
+            move || -> io::Result<()> {
+                for chunk in chunk_rx {
+                    let result = stdin.write_all(&chunk);
+                    queued.sub(chunk.len());
+
+                    result?;
+                }
+
+                stdin.flush()
+            }
+        })
+        .context("Failed to spawn player pipe")?;
+
+        Ok(Self {
+            chunk_tx,
+            handle,
+            queued,
+        })
+    }
+
+    //closes the pipe once everything sent was written, false if that took longer than timeout
+    fn finish(self, timeout: Duration) -> bool {
+        drop(self.chunk_tx);
+
+        let start = Instant::now();
+        while !self.handle.is_finished() {
+            if start.elapsed() >= timeout {
+                return false;
+            }
+
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let _ = self.handle.join();
+        true
+    }
+}
+
+//Tracks whether the player is still reading, its stdin can outlive it
+struct Liveness {
+    checked: Instant,
+    sent: u64,
+    consumed: u64,
+    stalled: bool,
+}
+
+impl Liveness {
+    const INTERVAL: Duration = Duration::from_secs(5);
+
+    fn new() -> Self {
+        Self {
+            checked: Instant::now(),
+            sent: u64::default(),
+            consumed: u64::default(),
+            stalled: bool::default(),
+        }
+    }
+
+    fn check_stalled(&mut self, queued: u64) {
+        let consumed = self.sent - queued;
+        if queued > 0 && consumed == self.consumed {
+            if !self.stalled {
+                warn!(
+                    "Player hasn't read anything in {}s, it may have exited without closing its input",
+                    Self::INTERVAL.as_secs()
+                );
+                self.stalled = true;
+            }
+        } else {
+            self.stalled = false;
+        }
+
+        self.consumed = consumed;
+    }
+}
+
+#[derive(Default)]
+struct Lag {
+    start: Option<Instant>,
+    received: u64,
+
+    dropped: u64,
+    last_warning: Option<Instant>,
+}
+
+impl Lag {
+    const WARNING_INTERVAL: Duration = Duration::from_secs(30);
+
+    fn update(&mut self, len: usize, queued: usize, buffer_size: usize) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        self.received += len as u64;
+
+        if queued < buffer_size / 2
+            || self
+                .last_warning
+                .is_some_and(|t| t.elapsed() < Self::WARNING_INTERVAL)
+        {
+            return;
+        }
+
+        //estimate how far behind the player is using the average incoming bitrate
+        let buffered_secs = (queued as u128 * start.elapsed().as_millis())
+            / (u128::from(self.received) * 1000).max(1);
+
+        warn!("Player is reading slower than realtime ({buffered_secs}s buffered)");
+        if self.dropped > 0 {
+            warn!(
+                "Dropped {} KB of stream data, player buffer is full",
+                self.dropped / 1024
+            );
+            self.dropped = 0;
+        }
+
+        self.last_warning = Some(Instant::now());
+    }
+
+    fn dropped(&mut self, len: usize) {
+        self.dropped += len as u64;
+    }
+}
+
+//Replaces - in the player arguments with the input, or appends it if there's none.
+//Replaced as a whole argument, the input is never split
+fn replace_input(pargs: &mut Vec<String>, input: &str) {
+    if pargs.iter().any(|a| a == "-") {
+        for arg in pargs.iter_mut().filter(|a| *a == "-") {
+            input.clone_into(arg);
+        }
+    } else {
+        pargs.push(input.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use crate::{
+        output::{tests::writer, ts, Sinks, Writer},
+        temp_dir::TempDir,
+    };
+
+    fn parsed(args: &[&str]) -> Result<Args> {
+        let mut player_args = Args::default();
+        player_args.parse(&mut Parser::from_args(args))?;
+
+        Ok(player_args)
+    }
+
+    #[cfg(unix)]
+    fn player(args: &[&str]) -> Player {
+        Player::spawn(
+            &parsed(args).unwrap(),
+            &StreamEnv::default(),
+            &Arc::new(Memory::new(None)),
+        )
+        .unwrap()
+        .unwrap()
+    }
+
+    //a player that never reads its input, killed before the player is dropped
+    //so the flush doesn't wait for it
+    #[cfg(unix)]
+    fn stuck_player(args: &[&str]) -> Player {
+        let args = [
+            &[
+                "-p",
+                "sh",
+                "-a",
+                "-c 'exec sleep 30'",
+                "--player-buffer",
+                "1",
+                "--player-chunk",
+                "64",
+            ],
+            args,
+        ]
+        .concat();
+
+        player(&args)
+    }
+
+    #[cfg(unix)]
+    fn write_segments(player: &mut Player, count: usize) -> io::Result<()> {
+        let segment = vec![0x47; 64 * 1024];
+        for _ in 0..count {
+            player.write_all(&segment)?;
+            player.flush()?;
+        }
+
+        Ok(())
+    }
+
+    //a player that copies its input to output
+    #[cfg(unix)]
+    fn copying_player(output: &Path, args: &[&str]) -> Player {
+        let command = format!("-c 'cat > {}'", output.display());
+        player(&[&["-p", "sh", "-a", &command, "--no-kill"], args].concat())
+    }
+
+    //what a player wrote, once it's at least len bytes
+    #[cfg(unix)]
+    fn received(path: &Path, len: usize) -> Vec<u8> {
+        for _ in 0..500 {
+            if let Some(data) = fs::read(path).ok().filter(|d| d.len() >= len) {
+                return data;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("Player didn't receive {len} bytes");
+    }
+
+    #[test]
+    fn lag() {
+        let mut lag = Lag::default();
+
+        lag.update(1024, 0, 4096);
+        lag.dropped(512);
+        assert!(lag.last_warning.is_none());
+        assert_eq!(lag.received, 1024);
+
+        //half the buffer is queued, the dropped bytes are reported once
+        lag.update(1024, 2048, 4096);
+        assert!(lag.last_warning.is_some());
+        assert_eq!(lag.dropped, 0);
+
+        //warnings are rate limited, dropped bytes add up until the next one
+        lag.dropped(512);
+        lag.update(1024, 4096, 4096);
+        assert_eq!(lag.dropped, 512);
+        assert_eq!(lag.received, 3072);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn full_buffer_drops_chunks() {
+        let mut player = stuck_player(&[]);
+
+        write_segments(&mut player, 64).unwrap();
+        let sent = player.take_sent();
+        assert!(sent > 0 && sent <= 2 * 1024 * 1024, "{sent}");
+        assert!(player.lag.dropped > 0);
+
+        player.process.as_mut().unwrap().kill().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn full_buffer_is_fatal() {
+        let mut player = stuck_player(&["--player-buffer-fatal"]);
+
+        let error = write_segments(&mut player, 64).unwrap_err();
+        assert!(PlayerLagError::is_player_lag(&error));
+        assert_eq!(player.lag.dropped, 0);
+
+        player.process.as_mut().unwrap().kill().unwrap();
+    }
+
+    //a player that exits without anything closing its input, as if a child inherited it
+    #[cfg(unix)]
+    fn exited_player(policy: &str) -> Player {
+        let mut player = player(&[
+            "-p",
+            "sh",
+            "-a",
+            "-c 'exit 3'",
+            "--player-exit-policy",
+            policy,
+        ]);
+        player.process.as_mut().unwrap().wait().unwrap();
+
+        player
+    }
+
+    //as if the last check was an interval ago
+    #[cfg(unix)]
+    fn check_alive_now(player: &mut Player) -> io::Result<()> {
+        player.liveness.checked = Instant::now().checked_sub(Liveness::INTERVAL).unwrap();
+        player.check_alive()
+    }
+
+    #[test]
+    fn exit_policies() {
+        assert_eq!(ExitPolicy::new("ignore").unwrap(), ExitPolicy::Ignore);
+        assert_eq!(ExitPolicy::new("stop").unwrap(), ExitPolicy::Stop);
+        assert_eq!(ExitPolicy::new("restart").unwrap(), ExitPolicy::Restart);
+        assert!(ExitPolicy::new("Stop").is_err());
+        assert_eq!(Args::default().exit_policy, ExitPolicy::Stop);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_is_checked_every_interval() {
+        let mut player = exited_player("stop");
+
+        player.check_alive().unwrap();
+        assert!(!player.exited);
+        assert!(check_alive_now(&mut player).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_policy_stop() {
+        let mut player = exited_player("stop");
+
+        let error = anyhow::Error::from(check_alive_now(&mut player).unwrap_err());
+        assert!(PipeClosedError::is_pipe_closed(&error));
+        assert!(player.exited);
+        assert!(player.pipe.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_policy_ignore() {
+        let mut player = exited_player("ignore");
+
+        check_alive_now(&mut player).unwrap();
+        assert!(player.exited);
+        assert!(player.pipe.is_some());
+
+        //not reported again
+        check_alive_now(&mut player).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exit_policy_restart() {
+        let mut player = exited_player("restart");
+
+        check_alive_now(&mut player).unwrap();
+        assert!(!player.exited);
+        assert!(player.restarted.is_some());
+        assert!(player.pipe.is_some());
+
+        //the restarted player exits right away too
+        player.process.as_mut().unwrap().wait().unwrap();
+        let error = anyhow::Error::from(check_alive_now(&mut player).unwrap_err());
+        assert!(PipeClosedError::is_pipe_closed(&error));
+    }
+
+    #[test]
+    fn player_args_are_split_once() {
+        let args = parsed(&[
+            "-a",
+            r#"--title "foo --script=evil.lua" --x='a b'  C:\mpv\mpv.exe "q\"uote" """#,
+        ])
+        .unwrap();
+        assert_eq!(
+            args.pargs,
+            [
+                "--title",
+                "foo --script=evil.lua",
+                "--x=a b",
+                r"C:\mpv\mpv.exe",
+                "q\"uote",
+                "",
+            ],
+        );
+
+        //quoted back the way they were split
+        let quoted = args.args().unwrap();
+        assert_eq!(parsed(&["-a", &quoted]).unwrap().pargs, args.pargs);
+        assert!(Args::default().args().is_none());
+
+        assert!(parsed(&["-a", "--title \"foo"]).is_err());
+    }
+
+    #[test]
+    fn input_is_one_argument() {
+        let url = "http://127.0.0.1/a b.m3u8?title=foo --script=evil.lua";
+
+        let mut pargs = vec!["--cache=yes".to_owned(), "-".to_owned(), "-".to_owned()];
+        replace_input(&mut pargs, url);
+        assert_eq!(pargs, ["--cache=yes", url, url]);
+
+        let mut pargs = vec!["--cache=yes".to_owned(), "--x=-".to_owned()];
+        replace_input(&mut pargs, url);
+        assert_eq!(pargs, ["--cache=yes", "--x=-", url]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn chunks_are_batched() {
+        let dir = TempDir::new("player-chunk");
+        let output = dir.join("received.ts");
+        let mut player = copying_player(&output, &["--player-chunk", "1"]);
+
+        //nothing is sent until a write doesn't fit in the chunk
+        player.write_all(&[1; 300]).unwrap();
+        player.write_all(&[2; 300]).unwrap();
+        assert_eq!(player.take_sent(), 0);
+        player.write_all(&[3; 600]).unwrap();
+        assert_eq!(player.take_sent(), 600);
+        assert_eq!(player.take_sent(), 0);
+
+        //a full chunk is sent right away, the rest after the segment
+        player.write_all(&[4; 1024]).unwrap();
+        assert_eq!(player.take_sent(), 600 + 1024);
+        player.write_all(&[5; 10]).unwrap();
+        assert_eq!(player.take_sent(), 0);
+        player.flush().unwrap();
+        assert_eq!(player.take_sent(), 10);
+
+        //the discarded part of a segment is never sent
+        player.write_all(&[6; 10]).unwrap();
+        player.discard_segment();
+        player.flush().unwrap();
+        assert_eq!(player.take_sent(), 0);
+        drop(player);
+
+        let expected = [&[1; 300][..], &[2; 300], &[3; 600], &[4; 1024], &[5; 10]].concat();
+        assert_eq!(received(&output, expected.len()), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unbatched_writes_are_sent_right_away() {
+        let dir = TempDir::new("player-unbatched");
+        let output = dir.join("received.ts");
+        let mut player = copying_player(&output, &["--player-chunk", "0"]);
+
+        player.write_all(&[1; 100]).unwrap();
+        assert_eq!(player.take_sent(), 100);
+        player.write_all(&[2; 50]).unwrap();
+        player.write_all(&[3; 50]).unwrap();
+        assert_eq!(player.take_sent(), 100);
+        player.flush().unwrap();
+        assert_eq!(player.take_sent(), 0);
+        drop(player);
+
+        assert_eq!(received(&output, 200).len(), 200);
+    }
+
+    #[cfg(unix)]
+    fn delayed_writer(output: &Path) -> Writer {
+        let command = format!("-c 'cat > {}'", output.display());
+        writer(&[
+            "-p",
+            "sh",
+            "-a",
+            &command,
+            "--no-kill",
+            "--delay-player-spawn",
+            "--keepalive-during-ads",
+            "null-packets",
+        ])
+    }
+
+    #[cfg(unix)]
+    fn is_spawned(writer: &Writer) -> bool {
+        let Sinks::Player(player) = &writer.sinks else {
+            unreachable!();
+        };
+
+        player.is_spawned()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delayed_spawn_sends_header_first() {
+        let dir = TempDir::new("player-delayed-header");
+        let output = dir.join("received.mp4");
+        let mut writer = delayed_writer(&output);
+
+        //neither the init segment nor keepalive packets during pre-roll ads open the player
+        writer.start_header();
+        writer.write_all(b"init").unwrap();
+        writer.flush().unwrap();
+        writer.keepalive().unwrap();
+        assert!(!is_spawned(&writer));
+
+        writer.set_segment(1, Duration::from_secs(2), None);
+        writer.write_all(b"segment").unwrap();
+        assert!(is_spawned(&writer));
+        writer.write_all(b" end").unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(received(&output, 15), b"initsegment end");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn delayed_spawn_starts_with_psi() {
+        let dir = TempDir::new("player-delayed-psi");
+        let output = dir.join("received.ts");
+        let mut writer = delayed_writer(&output);
+
+        writer.keepalive().unwrap();
+        assert!(!is_spawned(&writer));
+
+        //the stream's own PAT is the first packet the player reads, not keepalive packets
+        let mut pat = [0xff; 188];
+        pat[..4].copy_from_slice(&[0x47, 0x40, 0x00, 0x10]);
+        let segment = [&pat[..], &[0x47; 188]].concat();
+        writer.set_segment(1, Duration::from_secs(2), None);
+        writer.write_all(&segment).unwrap();
+        writer.flush().unwrap();
+        writer.keepalive().unwrap();
+        drop(writer);
+
+        let received = received(&output, segment.len());
+        assert_eq!(received[..segment.len()], segment);
+        assert_eq!(received[segment.len()..], ts::null_packets());
+    }
+
+    //a player that appends the file it's given to output
+    #[cfg(unix)]
+    fn file_player(dir: &TempDir, args: &[&str]) -> Player {
+        let command = format!(r#"-c 'cat "$0" >> {}'"#, dir.path_str("received.ts"));
+        let file = dir.path_str("player.ts");
+        player(
+            &[
+                &[
+                    "-p",
+                    "sh",
+                    "-a",
+                    &command,
+                    "--no-kill",
+                    "--player-file-mode",
+                    "--player-file",
+                    &file,
+                    "--player-chunk",
+                    "0",
+                ],
+                args,
+            ]
+            .concat(),
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn player_file_is_opened_once_initial_size_is_written() {
+        let dir = TempDir::new("player-file-initial");
+        let mut player = file_player(&dir, &["--player-file-initial", "1k"]);
+
+        player.write_all(&[1; 600]).unwrap();
+        player.flush().unwrap();
+        assert!(!player.is_spawned());
+        assert_eq!(fs::read(dir.join("player.ts")).unwrap(), [1; 600]);
+
+        player.write_all(&[2; 600]).unwrap();
+        assert!(player.is_spawned());
+        assert_eq!(player.take_sent(), 1200);
+        player.process.as_mut().unwrap().wait().unwrap();
+
+        //the player read the file from the start
+        assert_eq!(
+            received(&dir.join("received.ts"), 1200),
+            [[1; 600], [2; 600]].concat()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn restarted_player_reads_file_from_start() {
+        let dir = TempDir::new("player-file-restart");
+        let mut player = file_player(
+            &dir,
+            &[
+                "--player-file-initial",
+                "100",
+                "--player-exit-policy",
+                "restart",
+                "--ensure-psi",
+            ],
+        );
+
+        let mut pat = [0xff; 188];
+        pat[..4].copy_from_slice(&[0x47, 0x40, 0x00, 0x10]);
+        player.write_all(&pat).unwrap();
+        player.process.as_mut().unwrap().wait().unwrap();
+        player.write_all(&[0x47; 188]).unwrap();
+
+        check_alive_now(&mut player).unwrap();
+        assert!(player.restarted.is_some());
+        player.process.as_mut().unwrap().wait().unwrap();
+
+        //the whole file again, nothing is written to it for the restart
+        let file = [&pat[..], &[0x47; 188]].concat();
+        assert_eq!(fs::read(dir.join("player.ts")).unwrap(), file);
+        assert_eq!(
+            received(&dir.join("received.ts"), 188 + file.len()),
+            [&pat[..], &file].concat()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn player_file_is_removed() {
+        let dir = TempDir::new("player-file-removed");
+        let mut player = file_player(&dir, &[]);
+        player.write_all(&[1; 100]).unwrap();
+        assert!(dir.join("player.ts").exists());
+        drop(player);
+        assert_eq!(dir.entries(), Vec::<String>::new());
+
+        let mut player = file_player(&dir, &["--keep-player-file"]);
+        player.write_all(&[1; 100]).unwrap();
+        drop(player);
+        assert_eq!(fs::read(dir.join("player.ts")).unwrap(), [1; 100]);
+    }
 }
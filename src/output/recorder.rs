@@ -1,30 +1,73 @@
 use std::{
-    fs::File,
-    io::{self, Write},
+    fs::{File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
 };
 
-use anyhow::Result;
-use log::info;
+use anyhow::{bail, Context, Result};
+use fs4::fs_std::FileExt;
+use log::{error, info};
 
-use crate::args::{Parse, Parser};
+use crate::{
+    args::{Parse, Parser},
+    path_template::{Fields, PathTemplate},
+};
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub struct Args {
-    path: Option<String>,
+    path: Option<PathTemplate>,
     overwrite: bool,
+    no_lock: bool,
+    buffer_size: usize,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            buffer_size: 2 * 1024 * 1024,
+            path: Option::default(),
+            overwrite: bool::default(),
+            no_lock: bool::default(),
+        }
+    }
 }
 
 impl Parse for Args {
     fn parse(&mut self, parser: &mut Parser) -> Result<()> {
-        parser.parse_opt_string_cfg(&mut self.path, "-r", "record")?;
+        parser.parse_fn_cfg(&mut self.path, "-r", "record", |a| {
+            Ok(Some(PathTemplate::new(a)?))
+        })?;
         parser.parse_switch(&mut self.overwrite, "--overwrite")?;
+        parser.parse_switch(&mut self.no_lock, "--no-lock")?;
+        parser.parse_fn(&mut self.buffer_size, "--record-buffer", |a| {
+            a.parse::<usize>()?
+                .checked_mul(1024 * 1024)
+                .context("Record buffer size is too large")
+        })?;
 
         Ok(())
     }
 }
 
+impl Args {
+    pub const fn is_set(&self) -> bool {
+        self.path.is_some()
+    }
+}
+
+//Buffers writes in userspace until the end of each segment to avoid a syscall per chunk
 pub struct Recorder {
     file: File,
+    buffer: Vec<u8>,
+    //end of the last complete segment, None if the file can't be cut back (pipes and devices)
+    segment_start: Option<u64>,
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            error!("Failed to flush recording: {e}");
+        }
+    }
 }
 
 impl Write for Recorder {
@@ -33,29 +76,205 @@ impl Write for Recorder {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.file.flush()
+        self.flush_buffer()?;
+        self.file.flush()?;
+        if let Some(start) = &mut self.segment_start {
+            *start = self.file.stream_position()?;
+        }
+
+        Ok(())
     }
 
     fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
-        self.file.write_all(buf)
+        if self.buffer.len() + buf.len() > self.buffer.capacity() {
+            self.flush_buffer()?;
+        }
+
+        if buf.len() >= self.buffer.capacity() {
+            return self.file.write_all(buf);
+        }
+
+        self.buffer.extend_from_slice(buf);
+        Ok(())
     }
 }
 
 impl Recorder {
-    pub fn new(args: &Args) -> Result<Option<Self>> {
-        let Some(path) = &args.path else {
+    pub fn new(args: &Args, fields: &Fields) -> Result<Option<Self>> {
+        let Some(template) = &args.path else {
             return Ok(None);
         };
 
+        let mut path = template.expand(fields);
+        let file = if args.overwrite {
+            //truncated only once locked, another instance may still be recording to it
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?
+        } else {
+            let (file, created) = template.create_new(&path)?;
+            path = created;
+            file
+        };
+
+        let path = path.display();
         info!("Recording to: {path}");
-        if args.overwrite {
-            return Ok(Some(Self {
-                file: File::create(path)?,
-            }));
+
+        //pipes and devices like /dev/null are meant to be shared
+        let is_file = file.metadata()?.is_file();
+        if !args.no_lock && is_file {
+            let locked = file.try_lock_exclusive().with_context(|| {
+                format!("Failed to lock recording file {path} (use --no-lock to skip)")
+            })?;
+            if !locked {
+                bail!("Recording file is in use by another process: {path}");
+            }
+        }
+
+        if args.overwrite && is_file {
+            file.set_len(0)?;
         }
 
         Ok(Some(Self {
-            file: File::create_new(path)?,
+            file,
+            buffer: Vec::with_capacity(args.buffer_size),
+            segment_start: is_file.then_some(0),
         }))
     }
+
+    //Drops the part of the segment written since the last flush. What went past the buffer
+    //is cut off a regular file, a pipe has already passed it on
+    pub fn discard_segment(&mut self) -> io::Result<()> {
+        self.buffer.clear();
+        if let Some(start) = self.segment_start {
+            self.file.set_len(start)?;
+            self.file.seek(SeekFrom::Start(start))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.file.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn args(path: &str) -> Args {
+        Args {
+            path: Some(PathTemplate::new(path).unwrap()),
+            overwrite: true,
+            ..Args::default()
+        }
+    }
+
+    #[test]
+    fn second_handle_cant_lock() {
+        let dir = TempDir::new("recorder-lock");
+        let path = dir.join("recording.ts");
+        let args = args(path.to_str().unwrap());
+        let fields = Fields::new("channel", None);
+
+        let first = Recorder::new(&args, &fields).unwrap();
+        assert!(first.is_some());
+        let Err(error) = Recorder::new(&args, &fields) else {
+            panic!("second recorder took the lock");
+        };
+        assert!(error.to_string().contains("in use by another process"));
+
+        drop(first);
+        assert!(Recorder::new(&args, &fields).unwrap().is_some());
+    }
+
+    #[test]
+    fn discarded_segment_is_cut_off() {
+        let dir = TempDir::new("recorder-cut");
+        let path = dir.join("recording.ts");
+        let mut args = args(path.to_str().unwrap());
+        args.buffer_size = 4;
+        let mut recorder = Recorder::new(&args, &Fields::new("channel", None))
+            .unwrap()
+            .unwrap();
+
+        recorder.write_all(b"first").unwrap();
+        recorder.flush().unwrap();
+        recorder.write_all(b"partial").unwrap(); //past the buffer, already in the file
+        recorder.write_all(b"xy").unwrap(); //still buffered
+        recorder.discard_segment().unwrap();
+        recorder.write_all(b"next").unwrap();
+        recorder.flush().unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"firstnext");
+        drop(recorder);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn devices_are_shared() {
+        let args = args("/dev/null");
+        let fields = Fields::new("channel", None);
+
+        let first = Recorder::new(&args, &fields).unwrap();
+        let second = Recorder::new(&args, &fields).unwrap();
+        assert!(first.is_some() && second.is_some());
+    }
+
+    #[test]
+    fn writes_are_buffered_until_flush() {
+        let dir = TempDir::new("recorder-buffer");
+        let path = dir.join("recording.ts");
+        let mut args = args(path.to_str().unwrap());
+        args.buffer_size = 8;
+        let mut recorder = Recorder::new(&args, &Fields::new("channel", None))
+            .unwrap()
+            .unwrap();
+
+        recorder.write_all(b"abc").unwrap();
+        recorder.write_all(b"def").unwrap();
+        assert!(fs::read(&path).unwrap().is_empty());
+
+        //a write that doesn't fit sends the buffer first
+        recorder.write_all(b"ghi").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abcdef");
+
+        //one at least as large as the buffer goes straight through, after what's buffered
+        recorder.write_all(b"0123456789").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abcdefghi0123456789");
+
+        recorder.write_all(b"end").unwrap();
+        recorder.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abcdefghi0123456789end");
+
+        recorder.write_all(b"tail").unwrap();
+        drop(recorder);
+        assert_eq!(fs::read(&path).unwrap(), b"abcdefghi0123456789endtail");
+    }
+
+    #[test]
+    fn buffer_size() {
+        let mut args = Args::default();
+        args.parse(&mut Parser::from_args(&["--record-buffer", "4"]))
+            .unwrap();
+        assert_eq!(args.buffer_size, 4 * 1024 * 1024);
+
+        assert!(Args::default()
+            .parse(&mut Parser::from_args(&[
+                "--record-buffer",
+                &usize::MAX.to_string()
+            ]))
+            .is_err());
+    }
 }
@@ -0,0 +1,362 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, BufRead, Write},
+    mem,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error, info, warn};
+
+use crate::{
+    args::{self, Parse, Parser},
+    logger,
+    memory::{self, Kind, Memory, Tracked},
+    path_template::{Fields, PathTemplate},
+};
+
+#[derive(Debug)]
+pub struct Args {
+    duration: Option<Duration>,
+    dir: Option<PathTemplate>,
+    max_size: u64,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            dir: Option::default(),
+            max_size: 256 * 1024 * 1024,
+            duration: Option::default(),
+        }
+    }
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse_fn(&mut self.duration, "--replay-buffer", |a| {
+            Ok(Some(args::parse_duration(a)?))
+        })?;
+        parser.parse_fn(&mut self.dir, "--replay-dir", |a| {
+            Ok(Some(PathTemplate::new(a)?))
+        })?;
+        parser.parse_fn(&mut self.max_size, "--replay-max-size", args::parse_size)?;
+
+        Ok(())
+    }
+}
+
+//Keeps the last few complete segments in memory so they can be saved on demand
+pub struct Replay {
+    max_duration: Duration,
+    max_size: u64,
+    dir: PathBuf,
+    fields: Fields,
+    trigger: Arc<AtomicBool>,
+
+    header: Option<Arc<[u8]>>,
+    segments: VecDeque<(Arc<[u8]>, Duration)>,
+    buffered_size: u64,
+    buffered_duration: Duration,
+    current: Vec<u8>,
+    memory: Tracked,
+    limited: bool,
+}
+
+impl Replay {
+    pub fn new(args: &Args, fields: &Fields, memory: &Arc<Memory>) -> Result<Option<Self>> {
+        let Some(max_duration) = args.duration else {
+            return Ok(None);
+        };
+
+        let dir = args
+            .dir
+            .as_ref()
+            .map_or_else(|| ".".into(), |d| d.expand(fields));
+        fs::create_dir_all(&dir).context("Failed to create replay directory")?;
+
+        let trigger = Arc::new(AtomicBool::default());
+        logger::spawn("replay", {
+            let trigger = trigger.clone();
+            move || {
+                for line in io::stdin().lock().lines().map_while(Result::ok) {
+                    if line.trim() == "replay" {
+                        trigger.store(true, Ordering::Relaxed);
+                    }
+                }
+            }
+        })
+        .context("Failed to spawn replay trigger")?;
+
+        info!(
+            "Keeping the last {}s in memory, type replay and press enter to save them",
+            max_duration.as_secs(),
+        );
+
+        Ok(Some(Self {
+            max_duration,
+            max_size: args.max_size,
+            dir,
+            fields: fields.clone(),
+            trigger,
+            header: Option::default(),
+            segments: VecDeque::default(),
+            buffered_size: u64::default(),
+            buffered_duration: Duration::default(),
+            current: Vec::default(),
+            memory: Tracked::new(memory, Kind::Replay),
+            limited: bool::default(),
+        }))
+    }
+
+    pub fn write(&mut self, buf: &[u8]) {
+        self.current.extend_from_slice(buf);
+        self.memory.add(buf.len());
+    }
+
+    //the segment being written was abandoned
+    pub fn discard_segment(&mut self) {
+        self.memory.sub(self.current.len());
+        self.current.clear();
+    }
+
+    //fMP4 streams need the init segment at the start of every dump
+    pub fn finish_header(&mut self) {
+        if let Some(header) = self.header.replace(self.current.drain(..).collect()) {
+            self.memory.sub(header.len());
+        }
+    }
+
+    pub fn finish_segment(&mut self, duration: Duration) {
+        let segment: Arc<[u8]> = self.current.drain(..).collect();
+        self.buffered_size += segment.len() as u64;
+        self.buffered_duration += duration;
+        self.segments.push_back((segment, duration));
+
+        while self.segments.len() > 1
+            && (self.buffered_duration > self.max_duration || self.buffered_size > self.max_size)
+        {
+            self.pop_front();
+        }
+
+        //replay data is the first to go when all buffers together are too large
+        let mut evicted = (0, Duration::ZERO);
+        while self.segments.len() > 1 && self.memory.memory().over_limit() {
+            let (size, duration) = self.pop_front();
+            evicted = (evicted.0 + size, evicted.1 + duration);
+        }
+
+        //once the limit is reached it usually stays reached
+        if evicted.0 > 0 && !mem::replace(&mut self.limited, true) {
+            warn!(
+                "Buffer memory limit reached, replay buffer reduced to {}s",
+                self.buffered_duration.as_secs(),
+            );
+        } else if evicted.0 > 0 {
+            debug!(
+                "Dropped {}s ({}) of replay data",
+                evicted.1.as_secs(),
+                memory::format_size(evicted.0),
+            );
+        }
+
+        if self.trigger.swap(false, Ordering::Relaxed) {
+            self.dump();
+        }
+    }
+
+    fn pop_front(&mut self) -> (u64, Duration) {
+        let Some((segment, duration)) = self.segments.pop_front() else {
+            return (0, Duration::ZERO);
+        };
+
+        self.buffered_size -= segment.len() as u64;
+        self.buffered_duration -= duration;
+        self.memory.sub(segment.len());
+
+        (segment.len() as u64, duration)
+    }
+
+    //written on its own thread so live outputs aren't held up
+    fn dump(&self) {
+        let extension = if self.header.is_some() { "mp4" } else { "ts" };
+        let name =
+            PathTemplate::new(&format!("replay-%s.{extension}")).expect("Invalid replay file name");
+        let path = self.dir.join(name.expand(&self.fields.now()));
+
+        let header = self.header.clone();
+        let segments = self
+            .segments
+            .iter()
+            .map(|(s, _)| s.clone())
+            .collect::<Vec<_>>();
+        info!(
+            "Saving replay of {}s to {}",
+            self.buffered_duration.as_secs(),
+            path.display(),
+        );
+
+        let spawned = logger::spawn("replay dump", move || {
+            //two dumps within a second get -1, -2...
+            let result = name.create_new(&path).and_then(|(mut file, path)| {
+                for segment in header.iter().chain(&segments) {
+                    file.write_all(segment)?;
+                }
+
+                file.flush().map(|()| path)
+            });
+
+            match result {
+                Ok(saved) if saved != path => info!("Saved replay to {}", saved.display()),
+                Ok(_) => (),
+                Err(e) => error!("Failed to save replay to {}: {e}", path.display()),
+            }
+        });
+
+        if let Err(e) = spawned {
+            error!("Failed to spawn replay dump: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, thread};
+
+    use super::*;
+    use crate::{
+        output::{tests::writer, Writer},
+        temp_dir::TempDir,
+    };
+
+    fn replay_writer(dir: &TempDir, args: &[&str]) -> Writer {
+        let recording = dir.path_str("recording.ts");
+        let replay_dir = dir.path_str("replays");
+        writer(
+            &[
+                &["-r", &recording, "--overwrite", "--replay-dir", &replay_dir],
+                args,
+            ]
+            .concat(),
+        )
+    }
+
+    fn segment(writer: &mut Writer, sequence: u8, data: &[u8]) {
+        writer.set_segment(sequence.into(), Duration::from_secs(2), None);
+        writer.write_all(data).unwrap();
+        writer.flush().unwrap();
+    }
+
+    fn buffered(writer: &Writer) -> Vec<Vec<u8>> {
+        let replay = writer.replay.as_ref().unwrap();
+        replay.segments.iter().map(|(s, _)| s.to_vec()).collect()
+    }
+
+    //name and contents of the only dump, which is written on its own thread
+    fn dumped(dir: &TempDir, len: usize) -> (String, Vec<u8>) {
+        let dir = dir.join("replays");
+        for _ in 0..200 {
+            let dumps = fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            if let [dump] = dumps.as_slice() {
+                let data = fs::read(dir.join(dump)).unwrap();
+                if data.len() >= len {
+                    return (dump.clone(), data);
+                }
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        panic!("Replay wasn't saved");
+    }
+
+    #[test]
+    fn duration_cap() {
+        let dir = TempDir::new("replay-duration");
+        let mut writer = replay_writer(&dir, &["--replay-buffer", "6"]);
+        for sequence in 0..5 {
+            segment(&mut writer, sequence, &[sequence; 188]);
+        }
+        assert_eq!(
+            buffered(&writer),
+            [[2; 188], [3; 188], [4; 188]].map(Vec::from)
+        );
+
+        let replay = writer.replay.as_ref().unwrap();
+        assert_eq!(replay.buffered_duration, Duration::from_secs(6));
+        assert_eq!(replay.buffered_size, 3 * 188);
+
+        writer
+            .replay
+            .as_ref()
+            .unwrap()
+            .trigger
+            .store(true, Ordering::Relaxed);
+        segment(&mut writer, 5, &[5; 188]);
+        let (name, dump) = dumped(&dir, 3 * 188);
+        assert!(
+            name.starts_with("replay-") && Path::new(&name).extension() == Some("ts".as_ref()),
+            "{name}"
+        );
+        assert_eq!(dump, [[3; 188], [4; 188], [5; 188]].concat());
+    }
+
+    #[test]
+    fn size_cap() {
+        let dir = TempDir::new("replay-size");
+        let mut writer = replay_writer(&dir, &["--replay-buffer", "1m", "--replay-max-size", "1k"]);
+        for sequence in 0..4 {
+            segment(&mut writer, sequence, &[sequence; 400]);
+        }
+        assert_eq!(buffered(&writer), [[2; 400], [3; 400]].map(Vec::from));
+        assert_eq!(writer.replay.as_ref().unwrap().buffered_size, 800);
+
+        //a segment over the cap on its own is still kept
+        segment(&mut writer, 4, &[4; 2000]);
+        assert_eq!(buffered(&writer), [vec![4; 2000]]);
+    }
+
+    #[test]
+    fn header_is_prepended() {
+        let dir = TempDir::new("replay-header");
+        let mut writer = replay_writer(&dir, &["--replay-buffer", "4"]);
+        writer.start_header();
+        writer.write_all(b"old init").unwrap();
+        writer.flush().unwrap();
+
+        //a new init segment replaces the old one
+        writer.start_header();
+        writer.write_all(b"init").unwrap();
+        writer.flush().unwrap();
+        for sequence in 0..3 {
+            segment(
+                &mut writer,
+                sequence,
+                format!("segment{sequence}").as_bytes(),
+            );
+        }
+
+        let replay = writer.replay.as_ref().unwrap();
+        assert_eq!(replay.header.as_deref(), Some(&b"init"[..]));
+        assert_eq!(replay.buffered_size, 16);
+
+        replay.trigger.store(true, Ordering::Relaxed);
+        segment(&mut writer, 3, b"segment3");
+
+        let (name, dump) = dumped(&dir, 20);
+        assert!(
+            name.starts_with("replay-") && Path::new(&name).extension() == Some("mp4".as_ref()),
+            "{name}"
+        );
+        assert_eq!(dump, b"initsegment2segment3");
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::{debug, warn, LevelFilter};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Sink {
+    Player,
+    Recorder,
+}
+
+impl Sink {
+    pub const ALL: [Self; 2] = [Self::Player, Self::Recorder];
+
+    pub fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "player" => Ok(Self::Player),
+            "record" => Ok(Self::Recorder),
+            _ => bail!("Invalid output: {arg} (must be player or record)"),
+        }
+    }
+
+    //inverse of new()
+    pub const fn arg(self) -> &'static str {
+        match self {
+            Self::Player => "player",
+            Self::Recorder => "record",
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Player => "player",
+            Self::Recorder => "file",
+        }
+    }
+}
+
+//Time spent writing to each sink, aggregated per segment
+pub struct SinkStats {
+    enabled: bool,
+    elapsed: [Duration; Sink::ALL.len()],
+    last_warning: Option<Instant>,
+
+    //all sinks, until taken
+    blocked: Duration,
+}
+
+impl SinkStats {
+    const DEBUG_THRESHOLD: Duration = Duration::from_millis(50);
+
+    //Twitch segments are usually 2 seconds long
+    const WARN_THRESHOLD: Duration = Duration::from_secs(2);
+    const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new() -> Self {
+        Self {
+            enabled: log::max_level() >= LevelFilter::Warn,
+            elapsed: [Duration::ZERO; Sink::ALL.len()],
+            last_warning: Option::default(),
+            blocked: Duration::ZERO,
+        }
+    }
+
+    pub fn time<T>(&mut self, sink: Sink, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        self.elapsed[sink as usize] += elapsed;
+        self.blocked += elapsed;
+
+        result
+    }
+
+    //time spent writing since the last call
+    pub fn take_blocked(&mut self) -> Duration {
+        mem::take(&mut self.blocked)
+    }
+
+    pub fn finish_segment(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.elapsed.iter().any(|e| *e >= Self::DEBUG_THRESHOLD) {
+            let timings = Sink::ALL
+                .iter()
+                .map(|s| format!("{}={}ms", s.name(), self.elapsed[*s as usize].as_millis()))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            debug!("Sink timings: {timings}");
+        }
+
+        if let Some(slow) = Sink::ALL
+            .into_iter()
+            .find(|s| self.elapsed[*s as usize] >= Self::WARN_THRESHOLD)
+        {
+            if !self
+                .last_warning
+                .is_some_and(|t| t.elapsed() < Self::WARNING_INTERVAL)
+            {
+                warn!(
+                    "Writing to {} took longer than a segment ({}ms)",
+                    slow.name(),
+                    self.elapsed[slow as usize].as_millis(),
+                );
+                self.last_warning = Some(Instant::now());
+            }
+        }
+
+        self.elapsed = [Duration::ZERO; Sink::ALL.len()];
+    }
+}
@@ -0,0 +1,427 @@
+use std::{
+    ffi::OsString,
+    fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::JoinHandle,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, error};
+
+use super::Sink;
+use crate::{
+    args::{Parse, Parser},
+    json, logger,
+    path_template::{self, Fields, PathTemplate},
+};
+
+//rewritten at least this often so a stale "updated" means the process died
+const INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Default, Clone, Debug)]
+pub struct Args {
+    path: Option<PathTemplate>,
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse_fn(&mut self.path, "--status-file", |a| {
+            Ok(Some(PathTemplate::new(a)?))
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Starting,
+    Live,
+    AdBreak,
+    Offline,
+    Reconnecting,
+    Ended,
+}
+
+impl State {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Starting => "starting",
+            Self::Live => "live",
+            Self::AdBreak => "ad-break",
+            Self::Offline => "offline",
+            Self::Reconnecting => "reconnecting",
+            Self::Ended => "ended",
+        }
+    }
+}
+
+struct Inner {
+    state: State,
+    changed: bool,
+    channel: String,
+    last_segment_time: Option<String>,
+    behind_live: Option<f64>,
+}
+
+//Session state for external dashboards, shared by the writer and segment handler.
+//The file is replaced by a rename so readers never see a partial document
+pub struct Status {
+    path: PathBuf,
+    quality: Option<String>,
+    started: SystemTime,
+
+    written: [AtomicU64; Sink::ALL.len()],
+    media_millis: AtomicU64,
+
+    inner: Mutex<Inner>,
+    changed: Condvar,
+}
+
+impl Status {
+    fn new(path: PathBuf, channel: &str, quality: Option<&str>) -> Self {
+        Self {
+            path,
+            quality: quality.map(ToOwned::to_owned),
+            started: SystemTime::now(),
+            written: [const { AtomicU64::new(0) }; Sink::ALL.len()],
+            media_millis: AtomicU64::default(),
+            inner: Mutex::new(Inner {
+                state: State::Starting,
+                changed: false,
+                channel: channel.to_owned(),
+                last_segment_time: None,
+                behind_live: None,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn set_state(&self, state: State) {
+        let mut inner = self.lock();
+        if inner.state == state || inner.state == State::Ended {
+            return;
+        }
+
+        debug!("Status: {}", state.name());
+        inner.state = state;
+        inner.changed = true;
+        drop(inner);
+        self.changed.notify_one();
+    }
+
+    //the channel now played, it changes with --channel-failover
+    pub fn set_channel(&self, channel: &str) {
+        let mut inner = self.lock();
+        channel.clone_into(&mut inner.channel);
+        inner.changed = true;
+        drop(inner);
+        self.changed.notify_one();
+    }
+
+    pub fn written(&self, sink: Sink, len: usize) {
+        self.written[sink as usize].fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    //behind live is how long ago the end of the segment was broadcast
+    pub fn segment(&self, duration: Duration, program_date_time: Option<&str>) {
+        self.media_millis.fetch_add(
+            u64::try_from(duration.as_millis()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+
+        let Some(time) = program_date_time else {
+            return;
+        };
+
+        let mut inner = self.lock();
+        inner.behind_live = path_template::parse_timestamp(time)
+            .map(|t| (unix_time(SystemTime::now()) - t - duration.as_secs_f64()).max(0.0));
+        inner.last_segment_time = Some(time.to_owned());
+    }
+
+    fn run(&self) {
+        let mut failed = false;
+        let mut inner = self.lock();
+        loop {
+            inner.changed = false;
+            let ended = inner.state == State::Ended;
+            let json = self.json(&inner);
+            drop(inner);
+
+            match self.write(&json) {
+                Ok(()) => failed = false,
+                Err(e) if !failed => {
+                    error!("{e:#}");
+                    failed = true;
+                }
+                Err(_) => (),
+            }
+
+            if ended {
+                return;
+            }
+
+            inner = self
+                .changed
+                .wait_timeout_while(self.lock(), INTERVAL, |i| !i.changed)
+                .expect("Status mutex poisoned")
+                .0;
+        }
+    }
+
+    fn write(&self, json: &str) -> Result<()> {
+        let mut tmp = OsString::from(&self.path);
+        tmp.push(".tmp");
+        let tmp = Path::new(&tmp);
+
+        fs::write(tmp, json)
+            .and_then(|()| fs::rename(tmp, &self.path))
+            .with_context(|| format!("Failed to write status file {}", self.path.display()))
+    }
+
+    //times are Unix seconds and durations seconds, unknown values are null
+    fn json(&self, inner: &Inner) -> String {
+        let outputs = Sink::ALL
+            .iter()
+            .map(|s| {
+                format!(
+                    "{}:{}",
+                    json::string(s.arg()),
+                    self.written[*s as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!(
+            r#"{{"channel":{},"quality":{},"state":{},"started":{:.3},"updated":{:.3},"written":{{{outputs}}},"media_duration":{:.3},"behind_live":{},"last_segment_time":{}}}"#,
+            json::string(&inner.channel),
+            json::optional(self.quality.as_deref()),
+            json::string(inner.state.name()),
+            unix_time(self.started),
+            unix_time(SystemTime::now()),
+            Duration::from_millis(self.media_millis.load(Ordering::Relaxed)).as_secs_f64(),
+            inner
+                .behind_live
+                .map_or_else(|| "null".to_owned(), |b| format!("{b:.3}")),
+            json::optional(inner.last_segment_time.as_deref()),
+        )
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("Status mutex poisoned")
+    }
+}
+
+//Owned by the writer, the file is written a final time with state ended when it's dropped
+pub struct StatusFile {
+    status: Arc<Status>,
+    //Option to call take() because handle.join() consumes self
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for StatusFile {
+    fn drop(&mut self) {
+        self.status.set_state(State::Ended);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Deref for StatusFile {
+    type Target = Status;
+
+    fn deref(&self) -> &Status {
+        &self.status
+    }
+}
+
+impl StatusFile {
+    pub fn new(
+        args: &Args,
+        fields: &Fields,
+        channel: &str,
+        quality: Option<&str>,
+    ) -> Result<Option<Self>> {
+        let Some(template) = &args.path else {
+            return Ok(None);
+        };
+
+        let status = Arc::new(Status::new(template.expand(fields), channel, quality));
+
+        let handle = logger::spawn("status", {
+            let status = status.clone();
+            move || status.run()
+        })
+        .context("Failed to spawn status file writer")?;
+
+        Ok(Some(Self {
+            status,
+            handle: Some(handle),
+        }))
+    }
+
+    pub fn status(&self) -> Arc<Status> {
+        self.status.clone()
+    }
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    //JSON type of a field, strings are told apart from numbers by their quote
+    fn kind(json: &str, key: &str) -> &'static str {
+        let value = json::field(json, key).unwrap_or_else(|| panic!("{key} missing: {json}"));
+        if json.contains(&format!(r#""{key}":""#)) {
+            "string"
+        } else if value == "null" {
+            "null"
+        } else if value.starts_with('{') {
+            "object"
+        } else if value.parse::<f64>().is_ok() {
+            "number"
+        } else {
+            panic!("{key} isn't valid: {json}");
+        }
+    }
+
+    fn document(status: &Status) -> String {
+        let json = status.json(&status.lock());
+        assert!(json.starts_with('{') && json.ends_with('}'), "{json}");
+
+        json
+    }
+
+    #[test]
+    fn written_by_rename() {
+        let dir = TempDir::new("status-rename");
+        let status = Status::new(dir.join("status.json"), "channel", None);
+
+        status.write("first").unwrap();
+        status.write("second").unwrap();
+        assert_eq!(dir.entries(), ["status.json"]);
+        assert_eq!(
+            fs::read_to_string(dir.join("status.json")).unwrap(),
+            "second"
+        );
+
+        //the document is only replaced once it's fully written
+        fs::remove_file(dir.join("status.json")).unwrap();
+        fs::create_dir(dir.join("status.json")).unwrap();
+        assert!(status.write("third").is_err());
+        assert_eq!(dir.entries(), ["status.json", "status.json.tmp"]);
+        assert_eq!(
+            fs::read_to_string(dir.join("status.json.tmp")).unwrap(),
+            "third"
+        );
+    }
+
+    #[test]
+    fn ended_when_dropped() {
+        let dir = TempDir::new("status-ended");
+        let mut args = Args::default();
+        args.parse(&mut Parser::from_args(&[
+            "--status-file",
+            &dir.path_str("{channel}.json"),
+        ]))
+        .unwrap();
+
+        let file = StatusFile::new(&args, &Fields::new("channel", None), "channel", None)
+            .unwrap()
+            .unwrap();
+        file.set_state(State::Live);
+        drop(file);
+
+        let json = fs::read_to_string(dir.join("channel.json")).unwrap();
+        assert_eq!(json::field(&json, "state"), Some("ended"));
+        assert_eq!(dir.entries(), ["channel.json"]);
+    }
+
+    #[test]
+    fn ended_is_final() {
+        let status = Status::new(PathBuf::new(), "channel", None);
+        status.set_state(State::Ended);
+        status.set_state(State::Live);
+        assert_eq!(json::field(&document(&status), "state"), Some("ended"));
+    }
+
+    #[test]
+    fn key_types() {
+        let status = Status::new(PathBuf::new(), "channel", None);
+        let json = document(&status);
+        for (key, expected) in [
+            ("channel", "string"),
+            ("quality", "null"),
+            ("state", "string"),
+            ("started", "number"),
+            ("updated", "number"),
+            ("written", "object"),
+            ("player", "number"),
+            ("record", "number"),
+            ("media_duration", "number"),
+            ("behind_live", "null"),
+            ("last_segment_time", "null"),
+        ] {
+            assert_eq!(kind(&json, key), expected, "{key}: {json}");
+        }
+
+        let status = Status::new(PathBuf::new(), r#"chan"nel"#, Some("1080p60"));
+        status.set_state(State::AdBreak);
+        status.written(Sink::Recorder, 188);
+        status.segment(Duration::from_secs(2), Some("2024-01-01T00:00:00.000Z"));
+        status.segment(Duration::from_millis(1500), None);
+
+        let json = document(&status);
+        for (key, expected) in [
+            ("channel", "string"),
+            ("quality", "string"),
+            ("state", "string"),
+            ("started", "number"),
+            ("updated", "number"),
+            ("written", "object"),
+            ("player", "number"),
+            ("record", "number"),
+            ("media_duration", "number"),
+            ("behind_live", "number"),
+            ("last_segment_time", "string"),
+        ] {
+            assert_eq!(kind(&json, key), expected, "{key}: {json}");
+        }
+
+        assert_eq!(json::field(&json, "channel"), Some(r#"chan\"nel"#));
+        assert_eq!(json::field(&json, "quality"), Some("1080p60"));
+        assert_eq!(json::field(&json, "state"), Some("ad-break"));
+        assert_eq!(
+            json::field(&json, "written"),
+            Some(r#"{"player":0,"record":188}"#)
+        );
+        assert_eq!(json::field(&json, "media_duration"), Some("3.500"));
+        assert_eq!(
+            json::field(&json, "last_segment_time"),
+            Some("2024-01-01T00:00:00.000Z")
+        );
+        assert!(
+            json::field(&json, "behind_live")
+                .unwrap()
+                .parse::<f64>()
+                .unwrap()
+                > 0.0
+        );
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::info;
+
+use super::Sink;
+use crate::{json, memory};
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+    None,
+}
+
+impl Format {
+    pub fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "none" => Ok(Self::None),
+            _ => bail!("Invalid summary format: {arg} (must be text, json or none)"),
+        }
+    }
+}
+
+//Totals of every session and pipeline, printed once when the process exits.
+//Shared by the writers and segment handlers, which update it as things happen
+pub struct Summary {
+    started: Instant,
+    opened: AtomicBool,
+
+    //for benchmarking how long playback takes to start, including config, DNS and GQL time
+    first_byte: OnceLock<Duration>,
+    first_segment: OnceLock<(Duration, u64)>,
+
+    written: [AtomicU64; Sink::ALL.len()],
+    media_bytes: AtomicU64,
+    media_millis: AtomicU64,
+
+    ad_segments: AtomicU64,
+    ad_millis: AtomicU64,
+    retries: AtomicU64,
+    resets: AtomicU64,
+    skips: AtomicU64,
+    restarts: AtomicU64,
+    api_throttled: AtomicU64,
+    api_delay_millis: AtomicU64,
+}
+
+impl Summary {
+    //created before the arguments are parsed, so config, DNS and GQL time is included
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            opened: AtomicBool::default(),
+            first_byte: OnceLock::new(),
+            first_segment: OnceLock::new(),
+            written: [const { AtomicU64::new(0) }; Sink::ALL.len()],
+            media_bytes: AtomicU64::default(),
+            media_millis: AtomicU64::default(),
+            ad_segments: AtomicU64::default(),
+            ad_millis: AtomicU64::default(),
+            retries: AtomicU64::default(),
+            resets: AtomicU64::default(),
+            skips: AtomicU64::default(),
+            restarts: AtomicU64::default(),
+            api_throttled: AtomicU64::default(),
+            api_delay_millis: AtomicU64::default(),
+        }
+    }
+
+    //nothing is printed unless an output was opened, e.g. for --print-streams or offline channels
+    pub fn opened(&self) {
+        self.opened.store(true, Ordering::Relaxed);
+    }
+
+    //The first output of any session and rendition, logged once in a format that is kept stable.
+    //The init segment of fMP4 counts as a first byte but not as a first segment
+    pub fn first_byte(&self) {
+        self.first_byte.get_or_init(|| {
+            let elapsed = self.started.elapsed();
+            info!("first-byte {}", elapsed.as_millis());
+            elapsed
+        });
+    }
+
+    pub fn first_segment(&self, bytes: u64) {
+        self.first_segment.get_or_init(|| {
+            let elapsed = self.started.elapsed();
+            info!("first-segment {} {bytes}", elapsed.as_millis());
+            (elapsed, bytes)
+        });
+    }
+
+    pub fn written(&self, sink: Sink, len: usize) {
+        self.written[sink as usize].fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    //segment written to every output, init segments and keepalive packets aren't media
+    pub fn segment(&self, size: u64, duration: Duration) {
+        self.media_bytes.fetch_add(size, Ordering::Relaxed);
+        self.media_millis
+            .fetch_add(millis(duration), Ordering::Relaxed);
+    }
+
+    pub fn ad(&self, duration: Duration) {
+        self.ad_segments.fetch_add(1, Ordering::Relaxed);
+        self.ad_millis
+            .fetch_add(millis(duration), Ordering::Relaxed);
+    }
+
+    pub fn retries(&self, retries: u64) {
+        self.retries.fetch_add(retries, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn skip(&self) {
+        self.skips.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn restart(&self) {
+        self.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    //kept by the agent, which is shared by every session
+    pub fn api_throttled(&self, requests: u64, delay: Duration) {
+        self.api_throttled.store(requests, Ordering::Relaxed);
+        self.api_delay_millis
+            .store(millis(delay), Ordering::Relaxed);
+    }
+
+    pub fn print(&self, format: Format) {
+        if !self.opened.load(Ordering::Relaxed) {
+            return;
+        }
+
+        match format {
+            Format::Text => info!("{}", self.text()),
+            Format::Json => println!("{}", self.json()),
+            Format::None => (),
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut text = format!(
+            "Summary:\
+             \n  Wall time: {}\
+             \n  Media written: {} ({} at {} kbps)",
+            format_duration(self.started.elapsed()),
+            format_duration(self.media_duration()),
+            memory::format_size(self.media_bytes.load(Ordering::Relaxed)),
+            self.bitrate() / 1000,
+        );
+
+        if let Some(first_byte) = self.first_byte.get() {
+            let _ = write!(text, "\n  First byte after: {}ms", first_byte.as_millis());
+        }
+        if let Some((first_segment, bytes)) = self.first_segment.get() {
+            let _ = write!(
+                text,
+                "\n  First segment after: {}ms ({})",
+                first_segment.as_millis(),
+                memory::format_size(*bytes),
+            );
+        }
+
+        for sink in Sink::ALL {
+            let written = self.written[sink as usize].load(Ordering::Relaxed);
+            if written > 0 {
+                let _ = write!(
+                    text,
+                    "\n  Written to {}: {}",
+                    sink.name(),
+                    memory::format_size(written),
+                );
+            }
+        }
+
+        let _ = write!(
+            text,
+            "\n  Ads filtered: {} segments, {}\
+             \n  HTTP retries: {}\
+             \n  Worker resets: {}\
+             \n  Skipped to newest: {} times\
+             \n  Stream restarts: {}\
+             \n  API requests delayed: {} ({} in total)",
+            self.ad_segments.load(Ordering::Relaxed),
+            format_duration(self.ad_duration()),
+            self.retries.load(Ordering::Relaxed),
+            self.resets.load(Ordering::Relaxed),
+            self.skips.load(Ordering::Relaxed),
+            self.restarts.load(Ordering::Relaxed),
+            self.api_throttled.load(Ordering::Relaxed),
+            format_duration(self.api_delay()),
+        );
+
+        text
+    }
+
+    //durations are in seconds and the bitrate in bits per second
+    fn json(&self) -> String {
+        let outputs = Sink::ALL
+            .iter()
+            .map(|s| {
+                format!(
+                    "{}:{}",
+                    json::string(s.arg()),
+                    self.written[*s as usize].load(Ordering::Relaxed),
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let (first_segment, first_segment_bytes) = self.first_segment.get().copied().unzip();
+        format!(
+            r#"{{"wall_time":{:.3},"first_byte":{},"first_segment":{},"first_segment_bytes":{},"media_duration":{:.3},"media_bytes":{},"bitrate":{},"written":{{{outputs}}},"ad_segments":{},"ad_duration":{:.3},"retries":{},"resets":{},"skips":{},"restarts":{},"api_throttled":{},"api_delay":{:.3}}}"#,
+            self.started.elapsed().as_secs_f64(),
+            json_secs(self.first_byte.get().copied()),
+            json_secs(first_segment),
+            first_segment_bytes.map_or_else(|| "null".to_owned(), |b| b.to_string()),
+            self.media_duration().as_secs_f64(),
+            self.media_bytes.load(Ordering::Relaxed),
+            self.bitrate(),
+            self.ad_segments.load(Ordering::Relaxed),
+            self.ad_duration().as_secs_f64(),
+            self.retries.load(Ordering::Relaxed),
+            self.resets.load(Ordering::Relaxed),
+            self.skips.load(Ordering::Relaxed),
+            self.restarts.load(Ordering::Relaxed),
+            self.api_throttled.load(Ordering::Relaxed),
+            self.api_delay().as_secs_f64(),
+        )
+    }
+
+    fn media_duration(&self) -> Duration {
+        Duration::from_millis(self.media_millis.load(Ordering::Relaxed))
+    }
+
+    fn ad_duration(&self) -> Duration {
+        Duration::from_millis(self.ad_millis.load(Ordering::Relaxed))
+    }
+
+    fn api_delay(&self) -> Duration {
+        Duration::from_millis(self.api_delay_millis.load(Ordering::Relaxed))
+    }
+
+    fn bitrate(&self) -> u64 {
+        match self.media_millis.load(Ordering::Relaxed) {
+            0 => 0,
+            millis => self.media_bytes.load(Ordering::Relaxed) * 8 * 1000 / millis,
+        }
+    }
+}
+
+fn json_secs(duration: Option<Duration>) -> String {
+    duration.map_or_else(|| "null".to_owned(), |d| format!("{:.3}", d.as_secs_f64()))
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+//"1h02m03s", "2m03s" or "3s"
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, s) => format!("{h}h{m:02}m{s:02}s"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn api_throttling() {
+        let summary = Summary::new();
+        summary.api_throttled(3, Duration::from_millis(7500));
+
+        assert!(summary
+            .text()
+            .ends_with("\n  API requests delayed: 3 (7s in total)"));
+        assert!(summary
+            .json()
+            .ends_with(r#","api_throttled":3,"api_delay":7.500}"#));
+    }
+
+    #[test]
+    fn startup_times() {
+        let summary = Summary::new();
+        assert!(!summary.text().contains("First"));
+        assert!(summary
+            .json()
+            .contains(r#","first_byte":null,"first_segment":null,"first_segment_bytes":null,"#));
+
+        summary.first_byte();
+        summary.first_segment(2048);
+        summary.first_segment(4096);
+
+        let text = summary.text();
+        assert!(text.contains("\n  First byte after: "), "{text}");
+        assert!(text.contains("ms (2.0 KiB)\n"), "{text}");
+        assert!(summary.json().contains(r#","first_segment_bytes":2048,"#));
+    }
+}
@@ -0,0 +1,291 @@
+use std::mem;
+
+const PACKET_LEN: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0;
+const PAT_TABLE_ID: u8 = 0x00;
+const PMT_TABLE_ID: u8 = 0x02;
+
+const NULL_PACKET_COUNT: usize = 7;
+
+pub fn is_mpegts(start: &[u8]) -> bool {
+    start.first() == Some(&SYNC_BYTE) && start.get(PACKET_LEN).map_or(true, |b| *b == SYNC_BYTE)
+}
+
+//MPEG-TS null packets (PID 0x1FFF) are discarded by demuxers
+pub fn null_packets() -> [u8; PACKET_LEN * NULL_PACKET_COUNT] {
+    let mut packets = [0xff; PACKET_LEN * NULL_PACKET_COUNT];
+    for packet in packets.chunks_exact_mut(PACKET_LEN) {
+        packet[..4].copy_from_slice(&[SYNC_BYTE, 0x1f, 0xff, 0x10]);
+    }
+
+    packets
+}
+
+//Most recent PAT and PMT of the stream, a player that joins mid-stream can't decode
+//anything until it has seen both
+#[derive(Default)]
+pub struct Psi {
+    pat: Option<[u8; PACKET_LEN]>,
+    pmt: Option<[u8; PACKET_LEN]>,
+    pmt_pid: Option<u16>,
+
+    //start of a packet split between two writes
+    partial: Vec<u8>,
+}
+
+impl Psi {
+    pub fn scan(&mut self, mut buf: &[u8]) {
+        if !self.partial.is_empty() {
+            let len = buf.len().min(PACKET_LEN - self.partial.len());
+            self.partial.extend_from_slice(&buf[..len]);
+            buf = &buf[len..];
+
+            if self.partial.len() < PACKET_LEN {
+                return;
+            }
+
+            let packet = mem::take(&mut self.partial);
+            self.packet(&packet);
+        }
+
+        while buf.len() >= PACKET_LEN {
+            if buf[0] != SYNC_BYTE {
+                //alignment is lost after a truncated segment, continue at the next sync byte
+                let Some(next) = buf.iter().position(|b| *b == SYNC_BYTE) else {
+                    return;
+                };
+
+                buf = &buf[next..];
+                continue;
+            }
+
+            self.packet(&buf[..PACKET_LEN]);
+            buf = &buf[PACKET_LEN..];
+        }
+
+        self.partial.extend_from_slice(buf);
+    }
+
+    //copies to write before anything else, none until both tables were seen
+    pub fn packets(&self) -> Option<[u8; PACKET_LEN * 2]> {
+        let (pat, pmt) = self.pat.as_ref().zip(self.pmt.as_ref())?;
+
+        let mut packets = [0; PACKET_LEN * 2];
+        packets[..PACKET_LEN].copy_from_slice(pat);
+        packets[PACKET_LEN..].copy_from_slice(pmt);
+
+        Some(packets)
+    }
+
+    fn packet(&mut self, packet: &[u8]) {
+        if packet[0] != SYNC_BYTE {
+            return;
+        }
+
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        if pid == PAT_PID {
+            let Some(pmt_pid) = section(packet).and_then(pmt_pid) else {
+                return;
+            };
+
+            if self.pmt_pid != Some(pmt_pid) {
+                self.pmt = None;
+                self.pmt_pid = Some(pmt_pid);
+            }
+            self.pat = packet.try_into().ok();
+        } else if self.pmt_pid == Some(pid) && section(packet).is_some_and(|s| s[0] == PMT_TABLE_ID)
+        {
+            self.pmt = packet.try_into().ok();
+        }
+    }
+}
+
+//PSI section starting in the packet, sections that continue in the next packet are skipped
+fn section(packet: &[u8]) -> Option<&[u8]> {
+    const PAYLOAD_UNIT_START: u8 = 0x40;
+
+    if packet[1] & PAYLOAD_UNIT_START == 0 {
+        return None;
+    }
+
+    let payload = match (packet[3] >> 4) & 0b11 {
+        0b01 => &packet[4..],
+        0b11 => packet.get(5 + usize::from(packet[4])..)?,
+        _ => return None,
+    };
+
+    let pointer = usize::from(*payload.first()?);
+    let section = payload.get(1 + pointer..)?;
+    let len = usize::from(u16::from_be_bytes([
+        *section.get(1)? & 0x0f,
+        *section.get(2)?,
+    ]));
+
+    section.get(..3 + len)
+}
+
+//PID of the first program's PMT, program 0 points to the network information table
+fn pmt_pid(section: &[u8]) -> Option<u16> {
+    const HEADER_LEN: usize = 8;
+    const CRC_LEN: usize = 4;
+
+    if section[0] != PAT_TABLE_ID {
+        return None;
+    }
+
+    section
+        .get(HEADER_LEN..section.len().checked_sub(CRC_LEN)?)?
+        .chunks_exact(4)
+        .find(|program| program[..2] != [0, 0])
+        .map(|program| u16::from_be_bytes([program[2] & 0x1f, program[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //PAT and PMT sections as muxed by ffmpeg, program 1 with its PMT on PID 0x1000
+    const PAT: &[u8] = &[
+        0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00, 0x2a, 0xb1, 0x04,
+        0xb2,
+    ];
+    const PMT: &[u8] = &[
+        0x02, 0xb0, 0x17, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe1, 0x00, 0xf0, 0x00, 0x1b, 0xe1, 0x00,
+        0xf0, 0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00, 0x2f, 0x44, 0xb9, 0x9b,
+    ];
+
+    //packet starting a section, padded with stuffing bytes
+    fn packet(pid: u16, section: &[u8]) -> [u8; PACKET_LEN] {
+        let mut packet = [0xff; PACKET_LEN];
+        let [high, low] = pid.to_be_bytes();
+        packet[..5].copy_from_slice(&[SYNC_BYTE, 0x40 | high, low, 0x10, 0x00]);
+        packet[5..5 + section.len()].copy_from_slice(section);
+
+        packet
+    }
+
+    fn pes(pid: u16) -> [u8; PACKET_LEN] {
+        let mut packet = [0xaa; PACKET_LEN];
+        let [high, low] = pid.to_be_bytes();
+        packet[..4].copy_from_slice(&[SYNC_BYTE, 0x40 | high, low, 0x10]);
+
+        packet
+    }
+
+    fn stream() -> Vec<u8> {
+        [packet(0, PAT), packet(0x1000, PMT), pes(0x100), pes(0x101)].concat()
+    }
+
+    fn expected() -> Vec<u8> {
+        [packet(0, PAT), packet(0x1000, PMT)].concat()
+    }
+
+    #[test]
+    fn captured_tables() {
+        let mut psi = Psi::default();
+        assert!(psi.packets().is_none());
+
+        psi.scan(&packet(0, PAT));
+        assert_eq!(psi.pmt_pid, Some(0x1000));
+        assert!(psi.packets().is_none());
+
+        psi.scan(&stream()[PACKET_LEN..]);
+        assert_eq!(psi.packets().unwrap(), *expected());
+    }
+
+    #[test]
+    fn packets_split_between_writes() {
+        for size in [1, 100, 187, 189, 500] {
+            let mut psi = Psi::default();
+            for write in stream().chunks(size) {
+                psi.scan(write);
+            }
+
+            assert_eq!(psi.packets().unwrap(), *expected(), "{size}");
+        }
+    }
+
+    #[test]
+    fn alignment_is_recovered() {
+        let mut psi = Psi::default();
+        psi.scan(&[&[0x00, 0x12, 0x34][..], &stream()].concat());
+
+        assert_eq!(psi.packets().unwrap(), *expected());
+    }
+
+    #[test]
+    fn adaptation_field_and_pointer() {
+        let mut pat = [0xff; PACKET_LEN];
+        //adaptation field of 2 bytes, then a pointer skipping 3 bytes
+        pat[..11].copy_from_slice(&[
+            SYNC_BYTE, 0x40, 0x00, 0x30, 0x02, 0x00, 0xff, 0x03, 0xff, 0xff, 0xff,
+        ]);
+        pat[11..11 + PAT.len()].copy_from_slice(PAT);
+
+        let mut psi = Psi::default();
+        psi.scan(&[pat, packet(0x1000, PMT)].concat());
+        assert_eq!(&psi.packets().unwrap()[..PACKET_LEN], pat);
+    }
+
+    #[test]
+    fn new_pmt_pid_drops_the_old_pmt() {
+        let mut moved = PAT.to_vec();
+        moved[10..12].copy_from_slice(&[0xe1, 0x00]);
+
+        let mut psi = Psi::default();
+        psi.scan(&stream());
+        psi.scan(&packet(0, &moved));
+        assert!(psi.packets().is_none());
+
+        //the old PID is ignored now
+        psi.scan(&packet(0x1000, PMT));
+        assert!(psi.packets().is_none());
+
+        psi.scan(&packet(0x100, PMT));
+        assert_eq!(
+            psi.packets().unwrap(),
+            *[packet(0, &moved), packet(0x100, PMT)].concat()
+        );
+    }
+
+    #[test]
+    fn continued_sections_are_ignored() {
+        let mut continued = packet(0x1000, PMT);
+        continued[1] &= !0x40;
+
+        let mut psi = Psi::default();
+        psi.scan(&[packet(0, PAT), continued].concat());
+        assert!(psi.packets().is_none());
+    }
+
+    #[test]
+    fn mpegts_detection() {
+        assert!(is_mpegts(&stream()));
+        assert!(is_mpegts(&stream()[..PACKET_LEN]));
+        assert!(!is_mpegts(b"\x00\x00\x00\x18ftypiso5"));
+        assert!(!is_mpegts(&[&[SYNC_BYTE][..], &[0; PACKET_LEN]].concat()));
+        assert!(!is_mpegts(&[]));
+    }
+
+    #[test]
+    fn null_packet_alignment() {
+        let packets = null_packets();
+        assert_eq!(packets.len() % PACKET_LEN, 0);
+
+        for packet in packets.chunks_exact(PACKET_LEN) {
+            assert_eq!(packet[0], SYNC_BYTE);
+            assert_eq!(u16::from_be_bytes([packet[1] & 0x1f, packet[2]]), 0x1fff);
+            //payload only, no payload unit start
+            assert_eq!(packet[1] & 0x40, 0);
+            assert_eq!(packet[3] & 0x30, 0x10);
+        }
+        assert!(is_mpegts(&packets));
+
+        //nothing in them is taken for a table
+        let mut psi = Psi::default();
+        psi.scan(&[&packets[..], &stream()].concat());
+        assert_eq!(psi.packets().unwrap(), *expected());
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info};
+
+use crate::{
+    args::{Parse, Parser},
+    http::{Agent, Method, Url},
+    json, logger,
+};
+
+//events waiting to be sent, more are dropped so a slow webhook can't stall segments
+const QUEUE_LEN: usize = 64;
+
+#[derive(Debug)]
+pub struct Args {
+    url: Option<Url>,
+    events: Vec<EventKind>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            url: Option::default(),
+            events: EventKind::ALL.to_vec(),
+        }
+    }
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse_fn(&mut self.url, "--webhook", |a| Ok(Some(a.into())))?;
+        parser.parse_fn(&mut self.events, "--webhook-events", |a| {
+            a.split(',').map(EventKind::new).collect()
+        })?;
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Segment,
+    Offline,
+    AdStart,
+    AdEnd,
+    Restart,
+}
+
+impl EventKind {
+    const ALL: [Self; 5] = [
+        Self::Segment,
+        Self::Offline,
+        Self::AdStart,
+        Self::AdEnd,
+        Self::Restart,
+    ];
+
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "segment" => Ok(Self::Segment),
+            "offline" => Ok(Self::Offline),
+            "ad_start" => Ok(Self::AdStart),
+            "ad_end" => Ok(Self::AdEnd),
+            "restart" => Ok(Self::Restart),
+            _ => bail!("Invalid webhook event: {arg}"),
+        }
+    }
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Segment => "segment",
+            Self::Offline => "offline",
+            Self::AdStart => "ad_start",
+            Self::AdEnd => "ad_end",
+            Self::Restart => "restart",
+        }
+    }
+}
+
+//Segment that was fully written to the outputs
+pub struct SegmentEvent {
+    pub sequence: usize,
+    pub duration: Duration,
+    pub size: u64,
+    pub program_date_time: Option<String>,
+    pub offset: Duration,
+}
+
+//Fields of an event besides its name and the channel
+enum Payload {
+    None,
+    Segment(SegmentEvent),
+    Epoch(u32),
+}
+
+//Sends event notifications to --webhook from its own thread
+#[derive(Clone)]
+pub struct Webhook {
+    events_tx: SyncSender<Event>,
+    events: Vec<EventKind>,
+    queue_full: Arc<AtomicBool>,
+    //channel played when the event happened, it changes with --channel-failover
+    channel: Arc<Mutex<String>>,
+}
+
+struct Event {
+    kind: EventKind,
+    channel: String,
+    payload: Payload,
+}
+
+impl Webhook {
+    //the handle finishes once every clone is dropped and the queue is drained
+    pub fn spawn(
+        args: &Args,
+        channel: &str,
+        agent: &Agent,
+    ) -> Result<Option<(Self, JoinHandle<()>)>> {
+        let Some(url) = args.url.clone() else {
+            return Ok(None);
+        };
+
+        let (events_tx, events_rx) = mpsc::sync_channel(QUEUE_LEN);
+        let handle = logger::spawn("webhook", {
+            let agent = agent.clone();
+            move || Self::run(&events_rx, &url, &agent)
+        })
+        .context("Failed to spawn webhook")?;
+
+        info!("Sending webhook notifications");
+        Ok(Some((
+            Self {
+                events_tx,
+                events: args.events.clone(),
+                queue_full: Arc::default(),
+                channel: Arc::new(Mutex::new(channel.to_owned())),
+            },
+            handle,
+        )))
+    }
+
+    //the channel of the events from now on
+    pub fn set_channel(&self, channel: &str) {
+        channel.clone_into(&mut self.channel.lock().expect("Webhook mutex poisoned"));
+    }
+
+    pub fn segment(&self, event: SegmentEvent) {
+        self.send(EventKind::Segment, Payload::Segment(event));
+    }
+
+    pub fn offline(&self) {
+        self.send(EventKind::Offline, Payload::None);
+    }
+
+    pub fn ad_break(&self, started: bool) {
+        self.send(
+            if started {
+                EventKind::AdStart
+            } else {
+                EventKind::AdEnd
+            },
+            Payload::None,
+        );
+    }
+
+    //epoch counts the restarts of the session's stream
+    pub fn restart(&self, epoch: u32) {
+        self.send(EventKind::Restart, Payload::Epoch(epoch));
+    }
+
+    fn send(&self, kind: EventKind, payload: Payload) {
+        if !self.events.contains(&kind) {
+            return;
+        }
+
+        let channel = self.channel.lock().expect("Webhook mutex poisoned").clone();
+        match self.events_tx.try_send(Event {
+            kind,
+            channel,
+            payload,
+        }) {
+            Ok(()) => self.queue_full.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(_)) => {
+                //logged once until the queue drains
+                if !self.queue_full.swap(true, Ordering::Relaxed) {
+                    error!("Webhook queue full, dropping events");
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => debug!("Webhook stopped, dropping event"),
+        }
+    }
+
+    fn run(events_rx: &Receiver<Event>, url: &Url, agent: &Agent) {
+        let mut request = agent.text();
+        //failed events are dropped after one retry, the receiver may get an event twice
+        request.set_retries(1);
+        for Event {
+            kind,
+            channel,
+            payload,
+        } in events_rx
+        {
+            let body = Self::body(kind, &payload, &channel);
+            let result = request.text_fmt(
+                Method::Post,
+                url,
+                format_args!(
+                    "Content-Type: application/json\r\n\
+                     Content-Length: {}\r\n\
+                     \r\n\
+                     {body}",
+                    body.len(),
+                ),
+            );
+
+            if let Err(e) = result {
+                error!("Failed to send {} webhook: {e}", kind.name());
+            }
+        }
+    }
+
+    fn body(kind: EventKind, payload: &Payload, channel: &str) -> String {
+        let mut body = format!(
+            r#"{{"event":"{}","channel":{}"#,
+            kind.name(),
+            json::string(channel),
+        );
+        match payload {
+            Payload::None => (),
+            Payload::Segment(segment) => {
+                let _ = write!(
+                    body,
+                    r#","sequence":{},"duration":{:.3},"size":{},"program_date_time":{},"offset":{:.3}"#,
+                    segment.sequence,
+                    segment.duration.as_secs_f64(),
+                    segment.size,
+                    json::optional(segment.program_date_time.as_deref()),
+                    segment.offset.as_secs_f64(),
+                );
+            }
+            Payload::Epoch(epoch) => {
+                let _ = write!(body, r#","epoch":{epoch}"#);
+            }
+        }
+        body.push('}');
+
+        body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::scripted::{self, Reply, ScriptedServer};
+
+    fn spawn(server: &ScriptedServer) -> (Webhook, JoinHandle<()>) {
+        let mut args = Args::default();
+        args.parse(&mut Parser::from_args(&[
+            "--webhook",
+            &server.url("hook").to_string(),
+        ]))
+        .unwrap();
+
+        Webhook::spawn(&args, "channel", &scripted::agent())
+            .unwrap()
+            .unwrap()
+    }
+
+    //the JSON body of each request received
+    fn bodies(server: &ScriptedServer) -> Vec<String> {
+        server
+            .requests()
+            .iter()
+            .map(|r| r.split_once("\r\n\r\n").unwrap().1.to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn events_are_delivered() {
+        let ok = || Reply::Full(scripted::response("200 OK", "", b""));
+        let server = ScriptedServer::new([ok(), ok(), ok()]);
+        let (webhook, handle) = spawn(&server);
+
+        webhook.ad_break(true);
+        webhook.set_channel("other");
+        webhook.restart(2);
+        webhook.offline();
+        drop(webhook);
+        handle.join().unwrap();
+
+        let requests = server.requests();
+        assert!(requests[0].starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(requests[0].contains("Content-Type: application/json\r\n"));
+        assert_eq!(
+            bodies(&server),
+            [
+                r#"{"event":"ad_start","channel":"channel"}"#,
+                r#"{"event":"restart","channel":"other","epoch":2}"#,
+                r#"{"event":"offline","channel":"other"}"#,
+            ],
+        );
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn failures_are_retried_once() {
+        let server = ScriptedServer::new([
+            //the first event fails twice and is dropped
+            Reply::Cut(Vec::new()),
+            Reply::Cut(Vec::new()),
+            //the second fails once
+            Reply::Cut(Vec::new()),
+            Reply::Full(scripted::response("200 OK", "", b"")),
+        ]);
+        let (webhook, handle) = spawn(&server);
+
+        webhook.ad_break(true);
+        webhook.ad_break(false);
+        drop(webhook);
+        handle.join().unwrap();
+
+        assert_eq!(
+            bodies(&server),
+            [
+                r#"{"event":"ad_start","channel":"channel"}"#,
+                r#"{"event":"ad_start","channel":"channel"}"#,
+                r#"{"event":"ad_end","channel":"channel"}"#,
+                r#"{"event":"ad_end","channel":"channel"}"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn full_queue_drops_events() {
+        let (events_tx, events_rx) = mpsc::sync_channel(QUEUE_LEN);
+        let webhook = Webhook {
+            events_tx,
+            events: vec![EventKind::Offline],
+            queue_full: Arc::default(),
+            channel: Arc::new(Mutex::new("channel".to_owned())),
+        };
+
+        let ((), logged) = logger::capture(|| {
+            for _ in 0..QUEUE_LEN + 10 {
+                webhook.offline();
+            }
+            //filtered events don't count
+            webhook.ad_break(true);
+        });
+        assert_eq!(events_rx.try_iter().count(), QUEUE_LEN);
+        assert_eq!(
+            logged,
+            [(
+                log::Level::Error,
+                "Webhook queue full, dropping events".to_owned()
+            )],
+        );
+
+        //logged again once the queue drained and filled up
+        let ((), logged) = logger::capture(|| {
+            for _ in 0..=QUEUE_LEN {
+                webhook.offline();
+            }
+        });
+        assert_eq!(logged.len(), 1);
+    }
+
+    #[test]
+    fn body_is_escaped() {
+        let segment = Payload::Segment(SegmentEvent {
+            sequence: 7,
+            duration: Duration::from_millis(2500),
+            size: 1024,
+            program_date_time: Some("2024-01-01T00:00:00.000Z".to_owned()),
+            offset: Duration::from_secs(14),
+        });
+
+        assert_eq!(
+            Webhook::body(EventKind::Segment, &segment, "chan\"nel"),
+            r#"{"event":"segment","channel":"chan\"nel","sequence":7,"duration":2.500,"size":1024,"program_date_time":"2024-01-01T00:00:00.000Z","offset":14.000}"#,
+        );
+        assert_eq!(
+            Webhook::body(EventKind::Offline, &Payload::None, "channel"),
+            r#"{"event":"offline","channel":"channel"}"#,
+        );
+    }
+}
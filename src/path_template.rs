@@ -0,0 +1,430 @@
+use std::{
+    fs::File,
+    io, mem,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+
+//Path of a file written by an output, with placeholders filled in when the file is created:
+//{channel} {quality} {date} {time} and the strftime escapes %Y %m %d %H %M %S %s.
+//{{, }} and %% are literal. Times are UTC, there is no time zone database to go by
+#[derive(Debug, Clone)]
+pub struct PathTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Channel,
+    Quality,
+    Date,
+    Time,
+    Strftime(char),
+}
+
+impl PathTemplate {
+    const MAX_SUFFIX: u32 = 1000;
+
+    pub fn new(arg: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = arg.chars().peekable();
+        while let Some(c) = chars.next() {
+            let part = match c {
+                '{' | '}' if chars.next_if_eq(&c).is_some() => {
+                    literal.push(c);
+                    continue;
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("Unclosed {{ in path (use {{{{ for a literal {{): {arg}"),
+                        }
+                    }
+
+                    match name.as_str() {
+                        "channel" => Part::Channel,
+                        "quality" => Part::Quality,
+                        "date" => Part::Date,
+                        "time" => Part::Time,
+                        _ => bail!("Unknown placeholder {{{name}}} in path: {arg}"),
+                    }
+                }
+                '}' => bail!("Unmatched }} in path (use }}}} for a literal }}): {arg}"),
+                '%' => match chars.next() {
+                    Some('%') => {
+                        literal.push('%');
+                        continue;
+                    }
+                    Some(c @ ('Y' | 'm' | 'd' | 'H' | 'M' | 'S' | 's')) => Part::Strftime(c),
+                    Some(c) => bail!("Unknown escape %{c} in path (use %% for a literal %): {arg}"),
+                    None => bail!("Path ends with % (use %% for a literal %): {arg}"),
+                },
+                c => {
+                    literal.push(c);
+                    continue;
+                }
+            };
+
+            if !literal.is_empty() {
+                parts.push(Part::Literal(mem::take(&mut literal)));
+            }
+            parts.push(part);
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    pub fn expand(&self, fields: &Fields) -> PathBuf {
+        let time = DateTime::utc(fields.time);
+        let mut path = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => path.push_str(literal),
+                Part::Channel => path.push_str(&sanitize(&fields.channel)),
+                Part::Quality => path.push_str(&sanitize(&fields.quality)),
+                Part::Date => path.push_str(&time.format('D')),
+                Part::Time => path.push_str(&time.format('T')),
+                Part::Strftime(c) => path.push_str(&time.format(*c)),
+            }
+        }
+
+        path.into()
+    }
+
+    //{channel} and {quality} need a stream, times can be filled in anywhere
+    pub fn is_per_stream(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|p| matches!(p, Part::Channel | Part::Quality))
+    }
+
+    //A fixed path is what the user asked for, so it isn't renamed. With placeholders
+    //an existing file is usually from an earlier run and -1, -2... is appended instead
+    pub fn create_new(&self, path: &Path) -> io::Result<(File, PathBuf)> {
+        let fixed = self.parts.iter().all(|p| matches!(p, Part::Literal(_)));
+        let max_suffix = if fixed { 0 } else { Self::MAX_SUFFIX };
+        for suffix in 0..=max_suffix {
+            let candidate = if suffix == 0 {
+                path.to_owned()
+            } else {
+                suffixed(path, suffix)
+            };
+
+            match File::create_new(&candidate) {
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists && suffix < max_suffix => (),
+                result => return result.map(|f| (f, candidate)),
+            }
+        }
+
+        unreachable!()
+    }
+}
+
+//Values of the placeholders, shared by the files of one output so their names match
+#[derive(Clone)]
+pub struct Fields {
+    channel: String,
+    quality: String,
+    time: SystemTime,
+}
+
+impl Fields {
+    pub fn new(channel: &str, quality: Option<&str>) -> Self {
+        Self {
+            channel: channel.to_owned(),
+            quality: quality.unwrap_or("unknown").to_owned(),
+            time: SystemTime::now(),
+        }
+    }
+
+    //for paths that aren't tied to a stream, checked with PathTemplate::is_per_stream
+    pub fn unbound() -> Self {
+        Self::new("", None)
+    }
+
+    //same stream, for a file created later
+    pub fn now(&self) -> Self {
+        Self {
+            time: SystemTime::now(),
+            ..self.clone()
+        }
+    }
+}
+
+//Calendar date and time of a UTC timestamp
+pub struct DateTime {
+    pub year: u64,
+    pub month: u64,
+    pub day: u64,
+    pub hour: u64,
+    pub minute: u64,
+    pub second: u64,
+    epoch: u64,
+}
+
+impl DateTime {
+    pub fn utc(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let (days, secs_of_day) = (secs / 86_400, secs % 86_400);
+
+        //days to civil date, from Howard Hinnant's date algorithms
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let day_of_era = z % 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 {
+            month_index + 3
+        } else {
+            month_index - 9
+        };
+
+        Self {
+            year: year_of_era + era * 400 + u64::from(month <= 2),
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+            epoch: secs,
+        }
+    }
+
+    //strftime conversion, D and T are the {date} and {time} placeholders.
+    //Times use - instead of : which Windows doesn't allow in file names
+    fn format(&self, conversion: char) -> String {
+        match conversion {
+            'Y' => format!("{:04}", self.year),
+            'm' => format!("{:02}", self.month),
+            'd' => format!("{:02}", self.day),
+            'H' => format!("{:02}", self.hour),
+            'M' => format!("{:02}", self.minute),
+            'S' => format!("{:02}", self.second),
+            's' => self.epoch.to_string(),
+            'D' => format!("{:04}-{:02}-{:02}", self.year, self.month, self.day),
+            'T' => format!("{:02}-{:02}-{:02}", self.hour, self.minute, self.second),
+            _ => unreachable!(),
+        }
+    }
+}
+
+//Seconds since the Unix epoch of an ISO 8601 time like 2024-05-01T12:34:56.789Z
+pub fn parse_timestamp(value: &str) -> Option<f64> {
+    let (date, time) = value.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let (time, offset) = time
+        .find(['Z', '+', '-'])
+        .map_or((time, "Z"), |i| time.split_at(i));
+    let mut time = time.splitn(3, ':');
+    let (hour, minute, second) = (
+        time.next()?.parse::<i64>().ok()?,
+        time.next()?.parse::<i64>().ok()?,
+        time.next()?.parse::<f64>().ok()?,
+    );
+
+    let offset = match offset.split_at(1) {
+        ("Z", "") => 0,
+        (sign, offset) => {
+            let (hours, minutes) = offset.split_once(':').unwrap_or((offset, "0"));
+            let offset = hours.parse::<i64>().ok()? * 3600 + minutes.parse::<i64>().ok()? * 60;
+            if sign == "-" {
+                -offset
+            } else {
+                offset
+            }
+        }
+    };
+
+    //civil date to days, from Howard Hinnant's date algorithms
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    #[allow(clippy::cast_precision_loss)]
+    let secs = (days * 86_400 + hour * 3600 + minute * 60 - offset) as f64;
+    Some(secs + second)
+}
+
+//Channel names can come from anywhere with --force-playlist-url, characters that aren't
+//allowed in Windows file names and path separators are replaced
+fn sanitize(value: &str) -> String {
+    let sanitized = value
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    if sanitized.is_empty() || sanitized.chars().all(|c| c == '.') {
+        return "_".to_owned();
+    }
+
+    sanitized
+}
+
+//rec.ts becomes rec-1.ts
+fn suffixed(path: &Path, suffix: u32) -> PathBuf {
+    let mut name = path.file_stem().unwrap_or_default().to_owned();
+    name.push(format!("-{suffix}"));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    //2024-05-01T12:34:56Z
+    const TIME: u64 = 1_714_566_896;
+
+    fn fields(channel: &str, quality: Option<&str>) -> Fields {
+        Fields {
+            time: UNIX_EPOCH + Duration::from_secs(TIME),
+            ..Fields::new(channel, quality)
+        }
+    }
+
+    fn expand(template: &str, fields: &Fields) -> String {
+        PathTemplate::new(template)
+            .unwrap()
+            .expand(fields)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn placeholders() {
+        let stream = fields("channel", Some("720p60"));
+
+        assert_eq!(
+            expand("{channel}-{quality}-{date}_{time}.ts", &stream),
+            "channel-720p60-2024-05-01_12-34-56.ts",
+        );
+        assert_eq!(
+            expand("%Y%m%d-%H%M%S-%s", &stream),
+            "20240501-123456-1714566896"
+        );
+        assert_eq!(expand("{{channel}}%%.ts", &stream), "{channel}%.ts");
+        assert_eq!(expand("{quality}", &fields("channel", None)), "unknown");
+    }
+
+    #[test]
+    fn sanitized() {
+        assert_eq!(
+            expand("{channel}.ts", &fields("a:b/c\\d", None)),
+            "a_b_c_d.ts"
+        );
+        assert_eq!(expand("{channel}.ts", &fields("..", None)), "_.ts");
+        assert_eq!(expand("{channel}.ts", &fields("", None)), "_.ts");
+        assert_eq!(expand("{channel}.ts", &fields("a\nb", None)), "a_b.ts");
+    }
+
+    #[test]
+    fn invalid() {
+        let error = |template| PathTemplate::new(template).unwrap_err().to_string();
+
+        assert!(error("{chanel}.ts").contains("Unknown placeholder {chanel}"));
+        assert!(error("{channel.ts").contains("Unclosed {"));
+        assert!(error("channel}.ts").contains("Unmatched }"));
+        assert!(error("%q.ts").contains("Unknown escape %q"));
+        assert!(error("rec%").contains("ends with %"));
+        assert!(error("{seq}.ts").contains("Unknown placeholder {seq}"));
+    }
+
+    #[test]
+    fn collisions_are_suffixed() {
+        let dir = TempDir::new("template");
+        let template = PathTemplate::new(&format!("{}/{{channel}}.ts", dir.display())).unwrap();
+        let path = template.expand(&fields("channel", None));
+
+        let names = (0..3)
+            .map(|_| {
+                let (_, path) = template.create_new(&path).unwrap();
+                path.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["channel.ts", "channel-1.ts", "channel-2.ts"]);
+
+        //a fixed path is never renamed
+        let fixed = PathTemplate::new(&format!("{}/channel.ts", dir.display())).unwrap();
+        let error = fixed.create_new(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn date_time_round_trip() {
+        let date_time = DateTime::utc(UNIX_EPOCH + Duration::from_secs(TIME));
+        assert_eq!(
+            [
+                date_time.year,
+                date_time.month,
+                date_time.day,
+                date_time.hour,
+                date_time.minute,
+                date_time.second
+            ],
+            [2024, 5, 1, 12, 34, 56],
+        );
+
+        //leap day, end of year and the epoch itself
+        for secs in [951_782_400, 1_704_067_199, 0, TIME] {
+            let date_time = DateTime::utc(UNIX_EPOCH + Duration::from_secs(secs));
+            let formatted = format!(
+                "{}T{:02}:{:02}:{:02}Z",
+                date_time.format('D'),
+                date_time.hour,
+                date_time.minute,
+                date_time.second,
+            );
+
+            #[allow(clippy::cast_precision_loss)]
+            let expected = secs as f64;
+            assert_eq!(parse_timestamp(&formatted), Some(expected), "{formatted}");
+        }
+    }
+
+    #[test]
+    fn timestamps() {
+        #[allow(clippy::cast_precision_loss)]
+        let time = TIME as f64;
+
+        assert_eq!(
+            parse_timestamp("2024-05-01T12:34:56.500Z"),
+            Some(time + 0.5)
+        );
+        assert_eq!(parse_timestamp("2024-05-01T14:34:56+02:00"), Some(time));
+        assert_eq!(parse_timestamp("2024-05-01T07:34:56-05:00"), Some(time));
+        assert_eq!(parse_timestamp("2024-05-01T12:34:56"), Some(time));
+        assert_eq!(parse_timestamp("2024-05-01 12:34:56Z"), None);
+        assert_eq!(parse_timestamp("garbage"), None);
+    }
+}
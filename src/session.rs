@@ -0,0 +1,491 @@
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, error, info};
+
+use crate::{
+    crash,
+    hls::{
+        self,
+        segment::{Handler, LimitError, Limits, SmallSegmentsError},
+        Args as HlsArgs, Container, Heartbeat, MediaPlaylist, OfflineError, StaleError, Variants,
+    },
+    http::Agent,
+    logger,
+    memory::Memory,
+    output::{
+        Args as OutputArgs, PipeClosedError, Player, StatusState, StreamEnv, Summary, Webhook,
+        Writer,
+    },
+    worker::{self, Header, SegmentCache, Worker},
+    Args as MainArgs,
+};
+
+//far longer than a playlist reload and segment sleep, the machine was most likely suspended
+const SUSPEND_GAP: Duration = Duration::from_secs(60);
+
+//how long a stream that just played or showed ads gets to come back from looking offline
+const OFFLINE_RECENT: Duration = Duration::from_secs(10);
+const OFFLINE_GRACE: Duration = Duration::from_secs(15);
+const OFFLINE_RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+//One channel with its own playlist, handler, worker and writer
+#[derive(Debug)]
+pub struct Session {
+    pub name: Option<String>,
+    pub hls: HlsArgs,
+    pub output: OutputArgs,
+}
+
+impl Session {
+    //returns the exit code instead of exiting so other sessions keep running
+    pub fn run(self, main_args: &MainArgs, agent: &Agent, summary: &Arc<Summary>) -> Result<i32> {
+        //the webhook reports the channel that is played
+        if let Err(e) = hls::select_channel(&self.hls, agent) {
+            return offline(e, None);
+        }
+
+        let (webhook, webhook_handle) =
+            Webhook::spawn(&self.output.webhook, self.hls.channel(), agent)?.unzip();
+
+        let result = self.run_pipeline(webhook.as_ref(), main_args, agent, summary);
+
+        //deliver queued events before the session ends
+        drop(webhook);
+        if let Some(handle) = webhook_handle {
+            let _ = handle.join();
+        }
+
+        result
+    }
+
+    fn run_pipeline(
+        self,
+        webhook: Option<&Webhook>,
+        main_args: &MainArgs,
+        agent: &Agent,
+        summary: &Arc<Summary>,
+    ) -> Result<i32> {
+        let Self {
+            hls: hls_args,
+            output: mut output_args,
+            ..
+        } = self;
+
+        //shared by both qualities, sessions don't evict each other's buffers
+        let memory = Arc::new(Memory::new(main_args.max_buffer_memory));
+        let pipeline = Pipeline {
+            hls_args: &hls_args,
+            webhook,
+            main_args,
+            agent,
+            summary,
+            memory: &memory,
+        };
+
+        let renditions = hls_args.renditions().to_vec();
+        let mut variants = match hls::fetch_playlist(&hls_args, agent) {
+            Ok(Some(variants)) => variants,
+            Ok(None) => return Ok(0),
+            Err(e) => {
+                //the listing is the output, so being offline is one too
+                if hls_args.print_streams() && e.is::<OfflineError>() {
+                    println!("Channel {} is offline", hls_args.channel());
+                }
+
+                return offline(e, webhook);
+            }
+        };
+
+        if main_args.passthrough {
+            let multivariant_url = variants.multivariant_url().map(ToString::to_string);
+            let (name, conn) = variants.next().context("Missing playlist URL")?;
+            let env = StreamEnv::new(
+                &conn.url,
+                hls_args.channel(),
+                name.as_deref().or_else(|| hls_args.quality()),
+                multivariant_url.as_deref(),
+            );
+
+            Player::passthrough(&mut output_args.player, &conn.url, &env)?;
+            return Ok(0);
+        }
+
+        let heartbeat = variants.take_heartbeat();
+        let [(first_quality, _), (second_quality, second_sink)] = renditions.as_slice() else {
+            return pipeline.run(variants, &output_args, heartbeat, false);
+        };
+
+        //only a multivariant playlist has a second quality to choose
+        let Some(second) = variants.take_second() else {
+            bail!("Two qualities need a multivariant playlist");
+        };
+
+        //each quality gets its own playlist, worker and outputs
+        let second_args = output_args.split_off(*second_sink);
+        let pipeline = &pipeline;
+        thread::scope(|scope| {
+            let handles = [
+                (first_quality, variants, &output_args, heartbeat, false),
+                (second_quality, second, &second_args, None, true),
+            ]
+            .map(|(quality, variants, output_args, heartbeat, is_second)| {
+                let name = logger::sub_session(quality);
+                thread::Builder::new()
+                    .name(quality.clone())
+                    .spawn_scoped(scope, move || {
+                        logger::set_session(&name);
+                        pipeline.run(variants, output_args, heartbeat, is_second)
+                    })
+            });
+
+            let mut code = 0;
+            for handle in handles {
+                let result = handle
+                    .context("Failed to spawn pipeline")?
+                    .join()
+                    .unwrap_or_else(|p| {
+                        Err(anyhow!("Pipeline panicked: {}", logger::panic_message(&*p)))
+                    });
+
+                code = code.max(result?);
+            }
+
+            Ok(code)
+        })
+    }
+}
+
+//Playback of one quality from its media playlist to its outputs
+struct Pipeline<'a> {
+    hls_args: &'a HlsArgs,
+    webhook: Option<&'a Webhook>,
+    main_args: &'a MainArgs,
+    agent: &'a Agent,
+    summary: &'a Arc<Summary>,
+    memory: &'a Arc<Memory>,
+}
+
+impl Pipeline<'_> {
+    fn run(
+        &self,
+        variants: Variants,
+        output_args: &OutputArgs,
+        heartbeat: Option<Heartbeat>,
+        second: bool,
+    ) -> Result<i32> {
+        let multivariant_url = variants.multivariant_url().map(ToString::to_string);
+        let opened = variants
+            .open()
+            .and_then(|(name, playlist)| self.fetch_header(name, playlist, second));
+        let (name, playlist, header) = match opened {
+            Ok(opened) => opened,
+            Err(e) => return offline(e, self.webhook),
+        };
+
+        let env = StreamEnv::new(
+            playlist.url(),
+            self.hls_args.channel(),
+            name.as_deref().or_else(|| self.hls_args.quality()),
+            multivariant_url.as_deref(),
+        );
+        crash::set_stream(env.channel(), env.quality());
+        let writer = Writer::new(
+            output_args,
+            self.webhook.cloned(),
+            &env,
+            self.summary.clone(),
+            self.memory.clone(),
+        )?;
+        let limits = Limits::new(self.main_args.duration, writer.size_limit());
+        let status = writer.status();
+        let worker = Worker::spawn(
+            writer,
+            header,
+            self.main_args
+                .max_queued_segments
+                .unwrap_or(worker::DEFAULT_MAX_QUEUED),
+            self.hls_args.has_fallback_urls(),
+            SegmentCache::new(&self.main_args.segment_cache)?,
+            self.memory,
+            self.agent.clone(),
+        )?;
+        let mut handler = Handler::new(
+            worker,
+            limits,
+            self.hls_args.small_segments(),
+            self.hls_args.low_latency(),
+            heartbeat,
+            self.webhook.cloned(),
+            self.summary.clone(),
+        );
+        handler.set_status(status);
+        handler.set_poll_jitter(self.hls_args.poll_jitter());
+
+        match self.play(playlist, handler, second) {
+            Ok(()) => Ok(0),
+            Err(e) if PipeClosedError::is_pipe_closed(&e) => {
+                info!("Player closed, exiting...");
+                Ok(0)
+            }
+            Err(e) if e.downcast_ref::<LimitError>().is_some() => {
+                info!("{e}, exiting...");
+                Ok(0)
+            }
+            Err(e) if e.is::<SmallSegmentsError>() => {
+                error!("{e}, exiting...");
+                Ok(SmallSegmentsError::EXIT_CODE)
+            }
+            Err(e) => offline(e, self.webhook),
+        }
+    }
+
+    //With --channel-failover an ended channel is replaced by the next live one of the list,
+    //played through the same outputs like a restarted stream
+    fn play(&self, mut playlist: MediaPlaylist, mut handler: Handler, second: bool) -> Result<()> {
+        loop {
+            let error = match self.main_loop(playlist, &mut handler, second) {
+                Err(e) if e.is::<OfflineError>() => e,
+                result => return result,
+            };
+
+            if !hls::fail_over_channel(self.hls_args, self.agent) {
+                return Err(error);
+            }
+
+            handler.set_state(StatusState::Reconnecting);
+            playlist = self.switch_channel(&mut handler)?;
+        }
+    }
+
+    //Fetched like at the start of the session, which starts a watch heartbeat for the channel
+    //if enabled. Failover isn't used with two qualities, so this is the only pipeline
+    fn switch_channel(&self, handler: &mut Handler) -> Result<MediaPlaylist> {
+        let mut variants =
+            hls::fetch_playlist(self.hls_args, self.agent)?.context("Missing playlist URL")?;
+        handler.set_channel(self.hls_args.channel(), variants.take_heartbeat());
+
+        let mut playlist = variants.open()?.1;
+        handler.reset(playlist.header.take())?;
+
+        Ok(playlist)
+    }
+
+    fn main_loop(
+        &self,
+        mut playlist: MediaPlaylist,
+        handler: &mut Handler,
+        second: bool,
+    ) -> Result<()> {
+        handler.process(&mut playlist, Instant::now())?;
+        let mut last = SystemTime::now();
+        loop {
+            let time = Instant::now();
+
+            //the monotonic clock may not count a suspend, the wall clock does. A wall clock
+            //stepped forward looks the same, which only costs a reconnect, and pacing
+            //doesn't depend on it
+            let now = SystemTime::now();
+            let gap = now.duration_since(last).unwrap_or_default();
+            last = now;
+
+            let resumed = gap > SUSPEND_GAP;
+            if resumed {
+                debug!("Loop stalled for {}s, reconnecting", gap.as_secs());
+                handler.set_state(StatusState::Reconnecting);
+                playlist.resume();
+                handler.resync()?;
+            }
+
+            if let Err(e) = self.reload(&mut playlist, handler, second, resumed) {
+                self.confirm_offline(e, &mut playlist, handler, second)?;
+            }
+
+            if resumed {
+                info!("Resumed after {}s suspend, resynced to live", gap.as_secs());
+            }
+
+            handler.process(&mut playlist, time)?;
+        }
+    }
+
+    fn reload(
+        &self,
+        playlist: &mut MediaPlaylist,
+        handler: &mut Handler,
+        second: bool,
+        resumed: bool,
+    ) -> Result<()> {
+        match playlist.reload() {
+            Err(e) if e.is::<StaleError>() || self.hls_args.can_fail_over(&e) => {
+                info!("{e}, refetching playlist...");
+                handler.set_state(StatusState::Reconnecting);
+                let (name, new) = self.reselect(handler, second)?;
+                if !resumed && e.is::<StaleError>() {
+                    info!(
+                        "Stream renditions changed, now playing {}",
+                        name.as_deref().unwrap_or("<unknown>")
+                    );
+                }
+
+                *playlist = new;
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    //The weaver can briefly return 404 or an ended playlist around ad breaks while the
+    //stream is still live. Right after ads or playback the verdict is only accepted once
+    //it persists or the channel is confirmed offline
+    fn confirm_offline(
+        &self,
+        error: anyhow::Error,
+        playlist: &mut MediaPlaylist,
+        handler: &mut Handler,
+        second: bool,
+    ) -> Result<()> {
+        if !error.is::<OfflineError>()
+            || !(handler.ads_within(OFFLINE_RECENT) || playlist.updated_within(OFFLINE_RECENT))
+        {
+            return Err(error);
+        }
+
+        if hls::check_live(self.hls_args, self.agent) == Some(false) {
+            debug!("Live check confirmed the channel is offline");
+            return Err(error);
+        }
+
+        handler.set_state(StatusState::Offline);
+        info!(
+            "{error}, checking again for {}s...",
+            OFFLINE_GRACE.as_secs()
+        );
+        let start = Instant::now();
+        while start.elapsed() < OFFLINE_GRACE {
+            thread::sleep(OFFLINE_RETRY_INTERVAL);
+            match self.reload(playlist, handler, second, false) {
+                Ok(()) if !playlist.is_failing() => {
+                    info!("Stream is still live, continuing");
+                    return Ok(());
+                }
+                Ok(()) => (),
+                Err(e) if e.is::<OfflineError>() => debug!("Still offline: {e}"),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Err(error)
+    }
+
+    //An fMP4 stream can't start without its init segment, which some edges refuse for
+    //AV1 and HEVC. The closest MPEG-TS quality is played instead
+    fn fetch_header(
+        &self,
+        name: Option<String>,
+        mut playlist: MediaPlaylist,
+        second: bool,
+    ) -> Result<(Option<String>, MediaPlaylist, Option<Header>)> {
+        let Some(url) = playlist.header.take() else {
+            return Ok((name, playlist, None));
+        };
+
+        match Header::fetch(&url, self.agent) {
+            Ok(header) => Ok((name, playlist, Some(header))),
+            Err(e) if self.hls_args.codec_fallback() => {
+                error!("{e:#}");
+                info!("Falling back to an MPEG-TS quality (disable with --no-codec-fallback)");
+
+                let mut variants = self.refetch(second)?;
+                variants.set_container(Container::Ts);
+                let (name, playlist) = variants.open()?;
+
+                Ok((name, playlist, None))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    //the channel is offline if the quality can't be chosen again
+    fn reselect(
+        &self,
+        handler: &mut Handler,
+        second: bool,
+    ) -> Result<(Option<String>, MediaPlaylist)> {
+        let (name, mut playlist) = self.refetch(second)?.open()?;
+        handler.reset(playlist.header.take())?;
+
+        Ok((name, playlist))
+    }
+
+    fn refetch(&self, second: bool) -> Result<Variants> {
+        let mut variants =
+            hls::refetch_playlist(self.hls_args, self.agent)?.context("Missing playlist URL")?;
+        if second {
+            variants = variants.take_second().ok_or(OfflineError::ChannelOffline)?;
+        }
+
+        Ok(variants)
+    }
+}
+
+//Runs every session on its own thread, returns the highest exit code once the last one ends
+pub fn run_all(
+    sessions: Vec<Session>,
+    main_args: &MainArgs,
+    agent: &Agent,
+    summary: &Arc<Summary>,
+) -> i32 {
+    thread::scope(|scope| {
+        //all sessions are started before any is joined
+        let mut handles = Vec::with_capacity(sessions.len());
+        for session in sessions {
+            let name = session.name.clone().unwrap_or_default();
+            let spawned =
+                thread::Builder::new()
+                    .name(name.clone())
+                    .spawn_scoped(scope, move || {
+                        logger::set_session(&name);
+                        info!("Starting session");
+
+                        let code = session.run(main_args, agent, summary).unwrap_or_else(|e| {
+                            error!("{e:#}");
+                            1
+                        });
+
+                        info!("Session ended");
+                        code
+                    });
+
+            match spawned {
+                Ok(handle) => handles.push(handle),
+                Err(e) => error!("Failed to spawn session: {e}"),
+            }
+        }
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle.join().unwrap_or_else(|p| {
+                    error!("Session panicked: {}", logger::panic_message(&*p));
+                    1
+                })
+            })
+            .max()
+            .unwrap_or(1)
+    })
+}
+
+fn offline(error: anyhow::Error, webhook: Option<&Webhook>) -> Result<i32> {
+    let error = error.downcast::<OfflineError>()?;
+    info!("{error}, exiting...");
+    if let Some(webhook) = webhook {
+        webhook.offline();
+    }
+
+    Ok(error.exit_code())
+}
@@ -0,0 +1,73 @@
+use std::{
+    env, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+};
+
+//Directory of a test, removed when it ends. Names are per test and process,
+//so tests running in parallel never share one
+pub struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("twitch-hls-client-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir); //left over from a test that was killed
+        fs::create_dir_all(&dir).unwrap();
+
+        Self(dir)
+    }
+
+    pub fn with_files(name: &str, files: &[(&str, &[u8])]) -> Self {
+        let dir = Self::new(name);
+        for (path, contents) in files {
+            dir.write(path, contents);
+        }
+
+        dir
+    }
+
+    //creates parent directories of nested paths
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.0.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    pub fn path_str(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+
+    //sorted file names
+    pub fn entries(&self) -> Vec<String> {
+        let mut names = fs::read_dir(&self.0)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+
+        names
+    }
+}
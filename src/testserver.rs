@@ -0,0 +1,783 @@
+//Synthetic live stream for development, only built with --features testserver.
+//Run with --dev-serve "[addr=127.0.0.1:8080] [KEY=VALUE]..." and play the printed URL
+//with --force-playlist-url, or spawn a Server in-process.
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    ops::Range,
+    sync::{Arc, Mutex, MutexGuard},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{args, path_template::DateTime};
+
+pub fn serve(spec: &str) -> Result<()> {
+    let server = Server::spawn(Config::new(spec)?)?;
+    let base = server.base_url();
+    println!("Serving test stream: {base}/master.m3u8");
+    println!("Controls: {base}/control/ad/<COUNT>, {base}/control/fault/<404|truncate|html>,");
+    println!("          {base}/control/map, {base}/control/end,");
+    println!("          {base}/control/blip/<404|end>/<SECONDS>");
+
+    server.join()
+}
+
+//"addr=127.0.0.1:0 duration=2 window=6 prefetch=2 size=64k rate=500k ads=30/5
+// faults=404@1010,truncate@1020 map-every=50 deny-init=true"
+#[derive(Debug)]
+pub struct Config {
+    addr: String,
+    duration: Duration,
+    window: u64,
+    prefetch: u64,
+    size: usize,
+    rate: Option<u64>,
+    ads: Option<(u64, u64)>,
+    faults: Vec<(u64, Fault)>,
+    map_every: Option<u64>,
+    //init segments are refused like some edges do for AV1 and HEVC
+    deny_init: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8080".to_owned(),
+            duration: Duration::from_secs(2),
+            window: 6,
+            prefetch: 2,
+            size: 64 * 1024,
+            rate: Option::default(),
+            ads: Option::default(),
+            faults: Vec::default(),
+            map_every: Option::default(),
+            deny_init: bool::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn new(spec: &str) -> Result<Self> {
+        let mut config = Self::default();
+        for word in spec.split_whitespace() {
+            let (key, value) = word
+                .split_once('=')
+                .with_context(|| format!("Test server option must be KEY=VALUE: {word}"))?;
+
+            match key {
+                "addr" => value.clone_into(&mut config.addr),
+                "duration" => config.duration = args::parse_duration(value)?,
+                "window" => config.window = value.parse()?,
+                "prefetch" => config.prefetch = value.parse()?,
+                "size" => config.size = usize::try_from(args::parse_size(value)?)?,
+                "rate" => config.rate = Some(args::parse_size(value)?),
+                "ads" => {
+                    let (every, len) = value
+                        .split_once('/')
+                        .context("Ads must be EVERY/LENGTH in segments")?;
+
+                    let (every, len) = (every.parse()?, len.parse()?);
+                    ensure!(
+                        len < every,
+                        "Ad breaks must be shorter than {every} segments"
+                    );
+                    config.ads = Some((every, len));
+                }
+                "faults" => {
+                    for fault in value.split(',') {
+                        let (kind, sequence) = fault
+                            .split_once('@')
+                            .context("Faults must be KIND@SEQUENCE")?;
+
+                        config.faults.push((sequence.parse()?, Fault::new(kind)?));
+                    }
+                }
+                "map-every" => config.map_every = Some(value.parse()?),
+                "deny-init" => config.deny_init = value.parse()?,
+                _ => bail!("Unknown test server option: {key}"),
+            }
+        }
+
+        ensure!(config.window > 0, "Test server window can't be empty");
+        ensure!(
+            !config.duration.is_zero(),
+            "Test server duration can't be zero"
+        );
+        Ok(config)
+    }
+}
+
+//Media playlists look offline for a while, like the weaver briefly does around ad breaks
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Blip {
+    NotFound,
+    End,
+}
+
+impl Blip {
+    fn new(arg: &str) -> Option<Self> {
+        match arg {
+            "404" => Some(Self::NotFound),
+            "end" => Some(Self::End),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    NotFound,
+    Truncate,
+    Html,
+}
+
+impl Fault {
+    fn new(arg: &str) -> Result<Self> {
+        match arg {
+            "404" => Ok(Self::NotFound),
+            "truncate" => Ok(Self::Truncate),
+            "html" => Ok(Self::Html),
+            _ => bail!("Invalid fault: {arg} (must be 404, truncate or html)"),
+        }
+    }
+}
+
+//Name, resolution, frame rate, bandwidth and whether segments are fMP4
+const VARIANTS: [(&str, &str, u32, u64, bool); 4] = [
+    ("1080p60", "1920x1080", 60, 6_000_000, false),
+    ("1080p60", "1920x1080", 60, 4_000_000, true),
+    ("720p30", "1280x720", 30, 3_000_000, false),
+    ("audio_only", "", 0, 160_000, false),
+];
+
+//first media sequence, non-zero like Twitch's
+const FIRST_SEQUENCE: u64 = 1000;
+
+pub struct Server {
+    addr: SocketAddr,
+    handle: JoinHandle<Result<()>>,
+}
+
+impl Server {
+    pub fn spawn(config: Config) -> Result<Self> {
+        let listener = TcpListener::bind(&config.addr)
+            .with_context(|| format!("Failed to bind test server to {}", config.addr))?;
+        let addr = listener.local_addr()?;
+        let stream = Arc::new(Stream::new(config, format!("http://{addr}")));
+
+        let handle = thread::Builder::new()
+            .name("testserver".to_owned())
+            .spawn(move || -> Result<()> {
+                for connection in listener.incoming() {
+                    let connection = connection?;
+                    let stream = stream.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = stream.handle(connection) {
+                            eprintln!("Test server connection failed: {e}");
+                        }
+                    });
+                }
+
+                Ok(())
+            })
+            .context("Failed to spawn test server")?;
+
+        Ok(Self { addr, handle })
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn join(self) -> Result<()> {
+        self.handle
+            .join()
+            .unwrap_or_else(|_| bail!("Test server panicked"))
+    }
+}
+
+enum Response {
+    Ok(&'static str, Vec<u8>),
+    NotFound,
+    Forbidden,
+    //Content-Length of the whole body, but only half of it is sent
+    Truncated(Vec<u8>),
+}
+
+//The live stream every connection serves, sequences advance with time since start
+struct Stream {
+    config: Config,
+    base: String,
+    start: Instant,
+    epoch: SystemTime,
+
+    //changed on demand by the control endpoints, always after the published segments
+    ads: Mutex<Vec<Range<u64>>>,
+    faults: Mutex<Vec<(u64, Fault)>>,
+    map_changes: Mutex<Vec<u64>>,
+    ended: Mutex<Option<u64>>,
+    blip: Mutex<Option<(Blip, Instant)>>,
+}
+
+impl Stream {
+    fn new(config: Config, base: String) -> Self {
+        Self {
+            base,
+            start: Instant::now(),
+            epoch: SystemTime::now(),
+            ads: Mutex::new(Vec::new()),
+            faults: Mutex::new(config.faults.clone()),
+            map_changes: Mutex::new(Vec::new()),
+            ended: Mutex::new(None),
+            blip: Mutex::new(None),
+            config,
+        }
+    }
+
+    fn handle(&self, connection: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(connection.try_clone()?);
+        let mut writer = connection;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line)? == 0 {
+                return Ok(());
+            }
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                    break;
+                }
+
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap_or_default();
+                    }
+                }
+            }
+            io::copy(&mut (&mut reader).take(content_length), &mut io::sink())?;
+
+            let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+            let path = path.split('?').next().unwrap_or_default();
+            let response = self.route(path);
+            println!(
+                "{path} -> {}",
+                match response {
+                    Response::Ok(..) => "200",
+                    Response::NotFound => "404",
+                    Response::Forbidden => "403",
+                    Response::Truncated(_) => "200 (truncated)",
+                },
+            );
+
+            if !self.respond(&mut writer, response)? {
+                return Ok(());
+            }
+        }
+    }
+
+    //returns false if the connection has to be closed
+    fn respond(&self, writer: &mut TcpStream, response: Response) -> io::Result<bool> {
+        let (status, content_type, body, keep) = match response {
+            Response::Ok(content_type, body) => ("200 OK", content_type, body, true),
+            Response::NotFound => ("404 Not Found", "text/plain", b"not found\n".to_vec(), true),
+            Response::Forbidden => ("403 Forbidden", "text/plain", b"forbidden\n".to_vec(), true),
+            Response::Truncated(body) => ("200 OK", "video/mp2t", body, false),
+        };
+
+        write!(
+            writer,
+            "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n",
+            body.len(),
+        )?;
+
+        let sent = if keep {
+            &body[..]
+        } else {
+            &body[..body.len() / 2]
+        };
+        match self.config.rate {
+            //in tenths of a second so throttled bodies still arrive gradually
+            Some(rate) => {
+                let chunk = usize::try_from(rate / 10).unwrap_or(usize::MAX).max(1);
+                for part in sent.chunks(chunk) {
+                    writer.write_all(part)?;
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+            None => writer.write_all(sent)?,
+        }
+        writer.flush()?;
+
+        Ok(keep)
+    }
+
+    fn route(&self, path: &str) -> Response {
+        let parts = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        match parts.as_slice() {
+            ["master.m3u8"] => Response::Ok(
+                "application/vnd.apple.mpegurl",
+                self.multivariant_playlist().into_bytes(),
+            ),
+            ["v", file] => {
+                let blip = lock(&self.blip)
+                    .filter(|(_, until)| Instant::now() < *until)
+                    .map(|(blip, _)| blip);
+
+                match (Self::variant(file.trim_end_matches(".m3u8")), blip) {
+                    (None, _) | (_, Some(Blip::NotFound)) => Response::NotFound,
+                    (Some(variant), blip) => Response::Ok(
+                        "application/vnd.apple.mpegurl",
+                        self.media_playlist(variant, self.current(), blip == Some(Blip::End))
+                            .into_bytes(),
+                    ),
+                }
+            }
+            ["s", variant, file] => self.segment(variant, file),
+            ["init", ..] if self.config.deny_init => Response::Forbidden,
+            ["init", _, _] => Response::Ok("video/mp4", mp4_box(*b"ftyp", 32)),
+            ["control", "ad", count] => count.parse::<u64>().map_or(Response::NotFound, |count| {
+                let next = self.unpublished();
+                lock(&self.ads).push(next..next + count);
+                control(&format!("ad break of {count} segments at {next}"))
+            }),
+            ["control", "fault", kind] => Fault::new(kind).map_or(Response::NotFound, |fault| {
+                let next = self.unpublished();
+                lock(&self.faults).push((next, fault));
+                control(&format!("{fault:?} at {next}"))
+            }),
+            ["control", "map"] => {
+                let next = self.unpublished();
+                lock(&self.map_changes).push(next);
+                control(&format!("init segment changes at {next}"))
+            }
+            ["control", "blip", kind, secs] => match (Blip::new(kind), secs.parse()) {
+                (Some(blip), Ok(secs)) => {
+                    let until = Instant::now() + Duration::from_secs(secs);
+                    *lock(&self.blip) = Some((blip, until));
+                    control(&format!("playlists {blip:?} for {secs}s"))
+                }
+                _ => Response::NotFound,
+            },
+            ["control", "end"] => {
+                let current = self.current();
+                lock(&self.ended).get_or_insert(current);
+                control(&format!("stream ends after {current}"))
+            }
+            _ => Response::NotFound,
+        }
+    }
+
+    fn segment(&self, variant: &str, file: &str) -> Response {
+        let (Some(variant), Some(Ok(sequence))) = (
+            Self::variant(variant),
+            file.split('.').next().map(str::parse::<u64>),
+        ) else {
+            return Response::NotFound;
+        };
+
+        //prefetch segments are held until they're live, like Twitch does
+        let current = self.current();
+        if sequence > current + self.config.prefetch || sequence < FIRST_SEQUENCE {
+            return Response::NotFound;
+        }
+        if sequence > current {
+            thread::sleep(
+                self.available_at(sequence)
+                    .saturating_duration_since(Instant::now()),
+            );
+        }
+
+        //faults happen once, so retries of the segment succeed
+        let fault = {
+            let mut faults = lock(&self.faults);
+            faults
+                .iter()
+                .position(|(s, _)| *s == sequence)
+                .map(|i| faults.remove(i).1)
+        };
+
+        let fmp4 = VARIANTS[variant].4;
+        let body = if fmp4 {
+            mp4_box(*b"moof", self.config.size)
+        } else {
+            ts_packets(self.config.size)
+        };
+
+        match fault {
+            Some(Fault::NotFound) => Response::NotFound,
+            Some(Fault::Truncate) => Response::Truncated(body),
+            Some(Fault::Html) => Response::Ok(
+                "text/html",
+                b"<!DOCTYPE html><html><body>Access denied</body></html>".to_vec(),
+            ),
+            None => Response::Ok(if fmp4 { "video/mp4" } else { "video/mp2t" }, body),
+        }
+    }
+
+    fn multivariant_playlist(&self) -> String {
+        let mut playlist = String::from("#EXTM3U\n");
+        let _ = writeln!(
+            playlist,
+            r#"#EXT-X-TWITCH-INFO:NODE="testserver",MANIFEST-CLUSTER="test01",SUPPRESS="false",BROADCAST-ID="1",CLUSTER="test01""#,
+        );
+
+        for (index, (name, resolution, frame_rate, bandwidth, fmp4)) in VARIANTS.iter().enumerate()
+        {
+            let _ = writeln!(
+                playlist,
+                r#"#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="{name}-{index}",NAME="{name}",AUTOSELECT=YES,DEFAULT=YES"#,
+            );
+
+            let mut stream_inf = format!(
+                r#"#EXT-X-STREAM-INF:BANDWIDTH={bandwidth},CODECS="{}",VIDEO="{name}-{index}""#,
+                if *fmp4 {
+                    "av01.0.08M.08,mp4a.40.2"
+                } else {
+                    "avc1.64002A,mp4a.40.2"
+                },
+            );
+            if !resolution.is_empty() {
+                let _ = write!(
+                    stream_inf,
+                    ",RESOLUTION={resolution},FRAME-RATE={frame_rate}.000"
+                );
+            }
+
+            let _ = writeln!(playlist, "{stream_inf}\n{}/v/{index}.m3u8", self.base);
+        }
+
+        playlist
+    }
+
+    //the window of segments up to current, followed by prefetch segments
+    fn media_playlist(&self, variant: usize, current: u64, blip_end: bool) -> String {
+        let ended = lock(&self.ended).or(blip_end.then_some(current));
+        let newest = ended.map_or(current, |e| e.min(current));
+        let first = newest
+            .saturating_sub(self.config.window - 1)
+            .max(FIRST_SEQUENCE);
+        let fmp4 = VARIANTS[variant].4;
+        let extension = if fmp4 { "mp4" } else { "ts" };
+
+        let mut playlist = format!(
+            "#EXTM3U\n#EXT-X-VERSION:6\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:{first}\n",
+            self.config.duration.as_secs_f64().ceil(),
+        );
+
+        let mut generation = None;
+        for sequence in first..=newest {
+            if sequence > first && self.is_ad(sequence) != self.is_ad(sequence - 1) {
+                playlist.push_str("#EXT-X-DISCONTINUITY\n");
+            }
+
+            if fmp4 && generation != Some(self.map_generation(sequence)) {
+                let current_generation = self.map_generation(sequence);
+                let _ = writeln!(
+                    playlist,
+                    r#"#EXT-X-MAP:URI="{}/init/{variant}/{current_generation}.mp4""#,
+                    self.base,
+                );
+                generation = Some(current_generation);
+            }
+
+            let _ = writeln!(
+                playlist,
+                "#EXT-X-PROGRAM-DATE-TIME:{}\n#EXTINF:{:.3},{}\n{}/s/{variant}/{sequence}.{extension}",
+                format_date_time(self.epoch + self.offset(sequence)),
+                self.config.duration.as_secs_f64(),
+                if self.is_ad(sequence) { "Amazon|testserver" } else { "live" },
+                self.base,
+            );
+        }
+
+        if ended.is_some() {
+            playlist.push_str("#EXT-X-ENDLIST\n");
+        } else {
+            for sequence in newest + 1..=newest + self.config.prefetch {
+                let _ = writeln!(
+                    playlist,
+                    "#EXT-X-TWITCH-PREFETCH:{}/s/{variant}/{sequence}.{extension}",
+                    self.base,
+                );
+            }
+        }
+
+        playlist
+    }
+
+    fn variant(arg: &str) -> Option<usize> {
+        arg.parse().ok().filter(|i| *i < VARIANTS.len())
+    }
+
+    fn current(&self) -> u64 {
+        let elapsed = self.start.elapsed().as_secs_f64() / self.config.duration.as_secs_f64();
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let elapsed = elapsed as u64;
+        FIRST_SEQUENCE + elapsed
+    }
+
+    //first sequence that isn't in any playlist yet, not even as a prefetch segment
+    fn unpublished(&self) -> u64 {
+        self.current() + self.config.prefetch + 1
+    }
+
+    fn offset(&self, sequence: u64) -> Duration {
+        #[allow(clippy::cast_possible_truncation)]
+        let segments = (sequence - FIRST_SEQUENCE) as u32;
+        self.config.duration * segments
+    }
+
+    fn available_at(&self, sequence: u64) -> Instant {
+        self.start + self.offset(sequence)
+    }
+
+    fn is_ad(&self, sequence: u64) -> bool {
+        let scheduled = self
+            .config
+            .ads
+            .is_some_and(|(every, len)| sequence % every >= every - len);
+
+        scheduled || lock(&self.ads).iter().any(|r| r.contains(&sequence))
+    }
+
+    fn map_generation(&self, sequence: u64) -> u64 {
+        let scheduled = self.config.map_every.map_or(0, |every| sequence / every);
+        let changes = lock(&self.map_changes)
+            .iter()
+            .filter(|s| **s <= sequence)
+            .count() as u64;
+
+        scheduled + changes
+    }
+}
+
+fn control(message: &str) -> Response {
+    println!("Control: {message}");
+    Response::Ok("text/plain", format!("{message}\n").into_bytes())
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+//MPEG-TS packets with the sync byte, padded like null packets
+fn ts_packets(size: usize) -> Vec<u8> {
+    const PACKET_LEN: usize = 188;
+
+    let mut body = vec![0xff; size.div_ceil(PACKET_LEN).max(1) * PACKET_LEN];
+    for packet in body.chunks_exact_mut(PACKET_LEN) {
+        packet[..4].copy_from_slice(&[0x47, 0x01, 0x00, 0x10]);
+    }
+
+    body
+}
+
+//A single box spanning the whole body, enough for content checks
+fn mp4_box(kind: [u8; 4], size: usize) -> Vec<u8> {
+    let size = size.max(8);
+    let mut body = vec![0; size];
+    body[..4].copy_from_slice(&u32::try_from(size).unwrap_or(u32::MAX).to_be_bytes());
+    body[4..8].copy_from_slice(&kind);
+
+    body
+}
+
+//2024-01-01T12:00:00.000Z
+fn format_date_time(time: SystemTime) -> String {
+    let date_time = DateTime::utc(time);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        date_time.year,
+        date_time.month,
+        date_time.day,
+        date_time.hour,
+        date_time.minute,
+        date_time.second,
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_millis(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //started long enough ago that the sequence is past the first window
+    fn stream(spec: &str, segments: u32) -> Stream {
+        let mut stream = Stream::new(Config::new(spec).unwrap(), "http://test".to_owned());
+        stream.start -= stream.config.duration * segments + Duration::from_millis(500);
+
+        stream
+    }
+
+    fn lines_with<'a>(playlist: &'a str, prefix: &str) -> Vec<&'a str> {
+        playlist.lines().filter(|l| l.starts_with(prefix)).collect()
+    }
+
+    fn playlist(stream: &Stream, current: u64) -> String {
+        stream.media_playlist(0, current, false)
+    }
+
+    #[test]
+    fn media_playlist_window() {
+        let stream = stream("window=3 prefetch=2", 5);
+        assert_eq!(stream.current(), FIRST_SEQUENCE + 5);
+
+        let playlist = playlist(&stream, stream.current());
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:1003\n"));
+        assert!(playlist.contains("#EXT-X-TARGETDURATION:2\n"));
+        assert_eq!(
+            lines_with(&playlist, "http://test/s/0/"),
+            [
+                "http://test/s/0/1003.ts",
+                "http://test/s/0/1004.ts",
+                "http://test/s/0/1005.ts"
+            ],
+        );
+        assert_eq!(
+            lines_with(&playlist, "#EXT-X-TWITCH-PREFETCH:"),
+            [
+                "#EXT-X-TWITCH-PREFETCH:http://test/s/0/1006.ts",
+                "#EXT-X-TWITCH-PREFETCH:http://test/s/0/1007.ts",
+            ],
+        );
+        assert_eq!(lines_with(&playlist, "#EXT-X-PROGRAM-DATE-TIME:").len(), 3);
+        assert!(!playlist.contains("#EXT-X-ENDLIST"));
+    }
+
+    #[test]
+    fn media_playlist_start() {
+        //the window starts at the first sequence, fMP4 variants have init segments
+        let playlist = stream("window=6", 1).media_playlist(1, FIRST_SEQUENCE + 1, false);
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:1000\n"));
+        assert!(playlist.contains(r#"#EXT-X-MAP:URI="http://test/init/1/0.mp4""#));
+        assert_eq!(
+            lines_with(&playlist, "http://test/s/1/1001.mp4"),
+            ["http://test/s/1/1001.mp4"]
+        );
+    }
+
+    #[test]
+    fn ad_control() {
+        let stream = stream("window=3 prefetch=1", 5);
+        assert!(matches!(stream.route("/control/ad/2"), Response::Ok(..)));
+
+        //after the published segments, so the break shows up as the playlist moves on
+        let next = stream.unpublished();
+        assert_eq!(next, FIRST_SEQUENCE + 7);
+        assert!(!playlist(&stream, stream.current()).contains("Amazon"));
+
+        let playlist = playlist(&stream, next + 1);
+        assert_eq!(
+            lines_with(&playlist, "#EXTINF:"),
+            [
+                "#EXTINF:2.000,live",
+                "#EXTINF:2.000,Amazon|testserver",
+                "#EXTINF:2.000,Amazon|testserver"
+            ],
+        );
+        assert_eq!(lines_with(&playlist, "#EXT-X-DISCONTINUITY").len(), 1);
+        assert!(matches!(stream.route("/control/ad/x"), Response::NotFound));
+    }
+
+    #[test]
+    fn scheduled_ads() {
+        let stream = stream("window=6 ads=5/2", 10);
+        let playlist = playlist(&stream, FIRST_SEQUENCE + 9);
+
+        //1004 to 1009 with 1004, 1008 and 1009 being ads
+        assert_eq!(lines_with(&playlist, "#EXTINF:2.000,Amazon").len(), 3);
+        assert_eq!(lines_with(&playlist, "#EXT-X-DISCONTINUITY").len(), 2);
+    }
+
+    #[test]
+    fn fault_control() {
+        let mut stream = stream("prefetch=0", 5);
+        assert!(matches!(
+            stream.route("/control/fault/404"),
+            Response::Ok(..)
+        ));
+        assert!(matches!(
+            stream.route("/control/fault/html"),
+            Response::Ok(..)
+        ));
+        assert!(matches!(
+            stream.route("/control/fault/x"),
+            Response::NotFound
+        ));
+
+        //both apply to the first unpublished segment, one after the other
+        let next = stream.unpublished();
+        stream.start -= stream.config.duration;
+        assert_eq!(stream.current(), next);
+
+        let file = format!("{next}.ts");
+        assert!(matches!(stream.segment("0", &file), Response::NotFound));
+        assert!(matches!(
+            stream.segment("0", &file),
+            Response::Ok("text/html", _)
+        ));
+        match stream.segment("0", &file) {
+            Response::Ok("video/mp2t", body) => assert!(crate::output::is_mpegts(&body)),
+            _ => panic!("fault happened twice"),
+        }
+    }
+
+    #[test]
+    fn configured_faults() {
+        let stream = stream("prefetch=0 faults=truncate@1001,404@1002", 5);
+
+        assert!(matches!(
+            stream.segment("0", "1001.ts"),
+            Response::Truncated(_)
+        ));
+        assert!(matches!(stream.segment("0", "1002.ts"), Response::NotFound));
+        assert!(matches!(stream.segment("0", "1002.ts"), Response::Ok(..)));
+        assert!(matches!(stream.segment("0", "1003.mp4"), Response::Ok(..)));
+        assert!(matches!(
+            stream.segment("1", "1003.mp4"),
+            Response::Ok("video/mp4", _)
+        ));
+        assert!(matches!(stream.segment("0", "999.ts"), Response::NotFound));
+        assert!(matches!(stream.segment("9", "1003.ts"), Response::NotFound));
+    }
+
+    #[test]
+    fn end_control() {
+        let stream = stream("window=3 prefetch=2", 5);
+        assert!(matches!(stream.route("/control/end"), Response::Ok(..)));
+
+        //later playlists stay at the end
+        let playlist = playlist(&stream, stream.current() + 3);
+        assert!(playlist.ends_with("#EXT-X-ENDLIST\n"));
+        assert!(lines_with(&playlist, "#EXT-X-TWITCH-PREFETCH:").is_empty());
+        assert_eq!(
+            lines_with(&playlist, "http://test/s/0/").last(),
+            Some(&"http://test/s/0/1005.ts"),
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        for spec in [
+            "window=0",
+            "duration=0",
+            "ads=2/2",
+            "faults=404",
+            "size=big",
+            "nope=1",
+        ] {
+            assert!(Config::new(spec).is_err(), "{spec}");
+        }
+    }
+}
@@ -1,79 +1,717 @@
+mod cache;
+
+pub use cache::{Args as SegmentCacheArgs, SegmentCache};
+
 use std::{
-    sync::mpsc::{self, Receiver, Sender},
-    thread::{self, JoinHandle},
+    fmt::{self, Display, Formatter},
+    io::{self, Write},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TryIter},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{ensure, Context, Result};
-use log::{debug, info};
+use anyhow::{anyhow, ensure, Context, Result};
+use log::{debug, error, info, warn};
 
 use crate::{
-    http::{Agent, Method, StatusError, Url},
-    output::Writer,
+    crash,
+    http::{
+        Agent, CancelledError, DecodeError, InvalidContentError, Method, Request, StatusError, Url,
+    },
+    logger::{self, Condition},
+    memory::{Kind, Memory, Tracked},
+    output::{self, Marker, Writer},
 };
 
+use cache::CacheWriter;
+
+//consecutive panics before the worker gives up
+const MAX_PANICS: u32 = 3;
+
+//how often keepalive packets are written during ad breaks
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+//segments queued before the oldest ones are dropped, a minute of Twitch segments
+pub const DEFAULT_MAX_QUEUED: usize = 30;
+
+enum Job {
+    //init segment, sent again when the stream renditions change
+    Header(Url),
+    //drop the connection after a suspend
+    Reconnect,
+    //chapter marker, placed after the segments queued before it
+    Marker(Marker, SystemTime),
+    Segment(SegmentJob),
+}
+
+//Segment to download, everything but the URL goes to the writer and the download report
+struct SegmentJob {
+    url: Url,
+    sequence: usize,
+    duration: Duration,
+    program_date_time: Option<String>,
+    prefetch: bool,
+    _queued: Queued,
+}
+
+impl SegmentJob {
+    fn downloaded(&self, bytes: u64, retries: u64, start: Instant) -> Download {
+        Download {
+            sequence: self.sequence,
+            duration: self.duration,
+            bytes,
+            elapsed: start.elapsed(),
+            retries,
+            prefetch: self.prefetch,
+        }
+    }
+}
+
+//Segment downloaded by the worker, reported back so the handler knows if it keeps up
+#[derive(Debug)]
+pub struct Download {
+    pub sequence: usize,
+    pub duration: Duration,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub retries: u64,
+    pub prefetch: bool,
+}
+
+//Queued segment URLs, for dropping stale ones and reporting memory usage
+struct Queue {
+    segments: Tracked,
+    urls: Tracked,
+    max: usize,
+    dropped: AtomicUsize,
+}
+
+impl Queue {
+    //newer segments are still queued behind a stale one, called with the segment received
+    fn is_stale(&self) -> bool {
+        let queued = self.segments.get();
+        if queued > self.max as u64 || (self.urls.memory().over_limit() && queued > 1) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        let dropped = self.dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            warn!(
+                "Dropped {dropped} stale queued segments, buffered {}",
+                self.urls.memory().summary(),
+            );
+        }
+
+        false
+    }
+}
+
+//Released however the job leaves the channel, including when it's discarded
+struct Queued {
+    queue: Arc<Queue>,
+    len: usize,
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.queue.segments.sub(1);
+        self.queue.urls.sub(self.len);
+    }
+}
+
+impl Queued {
+    fn new(queue: &Arc<Queue>, url: &Url) -> Self {
+        queue.segments.add(1);
+        queue.urls.add(url.len());
+
+        Self {
+            queue: queue.clone(),
+            len: url.len(),
+        }
+    }
+}
+
 pub struct Worker {
     //Option to call take() because handle.join() consumes self
     handle: Option<JoinHandle<Result<()>>>,
-    url_tx: Sender<Url>,
+    url_tx: SyncSender<Job>,
+    download_rx: Receiver<Download>,
+    ad_break: Arc<AtomicBool>,
+    cancel: Arc<AtomicBool>,
+    queue: Arc<Queue>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        //close the channel so the worker exits and its writer flushes
+        self.url_tx = mpsc::sync_channel(0).0;
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
 }
 
 impl Worker {
-    pub fn spawn(writer: Writer, header_url: Option<Url>, agent: Agent) -> Result<Self> {
-        let (url_tx, url_rx): (Sender<Url>, Receiver<Url>) = mpsc::channel();
+    pub fn spawn(
+        writer: Writer,
+        header: Option<Header>,
+        max_queued: usize,
+        skip_unreachable: bool,
+        cache: Option<SegmentCache>,
+        memory: &Arc<Memory>,
+        agent: Agent,
+    ) -> Result<Self> {
+        //the handler waits for the worker once this is full
+        let (url_tx, url_rx): (SyncSender<_>, Receiver<Job>) = mpsc::sync_channel(max_queued + 1);
+        //unbounded, the worker must never wait for the handler
+        let (download_tx, download_rx) = mpsc::channel();
+        let ad_break = Arc::new(AtomicBool::default());
+        let cancel = Arc::new(AtomicBool::default());
+        let queue = Arc::new(Queue {
+            segments: Tracked::new(memory, Kind::QueuedSegments),
+            urls: Tracked::new(memory, Kind::Queue),
+            max: max_queued,
+            dropped: AtomicUsize::default(),
+        });
+        let is_mpegts_stream = header.is_none();
 
-        let handle = thread::Builder::new()
-            .name("worker".to_owned())
-            .spawn(move || -> Result<()> {
+        let handle = logger::spawn("worker", {
+            let (ad_break, cancel) = (ad_break.clone(), cancel.clone());
+            let queue = queue.clone();
+            move || -> Result<()> {
                 debug!("Starting");
 
-                let mut request = agent.binary(writer);
-                if let Some(header_url) = header_url {
-                    request.call(Method::Get, &header_url)?;
-                }
-
+                let writer = CacheWriter::new(writer, cache);
+                let mut request = segment_request(&agent, writer, header, cancel)?;
+                let mut ctx = SegmentContext::new();
+                let mut timing = Timing::new();
+                let mut panics = 0;
+                let mut not_found =
+                    Condition::new("Segment not found, skipping ahead...", "Skipping segments");
                 loop {
-                    let Ok(url) = url_rx.recv() else {
-                        debug!("Exiting");
-                        return Ok(());
+                    let mut job = match url_rx.recv_timeout(KEEPALIVE_INTERVAL) {
+                        Ok(Job::Segment(_)) if queue.is_stale() => continue,
+                        Ok(Job::Segment(job)) => job,
+                        Ok(job) => {
+                            control(&mut request, job)?;
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            if is_mpegts_stream && ad_break.load(Ordering::Relaxed) {
+                                request.writer_mut().keepalive()?;
+                            }
+
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            debug!("Exiting");
+                            return Ok(());
+                        }
+                    };
+
+                    ctx.next(job.sequence);
+                    request.writer_mut().set_segment(
+                        job.sequence,
+                        job.duration,
+                        job.program_date_time.take(),
+                    );
+                    request.set_context(ctx.to_string());
+                    crash::set_url(&job.url);
+                    let start = Instant::now();
+                    //a panic on one malformed segment shouldn't end the whole session
+                    let Ok(result) = panic::catch_unwind(AssertUnwindSafe(|| {
+                        download(&mut request, &job.url, &ctx)
+                    })) else {
+                        panics += 1;
+                        ensure!(panics < MAX_PANICS, "Worker panicked too many times");
+
+                        error!("Worker panicked on {ctx}, skipping segment");
+                        request.writer_mut().discard();
+                        request.writer_mut().discard_segment()?;
+                        request.reset();
+                        ctx.succeeded = 0;
+                        continue;
                     };
 
-                    match request.call(Method::Get, &url) {
-                        Ok(()) => (),
-                        Err(e) if StatusError::is_not_found(&e) => {
-                            info!("Segment not found, skipping ahead...");
-                            for _ in url_rx.try_iter() {} //consume all
+                    timing.add(start.elapsed(), request.writer_mut().take_blocked());
+                    match result {
+                        Ok((bytes, retries)) => {
+                            ctx.succeeded += 1;
+                            panics = 0;
+                            not_found.end();
+
+                            //fails only if the handler is gone, which ends the worker anyway
+                            let _ = download_tx.send(job.downloaded(bytes, retries, start));
+                        }
+                        Err(e) if CancelledError::is_cancelled(&e) => debug!("{e} ({ctx})"),
+                        //with fallback playlist URLs the handler switches to another server
+                        Err(e)
+                            if StatusError::is_not_found(&e)
+                                || InvalidContentError::is_invalid_content(&e)
+                                || (skip_unreachable && e.is::<io::Error>()) =>
+                        {
+                            if !StatusError::is_not_found(&e) {
+                                error!("{e}");
+                            }
+
+                            ctx.succeeded = 0;
+                            not_found.occur();
+                            skip_queued(&mut request, &url_rx)?;
+                        }
+                        Err(e) => {
+                            return Err(e.context(format!("Failed to download {ctx}: {}", job.url)))
                         }
-                        Err(e) => return Err(e),
                     }
                 }
-            })
-            .context("Failed to spawn worker")?;
+            }
+        })
+        .context("Failed to spawn worker")?;
 
         Ok(Self {
             handle: Some(handle),
             url_tx,
+            download_rx,
+            ad_break,
+            cancel,
+            queue,
         })
     }
 
-    pub fn url(&mut self, url: Url) -> Result<()> {
-        if self
-            .handle
-            .as_ref()
-            .expect("Missing worker handle")
-            .is_finished()
-        {
+    //segments downloaded since the last call
+    pub fn downloads(&self) -> TryIter<'_, Download> {
+        self.download_rx.try_iter()
+    }
+
+    pub fn set_ad_break(&self, ad_break: bool) {
+        self.ad_break.store(ad_break, Ordering::Relaxed);
+    }
+
+    //abandons the segment being downloaded, the queued ones are still downloaded
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn url(
+        &mut self,
+        url: Url,
+        sequence: usize,
+        duration: Duration,
+        program_date_time: Option<String>,
+        prefetch: bool,
+    ) -> Result<()> {
+        let queued = Queued::new(&self.queue, &url);
+        self.send(Job::Segment(SegmentJob {
+            url,
+            sequence,
+            duration,
+            program_date_time,
+            prefetch,
+            _queued: queued,
+        }))
+    }
+
+    pub fn header(&mut self, url: Url) -> Result<()> {
+        self.send(Job::Header(url))
+    }
+
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.send(Job::Reconnect)
+    }
+
+    pub fn marker(&mut self, marker: Marker) -> Result<()> {
+        self.send(Job::Marker(marker, SystemTime::now()))
+    }
+
+    fn send(&mut self, job: Job) -> Result<()> {
+        if self.handle.as_ref().is_some_and(JoinHandle::is_finished) {
             let result = self
                 .handle
                 .take()
                 .expect("Missing worker handle while joining worker")
                 .join()
-                .expect("Worker panicked");
+                .unwrap_or_else(|p| {
+                    Err(anyhow!("Worker panicked: {}", logger::panic_message(&*p)))
+                });
 
             ensure!(result.is_err(), "Worker died");
             return result;
         }
 
-        self.url_tx.send(url)?;
+        self.url_tx.send(job)?;
         Ok(())
     }
 }
+
+//Jobs the handler sent, for tests that check what was dispatched without downloading anything.
+//Downloads the handler is told about are made up by the test
+#[cfg(test)]
+pub struct Jobs(Receiver<Job>, mpsc::Sender<Download>);
+
+#[cfg(test)]
+impl Jobs {
+    //sent since the last call: segment URLs, "header <url>", marker names and "reconnect"
+    pub fn take(&self) -> Vec<String> {
+        self.0.try_iter().map(|job| Self::name(&job)).collect()
+    }
+
+    //like take, but the jobs are also written to the writer as the worker would,
+    //every segment and init segment with its URL as the content
+    pub fn write(&self, writer: &mut Writer) -> Vec<String> {
+        self.0
+            .try_iter()
+            .map(|job| {
+                let name = Self::name(&job);
+                match job {
+                    Job::Segment(job) => {
+                        writer.set_segment(job.sequence, job.duration, job.program_date_time);
+                        writer.write_all(job.url.as_bytes()).unwrap();
+                        writer.flush().unwrap();
+                    }
+                    Job::Header(url) => {
+                        writer.start_header();
+                        writer.write_all(url.as_bytes()).unwrap();
+                        writer.flush().unwrap();
+                    }
+                    Job::Marker(marker, time) => writer.marker(marker, time),
+                    Job::Reconnect => (),
+                }
+
+                name
+            })
+            .collect()
+    }
+
+    fn name(job: &Job) -> String {
+        match job {
+            Job::Segment(job) => job.url.to_string(),
+            Job::Header(url) => format!("header {}", **url),
+            Job::Marker(marker, _) => format!("marker {marker:?}"),
+            Job::Reconnect => "reconnect".to_owned(),
+        }
+    }
+
+    //a two second segment downloaded in a tenth of that
+    pub fn downloaded(&self, sequence: usize, bytes: u64) {
+        let _ = self.1.send(Download {
+            sequence,
+            duration: Duration::from_secs(2),
+            bytes,
+            elapsed: Duration::from_millis(200),
+            retries: 0,
+            prefetch: false,
+        });
+    }
+}
+
+#[cfg(test)]
+impl Worker {
+    //A worker without a thread, its jobs wait in Jobs
+    pub fn recording(max_queued: usize) -> (Self, Jobs) {
+        let (url_tx, url_rx) = mpsc::sync_channel(max_queued + 1);
+        let (download_tx, download_rx) = mpsc::channel();
+        let memory = Arc::new(Memory::new(None));
+        let worker = Self {
+            handle: None,
+            url_tx,
+            download_rx,
+            ad_break: Arc::default(),
+            cancel: Arc::default(),
+            queue: Arc::new(Queue {
+                segments: Tracked::new(&memory, Kind::QueuedSegments),
+                urls: Tracked::new(&memory, Kind::Queue),
+                max: max_queued,
+                dropped: AtomicUsize::default(),
+            }),
+        };
+
+        (worker, Jobs(url_rx, download_tx))
+    }
+}
+
+//jobs other than segments
+fn control(request: &mut Request<CacheWriter<Writer>>, job: Job) -> Result<()> {
+    match job {
+        Job::Header(url) => download_header(request, &url),
+        Job::Reconnect => {
+            request.reset();
+            Ok(())
+        }
+        Job::Marker(marker, time) => {
+            request.writer_mut().marker(marker, time);
+            Ok(())
+        }
+        Job::Segment(..) => unreachable!(),
+    }
+}
+
+//skips ahead to the newest segment, markers and headers queued meanwhile still apply
+fn skip_queued(request: &mut Request<CacheWriter<Writer>>, url_rx: &Receiver<Job>) -> Result<()> {
+    for job in url_rx.try_iter() {
+        if !matches!(job, Job::Segment(_)) {
+            control(request, job)?;
+        }
+    }
+
+    Ok(())
+}
+
+//Downloads a segment unless it's cached, returns its size and the retries it took
+fn download(
+    request: &mut Request<CacheWriter<Writer>>,
+    url: &Url,
+    ctx: &SegmentContext,
+) -> Result<(u64, u64)> {
+    if let Some(bytes) = request.writer_mut().write_cached(url)? {
+        return Ok((bytes, 0));
+    }
+
+    let result = request.call(Method::Get, url);
+    retry_uncompressed(request, url, ctx, result)?;
+
+    Ok((request.written(), request.retried()))
+}
+
+//edges sometimes serve broken gzip, the same segment is usually fine uncompressed
+fn retry_uncompressed(
+    request: &mut Request<CacheWriter<Writer>>,
+    url: &Url,
+    ctx: &SegmentContext,
+    result: Result<()>,
+) -> Result<()> {
+    match result {
+        Err(e) if DecodeError::is_decode_error(&e) => {
+            warn!("{e} ({ctx}), downloading again uncompressed");
+            request.call_uncompressed(Method::Get, url)
+        }
+        result => result,
+    }
+}
+
+//fMP4 streams start with the init segment
+fn segment_request(
+    agent: &Agent,
+    writer: CacheWriter<Writer>,
+    header: Option<Header>,
+    cancel: Arc<AtomicBool>,
+) -> Result<Request<CacheWriter<Writer>>> {
+    let mut request = agent.binary(writer);
+    if let Some(header) = header {
+        request = header.request.hand_over(request);
+        request.writer_mut().start_header();
+        request.writer_mut().write_all(&header.data)?;
+        request.writer_mut().flush()?;
+        request.set_content_check(is_fmp4);
+    } else {
+        request.set_content_check(output::is_mpegts);
+    }
+
+    request.set_cancel(cancel);
+    request.set_progress(|p| info!("{p}"));
+
+    Ok(request)
+}
+
+fn download_header(request: &mut Request<CacheWriter<Writer>>, url: &Url) -> Result<()> {
+    request.set_context("init segment".to_owned());
+    request.writer_mut().discard();
+    request.writer_mut().start_header();
+    request
+        .call(Method::Get, url)
+        .with_context(|| format!("Failed to download header segment: {url}"))
+}
+
+//Init segment downloaded before the worker starts, so the variant can still be changed
+//if it fails. Its connection is used for the segments
+pub struct Header {
+    data: Vec<u8>,
+    request: Request<Vec<u8>>,
+}
+
+impl Header {
+    pub fn fetch(url: &Url, agent: &Agent) -> Result<Self> {
+        let mut request = agent.binary(Vec::new());
+        request.set_content_check(is_fmp4);
+        request.set_context("init segment".to_owned());
+        request
+            .call(Method::Get, url)
+            .with_context(|| format!("Failed to download header segment: {url}"))?;
+
+        Ok(Self {
+            data: mem::take(request.writer_mut()),
+            request,
+        })
+    }
+}
+
+struct SegmentContext {
+    index: u64,
+    sequence: usize,
+    succeeded: u64,
+    start: Instant,
+    elapsed: Duration,
+}
+
+impl Display for SegmentContext {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "segment {} (sequence {}, {}s into session, {} succeeded since last failure)",
+            self.index,
+            self.sequence,
+            self.elapsed.as_secs(),
+            self.succeeded,
+        )
+    }
+}
+
+impl SegmentContext {
+    fn new() -> Self {
+        Self {
+            index: u64::default(),
+            sequence: usize::default(),
+            succeeded: u64::default(),
+            start: Instant::now(),
+            elapsed: Duration::default(),
+        }
+    }
+
+    fn next(&mut self, sequence: usize) {
+        self.index += 1;
+        self.sequence = sequence;
+        self.elapsed = self.start.elapsed();
+    }
+}
+
+//Share of the worker's time spent downloading, blocked writing to the outputs and waiting
+//for the next segment, tells which one is the bottleneck when playback stutters
+struct Timing {
+    start: Instant,
+    download: Duration,
+    output: Duration,
+}
+
+impl Timing {
+    const INTERVAL: Duration = Duration::from_secs(60);
+
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            download: Duration::default(),
+            output: Duration::default(),
+        }
+    }
+
+    //writes happen while downloading, so they are part of the call
+    fn add(&mut self, call: Duration, output: Duration) {
+        self.download += call.saturating_sub(output);
+        self.output += output;
+
+        let elapsed = self.start.elapsed();
+        if elapsed < Self::INTERVAL {
+            return;
+        }
+
+        let percent = |d: Duration| d.as_secs_f64() / elapsed.as_secs_f64() * 100.0;
+        let (download, output) = (percent(self.download), percent(self.output));
+        debug!(
+            "Time: {download:.0}% download, {output:.0}% output, {:.0}% idle",
+            (100.0 - download - output).max(0.0),
+        );
+
+        *self = Self::new();
+    }
+}
+
+fn is_fmp4(start: &[u8]) -> bool {
+    const BOX_TYPES: [&[u8]; 6] = [b"ftyp", b"styp", b"moof", b"sidx", b"prft", b"emsg"];
+
+    start
+        .get(4..8)
+        .is_some_and(|kind| BOX_TYPES.contains(&kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        args::{Parse, Parser},
+        http::scripted::{self, Reply, ScriptedServer},
+        output::{Args as OutputArgs, StreamEnv, Summary},
+        temp_dir::TempDir,
+    };
+
+    #[test]
+    fn skipping_keeps_control_jobs() {
+        let dir = TempDir::new("skip");
+        let (recording, chapters) = (dir.join("recording.ts"), dir.join("chapters.csv"));
+
+        let mut args = OutputArgs::default();
+        args.parse(&mut Parser::from_args(&[
+            "-r",
+            recording.to_str().unwrap(),
+            "--overwrite",
+            "--chapters",
+            chapters.to_str().unwrap(),
+        ]))
+        .unwrap();
+
+        let summary = Arc::new(Summary::new());
+        let memory = Arc::new(Memory::new(None));
+        let writer = Writer::new(
+            &args,
+            None,
+            &StreamEnv::new("", "channel", None, None),
+            summary,
+            memory.clone(),
+        )
+        .unwrap();
+
+        //the segment queued behind the missing one is skipped, so only the header follows
+        let header = [0x47; 2 * 188];
+        let server = ScriptedServer::new([
+            Reply::Delayed(
+                Duration::from_millis(200),
+                scripted::response("404 Not Found", "", b"not found"),
+            ),
+            Reply::Full(scripted::response("200 OK", "", &header)),
+        ]);
+
+        let mut worker = Worker::spawn(
+            writer,
+            None,
+            DEFAULT_MAX_QUEUED,
+            false,
+            None,
+            &memory,
+            scripted::agent(),
+        )
+        .unwrap();
+        let two_seconds = Duration::from_secs(2);
+        worker
+            .url(server.url("1.ts"), 1, two_seconds, None, false)
+            .unwrap();
+        worker.marker(Marker::AdBreak).unwrap();
+        worker.header(server.url("init.ts")).unwrap();
+        worker
+            .url(server.url("2.ts"), 2, two_seconds, None, false)
+            .unwrap();
+        drop(worker);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].starts_with("GET /init.ts "));
+        assert_eq!(fs::read(&recording).unwrap(), header);
+        assert!(fs::read_to_string(&chapters)
+            .unwrap()
+            .contains(",ad_break\n"));
+    }
+}
@@ -0,0 +1,489 @@
+use std::{
+    fs::{self, File},
+    hash::{DefaultHasher, Hasher},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+
+use crate::{
+    args::{self, Parse, Parser},
+    http::Url,
+};
+
+//entries older than this are misses, live segments are only requested for a few seconds
+const TTL: Duration = Duration::from_secs(5 * 60);
+
+//length prefix of an entry, a shorter file was cut off while being written or evicted
+const PREFIX_LEN: usize = size_of::<u64>();
+
+const EXTENSION: &str = "seg";
+const TEMP_EXTENSION: &str = "tmp";
+
+#[derive(Debug)]
+pub struct Args {
+    dir: Option<PathBuf>,
+    max_size: u64,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            dir: Option::default(),
+            max_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
+impl Parse for Args {
+    fn parse(&mut self, parser: &mut Parser) -> Result<()> {
+        parser.parse_fn(&mut self.dir, "--segment-cache-dir", |a| Ok(Some(a.into())))?;
+        parser.parse_fn(&mut self.max_size, "--segment-cache-size", args::parse_size)?;
+
+        Ok(())
+    }
+}
+
+//Segments shared with other instances on the same machine through a directory.
+//Entries are written to a temporary file and renamed, so two instances downloading
+//the same segment both finish and the last rename wins
+pub struct SegmentCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl SegmentCache {
+    pub fn new(args: &Args) -> Result<Option<Self>> {
+        let Some(dir) = &args.dir else {
+            return Ok(None);
+        };
+
+        fs::create_dir_all(dir).context("Failed to create segment cache directory")?;
+        info!("Caching segments in: {}", dir.display());
+
+        Ok(Some(Self {
+            dir: dir.clone(),
+            max_size: args.max_size,
+        }))
+    }
+
+    //None if missing, expired or cut off
+    fn read(&self, key: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut file = match File::open(self.path(key, EXTENSION)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let metadata = file.metadata()?;
+        if !is_fresh(&metadata) {
+            return Ok(None);
+        }
+
+        let mut prefix = [0; PREFIX_LEN];
+        file.read_exact(&mut prefix)?;
+        let len = u64::from_le_bytes(prefix);
+        if metadata.len().checked_sub(PREFIX_LEN as u64) != Some(len) {
+            debug!(
+                "Segment cache entry {key:016x} is {} bytes, expected {len}",
+                metadata.len()
+            );
+            return Ok(None);
+        }
+
+        let mut buf = Vec::with_capacity(usize::try_from(len).unwrap_or_default());
+        file.read_to_end(&mut buf)?;
+
+        Ok(Some(buf))
+    }
+
+    fn create(&self, key: u64) -> io::Result<Entry> {
+        //unique among instances and among entries of this one left behind by a crash
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let temp = self.dir.join(format!(
+            "{key:016x}.{}.{}.{TEMP_EXTENSION}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed),
+        ));
+
+        let mut file = File::create_new(&temp)?;
+        file.write_all(&[0; PREFIX_LEN])?;
+
+        Ok(Entry {
+            file,
+            temp,
+            path: self.path(key, EXTENSION),
+            len: 0,
+        })
+    }
+
+    //removes expired entries and temporary files, then the oldest entries until under the limit
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            let extension = path.extension().unwrap_or_default();
+            if extension != EXTENSION && extension != TEMP_EXTENSION {
+                continue;
+            }
+
+            //another instance may have removed it since the directory was read
+            let Ok(metadata) = fs::metadata(&path) else {
+                continue;
+            };
+
+            if is_fresh(&metadata) {
+                if extension == EXTENSION {
+                    entries.push((metadata.modified()?, metadata.len(), path));
+                }
+            } else {
+                remove(&path);
+            }
+        }
+
+        let mut size = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+        if size <= self.max_size {
+            return Ok(());
+        }
+
+        entries.sort_unstable_by_key(|(modified, ..)| *modified);
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+
+            debug!("Evicting {}", path.display());
+            remove(&path);
+            size -= len;
+        }
+
+        Ok(())
+    }
+
+    fn path(&self, key: u64, extension: &str) -> PathBuf {
+        self.dir.join(format!("{key:016x}.{extension}"))
+    }
+
+    //instances of a channel get the same segment paths from different edge servers
+    fn key(url: &Url) -> Result<u64> {
+        let path = url.path()?;
+        let mut hasher = DefaultHasher::new();
+        hasher.write(path.split('?').next().unwrap_or(path).as_bytes());
+
+        Ok(hasher.finish())
+    }
+}
+
+//Segment being downloaded, the temporary file is removed unless it was committed
+struct Entry {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    len: u64,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        remove(&self.temp);
+    }
+}
+
+impl Entry {
+    fn commit(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&self.len.to_le_bytes())?;
+        fs::rename(&self.temp, &self.path)
+    }
+}
+
+//Output writer that also writes downloaded segments to the cache
+pub struct CacheWriter<W: Write> {
+    inner: W,
+    cache: Option<SegmentCache>,
+    entry: Option<Entry>,
+}
+
+impl<W: Write> Deref for CacheWriter<W> {
+    type Target = W;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<W: Write> DerefMut for CacheWriter<W> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl<W: Write> Write for CacheWriter<W> {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        unreachable!();
+    }
+
+    //only called once the whole segment was downloaded
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        if let (Some(cache), Some(entry)) = (&self.cache, self.entry.take()) {
+            if let Err(e) = entry.commit().and_then(|()| cache.evict()) {
+                warn!("Failed to write segment cache: {e}");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        if let Some(entry) = &mut self.entry {
+            if let Err(e) = entry.file.write_all(buf) {
+                warn!("Failed to write segment cache: {e}");
+                self.entry = None;
+            } else {
+                entry.len += buf.len() as u64;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: Write> CacheWriter<W> {
+    pub const fn new(inner: W, cache: Option<SegmentCache>) -> Self {
+        Self {
+            inner,
+            cache,
+            entry: None,
+        }
+    }
+
+    //Writes the segment if it's cached and returns its size,
+    //otherwise the next download is written to the cache
+    pub fn write_cached(&mut self, url: &Url) -> Result<Option<u64>> {
+        self.entry = None;
+        let Some(cache) = &self.cache else {
+            return Ok(None);
+        };
+
+        let key = SegmentCache::key(url)?;
+        match cache.read(key) {
+            Ok(Some(buf)) => {
+                debug!("Segment cache hit {key:016x}");
+                self.inner.write_all(&buf)?;
+                self.inner.flush()?;
+
+                return Ok(Some(buf.len() as u64));
+            }
+            Ok(None) => (),
+            Err(e) => warn!("Failed to read segment cache: {e}"),
+        }
+
+        match cache.create(key) {
+            Ok(entry) => self.entry = Some(entry),
+            Err(e) => warn!("Failed to write segment cache: {e}"),
+        }
+
+        Ok(None)
+    }
+
+    //drops a partially cached segment so the next download isn't appended to it
+    pub fn discard(&mut self) {
+        self.entry = None;
+    }
+}
+
+fn is_fresh(metadata: &fs::Metadata) -> bool {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|m| SystemTime::now().duration_since(m).ok())
+        .is_some_and(|age| age < TTL)
+}
+
+fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            debug!("Failed to remove {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_dir::TempDir;
+
+    fn cache_writer(dir: &TempDir, max_size: u64) -> CacheWriter<Vec<u8>> {
+        let args = Args {
+            dir: Some(dir.to_path_buf()),
+            max_size,
+        };
+
+        CacheWriter::new(Vec::new(), SegmentCache::new(&args).unwrap())
+    }
+
+    fn entry(dir: &TempDir, url: &Url) -> PathBuf {
+        dir.join(format!(
+            "{:016x}.{EXTENSION}",
+            SegmentCache::key(url).unwrap()
+        ))
+    }
+
+    fn url(segment: &str) -> Url {
+        format!("https://edge.example.com/v1/segment/{segment}?token=abc").into()
+    }
+
+    //what the worker does for a segment, returns whether it was a cache hit
+    fn download(writer: &mut CacheWriter<Vec<u8>>, url: &Url, body: &[u8]) -> bool {
+        if writer.write_cached(url).unwrap().is_some() {
+            return true;
+        }
+
+        writer.write_all(body).unwrap();
+        writer.flush().unwrap();
+        false
+    }
+
+    fn age(path: &Path, age: Duration) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    #[test]
+    fn disabled_without_dir() {
+        assert!(SegmentCache::new(&Args::default()).unwrap().is_none());
+
+        let mut writer = CacheWriter::new(Vec::new(), None);
+        assert!(!download(&mut writer, &url("1.ts"), b"segment"));
+        assert!(!download(&mut writer, &url("1.ts"), b"segment"));
+        assert_eq!(*writer, b"segmentsegment");
+    }
+
+    #[test]
+    fn hit_and_miss() {
+        let dir = TempDir::new("segment-cache-hit");
+        let mut first = cache_writer(&dir, u64::MAX);
+        let mut second = cache_writer(&dir, u64::MAX);
+
+        assert!(!download(&mut first, &url("1.ts"), b"first"));
+        //other edges and tokens serve the same segment
+        let other_edge = Url::from("https://other.example.com/v1/segment/1.ts?token=xyz");
+        assert!(download(&mut second, &other_edge, b"unused"));
+        assert!(!download(&mut second, &url("2.ts"), b"second"));
+        assert_eq!(*first, b"first");
+        assert_eq!(*second, b"firstsecond");
+
+        assert_eq!(dir.entries().len(), 2);
+        assert_eq!(
+            fs::read(entry(&dir, &url("1.ts"))).unwrap(),
+            [&5_u64.to_le_bytes(), b"first".as_slice()].concat()
+        );
+    }
+
+    #[test]
+    fn cut_off_entries_are_misses() {
+        let dir = TempDir::new("segment-cache-cut-off");
+        let mut writer = cache_writer(&dir, u64::MAX);
+        let url = url("1.ts");
+        download(&mut writer, &url, b"segment");
+
+        let entry = entry(&dir, &url);
+        let data = fs::read(&entry).unwrap();
+        fs::write(&entry, &data[..data.len() - 1]).unwrap();
+        assert!(!download(&mut writer, &url, b"segment"));
+
+        //shorter than the length prefix
+        fs::write(&entry, b"abc").unwrap();
+        let mut writer = cache_writer(&dir, u64::MAX);
+        assert!(writer.write_cached(&url).is_ok_and(|r| r.is_none()));
+        assert_eq!(*writer, b"");
+    }
+
+    #[test]
+    fn expired_entries_are_misses() {
+        let dir = TempDir::new("segment-cache-expired");
+        let mut writer = cache_writer(&dir, u64::MAX);
+        let url = url("1.ts");
+        download(&mut writer, &url, b"old");
+
+        age(&entry(&dir, &url), TTL + Duration::from_secs(1));
+        assert!(!download(&mut writer, &url, b"new"));
+        assert!(download(&mut writer, &url, b"unused"));
+        assert_eq!(*writer, b"oldnewnew");
+    }
+
+    #[test]
+    fn concurrent_writes() {
+        let dir = TempDir::new("segment-cache-race");
+        let mut first = cache_writer(&dir, u64::MAX);
+        let mut second = cache_writer(&dir, u64::MAX);
+        let url = url("1.ts");
+
+        //both miss and download, the last to finish replaces the entry
+        assert!(first.write_cached(&url).unwrap().is_none());
+        assert!(second.write_cached(&url).unwrap().is_none());
+        first.write_all(b"from first").unwrap();
+        second.write_all(b"from second").unwrap();
+        first.flush().unwrap();
+        second.flush().unwrap();
+
+        assert_eq!(dir.entries().len(), 1);
+        let mut third = cache_writer(&dir, u64::MAX);
+        assert!(download(&mut third, &url, b"unused"));
+        assert_eq!(*third, b"from second");
+    }
+
+    #[test]
+    fn discarded_download_isnt_cached() {
+        let dir = TempDir::new("segment-cache-discarded");
+        let mut writer = cache_writer(&dir, u64::MAX);
+        let url = url("1.ts");
+
+        assert!(writer.write_cached(&url).unwrap().is_none());
+        writer.write_all(b"partial").unwrap();
+        writer.discard();
+        assert!(dir.entries().is_empty());
+
+        //the retry is cached from the start
+        assert!(!download(&mut writer, &url, b"segment"));
+        assert!(download(&mut writer, &url, b"unused"));
+        assert_eq!(*writer, b"partialsegmentsegment");
+    }
+
+    #[test]
+    fn eviction() {
+        let dir = TempDir::new("segment-cache-eviction");
+        //two entries of 8 + 8 bytes fit
+        let mut writer = cache_writer(&dir, 32);
+        for (i, segment) in ["1.ts", "2.ts"].iter().enumerate() {
+            download(&mut writer, &url(segment), b"12345678");
+            age(
+                &entry(&dir, &url(segment)),
+                Duration::from_secs(60 - i as u64),
+            );
+        }
+        fs::write(dir.join("unrelated.txt"), b"kept").unwrap();
+        //left behind by a crashed instance
+        let temp = dir.join(format!("0.1.0.{TEMP_EXTENSION}"));
+        fs::write(&temp, b"").unwrap();
+        age(&temp, TTL + Duration::from_secs(1));
+
+        download(&mut writer, &url("3.ts"), b"12345678");
+        assert!(!entry(&dir, &url("1.ts")).exists());
+        assert!(entry(&dir, &url("2.ts")).exists());
+        assert!(entry(&dir, &url("3.ts")).exists());
+        assert!(!temp.exists());
+        assert!(dir.join("unrelated.txt").exists());
+    }
+}